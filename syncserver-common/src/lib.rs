@@ -3,6 +3,7 @@ extern crate slog_scope;
 
 mod metrics;
 pub mod middleware;
+mod prometheus;
 mod tags;
 
 use std::{
@@ -18,11 +19,13 @@ use serde_json::Value;
 use sha2::Sha256;
 
 pub use metrics::{MetricError, Metrics, metrics_from_opts};
+pub use prometheus::PrometheusRecorder;
 pub use tags::Taggable;
 
 // header statics must be lower case, numbers and symbols per the RFC spec. This reduces chance of error.
 pub static X_LAST_MODIFIED: &str = "x-last-modified";
 pub static X_WEAVE_TIMESTAMP: &str = "x-weave-timestamp";
+pub static X_WEAVE_BACKOFF: &str = "x-weave-backoff";
 pub static X_WEAVE_NEXT_OFFSET: &str = "x-weave-next-offset";
 pub static X_WEAVE_RECORDS: &str = "x-weave-records";
 pub static X_WEAVE_BYTES: &str = "x-weave-bytes";
@@ -1,13 +1,17 @@
 use std::collections::HashMap;
 use std::net::UdpSocket;
+use std::panic::RefUnwindSafe;
 use std::sync::Arc;
 use std::time::Instant;
 
 use cadence::{
-    BufferedUdpMetricSink, Counted, Metric, NopMetricSink, QueuingMetricSink, StatsdClient, Timed,
+    BufferedUdpMetricSink, Counted, Metric, MetricSink, NopMetricSink, QueuingMetricSink,
+    StatsdClient, StatsdClientBuilder, Timed,
 };
 use slog::{KV, Key, Record};
 
+use crate::prometheus::{PrometheusRecorder, PrometheusSink};
+
 pub use cadence::MetricError;
 
 #[derive(Debug, Clone)]
@@ -133,10 +137,13 @@ impl Metrics {
     }
 }
 
+/// Build the client metrics are sent through: to statsd when `host` is set,
+/// and also recorded by `prometheus` when set.
 pub fn metrics_from_opts(
     label: &str,
     host: Option<&str>,
     port: u16,
+    prometheus: Option<&PrometheusRecorder>,
 ) -> Result<Arc<StatsdClient>, MetricError> {
     let builder = if let Some(statsd_host) = host {
         let socket = UdpSocket::bind("0.0.0.0:0")?;
@@ -145,9 +152,9 @@ pub fn metrics_from_opts(
         let host = (statsd_host, port);
         let udp_sink = BufferedUdpMetricSink::from(host, socket)?;
         let sink = QueuingMetricSink::from(udp_sink);
-        StatsdClient::builder(label, sink)
+        client_builder(label, sink, prometheus)
     } else {
-        StatsdClient::builder(label, NopMetricSink)
+        client_builder(label, NopMetricSink, prometheus)
     };
     Ok(Arc::new(
         builder
@@ -158,6 +165,20 @@ pub fn metrics_from_opts(
     ))
 }
 
+fn client_builder<S>(
+    label: &str,
+    sink: S,
+    prometheus: Option<&PrometheusRecorder>,
+) -> StatsdClientBuilder
+where
+    S: MetricSink + Sync + Send + RefUnwindSafe + 'static,
+{
+    match prometheus {
+        Some(recorder) => StatsdClient::builder(label, PrometheusSink::new(sink, recorder.clone())),
+        None => StatsdClient::builder(label, sink),
+    }
+}
+
 impl From<&Arc<StatsdClient>> for Metrics {
     fn from(client: &Arc<StatsdClient>) -> Self {
         Metrics {
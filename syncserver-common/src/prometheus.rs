@@ -0,0 +1,347 @@
+//! A Prometheus exporter for the metrics sent through a [cadence::StatsdClient]
+//! (see [crate::metrics_from_opts]).
+//!
+//! Every statsd line is recorded as it's emitted: counters as counters (scaled
+//! by their sample rate), timers as histograms (in seconds) and gauges as
+//! gauges, with their tags as labels. The metrics are exported in the
+//! Prometheus text format by [PrometheusRecorder::render].
+//!
+//! Tags with unbounded values (see [DROPPED_TAGS]) aren't recorded, and each
+//! metric is limited to [MAX_SERIES] label sets: further ones are dropped.
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    io,
+    sync::{Arc, Mutex},
+};
+
+use cadence::MetricSink;
+
+/// The histogram buckets, in seconds (the Prometheus client defaults)
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Tags too varied to be labels (e.g. every browser version seen): a series
+/// would be kept for each of their values
+const DROPPED_TAGS: &[&str] = &["ua.browser.ver", "ua.os.ver"];
+
+/// The max number of label sets recorded per metric
+const MAX_SERIES: usize = 1000;
+
+/// Sorted label pairs
+type Labels = Vec<(String, String)>;
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// The number of observations per bucket (not cumulative)
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = BUCKETS.iter().position(|bound| value <= *bound) {
+            self.buckets[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    counters: BTreeMap<String, BTreeMap<Labels, f64>>,
+    gauges: BTreeMap<String, BTreeMap<Labels, f64>>,
+    histograms: BTreeMap<String, BTreeMap<Labels, Histogram>>,
+}
+
+/// Accumulates the statsd metrics for the `/__metrics__` endpoint. Clones
+/// share the same metrics.
+#[derive(Clone, Debug, Default)]
+pub struct PrometheusRecorder(Arc<Mutex<Registry>>);
+
+impl PrometheusRecorder {
+    /// Record a statsd line, e.g. `syncstorage.request:1|c|#status:200`.
+    /// Unsupported metric types (sets, histograms, distributions) are ignored.
+    pub fn record(&self, line: &str) {
+        let Some(metric) = parse(line) else {
+            return;
+        };
+        let Ok(mut registry) = self.0.lock() else {
+            return;
+        };
+        let registry = &mut *registry;
+        match metric.kind {
+            "c" => {
+                let name = format!("{}_total", metric.name);
+                if let Some(counter) = series(&mut registry.counters, name, metric.labels) {
+                    *counter += metric.value / metric.rate;
+                }
+            }
+            "g" => {
+                if let Some(gauge) = series(&mut registry.gauges, metric.name, metric.labels) {
+                    *gauge = metric.value;
+                }
+            }
+            "ms" => {
+                let name = format!("{}_seconds", metric.name);
+                if let Some(histogram) = series(&mut registry.histograms, name, metric.labels) {
+                    histogram.observe(metric.value / 1000.0);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Render the metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let Ok(registry) = self.0.lock() else {
+            return out;
+        };
+        for (kind, families) in [("counter", &registry.counters), ("gauge", &registry.gauges)] {
+            for (name, series) in families {
+                let _ = writeln!(out, "# TYPE {} {}", name, kind);
+                for (labels, value) in series {
+                    let _ = writeln!(out, "{}{} {}", name, format_labels(labels, None), value);
+                }
+            }
+        }
+        for (name, series) in &registry.histograms {
+            let _ = writeln!(out, "# TYPE {} histogram", name);
+            for (labels, histogram) in series {
+                let mut cumulative = 0;
+                for (bound, count) in BUCKETS.iter().zip(histogram.buckets) {
+                    cumulative += count;
+                    let le = bound.to_string();
+                    let labels = format_labels(labels, Some(&le));
+                    let _ = writeln!(out, "{}_bucket{} {}", name, labels, cumulative);
+                }
+                let inf = format_labels(labels, Some("+Inf"));
+                let labels = format_labels(labels, None);
+                let _ = writeln!(out, "{}_bucket{} {}", name, inf, histogram.count);
+                let _ = writeln!(out, "{}_sum{} {}", name, labels, histogram.sum);
+                let _ = writeln!(out, "{}_count{} {}", name, labels, histogram.count);
+            }
+        }
+        out
+    }
+}
+
+/// A [MetricSink] recording every metric before passing it on to `inner`
+pub struct PrometheusSink<S> {
+    inner: S,
+    recorder: PrometheusRecorder,
+}
+
+impl<S> PrometheusSink<S> {
+    pub fn new(inner: S, recorder: PrometheusRecorder) -> Self {
+        Self { inner, recorder }
+    }
+}
+
+impl<S: MetricSink> MetricSink for PrometheusSink<S> {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        self.recorder.record(metric);
+        self.inner.emit(metric)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The metric's series with the given labels, or `None` when it has reached
+/// [MAX_SERIES] others
+fn series<T: Default>(
+    families: &mut BTreeMap<String, BTreeMap<Labels, T>>,
+    name: String,
+    labels: Labels,
+) -> Option<&mut T> {
+    let family = families.entry(name).or_default();
+    if family.len() >= MAX_SERIES && !family.contains_key(&labels) {
+        return None;
+    }
+    Some(family.entry(labels).or_default())
+}
+
+struct ParsedMetric<'a> {
+    name: String,
+    value: f64,
+    kind: &'a str,
+    /// The fraction of the metric's events that were sent
+    rate: f64,
+    labels: Labels,
+}
+
+/// Parse a statsd line: `name:value|type[|@rate][|#key:value,...]`
+fn parse(line: &str) -> Option<ParsedMetric<'_>> {
+    let (name, rest) = line.split_once(':')?;
+    let mut fields = rest.split('|');
+    let value = fields.next()?.parse().ok()?;
+    let kind = fields.next()?;
+    let (mut rate, mut labels) = (1.0, Labels::new());
+    for field in fields {
+        if let Some(sample_rate) = field.strip_prefix('@') {
+            rate = sample_rate
+                .parse()
+                .ok()
+                .filter(|rate: &f64| *rate > 0.0 && *rate <= 1.0)
+                .unwrap_or(1.0);
+        } else if let Some(tags) = field.strip_prefix('#') {
+            labels = tags
+                .split(',')
+                .filter(|tag| !tag.is_empty())
+                .map(|tag| tag.split_once(':').unwrap_or((tag, "")))
+                .filter(|(key, _)| !DROPPED_TAGS.contains(key))
+                .map(|(key, value)| (sanitize(key, false), value.to_owned()))
+                .collect();
+        }
+    }
+    labels.sort();
+    labels.dedup_by(|a, b| a.0 == b.0);
+    Some(ParsedMetric {
+        name: sanitize(name, true),
+        value,
+        kind,
+        rate,
+        labels,
+    })
+}
+
+/// Replace the characters Prometheus doesn't allow in metric (or label) names
+/// with underscores, e.g. `storage.get_collection` -> `storage_get_collection`
+fn sanitize(name: &str, metric_name: bool) -> String {
+    let mut sanitized: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || (metric_name && c == ':') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if sanitized.starts_with(|c: char| c.is_ascii_digit()) {
+        sanitized.insert(0, '_');
+    }
+    sanitized
+}
+
+fn format_labels(labels: &Labels, le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| {
+            let value = value
+                .replace('\\', r"\\")
+                .replace('"', "\\\"")
+                .replace('\n', r"\n");
+            format!("{}=\"{}\"", key, value)
+        })
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cadence::{Counted, Gauged, NopMetricSink, StatsdClient, Timed};
+
+    use super::*;
+
+    #[test]
+    fn records_statsd_metrics() {
+        let recorder = PrometheusRecorder::default();
+        let client = StatsdClient::builder(
+            "syncstorage",
+            PrometheusSink::new(NopMetricSink, recorder.clone()),
+        )
+        .build();
+
+        client
+            .count_with_tags("request.count", 2)
+            .with_tag("status", "200")
+            .send();
+        client
+            .count_with_tags("request.count", 3)
+            .with_tag("status", "200")
+            .send();
+        client.count("request.count", 1).unwrap();
+        client
+            .gauge_with_tags("storage.pool.connections.idle", 4)
+            .with_tag("hostname", "a\"b")
+            .send();
+        client.gauge("storage.pool.connections.idle", 5).unwrap();
+        client.gauge("storage.pool.connections.idle", 6).unwrap();
+        client.time("storage.get_collection", 30).unwrap();
+        client.time("storage.get_collection", 2000).unwrap();
+
+        let rendered = recorder.render();
+        let expected = [
+            "# TYPE syncstorage_request_count_total counter",
+            "syncstorage_request_count_total 1",
+            "syncstorage_request_count_total{status=\"200\"} 5",
+            "# TYPE syncstorage_storage_pool_connections_idle gauge",
+            "syncstorage_storage_pool_connections_idle 6",
+            "syncstorage_storage_pool_connections_idle{hostname=\"a\\\"b\"} 4",
+            "# TYPE syncstorage_storage_get_collection_seconds histogram",
+            "syncstorage_storage_get_collection_seconds_bucket{le=\"0.025\"} 0",
+            "syncstorage_storage_get_collection_seconds_bucket{le=\"0.05\"} 1",
+            "syncstorage_storage_get_collection_seconds_bucket{le=\"2.5\"} 2",
+            "syncstorage_storage_get_collection_seconds_bucket{le=\"+Inf\"} 2",
+            "syncstorage_storage_get_collection_seconds_sum 2.03",
+            "syncstorage_storage_get_collection_seconds_count 2",
+        ];
+        for line in expected {
+            assert!(rendered.lines().any(|l| l == line), "{line}\n{rendered}");
+        }
+    }
+
+    #[test]
+    fn limits_series() {
+        let recorder = PrometheusRecorder::default();
+        recorder.record("token.count:1|c|@0.25|#ua.browser.ver:120.0,ua.name:firefox");
+        recorder.record("token.count:1|c|#ua.browser.ver:121.0,ua.name:firefox|@0.5");
+        for i in 0..MAX_SERIES + 10 {
+            recorder.record(&format!("request.count:1|c|#path:/{i}"));
+        }
+        recorder.record("request.count:1|c|#path:/0");
+
+        let rendered = recorder.render();
+        // Sampled counts are scaled up and version tags dropped
+        assert!(
+            rendered
+                .lines()
+                .any(|l| l == "token_count_total{ua_name=\"firefox\"} 6"),
+            "{rendered}"
+        );
+        assert!(!rendered.contains("ua_browser_ver"));
+        // Existing series are still updated past the limit, but no others added
+        let series = rendered
+            .lines()
+            .filter(|l| l.starts_with("request_count_total"));
+        assert_eq!(series.count(), MAX_SERIES);
+        assert!(rendered.contains("request_count_total{path=\"/0\"} 2"));
+        assert!(!rendered.contains(&format!("path=\"/{MAX_SERIES}\"")));
+    }
+
+    #[test]
+    fn sanitizes_names() {
+        assert_eq!(
+            sanitize("storage.get-collection", true),
+            "storage_get_collection"
+        );
+        assert_eq!(sanitize("1st:metric", true), "_1st:metric");
+        assert_eq!(sanitize("ua.os:family", false), "ua_os_family");
+        assert!(parse("not a metric").is_none());
+        assert!(parse("metric:abc|c").is_none());
+    }
+}
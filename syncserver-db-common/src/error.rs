@@ -15,7 +15,6 @@ pub struct SqlError {
 }
 
 impl SqlError {
-    #[cfg(debug_assertions)]
     pub fn is_diesel_not_found(&self) -> bool {
         matches!(
             self.kind,
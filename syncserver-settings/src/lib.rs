@@ -2,13 +2,14 @@
 extern crate slog_scope;
 
 use config::{Config, ConfigError, Environment, File};
+use ipnet::IpNet;
 use serde::{Deserialize, Deserializer};
 use syncserver_common::{
     X_LAST_MODIFIED, X_VERIFY_CODE, X_WEAVE_BYTES, X_WEAVE_NEXT_OFFSET, X_WEAVE_RECORDS,
     X_WEAVE_TIMESTAMP, X_WEAVE_TOTAL_BYTES, X_WEAVE_TOTAL_RECORDS,
 };
 use syncstorage_settings::Settings as SyncstorageSettings;
-use tokenserver_settings::Settings as TokenserverSettings;
+use tokenserver_settings::{IdentityProviderType, Settings as TokenserverSettings};
 use url::Url;
 
 static PREFIX: &str = "sync";
@@ -32,6 +33,9 @@ pub struct Settings {
     /// Whether to  include the hostname in metrics, which increases cardinality significantly in
     /// prod.
     pub include_hostname_tag: bool,
+    /// Whether to serve the metrics in the Prometheus text format on `/__metrics__` (in addition
+    /// to sending them to statsd).
+    pub prometheus_enabled: bool,
 
     /// Environment of Sync application (Stage, Prod, Dev, etc).
     pub environment: String,
@@ -42,6 +46,21 @@ pub struct Settings {
     pub cors_allowed_methods: Option<Vec<String>>,
     pub cors_allowed_headers: Option<Vec<String>>,
 
+    /// Storage response compression settings
+    pub compression: Compression,
+
+    /// Native HTTPS settings
+    pub tls: Tls,
+
+    /// Networks (in CIDR notation) of the proxies whose `Forwarded` and
+    /// `X-Forwarded-*` headers are trusted to describe the host, port and
+    /// scheme a request was originally sent to. They're ignored from other
+    /// peers.
+    pub trusted_proxies: Vec<IpNet>,
+    /// The canonical external URL of the server. When set, Hawk requests are
+    /// verified against its host and port rather than the request's headers.
+    pub public_url: Option<String>,
+
     /// The maximum number of blocking threads that can be used by the worker.
     /// Note, we don't want "Option" here because we use this as part of the
     /// metric periodic reporter. The default value is 512.
@@ -124,6 +143,7 @@ impl Settings {
         };
 
         let fxa_threads = if self.tokenserver.enabled
+            && self.tokenserver.identity_provider == IdentityProviderType::Fxa
             && self.tokenserver.fxa_oauth_primary_jwk.is_none()
             && self.tokenserver.fxa_oauth_secondary_jwk.is_none()
         {
@@ -161,6 +181,52 @@ impl Settings {
             ));
         }
 
+        // An empty token would let any `Authorization: Bearer ` request in
+        let empty = |token: &Option<String>| token.as_ref().is_some_and(|t| t.trim().is_empty());
+        if empty(&self.syncstorage.admin_token) {
+            return Err(ConfigError::Message(
+                "SYNC_SYNCSTORAGE__ADMIN_TOKEN must not be empty".to_owned(),
+            ));
+        }
+        if empty(&self.tokenserver.admin_token) {
+            return Err(ConfigError::Message(
+                "SYNC_TOKENSERVER__ADMIN_TOKEN must not be empty".to_owned(),
+            ));
+        }
+
+        if let Some(route) = self
+            .compression
+            .disabled_routes
+            .iter()
+            .find(|route| !COMPRESSIBLE_ROUTES.contains(&route.as_str()))
+        {
+            return Err(ConfigError::Message(format!(
+                "Invalid SYNC_COMPRESSION__DISABLED_ROUTES: unknown route {route:?}"
+            )));
+        }
+
+        if self.tls.cert_path.is_some() != self.tls.key_path.is_some() {
+            return Err(ConfigError::Message(
+                "SYNC_TLS__CERT_PATH and SYNC_TLS__KEY_PATH must be set together".to_owned(),
+            ));
+        }
+        if self.tls.port.is_some_and(|port| port == self.port) {
+            return Err(ConfigError::Message(
+                "SYNC_TLS__PORT must differ from SYNC_PORT".to_owned(),
+            ));
+        }
+
+        if let Some(public_url) = &self.public_url {
+            let url = Url::parse(public_url)
+                .map_err(|e| ConfigError::Message(format!("Invalid SYNC_PUBLIC_URL: {e}")))?;
+            if !["http", "https"].contains(&url.scheme()) || url.host_str().is_none() {
+                return Err(ConfigError::Message(
+                    "Invalid SYNC_PUBLIC_URL: requires an \"https\"/\"http\" scheme and a host"
+                        .to_owned(),
+                ));
+            }
+        }
+
         if let Some(init_node_url) = &self.tokenserver.init_node_url {
             let url = Url::parse(init_node_url).map_err(|e| {
                 ConfigError::Message(format!("Invalid SYNC_TOKENSERVER__INIT_NODE_URL: {e}"))
@@ -173,6 +239,41 @@ impl Settings {
                 ));
             }
         }
+
+        if self.tokenserver.enabled
+            && self.tokenserver.identity_provider == IdentityProviderType::Oidc
+        {
+            let oidc = &self.tokenserver.oidc;
+            if oidc.issuer.trim().is_empty() {
+                return Err(ConfigError::Message(
+                    "SYNC_TOKENSERVER__OIDC__ISSUER must be set".to_owned(),
+                ));
+            }
+            // Otherwise tokens the provider issued to any of its clients would be accepted
+            if oidc.audience.as_ref().is_none_or(|aud| aud.trim().is_empty()) {
+                return Err(ConfigError::Message(
+                    "SYNC_TOKENSERVER__OIDC__AUDIENCE must be set".to_owned(),
+                ));
+            }
+        }
+
+        // Sync 1.5 is configured by the top-level Tokenserver settings
+        let mut service_names = vec!["sync-1.5".to_owned()];
+        for service in &self.tokenserver.services {
+            let name = service.name();
+            // The `services.service` column is a VARCHAR(30)
+            if service.application.is_empty() || service.version.is_empty() || name.len() > 30 {
+                return Err(ConfigError::Message(format!(
+                    "Invalid SYNC_TOKENSERVER__SERVICES: invalid service {name:?}"
+                )));
+            }
+            if service_names.contains(&name) {
+                return Err(ConfigError::Message(format!(
+                    "Invalid SYNC_TOKENSERVER__SERVICES: duplicate service {name:?}"
+                )));
+            }
+            service_names.push(name);
+        }
         Ok(())
     }
 
@@ -228,6 +329,7 @@ impl Default for Settings {
             statsd_host: Some("localhost".to_owned()),
             statsd_port: 8125,
             include_hostname_tag: false,
+            prometheus_enabled: false,
             environment: "dev".to_owned(),
             human_logs: false,
             cors_allowed_origin: Some("*".to_owned()),
@@ -257,6 +359,15 @@ impl Default for Settings {
                 .collect(),
             ),
             cors_max_age: Some(1728000),
+            compression: Compression::default(),
+            tls: Tls::default(),
+            // Only a proxy on the same host: any other must be configured
+            // explicitly, private networks may be shared with untrusted peers
+            trusted_proxies: ["127.0.0.0/8", "::1/128"]
+                .into_iter()
+                .map(|net| net.parse().expect("Invalid default trusted proxy"))
+                .collect(),
+            public_url: None,
             worker_max_blocking_threads: 512,
             syncstorage: SyncstorageSettings::default(),
             tokenserver: TokenserverSettings::default(),
@@ -264,6 +375,73 @@ impl Default for Settings {
     }
 }
 
+/// Names of the storage routes whose responses may be compressed
+pub const COMPRESSIBLE_ROUTES: &[&str] = &[
+    "get_collection",
+    "info_collections",
+    "info_collection_counts",
+    "info_collection_usage",
+    "info_configuration",
+    "info_quota",
+];
+
+/// Negotiated `Content-Encoding` of storage responses.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Compression {
+    /// Whether to compress responses for clients that accept it
+    pub enabled: bool,
+    /// Responses of a known size below this many bytes are sent uncompressed
+    /// (streamed responses are always compressed)
+    pub min_bytes: usize,
+    /// Routes (from [COMPRESSIBLE_ROUTES]) whose responses are never
+    /// compressed
+    pub disabled_routes: Vec<String>,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_bytes: 1024,
+            disabled_routes: vec![],
+        }
+    }
+}
+
+impl Compression {
+    /// Whether responses for the route should be compressed
+    pub fn route_enabled(&self, route: &str) -> bool {
+        self.enabled
+            && !self
+                .disabled_routes
+                .iter()
+                .any(|disabled| disabled == route)
+    }
+}
+
+/// Native HTTPS (rustls) settings, for serving without a TLS terminating
+/// proxy in front.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Tls {
+    /// Path to the PEM encoded certificate chain. HTTPS is served when both
+    /// this and `key_path` are set, and both are reloaded on `SIGHUP`
+    pub cert_path: Option<String>,
+    /// Path to the PEM encoded private key
+    pub key_path: Option<String>,
+    /// Serve HTTPS on this port in addition to plain HTTP on `port`. When
+    /// unset, HTTPS replaces plain HTTP on `port`
+    pub port: Option<u16>,
+}
+
+impl Tls {
+    /// Whether HTTPS is to be served
+    pub fn enabled(&self) -> bool {
+        self.cert_path.is_some() && self.key_path.is_some()
+    }
+}
+
 /// Secrets used during Hawk authentication.
 #[derive(Clone, Debug)]
 pub struct Secrets {
@@ -274,11 +452,14 @@ pub struct Secrets {
 
     /// The signing secret used during Hawk authentication.
     pub signing_secret: [u8; 32],
+
+    /// The secret used to sign storage pagination cursors.
+    pub offset_secret: [u8; 32],
 }
 
 impl Secrets {
     /// Decode the master secret to a byte array
-    /// and derive the signing secret from it.
+    /// and derive the signing and offset secrets from it.
     pub fn new(master_secret: &str) -> Result<Self, String> {
         let master_secret = master_secret.as_bytes().to_vec();
         let signing_secret = syncserver_common::hkdf_expand_32(
@@ -286,9 +467,15 @@ impl Secrets {
             None,
             &master_secret,
         )?;
+        let offset_secret = syncserver_common::hkdf_expand_32(
+            b"services.mozilla.com/syncstorage/v1/offset",
+            None,
+            &master_secret,
+        )?;
         Ok(Self {
             master_secret,
             signing_secret,
+            offset_secret,
         })
     }
 }
@@ -299,6 +486,7 @@ impl Default for Secrets {
         Self {
             master_secret: vec![],
             signing_secret: [0u8; 32],
+            offset_secret: [0u8; 32],
         }
     }
 }
@@ -319,6 +507,7 @@ impl<'d> Deserialize<'d> for Secrets {
 #[cfg(test)]
 mod test {
     use super::*;
+    use tokenserver_settings::ServiceSettings;
 
     // A syncstorage DATABASE_URL is required to pass validation, since
     // syncstorage is enabled by default. Tests that exercise unrelated config
@@ -423,4 +612,110 @@ mod test {
             },
         );
     }
+
+    #[test]
+    fn test_unknown_compression_route_fails_validation() {
+        let mut settings = Settings::default();
+        settings.syncstorage.database_url = TEST_SYNCSTORAGE_DATABASE_URL.to_owned();
+        settings.compression.disabled_routes = vec!["info_quota".to_owned()];
+        assert!(settings.validate().is_ok());
+        assert!(!settings.compression.route_enabled("info_quota"));
+        assert!(settings.compression.route_enabled("get_collection"));
+
+        settings.compression.disabled_routes = vec!["get_bso".to_owned()];
+        let err = settings
+            .validate()
+            .expect_err("an unknown route should fail validation");
+        assert!(err.to_string().contains("get_bso"));
+    }
+
+    #[test]
+    fn test_empty_admin_token_fails_validation() {
+        let mut settings = Settings::default();
+        settings.syncstorage.database_url = TEST_SYNCSTORAGE_DATABASE_URL.to_owned();
+        settings.syncstorage.admin_token = Some("admin-secret".to_owned());
+        assert!(settings.validate().is_ok());
+
+        for token in ["", "  "] {
+            settings.syncstorage.admin_token = Some(token.to_owned());
+            assert!(settings.validate().is_err());
+        }
+
+        settings.syncstorage.admin_token = None;
+        settings.tokenserver.admin_token = Some("admin-secret".to_owned());
+        assert!(settings.validate().is_ok());
+        settings.tokenserver.admin_token = Some(" ".to_owned());
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn test_oidc_requires_issuer_and_audience() {
+        let mut settings = Settings::default();
+        settings.syncstorage.database_url = TEST_SYNCSTORAGE_DATABASE_URL.to_owned();
+        settings.tokenserver.enabled = true;
+        settings.tokenserver.database_url = TEST_TOKENSERVER_DATABASE_URL.to_owned();
+        settings.tokenserver.identity_provider = IdentityProviderType::Oidc;
+        assert!(settings.validate().is_err());
+
+        settings.tokenserver.oidc.issuer = "https://accounts.example.com".to_owned();
+        assert!(settings.validate().is_err());
+        settings.tokenserver.oidc.audience = Some("".to_owned());
+        assert!(settings.validate().is_err());
+        settings.tokenserver.oidc.audience = Some("sync".to_owned());
+        assert!(settings.validate().is_ok());
+
+        settings.tokenserver.oidc.issuer = " ".to_owned();
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn test_tokenserver_services() {
+        let mut settings = Settings::default();
+        settings.syncstorage.database_url = TEST_SYNCSTORAGE_DATABASE_URL.to_owned();
+        let service = |application: &str, version: &str| ServiceSettings {
+            application: application.to_owned(),
+            version: version.to_owned(),
+            token_duration: None,
+            master_secret: None,
+            node_capacity_release_rate: None,
+        };
+        settings.tokenserver.services = vec![service("sync", "1.6"), service("notes", "1.0")];
+        assert!(settings.validate().is_ok());
+
+        // Sync 1.5 is configured by the top-level settings
+        settings.tokenserver.services = vec![service("sync", "1.5")];
+        assert!(settings.validate().is_err());
+        settings.tokenserver.services = vec![service("sync", "1.6"), service("sync", "1.6")];
+        assert!(settings.validate().is_err());
+        settings.tokenserver.services = vec![service("sync", "")];
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn test_tls_requires_cert_and_key() {
+        let mut settings = Settings::default();
+        settings.syncstorage.database_url = TEST_SYNCSTORAGE_DATABASE_URL.to_owned();
+        settings.tls.cert_path = Some("cert.pem".to_owned());
+        assert!(settings.validate().is_err());
+        assert!(!settings.tls.enabled());
+
+        settings.tls.key_path = Some("key.pem".to_owned());
+        assert!(settings.validate().is_ok());
+        assert!(settings.tls.enabled());
+
+        settings.tls.port = Some(settings.port);
+        assert!(settings.validate().is_err());
+    }
+    #[test]
+    fn test_public_url() {
+        let mut settings = Settings::default();
+        settings.syncstorage.database_url = TEST_SYNCSTORAGE_DATABASE_URL.to_owned();
+        settings.public_url = Some("https://sync.example.com".to_owned());
+        assert!(settings.validate().is_ok());
+
+        settings.public_url = Some("sync.example.com:443".to_owned());
+        assert!(settings.validate().is_err());
+        settings.public_url = Some("file:///sync".to_owned());
+        assert!(settings.validate().is_err());
+    }
 }
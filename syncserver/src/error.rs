@@ -10,7 +10,7 @@ use std::fmt;
 
 use actix_web::{
     HttpResponse, HttpResponseBuilder, Result, dev::ServiceResponse, error::ResponseError,
-    middleware::ErrorHandlerResponse,
+    http::header::WWW_AUTHENTICATE, middleware::ErrorHandlerResponse,
 };
 use http::StatusCode;
 use serde::{
@@ -48,7 +48,8 @@ pub enum WeaveError {
 /// Common `Result` type.
 pub type ApiResult<T> = Result<T, ApiError>;
 
-/// How long the client should wait before retrying a conflicting write.
+/// How long the client should wait before retrying a conflicting write (or one
+/// refused while storage is read-only).
 pub const RETRY_AFTER: u8 = 10;
 
 /// Top-level error type.
@@ -72,9 +73,15 @@ pub enum ApiErrorKind {
     #[error("HAWK authentication error: {}", _0)]
     Hawk(HawkError),
 
+    #[error("Admin authentication error: {}", _0)]
+    AdminAuth(String),
+
     #[error("No app_data ServerState")]
     NoServerState,
 
+    #[error("Storage is read-only")]
+    ReadOnly,
+
     #[error("{}", _0)]
     Internal(String),
 
@@ -88,6 +95,7 @@ impl ApiErrorKind {
             ApiErrorKind::Hawk(err) => err.metric_label(),
             ApiErrorKind::Db(err) => err.metric_label(),
             ApiErrorKind::Validation(err) => err.metric_label(),
+            ApiErrorKind::ReadOnly => Some("storage.read_only"),
             _ => None,
         }
     }
@@ -138,6 +146,10 @@ impl ApiError {
         matches!(&self.kind, ApiErrorKind::Db(dbe) if dbe.is_quota())
     }
 
+    pub fn is_read_only(&self) -> bool {
+        matches!(&self.kind, ApiErrorKind::ReadOnly)
+    }
+
     pub fn is_bso_not_found(&self) -> bool {
         matches!(&self.kind, ApiErrorKind::Db(dbe) if dbe.is_bso_not_found())
     }
@@ -171,10 +183,11 @@ impl From<ApiErrorKind> for ApiError {
     fn from(kind: ApiErrorKind) -> Self {
         let status = match &kind {
             ApiErrorKind::Db(error) => error.status,
-            ApiErrorKind::Hawk(_) => StatusCode::UNAUTHORIZED,
+            ApiErrorKind::Hawk(_) | ApiErrorKind::AdminAuth(_) => StatusCode::UNAUTHORIZED,
             ApiErrorKind::NoServerState | ApiErrorKind::Internal(_) => {
                 StatusCode::INTERNAL_SERVER_ERROR
             }
+            ApiErrorKind::ReadOnly => StatusCode::SERVICE_UNAVAILABLE,
             ApiErrorKind::Validation(error) => error.status,
         };
 
@@ -197,9 +210,14 @@ impl ResponseError for ApiError {
         let mut resp = HttpResponse::build(
             actix_web::http::StatusCode::from_u16(self.status.as_u16()).unwrap(),
         );
-        if self.is_conflict() {
+        if self.is_conflict() || self.is_read_only() {
             resp.insert_header(("Retry-After", RETRY_AFTER.to_string()));
         };
+        if let ApiErrorKind::Hawk(error) = &self.kind
+            && let Some(challenge) = error.www_authenticate()
+        {
+            resp.insert_header((WWW_AUTHENTICATE, challenge));
+        }
         resp.json(self.weave_error_code() as i32)
     }
 }
@@ -235,13 +253,14 @@ impl Serialize for ApiErrorKind {
         match *self {
             ApiErrorKind::Db(ref error) => serialize_string_to_array(serializer, error),
             ApiErrorKind::Hawk(ref error) => serialize_string_to_array(serializer, error),
-            ApiErrorKind::Internal(ref description) => {
+            ApiErrorKind::AdminAuth(ref description) | ApiErrorKind::Internal(ref description) => {
                 serialize_string_to_array(serializer, description)
             }
             ApiErrorKind::Validation(ref error) => Serialize::serialize(error, serializer),
             ApiErrorKind::NoServerState => {
                 Serialize::serialize("No State information found", serializer)
             }
+            ApiErrorKind::ReadOnly => serialize_string_to_array(serializer, self),
         }
     }
 }
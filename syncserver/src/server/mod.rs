@@ -1,6 +1,11 @@
 //! Main application server
 
-use std::{convert::Infallible, num::NonZeroUsize, sync::Arc, time::Duration};
+use std::{
+    convert::Infallible,
+    num::NonZeroUsize,
+    sync::{Arc, atomic::AtomicBool},
+    time::Duration,
+};
 
 use crate::error::ApiError;
 use actix_cors::Cors;
@@ -13,22 +18,27 @@ use actix_web::{
     web::{self, Data},
 };
 use cadence::{Gauged, StatsdClient};
+use chrono::Utc;
 use futures::future::{self, Ready};
 use glean::server_events::GleanEventsLogger;
 use syncserver_common::{
-    BlockingThreadpool, BlockingThreadpoolMetrics, Metrics, Taggable,
+    BlockingThreadpool, BlockingThreadpoolMetrics, Metrics, PrometheusRecorder, Taggable,
     middleware::sentry::SentryWrapper,
 };
 use syncserver_db_common::GetPoolStatus;
-use syncserver_settings::Settings;
-use syncstorage_db::{DbError, DbPool, DbPoolImpl};
-use syncstorage_settings::{Deadman, ServerLimits};
+use syncserver_settings::{Compression, Secrets, Settings};
+use syncstorage_db::{DbError, DbPool, params, pool_from_settings, results};
+use syncstorage_settings::{Backoff, Deadman, ServerLimits};
 use tokio::{sync::RwLock, time};
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
 
 use crate::tokenserver;
-use crate::web::{handlers, middleware};
+use crate::web::{
+    auth::{HawkOrigin, ReplayGuard},
+    handlers, middleware,
+    middleware::ratelimit::RateLimiter,
+};
 
 pub const BSO_ID_REGEX: &str = r"[ -~]{1,64}";
 pub const COLLECTION_ID_REGEX: &str = r"[a-zA-Z0-9._-]{1,32}";
@@ -41,6 +51,7 @@ const SYNC_VERSION_PATH: &str = "1.5";
 
 #[cfg(test)]
 mod test;
+mod tls;
 pub mod user_agent;
 
 /// This is the global HTTP state object that will be made available to all
@@ -67,6 +78,27 @@ pub struct ServerState {
     pub glean_logger: Arc<GleanEventsLogger>,
 
     pub glean_enabled: bool,
+
+    /// Bearer token for the `/__admin__` endpoints
+    pub admin_token: Option<String>,
+
+    /// Per-user request rate limiter, when enabled
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+
+    /// Client backoff (maintenance mode) settings, adjustable at runtime
+    pub backoff: Arc<RwLock<Backoff>>,
+
+    /// Whether writes are refused, adjustable at runtime
+    pub read_only: Arc<AtomicBool>,
+
+    /// Storage response compression settings
+    pub compression: Arc<Compression>,
+
+    /// Where Hawk requests are considered to have been sent
+    pub hawk_origin: Arc<HawkOrigin>,
+
+    /// Rejects stale and replayed Hawk requests
+    pub replay_guard: Arc<ReplayGuard>,
 }
 
 pub fn cfg_path(path: &str) -> String {
@@ -130,7 +162,9 @@ pub struct Server;
 
 #[macro_export]
 macro_rules! build_app {
-    ($syncstorage_state: expr, $tokenserver_state: expr, $secrets: expr, $limits: expr, $cors: expr, $metrics: expr) => {
+    ($syncstorage_state: expr, $tokenserver_state: expr, $secrets: expr, $limits: expr, $cors: expr, $metrics: expr) => {{
+        let admin_enabled =
+            $crate::web::auth::admin_token_enabled($syncstorage_state.admin_token.as_deref());
         App::new()
             .configure(|cfg| {
                 cfg.app_data(Data::new($syncstorage_state));
@@ -146,9 +180,12 @@ macro_rules! build_app {
             .wrap(ErrorHandlers::new().handler(StatusCode::NOT_FOUND, ApiError::render_404))
             // These are our wrappers
             .wrap(SentryWrapper::<ApiError>::new($metrics.clone()))
+            .wrap_fn(middleware::ratelimit::rate_limit)
+            .wrap_fn(middleware::weave::set_weave_backoff)
             .wrap_fn(middleware::weave::set_weave_timestamp)
             .wrap_fn(tokenserver::logging::handle_request_log_line)
             .wrap_fn(middleware::rejectua::reject_user_agent)
+            .wrap_fn(middleware::compress::compress)
             .wrap($cors)
             .service(
                 web::resource(&cfg_path("/info/collections"))
@@ -202,6 +239,24 @@ macro_rules! build_app {
                     .route(web::get().to(handlers::get_bso))
                     .route(web::put().to(handlers::put_bso)),
             )
+            .configure(move |cfg| {
+                if admin_enabled {
+                    cfg.service(
+                        web::resource("/__admin__/export")
+                            .route(web::get().to(handlers::admin_export_user)),
+                    )
+                    .service(
+                        web::resource("/__admin__/backoff")
+                            .route(web::get().to(handlers::admin_get_backoff))
+                            .route(web::put().to(handlers::admin_put_backoff)),
+                    )
+                    .service(
+                        web::resource("/__admin__/read_only")
+                            .route(web::get().to(handlers::admin_get_read_only))
+                            .route(web::put().to(handlers::admin_put_read_only)),
+                    );
+                }
+            })
             // Tokenserver
             .service(
                 web::resource("/1.0/{application}/{version}")
@@ -218,6 +273,15 @@ macro_rules! build_app {
                             .route(web::post().to(tokenserver::handlers::handle_fxa_events)),
                     );
                 }
+                let tokenserver_admin_enabled =
+                    $tokenserver_state
+                        .as_ref()
+                        .is_some_and(|s: &tokenserver::ServerState| {
+                            $crate::web::auth::admin_token_enabled(s.admin_token.as_deref())
+                        });
+                if tokenserver_admin_enabled {
+                    tokenserver::admin::configure(cfg);
+                }
             })
             // Dockerflow
             // Remember to update .::web::middleware::DOCKER_FLOW_ENDPOINTS
@@ -259,13 +323,14 @@ macro_rules! build_app {
                 SwaggerUi::new("/swagger-ui/{_:.*}")
                     .url("/api-doc/openapi.json", ApiDoc::openapi()),
             )
-    };
+    }};
 }
 
 #[macro_export]
 macro_rules! build_app_without_syncstorage {
     ($state: expr, $secrets: expr, $cors: expr, $metrics: expr) => {{
         let fxa_webhook_enabled = $state.fxa_webhook_enabled;
+        let admin_enabled = $crate::web::auth::admin_token_enabled($state.admin_token.as_deref());
         App::new()
             .app_data(Data::new($state))
             .app_data(Data::new($secrets))
@@ -295,6 +360,9 @@ macro_rules! build_app_without_syncstorage {
                             .route(web::post().to(tokenserver::handlers::handle_fxa_events)),
                     );
                 }
+                if admin_enabled {
+                    tokenserver::admin::configure(cfg);
+                }
             })
             // Dockerflow
             // Remember to update .::web::middleware::DOCKER_FLOW_ENDPOINTS
@@ -342,21 +410,47 @@ macro_rules! build_app_without_syncstorage {
     }};
 }
 
+/// Bind the server's listeners: plain HTTP on `port` and HTTPS on `tls.port`,
+/// or only HTTPS on `port` when TLS is enabled without a `tls.port`
+macro_rules! bind_listeners {
+    ($server: expr, $host: expr, $port: expr, $tls: expr) => {{
+        let addr = |port| format!("{}:{}", $host, port);
+        match tls::ReloadableCert::from_settings($tls)? {
+            Some(cert) => {
+                let config = cert.server_config()?;
+                tls::spawn_reload_on_sighup(cert)?;
+                match $tls.port {
+                    Some(tls_port) => $server
+                        .bind(addr($port))?
+                        .bind_rustls_0_23(addr(tls_port), config)?,
+                    None => $server.bind_rustls_0_23(addr($port), config)?,
+                }
+            }
+            None => $server.bind(addr($port))?,
+        }
+    }};
+}
+
 impl Server {
     pub async fn with_settings(settings: Settings) -> Result<dev::Server, ApiError> {
         let settings_copy = settings.clone();
+        let prometheus = settings
+            .prometheus_enabled
+            .then(PrometheusRecorder::default);
         let metrics = syncserver_common::metrics_from_opts(
             &settings.syncstorage.statsd_label,
             settings.statsd_host.as_deref(),
             settings.statsd_port,
+            prometheus.as_ref(),
         )?;
         let host = settings.host.clone();
         let port = settings.port;
+        let tls = settings.tls.clone();
         let deadman = Arc::new(RwLock::new(Deadman::from(&settings.syncstorage)));
         let blocking_threadpool = Arc::new(BlockingThreadpool::new(
             settings.worker_max_blocking_threads,
         ));
-        let mut db_pool = DbPoolImpl::new(
+        let mut db_pool = pool_from_settings(
             &settings.syncstorage,
             &Metrics::from(&metrics),
             blocking_threadpool.clone(),
@@ -369,6 +463,14 @@ impl Server {
                 .database_pool_sweeper_task_interval
                 .into(),
         ));
+        if let Some(interval) = settings.syncstorage.purge_expired_interval {
+            spawn_expired_purger(
+                Duration::from_secs(interval.into()),
+                settings.syncstorage.purge_expired_batch_size,
+                &metrics,
+                db_pool.clone(),
+            );
+        }
         let glean_logger = Arc::new(GleanEventsLogger {
             // app_id corresponds to probe-scraper entry.
             // https://github.com/mozilla/probe-scraper/blob/main/repositories.yaml
@@ -379,11 +481,18 @@ impl Server {
         let glean_enabled = settings.syncstorage.glean_enabled;
         let worker_thread_count =
             calculate_worker_max_blocking_threads(settings.worker_max_blocking_threads);
+        let hawk_origin = Arc::new(HawkOrigin::from_settings(&settings)?);
+        let replay_guard = Arc::new(ReplayGuard::from_settings(&settings.syncstorage.hawk));
         let limits = Arc::new(settings.syncstorage.limits);
         let limits_json =
             serde_json::to_string(&*limits).expect("ServerLimits failed to serialize");
         let secrets = Arc::new(settings.master_secret);
         let quota_enabled = settings.syncstorage.enable_quota;
+        let admin_token = settings.syncstorage.admin_token.clone();
+        let rate_limiter = RateLimiter::from_settings(&settings.syncstorage.rate_limit);
+        let backoff = Arc::new(RwLock::new(settings.syncstorage.backoff.clone()));
+        let read_only = Arc::new(AtomicBool::new(settings.syncstorage.read_only));
+        let compression = Arc::new(settings.compression.clone());
         let actix_keep_alive = settings.actix_keep_alive;
         let tokenserver_state = if settings.tokenserver.enabled {
             let mut state = tokenserver::ServerState::from_settings(
@@ -392,10 +501,24 @@ impl Server {
                     &settings.tokenserver.statsd_label,
                     settings.statsd_host.as_deref(),
                     settings.statsd_port,
+                    prometheus.as_ref(),
                 )?,
-                blocking_threadpool,
-            )?;
+                blocking_threadpool.clone(),
+            )
+            .await?;
             state.init().await;
+            spawn_replaced_user_purger(&settings.tokenserver, &secrets, &state);
+            spawn_processed_event_purger(&settings.tokenserver, &state);
+            if prometheus.is_some() {
+                // Scrapers of a single service still expect its pool gauges
+                spawn_metric_periodic_reporter(
+                    Duration::from_secs(10),
+                    metrics.clone(),
+                    db_pool.clone(),
+                    blocking_threadpool,
+                    settings.include_hostname_tag,
+                )?;
+            }
 
             Some(state)
         } else {
@@ -415,7 +538,7 @@ impl Server {
 
         let mut server = HttpServer::new(move || {
             let syncstorage_state = ServerState {
-                db_pool: Box::new(db_pool.clone()),
+                db_pool: db_pool.clone(),
                 limits: Arc::clone(&limits),
                 limits_json: limits_json.clone(),
                 metrics: metrics.clone(),
@@ -424,6 +547,13 @@ impl Server {
                 deadman: Arc::clone(&deadman),
                 glean_logger: Arc::clone(&glean_logger),
                 glean_enabled,
+                admin_token: admin_token.clone(),
+                rate_limiter: rate_limiter.clone(),
+                backoff: Arc::clone(&backoff),
+                read_only: Arc::clone(&read_only),
+                compression: Arc::clone(&compression),
+                hawk_origin: Arc::clone(&hawk_origin),
+                replay_guard: Arc::clone(&replay_guard),
             };
 
             build_app!(
@@ -434,17 +564,15 @@ impl Server {
                 build_cors(&settings_copy),
                 metrics.clone()
             )
+            .configure(|cfg| configure_prometheus(cfg, prometheus.clone()))
         });
 
         if let Some(keep_alive) = actix_keep_alive {
             server = server.keep_alive(Duration::from_secs(keep_alive as u64));
         }
 
-        let server = server
-            .worker_max_blocking_threads(worker_thread_count)
-            .bind(format!("{}:{}", host, port))
-            .expect("Could not get Server in Server::with_settings")
-            .run();
+        let server = server.worker_max_blocking_threads(worker_thread_count);
+        let server = bind_listeners!(server, host, port, &tls).run();
         Ok(server)
     }
 
@@ -455,6 +583,7 @@ impl Server {
 
         let host = settings.host.clone();
         let port = settings.port;
+        let tls = settings.tls.clone();
         let secrets = Arc::new(settings.master_secret.clone());
         // Adjust the thread count to include FxA blocking threads.
         let thread_count = settings.worker_max_blocking_threads
@@ -464,16 +593,23 @@ impl Server {
                 .unwrap_or(0) as usize;
         let blocking_threadpool = Arc::new(BlockingThreadpool::new(thread_count));
         let worker_thread_count = calculate_worker_max_blocking_threads(thread_count);
+        let prometheus = settings
+            .prometheus_enabled
+            .then(PrometheusRecorder::default);
         let mut tokenserver_state = tokenserver::ServerState::from_settings(
             &settings.tokenserver,
             syncserver_common::metrics_from_opts(
                 &settings.tokenserver.statsd_label,
                 settings.statsd_host.as_deref(),
                 settings.statsd_port,
+                prometheus.as_ref(),
             )?,
             blocking_threadpool.clone(),
-        )?;
+        )
+        .await?;
         tokenserver_state.init().await;
+        spawn_replaced_user_purger(&settings.tokenserver, &secrets, &tokenserver_state);
+        spawn_processed_event_purger(&settings.tokenserver, &tokenserver_state);
 
         spawn_metric_periodic_reporter(
             Duration::from_secs(10),
@@ -490,17 +626,29 @@ impl Server {
                 build_cors(&settings_copy),
                 tokenserver_state.metrics.clone()
             )
+            .configure(|cfg| configure_prometheus(cfg, prometheus.clone()))
         });
 
-        let server = server
-            .worker_max_blocking_threads(worker_thread_count)
-            .bind(format!("{}:{}", host, port))
-            .expect("Could not get Server in Server::with_settings")
-            .run();
+        let server = server.worker_max_blocking_threads(worker_thread_count);
+        let server = bind_listeners!(server, host, port, &tls).run();
         Ok(server)
     }
 }
 
+/// Serve the metrics in the Prometheus text format on `/__metrics__`, when enabled
+fn configure_prometheus(cfg: &mut web::ServiceConfig, prometheus: Option<PrometheusRecorder>) {
+    if let Some(recorder) = prometheus {
+        cfg.app_data(Data::new(recorder))
+            .service(web::resource("/__metrics__").route(web::get().to(
+                |recorder: Data<PrometheusRecorder>| async move {
+                    HttpResponse::Ok()
+                        .content_type("text/plain; version=0.0.4")
+                        .body(recorder.render())
+                },
+            )));
+    }
+}
+
 fn calculate_worker_max_blocking_threads(count: usize) -> usize {
     let parallelism = std::thread::available_parallelism().map_or(2, NonZeroUsize::get);
     std::cmp::max(count / parallelism, 1)
@@ -573,6 +721,137 @@ impl FromRequest for MetricsWrapper {
     }
 }
 
+/// Periodically delete expired BSOs and batches
+fn spawn_expired_purger(
+    interval: Duration,
+    batch_size: u32,
+    metrics: &Arc<StatsdClient>,
+    pool: Box<dyn DbPool<Error = DbError>>,
+) {
+    let metrics = Metrics::from(metrics);
+    // Db futures aren't Send: run on the current thread's runtime
+    actix_web::rt::spawn(async move {
+        loop {
+            time::sleep(interval).await;
+            let mut metrics = metrics.clone();
+            metrics.start_timer("storage.purge_expired", None);
+            match purge_expired(&*pool, batch_size).await {
+                Ok(purged) => {
+                    debug!(
+                        "Purged {} expired bsos and {} expired batches",
+                        purged.bsos, purged.batches
+                    );
+                    metrics.count("storage.purge_expired.bsos", purged.bsos as i64);
+                    metrics.count("storage.purge_expired.batches", purged.batches as i64);
+                }
+                Err(e) => {
+                    error!("⚠️ Purging expired rows failed: {}", e);
+                    metrics.incr("storage.purge_expired.error");
+                }
+            }
+        }
+    });
+}
+
+/// Spawn the tokenserver's purge of replaced users, when enabled
+fn spawn_replaced_user_purger(
+    settings: &tokenserver_settings::Settings,
+    secrets: &Secrets,
+    state: &tokenserver::ServerState,
+) {
+    let Some(interval) = settings.purge_replaced_users_interval else {
+        return;
+    };
+    let shared_secret =
+        String::from_utf8(secrets.master_secret.clone()).expect("Failed to read the master secret");
+    let client = tokenserver::purge::HawkStorageClient::new(
+        shared_secret,
+        settings.fxa_metrics_hash_secret.clone(),
+        Duration::from_secs(settings.purge_replaced_users_request_timeout),
+    )
+    .expect("Failed to create the storage client for purging replaced users");
+    tokenserver::purge::spawn_replaced_user_purger(
+        Duration::from_secs(interval.into()),
+        settings.into(),
+        Box::new(client),
+        &state.metrics,
+        state.db_pool.clone(),
+    );
+}
+
+/// Periodically forget the FxA webhook's processed events older than their retention, while the
+/// webhook is enabled
+fn spawn_processed_event_purger(
+    settings: &tokenserver_settings::Settings,
+    state: &tokenserver::ServerState,
+) {
+    if !settings.fxa_webhook_enabled || settings.fxa_webhook_metrics_only {
+        return;
+    }
+    let retention_ms = i64::from(settings.fxa_webhook_processed_events_retention) * 1000;
+    let metrics = Metrics::from(&state.metrics);
+    let pool = state.db_pool.clone();
+    // Db futures aren't Send: run on the current thread's runtime
+    actix_web::rt::spawn(async move {
+        loop {
+            time::sleep(Duration::from_secs(3600)).await;
+            let result = match pool.get().await {
+                Ok(mut db) => {
+                    db.delete_processed_events(tokenserver_db::params::DeleteProcessedEvents {
+                        processed_before: Utc::now().timestamp_millis() - retention_ms,
+                    })
+                    .await
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(purged) => {
+                    debug!("Purged {} processed webhook events", purged);
+                    metrics.count("webhook.processed_events_purged", purged as i64);
+                }
+                Err(e) => {
+                    error!("⚠️ Purging processed webhook events failed: {}", e);
+                    metrics.incr("webhook.processed_events_purge_error");
+                }
+            }
+        }
+    });
+}
+
+/// Delete all expired BSOs and batches, `batch_size` rows per transaction
+async fn purge_expired(
+    pool: &dyn DbPool<Error = DbError>,
+    batch_size: u32,
+) -> Result<results::PurgeExpired, DbError> {
+    let mut total = results::PurgeExpired::default();
+    loop {
+        let mut db = pool.get().await?;
+        db.begin(true).await?;
+        let purged = match db
+            .purge_expired(params::PurgeExpired { limit: batch_size })
+            .await
+        {
+            Ok(purged) => purged,
+            Err(e) => {
+                db.rollback().await?;
+                return Err(e);
+            }
+        };
+        db.commit().await?;
+
+        total.bsos += purged.bsos;
+        total.batches += purged.batches;
+        total.batch_items += purged.batch_items;
+        let batch_size = u64::from(batch_size);
+        if purged.bsos < batch_size
+            && purged.batches < batch_size
+            && purged.batch_items < batch_size
+        {
+            return Ok(total);
+        }
+    }
+}
+
 /// Emit database pool and threadpool metrics periodically
 fn spawn_metric_periodic_reporter<T: GetPoolStatus + Send + 'static>(
     interval: Duration,
@@ -21,7 +21,9 @@ use sha2::Sha256;
 use syncserver_common::{self, X_LAST_MODIFIED};
 use syncserver_settings::{Secrets, Settings};
 use syncstorage_db::{
-    DbPoolImpl, SyncTimestamp, params,
+    SyncTimestamp,
+    archive::{ARCHIVE_VERSION, ArchiveRecord},
+    params, pool_from_settings,
     results::{DeleteBso, GetBso, PutBso},
 };
 use syncstorage_settings::ServerLimits;
@@ -90,14 +92,12 @@ async fn get_test_state(settings: &Settings) -> ServerState {
         app_channel: settings.environment.clone(),
     });
 
-    let mut db_pool = Box::new(
-        DbPoolImpl::new(
-            &settings.syncstorage,
-            &Metrics::from(&metrics),
-            blocking_threadpool,
-        )
-        .expect("Could not get db_pool in get_test_state"),
-    );
+    let mut db_pool = pool_from_settings(
+        &settings.syncstorage,
+        &Metrics::from(&metrics),
+        blocking_threadpool,
+    )
+    .expect("Could not get db_pool in get_test_state");
     db_pool
         .init()
         .await
@@ -113,6 +113,13 @@ async fn get_test_state(settings: &Settings) -> ServerState {
         deadman: Arc::new(RwLock::new(Deadman::from(&settings.syncstorage))),
         glean_logger,
         glean_enabled: settings.syncstorage.glean_enabled,
+        admin_token: settings.syncstorage.admin_token.clone(),
+        rate_limiter: RateLimiter::from_settings(&settings.syncstorage.rate_limit),
+        backoff: Arc::new(RwLock::new(settings.syncstorage.backoff.clone())),
+        read_only: Arc::new(AtomicBool::new(settings.syncstorage.read_only)),
+        compression: Arc::new(settings.compression.clone()),
+        hawk_origin: Arc::new(HawkOrigin::from_settings(settings).unwrap()),
+        replay_guard: Arc::new(ReplayGuard::from_settings(&settings.syncstorage.hawk)),
     }
 }
 
@@ -437,6 +444,21 @@ async fn get_collection() {
         },
     )
     .await;
+    // Full BSOs are streamed
+    test_endpoint(
+        http::Method::GET,
+        "/1.5/42/storage/bookmarks?full=1",
+        None,
+        Some("[]"),
+    )
+    .await;
+    test_endpoint(
+        http::Method::GET,
+        "/1.5/42/storage/nonexistent?full=1&limit=10",
+        None,
+        Some("[]"),
+    )
+    .await;
 }
 
 #[actix_rt::test]
@@ -762,6 +784,382 @@ async fn overquota() {
     assert!(resp.response().status().is_success());
 }
 
+#[actix_rt::test]
+async fn admin_export() {
+    let mut settings = get_test_settings();
+    settings.syncstorage.admin_token = Some("admin-secret".to_owned());
+    // persist the db across requests
+    settings.syncstorage.database_use_test_transactions = false;
+    let query = if settings.syncstorage.uses_spanner() {
+        format!(
+            "fxa_uid=xxx_test_uid_{}&fxa_kid=xxx_test_kid_{}",
+            *RAND_UID, *RAND_UID
+        )
+    } else {
+        "legacy_id=42".to_owned()
+    };
+    let app = init_app!(settings).await;
+
+    let req = create_request(http::Method::DELETE, "/1.5/42/storage", None, None).to_request();
+    let resp = app.call(req).await.unwrap();
+    assert!(resp.response().status().is_success());
+    let req = create_request(
+        http::Method::PUT,
+        "/1.5/42/storage/xxx_col2/12345",
+        None,
+        Some(json!({"payload": "xxx", "sortindex": 2})),
+    )
+    .to_request();
+    let resp = app.call(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let path = format!("/__admin__/export?{query}");
+    for token in [None, Some("wrong")] {
+        let mut req = test::TestRequest::with_uri(&path);
+        if let Some(token) = token {
+            req = req.insert_header(("Authorization", format!("Bearer {token}")));
+        }
+        let resp = app.call(req.to_request()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    let req = test::TestRequest::with_uri("/__admin__/export")
+        .insert_header(("Authorization", "Bearer admin-secret"))
+        .to_request();
+    let resp = app.call(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let req = test::TestRequest::with_uri(&path)
+        .insert_header(("Authorization", "Bearer admin-secret"))
+        .to_request();
+    let resp = app.call(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+    let records: Vec<ArchiveRecord> = body
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(records.len(), 3);
+    assert!(matches!(
+        records[0],
+        ArchiveRecord::Header {
+            version: ARCHIVE_VERSION,
+            ..
+        }
+    ));
+    assert!(matches!(&records[1], ArchiveRecord::Collection { name, .. } if name == "xxx_col2"));
+    match &records[2] {
+        ArchiveRecord::Bso {
+            id,
+            payload,
+            sortindex,
+            ..
+        } => {
+            assert_eq!(id, "12345");
+            assert_eq!(payload, "xxx");
+            assert_eq!(*sortindex, Some(2));
+        }
+        record => panic!("Expected a bso, got {record:?}"),
+    }
+
+    let req = create_request(http::Method::DELETE, "/1.5/42/storage", None, None).to_request();
+    let resp = app.call(req).await.unwrap();
+    assert!(resp.response().status().is_success());
+}
+
+#[actix_rt::test]
+async fn admin_export_disabled() {
+    let app = init_app!().await;
+    let req = test::TestRequest::with_uri("/__admin__/export?legacy_id=42")
+        .insert_header(("Authorization", "Bearer admin-secret"))
+        .to_request();
+    let resp = app.call(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+async fn admin_empty_token_disabled() {
+    let mut settings = get_test_settings();
+    settings.syncstorage.admin_token = Some("".to_owned());
+    let app = init_app!(settings).await;
+    for header in ["Bearer ", "Bearer  "] {
+        let req = test::TestRequest::with_uri("/__admin__/export?legacy_id=42")
+            .insert_header(("Authorization", header))
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
+
+#[actix_rt::test]
+async fn admin_backoff() {
+    let mut settings = get_test_settings();
+    settings.syncstorage.admin_token = Some("admin-secret".to_owned());
+    settings.syncstorage.backoff.when_pool_saturated = false;
+    let app = init_app!(settings).await;
+
+    let req =
+        create_request(http::Method::GET, "/1.5/42/info/collections", None, None).to_request();
+    let resp = app.call(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.headers().get("X-Weave-Backoff").is_none());
+
+    let req = test::TestRequest::put()
+        .uri("/__admin__/backoff")
+        .set_json(json!({"enabled": true, "seconds": 900}))
+        .to_request();
+    let resp = app.call(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let req = test::TestRequest::put()
+        .uri("/__admin__/backoff")
+        .insert_header(("Authorization", "Bearer admin-secret"))
+        .set_json(json!({"enabled": true, "fraction": 2.0}))
+        .to_request();
+    let resp = app.call(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let req = test::TestRequest::put()
+        .uri("/__admin__/backoff")
+        .insert_header(("Authorization", "Bearer admin-secret"))
+        .set_json(json!({"enabled": true, "seconds": 900}))
+        .to_request();
+    let resp = app.call(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let req = test::TestRequest::with_uri("/__admin__/backoff")
+        .insert_header(("Authorization", "Bearer admin-secret"))
+        .to_request();
+    let backoff: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(backoff["enabled"], true);
+    assert_eq!(backoff["seconds"], 900);
+
+    let req =
+        create_request(http::Method::GET, "/1.5/42/info/collections", None, None).to_request();
+    let resp = app.call(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get("X-Weave-Backoff").unwrap(), "900");
+
+    // Only storage requests are asked to back off
+    let req = test::TestRequest::with_uri("/__lbheartbeat__").to_request();
+    let resp = app.call(req).await.unwrap();
+    assert!(resp.headers().get("X-Weave-Backoff").is_none());
+}
+
+#[actix_rt::test]
+async fn read_only() {
+    let mut settings = get_test_settings();
+    settings.syncstorage.admin_token = Some("admin-secret".to_owned());
+    settings.syncstorage.read_only = true;
+    let app = init_app!(settings).await;
+
+    let req =
+        create_request(http::Method::GET, "/1.5/42/info/collections", None, None).to_request();
+    let resp = app.call(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let req =
+        create_request(http::Method::GET, "/1.5/42/storage/bookmarks", None, None).to_request();
+    let resp = app.call(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let put_bso = || {
+        create_request(
+            http::Method::PUT,
+            "/1.5/42/storage/bookmarks/wibble",
+            None,
+            Some(json!({"payload": "xxx"})),
+        )
+        .to_request()
+    };
+    let resp = app.call(put_bso()).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(resp.headers().get("Retry-After").is_some());
+    let req = create_request(http::Method::DELETE, "/1.5/42/storage", None, None).to_request();
+    let resp = app.call(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    let req = test::TestRequest::with_uri("/__heartbeat__").to_request();
+    let heartbeat: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(heartbeat["read_only"], true);
+
+    let req = test::TestRequest::put()
+        .uri("/__admin__/read_only")
+        .insert_header(("Authorization", "Bearer admin-secret"))
+        .set_json(json!({"read_only": false}))
+        .to_request();
+    let resp = app.call(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = app.call(put_bso()).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let req = test::TestRequest::with_uri("/__heartbeat__").to_request();
+    let heartbeat: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(heartbeat["read_only"], false);
+}
+
+#[cfg(feature = "actix-compress")]
+#[actix_rt::test]
+async fn compression() {
+    use actix_http::encoding::Encoder;
+    use actix_web::{HttpResponse, body::to_bytes, http::header::ContentEncoding};
+
+    let mut settings = get_test_settings();
+    settings.compression.min_bytes = 0;
+    settings.compression.disabled_routes = vec!["info_quota".to_owned()];
+    let app = init_app!(settings).await;
+    let get = |path: &str, accept_encoding: &str| {
+        let headers = HashMap::from([("Accept-Encoding", accept_encoding.to_owned())]);
+        create_request(http::Method::GET, path, Some(headers), None).to_request()
+    };
+
+    let resp = app
+        .call(get("/1.5/42/info/configuration", "gzip"))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get("Content-Encoding").unwrap(), "gzip");
+    let vary = resp.headers().get("Vary").unwrap().to_str().unwrap();
+    assert!(vary.contains("accept-encoding"));
+    let resp = app
+        .call(get("/1.5/42/storage/bookmarks?full=1", "gzip;q=0.5, zstd"))
+        .await
+        .unwrap();
+    assert_eq!(resp.headers().get("Content-Encoding").unwrap(), "zstd");
+    // Opted out, or not accepted by the client
+    let resp = app.call(get("/1.5/42/info/quota", "gzip")).await.unwrap();
+    assert!(resp.headers().get("Content-Encoding").is_none());
+    let resp = app
+        .call(get("/1.5/42/info/configuration", "identity"))
+        .await
+        .unwrap();
+    assert!(resp.headers().get("Content-Encoding").is_none());
+
+    // Below the default size threshold
+    let app = init_app!().await;
+    let resp = app
+        .call(get("/1.5/42/info/configuration", "gzip"))
+        .await
+        .unwrap();
+    assert!(resp.headers().get("Content-Encoding").is_none());
+
+    // Compressed request bodies are accepted
+    let mut head = HttpResponse::Ok().finish().into_parts().0;
+    let body = to_bytes(Encoder::response(
+        ContentEncoding::Gzip,
+        head.head_mut(),
+        json!({"payload": "x".repeat(1024)}).to_string(),
+    ))
+    .await
+    .unwrap();
+    let req = create_request(
+        http::Method::PUT,
+        "/1.5/42/storage/bookmarks/wibble",
+        None,
+        None,
+    )
+    .insert_header(("Content-Type", "application/json"))
+    .insert_header(("Content-Encoding", "gzip"))
+    .set_payload(body)
+    .to_request();
+    let resp = app.call(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn rate_limited() {
+    let mut settings = get_test_settings();
+    settings.syncstorage.rate_limit.requests_per_minute = Some(1);
+    settings.syncstorage.rate_limit.burst = 2;
+    settings.syncstorage.rate_limit.backoff = 300;
+    let app = init_app!(settings).await;
+
+    for _ in 0..2 {
+        let req =
+            create_request(http::Method::GET, "/1.5/42/info/collections", None, None).to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    let req =
+        create_request(http::Method::GET, "/1.5/42/info/collections", None, None).to_request();
+    let resp = app.call(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(resp.headers().get("X-Weave-Backoff").unwrap(), "300");
+    let retry_after: u64 = resp
+        .headers()
+        .get("Retry-After")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((1..=60).contains(&retry_after));
+
+    // Dockerflow endpoints aren't limited
+    let req = test::TestRequest::with_uri("/__lbheartbeat__").to_request();
+    let resp = app.call(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn rate_limited_reports_auth_errors() {
+    let mut settings = get_test_settings();
+    settings.syncstorage.rate_limit.requests_per_minute = Some(60);
+    let app = init_app!(settings).await;
+
+    // The token's for uid 42: the handler reports the mismatch (rather than
+    // a replay of the nonce checked by the rate limiter)
+    let req =
+        create_request(http::Method::GET, "/1.5/43/info/collections", None, None).to_request();
+    let resp = app.call(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_rt::test]
+async fn rate_limited_replays_not_charged() {
+    let mut settings = get_test_settings();
+    settings.syncstorage.rate_limit.requests_per_minute = Some(1);
+    settings.syncstorage.rate_limit.burst = 2;
+    let path = "/1.5/42/info/collections";
+    let authorization = create_hawk_header("GET", settings.port, path);
+    let app = init_app!(settings).await;
+
+    // Replays of a captured request are rejected without using up the
+    // user's bucket
+    for status in [
+        StatusCode::OK,
+        StatusCode::UNAUTHORIZED,
+        StatusCode::UNAUTHORIZED,
+    ] {
+        let req = test::TestRequest::with_uri(path)
+            .insert_header(("Authorization", authorization.clone()))
+            .insert_header(("Accept", "application/json"))
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), status);
+    }
+    let req = create_request(http::Method::GET, path, None, None).to_request();
+    let resp = app.call(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn replayed_request() {
+    let settings = get_test_settings();
+    let path = "/1.5/42/info/collections";
+    let authorization = create_hawk_header("GET", settings.port, path);
+    let app = init_app!(settings).await;
+
+    for status in [StatusCode::OK, StatusCode::UNAUTHORIZED] {
+        let req = test::TestRequest::with_uri(path)
+            .insert_header(("Authorization", authorization.clone()))
+            .insert_header(("Accept", "application/json"))
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), status);
+    }
+}
+
 #[actix_rt::test]
 async fn lbheartbeat_max_pool_size_check() {
     let mut settings = get_test_settings();
@@ -892,3 +1290,50 @@ async fn error_endpoint_logging_check() {
     )
     .await;
 }
+
+#[actix_rt::test]
+async fn prometheus_metrics() {
+    let settings = get_test_settings();
+    let recorder = PrometheusRecorder::default();
+    let mut state = get_test_state(&settings).await;
+    state.metrics = syncserver_common::metrics_from_opts(
+        &settings.syncstorage.statsd_label,
+        None,
+        0,
+        Some(&recorder),
+    )
+    .unwrap();
+    let limits = Arc::new(settings.syncstorage.limits.clone());
+    let metrics = state.metrics.clone();
+    let app = test::init_service(
+        build_app!(
+            state,
+            None::<tokenserver::ServerState>,
+            Arc::clone(&SECRETS),
+            limits,
+            build_cors(&settings),
+            metrics
+        )
+        .configure(|cfg| configure_prometheus(cfg, Some(recorder))),
+    )
+    .await;
+
+    let req =
+        create_request(http::Method::GET, "/1.5/42/info/collections", None, None).to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert_eq!(sresp.response().status(), StatusCode::OK);
+
+    let req = test::TestRequest::with_uri("/__metrics__").to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert_eq!(sresp.response().status(), StatusCode::OK);
+    let body = String::from_utf8(test::read_body(sresp).await.to_vec()).unwrap();
+    assert!(body.contains("# TYPE syncstorage_"), "{body}");
+}
+
+#[actix_rt::test]
+async fn prometheus_metrics_disabled() {
+    let app = init_app!().await;
+    let req = test::TestRequest::with_uri("/__metrics__").to_request();
+    let sresp = app.call(req).await.unwrap();
+    assert_eq!(sresp.response().status(), StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,485 @@
+//! Administrative API for managing the nodes users are allocated to, replacing
+//! the `tools/tokenserver` node scripts.
+//!
+//! Every endpoint requires the `admin_token` setting as a bearer token (see
+//! [AdminToken]). Nodes are listed, added and reported on per service, Sync 1.5
+//! unless the `service` query parameter names another.
+use actix_web::{
+    HttpResponse,
+    web::{self, Data, Json, Path, Query, ServiceConfig},
+};
+use chrono::Utc;
+use http::StatusCode;
+use serde::{Deserialize, Deserializer, Serialize};
+use tokenserver_common::{ErrorLocation, TokenserverError};
+use tokenserver_db::{DEFAULT_NODE_WEIGHT, Db, SYNC_SERVICE_NAME, params, results};
+
+use super::{
+    ServerState,
+    extractors::{AdminToken, DbWrapper},
+};
+
+/// Register the node management endpoints
+pub fn configure(cfg: &mut ServiceConfig) {
+    cfg.service(
+        web::resource("/__admin__/nodes")
+            .route(web::get().to(list_nodes))
+            .route(web::post().to(create_node)),
+    )
+    // Registered ahead of `/__admin__/nodes/{id}`, which would otherwise match it
+    .service(web::resource("/__admin__/nodes/load").route(web::get().to(node_loads)))
+    .service(
+        web::resource("/__admin__/nodes/{id}")
+            .route(web::get().to(get_node))
+            .route(web::patch().to(update_node))
+            .route(web::delete().to(remove_node)),
+    )
+    .service(web::resource("/__admin__/nodes/{id}/unassign").route(web::post().to(unassign_node)))
+    .service(web::resource("/__admin__/nodes/{id}/drain").route(web::post().to(drain_node)));
+}
+
+/// The fraction of a new node's capacity released to start with, when not
+/// configured by `node_capacity_release_rate`
+const DEFAULT_CAPACITY_RELEASE_RATE: f32 = 0.1;
+
+/// The number of users unassigned per drain request, when not specified
+const DEFAULT_DRAIN_BATCH_SIZE: i64 = 1000;
+
+#[derive(Debug, Eq, PartialEq, Serialize)]
+pub struct Node {
+    pub id: i64,
+    pub node: String,
+    pub available: i32,
+    pub current_load: i32,
+    pub capacity: i32,
+    pub downed: bool,
+    pub backoff: bool,
+    pub weight: i32,
+    pub region: Option<String>,
+}
+
+impl From<results::GetNode> for Node {
+    fn from(node: results::GetNode) -> Self {
+        Self {
+            id: node.id,
+            node: node.node,
+            available: node.available,
+            current_load: node.current_load,
+            capacity: node.capacity,
+            downed: node.downed != 0,
+            backoff: node.backoff != 0,
+            weight: node.weight,
+            region: node.region,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NewNode {
+    pub node: String,
+    pub capacity: i32,
+    /// Defaults to a fraction of `capacity` (see `node_capacity_release_rate`)
+    pub available: Option<i32>,
+    #[serde(default)]
+    pub current_load: i32,
+    #[serde(default)]
+    pub downed: bool,
+    #[serde(default)]
+    pub backoff: bool,
+    /// The node's share of new users under the weighted allocation policy
+    #[serde(default = "default_weight")]
+    pub weight: i32,
+    pub region: Option<String>,
+}
+
+fn default_weight() -> i32 {
+    DEFAULT_NODE_WEIGHT
+}
+
+/// The fields to update on a node. Omitted fields are left unchanged.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NodeUpdate {
+    pub available: Option<i32>,
+    pub current_load: Option<i32>,
+    pub capacity: Option<i32>,
+    pub downed: Option<bool>,
+    pub backoff: Option<bool>,
+    pub weight: Option<i32>,
+    /// An explicit `null` clears the node's region
+    #[serde(default, deserialize_with = "deserialize_present")]
+    pub region: Option<Option<String>>,
+}
+
+/// Distinguish a field that's present (even as `null`) from an omitted one,
+/// which is left as `None` by `#[serde(default)]`
+fn deserialize_present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
+/// The service whose nodes are managed, e.g. `sync-1.6`
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceQuery {
+    pub service: Option<String>,
+}
+
+impl ServiceQuery {
+    fn name(&self) -> &str {
+        self.service.as_deref().unwrap_or(SYNC_SERVICE_NAME)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DrainRequest {
+    /// The max number of users to unassign
+    pub batch_size: Option<i64>,
+}
+
+#[derive(Debug, Eq, PartialEq, Serialize)]
+pub struct DrainResult {
+    /// The number of users unassigned by this request
+    pub drained: usize,
+    /// The number of users still assigned to the node
+    pub remaining: i64,
+}
+
+/// A node's share of the service's users and capacity
+#[derive(Debug, PartialEq, Serialize)]
+pub struct NodeLoad {
+    #[serde(flatten)]
+    pub node: Node,
+    /// The number of users currently assigned to the node
+    pub current_users: i64,
+    /// `current_load` as a fraction of `capacity`
+    pub load: f64,
+    /// The node's fraction of every current user
+    pub user_share: f64,
+    /// The node's fraction of the weight of the nodes new users can be
+    /// allocated to, or 0 when it can't be allocated to
+    pub weight_share: f64,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct LoadReport {
+    pub nodes: Vec<NodeLoad>,
+    pub total_users: i64,
+    pub total_capacity: i64,
+}
+
+impl From<Vec<results::NodeLoad>> for LoadReport {
+    fn from(loads: Vec<results::NodeLoad>) -> Self {
+        let allocatable =
+            |node: &results::NodeLoad| node.downed == 0 && node.backoff == 0 && node.weight > 0;
+        let total_users: i64 = loads.iter().map(|node| node.current_users).sum();
+        let total_capacity: i64 = loads.iter().map(|node| i64::from(node.capacity)).sum();
+        let total_weight: i64 = loads
+            .iter()
+            .filter(|node| allocatable(node))
+            .map(|node| i64::from(node.weight))
+            .sum();
+        let ratio = |n: i64, total: i64| {
+            if total > 0 {
+                n as f64 / total as f64
+            } else {
+                0.0
+            }
+        };
+
+        let nodes = loads
+            .into_iter()
+            .map(|node| NodeLoad {
+                current_users: node.current_users,
+                load: ratio(node.current_load.into(), node.capacity.into()),
+                user_share: ratio(node.current_users, total_users),
+                weight_share: if allocatable(&node) {
+                    ratio(node.weight.into(), total_weight)
+                } else {
+                    0.0
+                },
+                node: Node {
+                    id: node.id,
+                    node: node.node,
+                    available: node.available,
+                    current_load: node.current_load,
+                    capacity: node.capacity,
+                    downed: node.downed != 0,
+                    backoff: node.backoff != 0,
+                    weight: node.weight,
+                    region: node.region,
+                },
+            })
+            .collect();
+        Self {
+            nodes,
+            total_users,
+            total_capacity,
+        }
+    }
+}
+
+fn node_error(description: String, http_status: StatusCode) -> TokenserverError {
+    TokenserverError {
+        status: "error",
+        location: ErrorLocation::Url,
+        context: description.clone(),
+        description,
+        http_status,
+        ..TokenserverError::default()
+    }
+}
+
+/// Get the id of the queried service, failing with a 404 when it doesn't exist
+async fn service_id(db: &mut Box<dyn Db>, query: &ServiceQuery) -> Result<i32, TokenserverError> {
+    let service = query.name();
+    match db
+        .get_service_id(params::GetServiceId {
+            service: service.to_owned(),
+        })
+        .await
+    {
+        Ok(result) => Ok(result.id),
+        Err(e) if e.is_diesel_not_found() => Err(node_error(
+            format!("Unknown service {service}"),
+            StatusCode::NOT_FOUND,
+        )),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Get a node's db record, failing with a 404 when it doesn't exist
+async fn existing_db_node(
+    db: &mut Box<dyn Db>,
+    id: i64,
+) -> Result<results::GetNode, TokenserverError> {
+    match db.get_node(params::GetNode { id }).await {
+        Ok(node) => Ok(node),
+        Err(e) if e.is_diesel_not_found() => Err(node_error(
+            format!("Unknown node {id}"),
+            StatusCode::NOT_FOUND,
+        )),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Get a node, failing with a 404 when it doesn't exist
+async fn existing_node(db: &mut Box<dyn Db>, id: i64) -> Result<Node, TokenserverError> {
+    existing_db_node(db, id).await.map(Node::from)
+}
+
+/// List every node of the service
+pub async fn list_nodes(
+    _: AdminToken,
+    DbWrapper(mut db): DbWrapper,
+    query: Query<ServiceQuery>,
+) -> Result<HttpResponse, TokenserverError> {
+    let service_id = service_id(&mut db, &query).await?;
+    let nodes: Vec<Node> = db
+        .get_nodes(params::GetNodes { service_id })
+        .await?
+        .into_iter()
+        .map(Node::from)
+        .collect();
+    Ok(HttpResponse::Ok().json(nodes))
+}
+
+/// Add a node to the service
+pub async fn create_node(
+    _: AdminToken,
+    DbWrapper(mut db): DbWrapper,
+    state: Data<ServerState>,
+    query: Query<ServiceQuery>,
+    Json(new_node): Json<NewNode>,
+) -> Result<HttpResponse, TokenserverError> {
+    let service_id = service_id(&mut db, &query).await?;
+    let exists = db
+        .get_nodes(params::GetNodes { service_id })
+        .await?
+        .iter()
+        .any(|node| node.node == new_node.node);
+    if exists {
+        return Err(node_error(
+            format!("Node {} already exists", new_node.node),
+            StatusCode::CONFLICT,
+        ));
+    }
+
+    // Only a fraction of the node's capacity is released to start with
+    let available = new_node.available.unwrap_or_else(|| {
+        let rate = state
+            .services
+            .iter()
+            .find(|service| service.name() == query.name())
+            .map_or(state.node_capacity_release_rate, |service| {
+                service.node_capacity_release_rate
+            })
+            .unwrap_or(DEFAULT_CAPACITY_RELEASE_RATE);
+        (new_node.capacity as f32 * rate).ceil() as i32
+    });
+    let id = db
+        .post_node(params::PostNode {
+            service_id,
+            node: new_node.node,
+            available,
+            current_load: new_node.current_load,
+            capacity: new_node.capacity,
+            downed: new_node.downed.into(),
+            backoff: new_node.backoff.into(),
+            weight: new_node.weight,
+            region: new_node.region,
+        })
+        .await?
+        .id;
+    let node = existing_node(&mut db, id).await?;
+    info!("Added node {} ({})", node.node, node.id);
+    Ok(HttpResponse::Created().json(node))
+}
+
+/// Get a node by id
+pub async fn get_node(
+    _: AdminToken,
+    DbWrapper(mut db): DbWrapper,
+    id: Path<i64>,
+) -> Result<HttpResponse, TokenserverError> {
+    let node = existing_node(&mut db, id.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(node))
+}
+
+/// Update a node's capacity, load or status
+pub async fn update_node(
+    _: AdminToken,
+    DbWrapper(mut db): DbWrapper,
+    id: Path<i64>,
+    Json(update): Json<NodeUpdate>,
+) -> Result<HttpResponse, TokenserverError> {
+    let id = id.into_inner();
+    existing_node(&mut db, id).await?;
+    db.update_node(params::UpdateNode {
+        id,
+        available: update.available,
+        current_load: update.current_load,
+        capacity: update.capacity,
+        downed: update.downed.map(Into::into),
+        backoff: update.backoff.map(Into::into),
+        weight: update.weight,
+        region: update.region.clone(),
+    })
+    .await?;
+    let node = existing_node(&mut db, id).await?;
+    info!("Updated node {} ({}): {:?}", node.node, node.id, update);
+    Ok(HttpResponse::Ok().json(node))
+}
+
+/// Mark every user assigned to a node as replaced, so they're moved to another
+/// node on their next token request
+pub async fn unassign_node(
+    _: AdminToken,
+    DbWrapper(mut db): DbWrapper,
+    id: Path<i64>,
+) -> Result<HttpResponse, TokenserverError> {
+    let node = existing_node(&mut db, id.into_inner()).await?;
+    db.unassign_node(params::UnassignNode { node_id: node.id })
+        .await?;
+    info!("Unassigned node {} ({})", node.node, node.id);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Unassign a batch of a node's users, backing the node off first so no new
+/// users are allocated to it. Repeat until no users remain to drain the node
+/// gradually.
+pub async fn drain_node(
+    _: AdminToken,
+    DbWrapper(mut db): DbWrapper,
+    id: Path<i64>,
+    request: Option<Json<DrainRequest>>,
+) -> Result<HttpResponse, TokenserverError> {
+    let db_node = existing_db_node(&mut db, id.into_inner()).await?;
+    let service_id = db_node.service_id;
+    let node = Node::from(db_node);
+    let batch_size = request
+        .and_then(|Json(request)| request.batch_size)
+        .unwrap_or(DEFAULT_DRAIN_BATCH_SIZE);
+    if batch_size <= 0 {
+        return Err(node_error(
+            "batch_size must be positive".to_owned(),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    // The node's backed off in the same transaction, so it's left as is when
+    // the drain fails
+    db.begin().await?;
+    let backoff = if node.backoff {
+        Ok(())
+    } else {
+        db.update_node(params::UpdateNode {
+            id: node.id,
+            backoff: Some(1),
+            ..Default::default()
+        })
+        .await
+    };
+    let result = match backoff {
+        Ok(()) => {
+            db.drain_node(params::DrainNode {
+                node_id: node.id,
+                limit: batch_size,
+                replaced_at: Utc::now().timestamp_millis(),
+            })
+            .await
+        }
+        Err(e) => Err(e),
+    };
+    if result.is_ok() {
+        db.commit().await?;
+    } else {
+        db.rollback().await?;
+    }
+    let drained = result?;
+    let remaining = db
+        .get_node_loads(params::GetNodeLoads { service_id })
+        .await?
+        .into_iter()
+        .find(|load| load.id == node.id)
+        .map_or(0, |load| load.current_users);
+    info!(
+        "Drained {} users from node {} ({}), {} remaining",
+        drained, node.node, node.id, remaining
+    );
+    Ok(HttpResponse::Ok().json(DrainResult { drained, remaining }))
+}
+
+/// Report how the service's users and allocation weight are distributed
+/// across its nodes
+pub async fn node_loads(
+    _: AdminToken,
+    DbWrapper(mut db): DbWrapper,
+    query: Query<ServiceQuery>,
+) -> Result<HttpResponse, TokenserverError> {
+    let service_id = service_id(&mut db, &query).await?;
+    let loads = db
+        .get_node_loads(params::GetNodeLoads { service_id })
+        .await?;
+    Ok(HttpResponse::Ok().json(LoadReport::from(loads)))
+}
+
+/// Remove a node, unassigning its users
+pub async fn remove_node(
+    _: AdminToken,
+    DbWrapper(mut db): DbWrapper,
+    id: Path<i64>,
+) -> Result<HttpResponse, TokenserverError> {
+    let node = existing_node(&mut db, id.into_inner()).await?;
+    db.remove_node(params::RemoveNode { node_id: node.id })
+        .await?;
+    db.unassign_node(params::UnassignNode { node_id: node.id })
+        .await?;
+    info!("Removed node {} ({})", node.node, node.id);
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -25,10 +25,10 @@ use syncserver_common::Taggable;
 use syncserver_settings::Secrets;
 use tokenserver_auth::{FxaWebhookClaims, JWTVerifyError};
 use tokenserver_common::{ErrorLocation, NodeType, TokenserverError};
-use tokenserver_db::{Db, DbPool, SYNC_SERVICE_NAME, params, results};
+use tokenserver_db::{Db, DbPool, params, results};
 
 use super::{LogItemsMutator, ServerState, TokenserverMetrics};
-use crate::server::MetricsWrapper;
+use crate::{server::MetricsWrapper, web::auth::admin_token_matches};
 
 lazy_static! {
     static ref CLIENT_STATE_REGEX: Regex = Regex::new("^[a-zA-Z0-9._-]{1,32}$").unwrap();
@@ -43,6 +43,8 @@ pub struct TokenserverRequest {
     pub hashed_fxa_uid: String,
     pub hashed_device_id: String,
     pub service_id: i32,
+    /// The version of the storage protocol requested, e.g. `1.5`
+    pub version: String,
     pub duration: u64,
     pub node_type: NodeType,
 }
@@ -207,7 +209,7 @@ impl FromRequest for TokenserverRequest {
             let hashed_device_id = hash_device_id(&hashed_fxa_uid, fxa_metrics_hash_secret);
 
             let DbWrapper(mut db) = DbWrapper::extract(&req).await?;
-            let service_id = {
+            let service = {
                 let path = req.match_info();
 
                 // If we've reached this extractor, we know that the Tokenserver path was matched,
@@ -216,31 +218,16 @@ impl FromRequest for TokenserverRequest {
                 let application = path.get("application").unwrap();
                 let version = path.get("version").unwrap();
 
-                if application == "sync" {
-                    if version == "1.5" {
-                        db.get_service_id(params::GetServiceId {
-                            service: SYNC_SERVICE_NAME.to_owned(),
-                        })
-                        .await?
-                        .id
-                    } else {
-                        return Err(TokenserverError::unsupported(
-                            "Unsupported application version".to_owned(),
-                            version.to_owned(),
-                        ));
-                    }
-                } else {
-                    // NOTE: It would probably be better to include the name of the unsupported
-                    // application in the error message, but the old Tokenserver only includes
-                    // "application" in the error message. To keep the APIs between the old and
-                    // new Tokenservers as close as possible, we defer to the error message from
-                    // the old Tokenserver.
-                    return Err(TokenserverError::unsupported(
-                        "Unsupported application".to_owned(),
-                        "application".to_owned(),
-                    ));
-                }
+                state.service(application, version)?
             };
+            let service_id = db
+                .get_service_id(params::GetServiceId {
+                    service: service.name(),
+                })
+                .await?
+                .id;
+            // Services may sign their tokens with their own secret
+            let shared_secret = service.secret.clone().unwrap_or(shared_secret);
             let user = db
                 .get_or_create_user(params::GetOrCreateUser {
                     service_id,
@@ -248,7 +235,7 @@ impl FromRequest for TokenserverRequest {
                     generation: auth_data.generation.unwrap_or(0),
                     client_state: auth_data.client_state.clone(),
                     keys_changed_at: auth_data.keys_changed_at,
-                    capacity_release_rate: state.node_capacity_release_rate,
+                    capacity_release_rate: service.node_capacity_release_rate,
                 })
                 .await?;
             log_items_mutator.insert("first_seen_at".to_owned(), user.first_seen_at.to_string());
@@ -271,7 +258,7 @@ impl FromRequest for TokenserverRequest {
                     match duration_string.parse::<u64>() {
                         // The specified token duration should never be greater than the default
                         // token duration set on the server.
-                        Ok(duration) if duration <= state.token_duration => Some(duration),
+                        Ok(duration) if duration <= service.token_duration => Some(duration),
                         _ => None,
                     }
                 })
@@ -284,7 +271,8 @@ impl FromRequest for TokenserverRequest {
                 hashed_fxa_uid,
                 hashed_device_id,
                 service_id,
-                duration: duration.unwrap_or(state.token_duration),
+                version: service.version,
+                duration: duration.unwrap_or(service.token_duration),
                 node_type: state.node_type,
             };
 
@@ -448,20 +436,31 @@ impl FromRequest for AuthData {
                     let mut tags = HashMap::default();
                     tags.insert("token_type".to_owned(), "OAuth".to_owned());
                     metrics.start_timer("token_verification", Some(tags));
-                    let verify_output = state.oauth_verifier.verify(token, &metrics).await?;
-
-                    // For requests using OAuth, the keys_changed_at and client state are embedded
-                    // in the X-KeyID header.
-                    let key_id = KeyId::extract(&req).await?;
+                    let verify_output = state.identity_provider.verify(token, &metrics).await?;
+
+                    // For requests using FxA OAuth, the keys_changed_at and client state are
+                    // embedded in the X-KeyID header. Other identity providers know nothing of
+                    // it, so their clients may only send the client state in X-Client-State.
+                    let (client_state, keys_changed_at) =
+                        if state.identity_provider.requires_key_id()
+                            || req.headers().contains_key("X-KeyID")
+                        {
+                            let key_id = KeyId::extract(&req).await?;
+                            (key_id.client_state, Some(key_id.keys_changed_at))
+                        } else {
+                            let XClientStateHeader(x_client_state) =
+                                XClientStateHeader::extract(&req).await?;
+                            (x_client_state.unwrap_or_default(), None)
+                        };
                     let fxa_uid = verify_output.fxa_uid;
-                    let email = format!("{}@{}", fxa_uid, state.fxa_email_domain);
+                    let email = state.identity_provider.email(&fxa_uid);
 
                     Ok(AuthData {
-                        client_state: key_id.client_state,
+                        client_state,
                         email,
                         fxa_uid,
                         generation: convert_zero_to_none(verify_output.generation),
-                        keys_changed_at: convert_zero_to_none(Some(key_id.keys_changed_at)),
+                        keys_changed_at: convert_zero_to_none(keys_changed_at),
                     })
                 }
             }
@@ -646,6 +645,33 @@ impl FromRequest for FxaWebhookToken {
     }
 }
 
+/// Authenticates an `/__admin__` request by the `admin_token` setting, sent as
+/// a bearer token.
+#[derive(Debug)]
+pub struct AdminToken;
+
+impl FromRequest for AdminToken {
+    type Error = TokenserverError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+
+        Box::pin(async move {
+            let state = get_server_state(&req)?;
+            if admin_token_matches(&req, state.admin_token.as_deref()) {
+                Ok(AdminToken)
+            } else {
+                Err(TokenserverError {
+                    location: ErrorLocation::Header,
+                    context: "Invalid admin bearer token".to_owned(),
+                    ..TokenserverError::unauthorized("Unauthorized".to_owned())
+                })
+            }
+        })
+    }
+}
+
 fn get_server_state(req: &HttpRequest) -> Result<&Data<ServerState>, TokenserverError> {
     req.app_data::<Data<ServerState>>()
         .ok_or_else(|| TokenserverError {
@@ -668,7 +694,7 @@ fn get_secret(req: &HttpRequest) -> Result<String, TokenserverError> {
     })
 }
 
-fn fxa_metrics_hash(fxa_uid: &str, hmac_key: &[u8]) -> String {
+pub(crate) fn fxa_metrics_hash(fxa_uid: &str, hmac_key: &[u8]) -> String {
     let mut mac = Hmac::<Sha256>::new_from_slice(hmac_key).expect("HMAC has no key size limit");
     mac.update(fxa_uid.as_bytes());
 
@@ -676,7 +702,7 @@ fn fxa_metrics_hash(fxa_uid: &str, hmac_key: &[u8]) -> String {
     hex::encode(result)
 }
 
-fn hash_device_id(fxa_uid: &str, hmac_key: &[u8]) -> String {
+pub(crate) fn hash_device_id(fxa_uid: &str, hmac_key: &[u8]) -> String {
     let mut to_hash = String::from(fxa_uid);
     // TODO: This value originally was the deviceID from BrowserID.
     // When support was dropped for BrowserID, the device string
@@ -705,13 +731,13 @@ mod tests {
     use syncserver_settings::Settings as GlobalSettings;
     use syncstorage_settings::ServerLimits;
     use tokenserver_auth::{
-        MockVerifier, SETVerifierImpl, oauth,
+        FxaIdentityProvider, MockVerifier, SETVerifierImpl, oauth,
         test_utils::{OTHER_PRIVATE_KEY_PEM, TEST_PRIVATE_KEY_PEM, make_set, test_jwk},
     };
     use tokenserver_db::mock::MockDbPool as MockTokenserverPool;
     use tokenserver_settings::Settings as TokenserverSettings;
 
-    use crate::tokenserver::ServerState;
+    use crate::tokenserver::{ServerState, Service};
 
     use chrono::Utc;
     use std::sync::Arc;
@@ -769,6 +795,7 @@ mod tests {
             hashed_fxa_uid: "4d00ecae64b98dd7dc7dea68d0dd615d".to_owned(),
             hashed_device_id: "3a41cccbdd666ebc4199f1f9d1249d44".to_owned(),
             service_id: i32::default(),
+            version: "1.5".to_owned(),
             duration: 100,
             node_type: NodeType::default(),
         };
@@ -919,6 +946,61 @@ mod tests {
         }
     }
 
+    #[actix_rt::test]
+    async fn test_configured_service() {
+        let oauth_verifier = MockVerifier {
+            valid: true,
+            verify_output: oauth::VerifyOutput {
+                fxa_uid: "test123".to_owned(),
+                generation: Some(1234),
+            },
+        };
+        let state = ServerState {
+            services: vec![Service {
+                application: "sync".to_owned(),
+                version: "1.6".to_owned(),
+                token_duration: 600,
+                secret: Some("service secret".to_owned()),
+                node_capacity_release_rate: None,
+            }],
+            ..make_state(oauth_verifier)
+        };
+        let build_request = |version: &str| {
+            TestRequest::default()
+                .data(state.clone())
+                .data(Arc::clone(&SECRETS))
+                .insert_header(("authorization", "Bearer fake_token"))
+                .insert_header(("accept", "application/json,text/plain:q=0.5"))
+                .insert_header(("x-keyid", "0000000001234-qqo"))
+                .param("application", "sync")
+                .param("version", version.to_owned())
+                .method(Method::GET)
+                .to_http_request()
+        };
+
+        // The service's token duration and secret are used
+        let request = build_request("1.6");
+        let result = TokenserverRequest::extract(&request).await.unwrap();
+        assert_eq!(result.version, "1.6");
+        assert_eq!(result.duration, 600);
+        assert_eq!(result.shared_secret, "service secret");
+
+        // Sync 1.5 keeps the top-level settings
+        let request = build_request("1.5");
+        let result = TokenserverRequest::extract(&request).await.unwrap();
+        assert_eq!(result.version, "1.5");
+        assert_eq!(result.duration, TOKEN_DURATION);
+        assert_eq!(result.shared_secret, "Ted Koppel is a robot");
+
+        // Versions that aren't configured are still unsupported
+        let request = build_request("1.7");
+        let response: HttpResponse = TokenserverRequest::extract(&request)
+            .await
+            .unwrap_err()
+            .into();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
     #[actix_rt::test]
     async fn test_key_id() {
         fn build_request() -> TestRequest {
@@ -1101,6 +1183,7 @@ mod tests {
             hashed_fxa_uid: "abcdef".to_owned(),
             hashed_device_id: "abcdef".to_owned(),
             service_id: 1,
+            version: "1.5".to_owned(),
             duration: TOKEN_DURATION,
             node_type: NodeType::default(),
         };
@@ -1143,6 +1226,7 @@ mod tests {
             hashed_fxa_uid: "abcdef".to_owned(),
             hashed_device_id: "abcdef".to_owned(),
             service_id: 1,
+            version: "1.5".to_owned(),
             duration: TOKEN_DURATION,
             node_type: NodeType::default(),
         };
@@ -1184,6 +1268,7 @@ mod tests {
             hashed_fxa_uid: "abcdef".to_owned(),
             hashed_device_id: "abcdef".to_owned(),
             service_id: 1,
+            version: "1.5".to_owned(),
             duration: TOKEN_DURATION,
             node_type: NodeType::default(),
         };
@@ -1226,6 +1311,7 @@ mod tests {
             hashed_fxa_uid: "abcdef".to_owned(),
             hashed_device_id: "abcdef".to_owned(),
             service_id: 1,
+            version: "1.5".to_owned(),
             duration: TOKEN_DURATION,
             node_type: NodeType::default(),
         };
@@ -1268,6 +1354,7 @@ mod tests {
             hashed_fxa_uid: "abcdef".to_owned(),
             hashed_device_id: "abcdef".to_owned(),
             service_id: 1,
+            version: "1.5".to_owned(),
             duration: TOKEN_DURATION,
             node_type: NodeType::default(),
         };
@@ -1308,6 +1395,7 @@ mod tests {
             hashed_fxa_uid: "abcdef".to_owned(),
             hashed_device_id: "abcdef".to_owned(),
             service_id: 1,
+            version: "1.5".to_owned(),
             duration: TOKEN_DURATION,
             node_type: NodeType::default(),
         };
@@ -1332,7 +1420,10 @@ mod tests {
         ServerState {
             fxa_email_domain: "test.com".to_owned(),
             fxa_metrics_hash_secret: "".to_owned(),
-            oauth_verifier: Box::new(oauth_verifier),
+            identity_provider: Box::new(FxaIdentityProvider::new(
+                Box::new(oauth_verifier),
+                "test.com",
+            )),
             db_pool: Box::new(MockTokenserverPool::new()),
             node_capacity_release_rate: None,
             node_type: NodeType::default(),
@@ -1340,12 +1431,15 @@ mod tests {
                 &tokenserver_settings.statsd_label,
                 syncserver_settings.statsd_host.as_deref(),
                 syncserver_settings.statsd_port,
+                None,
             )
             .unwrap(),
             token_duration: TOKEN_DURATION,
             set_verifiers: Vec::new(),
             fxa_webhook_enabled: false,
             fxa_webhook_metrics_only: false,
+            admin_token: None,
+            services: Vec::new(),
         }
     }
 
@@ -1355,7 +1449,10 @@ mod tests {
         ServerState {
             fxa_email_domain: "test.com".to_owned(),
             fxa_metrics_hash_secret: "".to_owned(),
-            oauth_verifier: Box::new(MockVerifier::<oauth::VerifyOutput>::default()),
+            identity_provider: Box::new(FxaIdentityProvider::new(
+                Box::new(MockVerifier::<oauth::VerifyOutput>::default()),
+                "test.com",
+            )),
             db_pool: Box::new(MockTokenserverPool::new()),
             node_capacity_release_rate: None,
             node_type: NodeType::default(),
@@ -1363,12 +1460,15 @@ mod tests {
                 &tokenserver_settings.statsd_label,
                 syncserver_settings.statsd_host.as_deref(),
                 syncserver_settings.statsd_port,
+                None,
             )
             .unwrap(),
             token_duration: TOKEN_DURATION,
             set_verifiers,
             fxa_webhook_enabled: true,
             fxa_webhook_metrics_only: false,
+            admin_token: None,
+            services: Vec::new(),
         }
     }
 
@@ -1451,4 +1551,53 @@ mod tests {
         let result = FxaWebhookToken::from_request(&req, &mut actix_web::dev::Payload::None).await;
         assert!(result.is_err());
     }
+
+    #[actix_rt::test]
+    async fn test_admin_token() {
+        let state = Data::new(ServerState {
+            admin_token: Some("admin-secret".to_owned()),
+            ..make_state(MockVerifier::default())
+        });
+        let req = TestRequest::default()
+            .app_data(state.clone())
+            .insert_header(("Authorization", "Bearer admin-secret"))
+            .to_http_request();
+        let result = AdminToken::from_request(&req, &mut actix_web::dev::Payload::None).await;
+        assert!(result.is_ok());
+
+        for header in [None, Some("Bearer wrong"), Some("admin-secret")] {
+            let mut req = TestRequest::default().app_data(state.clone());
+            if let Some(header) = header {
+                req = req.insert_header(("Authorization", header));
+            }
+            let result = AdminToken::from_request(
+                &req.to_http_request(),
+                &mut actix_web::dev::Payload::None,
+            )
+            .await;
+            assert_eq!(
+                result.unwrap_err().http_status,
+                http::StatusCode::UNAUTHORIZED
+            );
+        }
+
+        // The admin API is disabled without a configured token
+        let req = TestRequest::default()
+            .app_data(Data::new(make_state(MockVerifier::default())))
+            .insert_header(("Authorization", "Bearer "))
+            .to_http_request();
+        let result = AdminToken::from_request(&req, &mut actix_web::dev::Payload::None).await;
+        assert!(result.is_err());
+
+        // Nor does an empty token authenticate an empty bearer token
+        let req = TestRequest::default()
+            .app_data(Data::new(ServerState {
+                admin_token: Some("".to_owned()),
+                ..make_state(MockVerifier::default())
+            }))
+            .insert_header(("Authorization", "Bearer "))
+            .to_http_request();
+        let result = AdminToken::from_request(&req, &mut actix_web::dev::Payload::None).await;
+        assert!(result.is_err());
+    }
 }
@@ -4,16 +4,19 @@ use actix_web::{Error, HttpResponse, http::StatusCode, web::Data};
 use base64::{Engine, engine};
 use chrono::{TimeDelta, Utc};
 use serde::Serialize;
-use serde_json::Value;
+use serde_json::{Value, json};
 use tokio::time::timeout;
 use utoipa::ToSchema;
 
 use syncserver_common::Metrics;
-use tokenserver_auth::{MakeTokenPlaintext, Tokenlib, TokenserverOrigin};
+use tokenserver_auth::{FxaWebhookClaims, MakeTokenPlaintext, Tokenlib, TokenserverOrigin};
 use tokenserver_common::{NodeType, TokenserverError};
 use tokenserver_db::{
-    Db, SYNC_SERVICE_NAME,
-    params::{GetNodeId, PostUser, PutUser, ReplaceUsers, RetireUser, UpdateUserGeneration},
+    Db, SYNC_SERVICE_NAME, fxa_events,
+    params::{
+        ApplyFxaEvent, GetNodeId, MarkEventProcessed, PostFailedEvent, PostUser, PutUser,
+        ReplaceUsers,
+    },
 };
 
 use super::{
@@ -72,7 +75,7 @@ pub async fn get_tokenserver_result(
         id: token,
         key: derived_secret,
         uid: updates.uid,
-        api_endpoint: format!("{:}/1.5/{:}", req.user.node, updates.uid),
+        api_endpoint: format!("{:}/{:}/{:}", req.user.node, req.version, updates.uid),
         duration: req.duration,
         hashed_fxa_uid: req.hashed_fxa_uid,
         hashalg: "sha256",
@@ -281,6 +284,8 @@ async fn update_user(
 // statsd counter names
 const METRIC_EVENTS_RECEIVED: &str = "webhook.events_received";
 const METRIC_EVENTS_PROCESSED: &str = "webhook.events_processed";
+const METRIC_EVENTS_FAILED: &str = "webhook.events_failed";
+const METRIC_EVENTS_DUPLICATE: &str = "webhook.events_duplicate";
 
 pub async fn handle_fxa_events(
     FxaWebhookToken(claims): FxaWebhookToken,
@@ -300,6 +305,41 @@ pub async fn handle_fxa_events(
         return Ok(HttpResponse::Ok().finish());
     }
 
+    db.begin().await?;
+    match apply_fxa_events(&mut *db, &claims, events, &state, &metrics).await {
+        Ok(()) => db.commit().await?,
+        Err(e) => {
+            db.rollback().await?;
+            return Err(e);
+        }
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Apply a Security Event Token's events, within a transaction
+async fn apply_fxa_events(
+    db: &mut dyn Db,
+    claims: &FxaWebhookClaims,
+    events: &serde_json::Map<String, Value>,
+    state: &super::ServerState,
+    metrics: &Metrics,
+) -> Result<(), TokenserverError> {
+    // FxA redelivers the same token until it's acknowledged. It's claimed before its events are
+    // applied, in the same transaction: a concurrent redelivery waits for this one to end, then
+    // skips it once it's committed
+    if !db
+        .mark_event_processed(MarkEventProcessed {
+            jti: claims.jti.clone(),
+            processed_at: Utc::now().timestamp_millis(),
+        })
+        .await?
+    {
+        info!("Skipping already processed events {}", claims.jti);
+        metrics.count(METRIC_EVENTS_DUPLICATE, events.len() as i64);
+        return Ok(());
+    }
+
     let service_id = db
         .get_service_id(tokenserver_db::params::GetServiceId {
             service: SYNC_SERVICE_NAME.to_owned(),
@@ -307,40 +347,48 @@ pub async fn handle_fxa_events(
         .await?
         .id;
 
-    for event_type in events.keys() {
-        let email = format!("{}@{}", claims.sub, state.fxa_email_domain);
-        match event_type.as_str() {
-            "https://schemas.accounts.firefox.com/event/delete-user" => {
-                info!("Processing account delete for {}", email);
-                db.retire_user(RetireUser { service_id, email }).await?;
-                metrics.incr_with_tag(METRIC_EVENTS_PROCESSED, "event_type", "delete_user");
-            }
-            "https://schemas.accounts.firefox.com/event/password-change" => {
-                if let Some(change_time_ms) = events[event_type]
-                    .get("changeTime")
-                    .and_then(|t| t.as_i64())
-                {
-                    info!("Processing password change for {}", email);
-                    db.update_user_generation(UpdateUserGeneration {
-                        service_id,
-                        email,
-                        generation: Some(change_time_ms - 1),
-                        keys_changed_at: None,
-                    })
-                    .await?;
-                    metrics.incr_with_tag(METRIC_EVENTS_PROCESSED, "event_type", "password_change");
-                }
-            }
-            _ => {
-                info!(
-                    "Dropping unhandled event type {:?} for {}",
-                    event_type, email
-                );
+    let email = format!("{}@{}", claims.sub, state.fxa_email_domain);
+    for (event_type, payload) in events {
+        let name = fxa_events::name(event_type).unwrap_or("unknown");
+        // Each event is applied in a savepoint, so a failure only rolls back its own changes
+        db.begin().await?;
+        let result = db
+            .apply_fxa_event(ApplyFxaEvent {
+                service_id,
+                email: email.clone(),
+                event_type: event_type.clone(),
+                payload: payload.clone(),
+            })
+            .await;
+        if result.is_ok() {
+            db.commit().await?;
+        } else {
+            db.rollback().await?;
+        }
+        match result {
+            Ok(true) => metrics.incr_with_tag(METRIC_EVENTS_PROCESSED, "event_type", name),
+            Ok(false) => info!(
+                "Dropping unhandled event type {:?} for {}",
+                event_type, email
+            ),
+            // Kept to be replayed by `tokenserver-admin replay-failed-events`, rather than
+            // failing the whole token's redelivery
+            Err(e) => {
+                warn!("Failed to process {} for {}: {}", event_type, email, e);
+                db.post_failed_event(PostFailedEvent {
+                    jti: claims.jti.clone(),
+                    email: email.clone(),
+                    event_type: event_type.clone(),
+                    payload: payload.to_string(),
+                    error: e.to_string(),
+                    failed_at: Utc::now().timestamp_millis(),
+                })
+                .await?;
+                metrics.incr_with_tag(METRIC_EVENTS_FAILED, "event_type", name);
             }
         }
     }
-
-    Ok(HttpResponse::Ok().finish())
+    Ok(())
 }
 
 #[utoipa::path(
@@ -348,18 +396,24 @@ pub async fn handle_fxa_events(
     path = "/__heartbeat__",
     tag = "tokenserver",
     summary = "Tokenserver health check",
-    description = "Returns health status of the Tokenserver including database connectivity.",
+    description = "Returns health status of the Tokenserver including database connectivity and the OAuth keys cached from FxA.",
     responses(
         (status = 200, description = "Service is healthy", content_type = "application/json"),
         (status = 503, description = "Service is unhealthy", content_type = "application/json"),
     )
 )]
-pub async fn heartbeat(DbWrapper(mut db): DbWrapper) -> Result<HttpResponse, Error> {
+pub async fn heartbeat(
+    DbWrapper(mut db): DbWrapper,
+    state: Data<super::ServerState>,
+) -> Result<HttpResponse, Error> {
     let mut checklist = HashMap::new();
     checklist.insert(
         "version".to_owned(),
         Value::String(env!("CARGO_PKG_VERSION").to_owned()),
     );
+    if let Some(jwks) = state.identity_provider.jwks_status() {
+        checklist.insert("jwks".to_owned(), json!(jwks));
+    }
 
     match apply_timeout(db.timeout(), db.check()).await {
         Ok(result) => {
@@ -412,11 +466,13 @@ mod tests {
     use syncserver_common::middleware::sentry::SentryWrapper;
     use syncserver_settings::Settings;
     use tokenserver_auth::test_utils::{
-        OTHER_PRIVATE_KEY_PEM, TEST_PRIVATE_KEY_PEM, make_set, test_jwk,
+        OTHER_PRIVATE_KEY_PEM, TEST_PRIVATE_KEY_PEM, make_jwt, make_set, test_jwk,
+    };
+    use tokenserver_auth::{
+        FxaIdentityProvider, MockVerifier, OidcIdentityProvider, SETVerifierImpl, oauth,
     };
-    use tokenserver_auth::{MockVerifier, SETVerifierImpl, oauth};
     use tokenserver_db::mock::MockDbPool;
-    use tokenserver_settings::Settings as TokenserverSettings;
+    use tokenserver_settings::{OidcSettings, Settings as TokenserverSettings};
 
     use crate::build_app_without_syncstorage;
     use crate::error::ApiError;
@@ -448,7 +504,10 @@ mod tests {
         ServerState {
             fxa_email_domain: "api.accounts.firefox.com".to_owned(),
             fxa_metrics_hash_secret: "topsecretz".to_owned(),
-            oauth_verifier: Box::new(MockVerifier::<oauth::VerifyOutput>::default()),
+            identity_provider: Box::new(FxaIdentityProvider::new(
+                Box::new(MockVerifier::<oauth::VerifyOutput>::default()),
+                "api.accounts.firefox.com",
+            )),
             db_pool,
             node_capacity_release_rate: None,
             node_type: Default::default(),
@@ -456,12 +515,15 @@ mod tests {
                 &tokenserver_settings.statsd_label,
                 syncserver_settings.statsd_host.as_deref(),
                 syncserver_settings.statsd_port,
+                None,
             )
             .unwrap(),
             token_duration: 3600,
             set_verifiers,
             fxa_webhook_enabled: true,
             fxa_webhook_metrics_only: false,
+            admin_token: None,
+            services: Vec::new(),
         }
     }
 
@@ -686,4 +748,173 @@ mod tests {
         assert_eq!(call_log.update_user_generation.lock().unwrap().len(), 0);
         assert_eq!(call_log.retire_user.lock().unwrap().len(), 0);
     }
+
+    #[actix_web::test]
+    async fn test_redelivered_events_are_skipped() {
+        let verifier =
+            SETVerifierImpl::new(&test_jwk(), "testo", "https://accounts.firefox.com/").unwrap();
+        let (pool, call_log) = MockDbPool::with_capture();
+        let app =
+            make_app_from_state(make_state_with_db_pool(vec![verifier], Box::new(pool))).await;
+        let token = make_set(
+            "quux",
+            "testo",
+            json!({"https://schemas.accounts.firefox.com/event/delete-user": {}}),
+            3600,
+            TEST_PRIVATE_KEY_PEM,
+        );
+        for _ in 0..2 {
+            let req = TestRequest::post()
+                .uri("/1.0/webhooks/fxa/events")
+                .insert_header(("Authorization", format!("Bearer {token}")))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 200);
+        }
+        assert_eq!(call_log.retire_user.lock().unwrap().len(), 1);
+        assert_eq!(
+            *call_log.processed_events.lock().unwrap(),
+            vec!["wibble".to_owned()]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_failed_events_are_dead_lettered() {
+        let verifier =
+            SETVerifierImpl::new(&test_jwk(), "testo", "https://accounts.firefox.com/").unwrap();
+        let (pool, call_log) = MockDbPool::with_capture();
+        call_log
+            .fail_user_updates
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        let app =
+            make_app_from_state(make_state_with_db_pool(vec![verifier], Box::new(pool))).await;
+        let token = make_set(
+            "quux",
+            "testo",
+            json!({
+                "https://schemas.accounts.firefox.com/event/password-change": {"changeTime": 1234},
+                "https://schemas.accounts.firefox.com/event/metrics-change": {"enabled": false},
+            }),
+            3600,
+            TEST_PRIVATE_KEY_PEM,
+        );
+        let req = TestRequest::post()
+            .uri("/1.0/webhooks/fxa/events")
+            .insert_header(("Authorization", format!("Bearer {token}")))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+
+        let failed_events = call_log.failed_events.lock().unwrap();
+        assert_eq!(failed_events.len(), 1);
+        assert_eq!(failed_events[0].jti, "wibble");
+        assert_eq!(failed_events[0].email, "quux@api.accounts.firefox.com");
+        assert_eq!(
+            failed_events[0].event_type,
+            "https://schemas.accounts.firefox.com/event/password-change"
+        );
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&failed_events[0].payload).unwrap(),
+            json!({"changeTime": 1234})
+        );
+        assert_eq!(call_log.processed_events.lock().unwrap().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_profile_change_event() {
+        let verifier =
+            SETVerifierImpl::new(&test_jwk(), "testo", "https://accounts.firefox.com/").unwrap();
+        let (pool, call_log) = MockDbPool::with_capture();
+        let app =
+            make_app_from_state(make_state_with_db_pool(vec![verifier], Box::new(pool))).await;
+        let token = make_set(
+            "quux",
+            "testo",
+            json!({
+                "https://schemas.accounts.firefox.com/event/profile-change": {"uid": "quuz"},
+                "https://schemas.accounts.firefox.com/event/subscription-state-change": {
+                    "capabilities": ["sync"],
+                    "isActive": true,
+                    "changeTime": 1234,
+                },
+            }),
+            3600,
+            TEST_PRIVATE_KEY_PEM,
+        );
+        let req = TestRequest::post()
+            .uri("/1.0/webhooks/fxa/events")
+            .insert_header(("Authorization", format!("Bearer {token}")))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+
+        let update_user_email_calls = call_log.update_user_email.lock().unwrap();
+        assert_eq!(update_user_email_calls.len(), 1);
+        assert_eq!(
+            update_user_email_calls[0].email,
+            "quux@api.accounts.firefox.com"
+        );
+        assert_eq!(
+            update_user_email_calls[0].new_email,
+            "quuz@api.accounts.firefox.com"
+        );
+        assert!(call_log.failed_events.lock().unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_oidc_token_without_key_id() {
+        let mut server = mockito::Server::new_async().await;
+        let issuer = server.url();
+        server
+            .mock("GET", "/.well-known/openid-configuration")
+            .with_header("content-type", "application/json")
+            .with_body(json!({"issuer": issuer, "jwks_uri": format!("{issuer}/jwks")}).to_string())
+            .create_async()
+            .await;
+        let mut jwk = serde_json::to_value(test_jwk()).unwrap();
+        jwk["kid"] = json!("key-1");
+        server
+            .mock("GET", "/jwks")
+            .with_header("content-type", "application/json")
+            .with_body(json!({ "keys": [jwk] }).to_string())
+            .create_async()
+            .await;
+        let oidc_settings = OidcSettings {
+            issuer: issuer.clone(),
+            audience: Some("sync".to_owned()),
+            ..Default::default()
+        };
+        let provider = OidcIdentityProvider::discover(&oidc_settings)
+            .await
+            .unwrap();
+        let app = make_app_from_state(ServerState {
+            identity_provider: Box::new(provider),
+            ..make_state(vec![])
+        })
+        .await;
+
+        let exp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 300;
+        let claims = json!({"iss": issuer, "aud": "sync", "exp": exp, "sub": "alice"});
+        let token = make_jwt("key-1", &claims, TEST_PRIVATE_KEY_PEM);
+        let req = TestRequest::get()
+            .uri("/1.0/sync/1.5")
+            .insert_header(("Authorization", format!("Bearer {token}")))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+
+        // A token for another audience is still refused
+        let claims = json!({"iss": issuer, "aud": "other", "exp": exp, "sub": "alice"});
+        let token = make_jwt("key-1", &claims, TEST_PRIVATE_KEY_PEM);
+        let req = TestRequest::get()
+            .uri("/1.0/sync/1.5")
+            .insert_header(("Authorization", format!("Bearer {token}")))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 401);
+    }
 }
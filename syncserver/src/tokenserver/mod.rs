@@ -1,8 +1,11 @@
 #[allow(clippy::result_large_err)]
+pub mod admin;
+#[allow(clippy::result_large_err)]
 pub mod extractors;
 #[allow(clippy::result_large_err)]
 pub mod handlers;
 pub mod logging;
+pub mod purge;
 
 use actix_web::{HttpMessage, HttpRequest, dev::RequestHead, http::header::USER_AGENT};
 use cadence::StatsdClient;
@@ -13,12 +16,18 @@ use serde::{
 use syncserver_common::{BlockingThreadpool, Metrics};
 #[cfg(not(feature = "py_verifier"))]
 use tokenserver_auth::JWTVerifierImpl;
-use tokenserver_auth::{SETVerifierImpl, VerifyToken, oauth};
-use tokenserver_common::NodeType;
-use tokenserver_db::{DbPool, pool_from_settings};
-use tokenserver_settings::Settings;
+use tokenserver_auth::{
+    FxaIdentityProvider, IdentityProvider, OidcIdentityProvider, SETVerifierImpl, VerifyToken,
+    oauth,
+};
+use tokenserver_common::{NodeType, TokenserverError};
+use tokenserver_db::{DbPool, params, pool_from_settings};
+use tokenserver_settings::{IdentityProviderType, Settings};
 
-use crate::{error::ApiError, server::user_agent};
+use crate::{
+    error::{ApiError, ApiErrorKind},
+    server::user_agent,
+};
 
 use std::{collections::HashMap, fmt, sync::Arc};
 
@@ -27,7 +36,7 @@ pub struct ServerState {
     pub db_pool: Box<dyn DbPool>,
     pub fxa_email_domain: String,
     pub fxa_metrics_hash_secret: String,
-    pub oauth_verifier: Box<dyn VerifyToken<Output = oauth::VerifyOutput>>,
+    pub identity_provider: Box<dyn IdentityProvider>,
     pub node_capacity_release_rate: Option<f32>,
     pub node_type: NodeType,
     pub metrics: Arc<StatsdClient>,
@@ -35,45 +44,54 @@ pub struct ServerState {
     pub set_verifiers: Vec<SETVerifierImpl>,
     pub fxa_webhook_enabled: bool,
     pub fxa_webhook_metrics_only: bool,
+    /// Bearer token for the `/__admin__` endpoints
+    pub admin_token: Option<String>,
+    /// The services tokens are issued for in addition to Sync 1.5
+    pub services: Vec<Service>,
+}
+
+/// A service tokens are issued for, requested at `/1.0/{application}/{version}`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Service {
+    pub application: String,
+    pub version: String,
+    pub token_duration: u64,
+    /// Signs the service's tokens in place of the master secret when set
+    pub secret: Option<String>,
+    pub node_capacity_release_rate: Option<f32>,
+}
+
+impl Service {
+    /// The service's name in the `services` table, e.g. `sync-1.5`
+    pub fn name(&self) -> String {
+        format!("{}-{}", self.application, self.version)
+    }
 }
 
 impl ServerState {
-    pub fn from_settings(
+    pub async fn from_settings(
         settings: &Settings,
         metrics: Arc<StatsdClient>,
-        #[allow(unused_variables)] blocking_threadpool: Arc<BlockingThreadpool>,
+        blocking_threadpool: Arc<BlockingThreadpool>,
     ) -> Result<Self, ApiError> {
-        #[cfg(not(feature = "py_verifier"))]
-        let oauth_verifier = {
-            let mut jwk_verifiers: Vec<JWTVerifierImpl> = Vec::new();
-            if let Some(primary) = &settings.fxa_oauth_primary_jwk {
-                jwk_verifiers.push(
-                    primary
-                        .clone()
-                        .try_into()
-                        .expect("Invalid primary key, should either be fixed or removed"),
-                )
+        let identity_provider: Box<dyn IdentityProvider> = match settings.identity_provider {
+            IdentityProviderType::Fxa => Box::new(FxaIdentityProvider::new(
+                Self::fxa_oauth_verifier(settings, blocking_threadpool),
+                settings.fxa_email_domain.clone(),
+            )),
+            IdentityProviderType::Oidc => {
+                let provider = OidcIdentityProvider::discover(&settings.oidc)
+                    .await
+                    .map_err(|e| {
+                        ApiErrorKind::Internal(format!(
+                            "Failed to discover the Tokenserver OIDC provider: {e}"
+                        ))
+                    })?;
+                provider.jwks().spawn_refresher();
+                Box::new(provider)
             }
-            if let Some(secondary) = &settings.fxa_oauth_secondary_jwk {
-                jwk_verifiers.push(
-                    secondary
-                        .clone()
-                        .try_into()
-                        .expect("Invalid secondary key, should either be fixed or removed"),
-                );
-            }
-            Box::new(
-                oauth::Verifier::new(settings, jwk_verifiers)
-                    .expect("failed to create Tokenserver OAuth verifier"),
-            )
         };
 
-        #[cfg(feature = "py_verifier")]
-        let oauth_verifier = Box::new(
-            oauth::Verifier::new(settings, blocking_threadpool.clone())
-                .expect("failed to create Tokenserver OAuth verifier"),
-        );
-
         let set_verifiers = {
             let mut verifiers = Vec::with_capacity(2);
             if let Some(client_id) = &settings.fxa_client_id {
@@ -105,10 +123,23 @@ impl ServerState {
 
         let db_pool = pool_from_settings(settings, &Metrics::from(&metrics), use_test_transactions)
             .expect("Failed to create Tokenserver pool");
+        let services = settings
+            .services
+            .iter()
+            .map(|service| Service {
+                application: service.application.clone(),
+                version: service.version.clone(),
+                token_duration: service.token_duration.unwrap_or(settings.token_duration),
+                secret: service.master_secret.clone(),
+                node_capacity_release_rate: service
+                    .node_capacity_release_rate
+                    .or(settings.node_capacity_release_rate),
+            })
+            .collect();
         Ok(ServerState {
             fxa_email_domain: settings.fxa_email_domain.clone(),
             fxa_metrics_hash_secret: settings.fxa_metrics_hash_secret.clone(),
-            oauth_verifier,
+            identity_provider,
             db_pool,
             node_capacity_release_rate: settings.node_capacity_release_rate,
             node_type: settings.node_type,
@@ -117,15 +148,123 @@ impl ServerState {
             set_verifiers,
             fxa_webhook_enabled: settings.fxa_webhook_enabled,
             fxa_webhook_metrics_only: settings.fxa_webhook_metrics_only,
+            admin_token: settings.admin_token.clone(),
+            services,
         })
     }
 
-    /// Initialize the db_pool: run migrations, etc.
+    /// Get the service requested at `/1.0/{application}/{version}`
+    #[allow(clippy::result_large_err)]
+    pub fn service(&self, application: &str, version: &str) -> Result<Service, TokenserverError> {
+        if application == "sync" && version == "1.5" {
+            return Ok(Service {
+                application: application.to_owned(),
+                version: version.to_owned(),
+                token_duration: self.token_duration,
+                secret: None,
+                node_capacity_release_rate: self.node_capacity_release_rate,
+            });
+        }
+        if let Some(service) = self
+            .services
+            .iter()
+            .find(|service| service.application == application && service.version == version)
+        {
+            return Ok(service.clone());
+        }
+
+        if application == "sync"
+            || self
+                .services
+                .iter()
+                .any(|service| service.application == application)
+        {
+            Err(TokenserverError::unsupported(
+                "Unsupported application version".to_owned(),
+                version.to_owned(),
+            ))
+        } else {
+            // NOTE: It would probably be better to include the name of the unsupported
+            // application in the error message, but the old Tokenserver only includes
+            // "application" in the error message. To keep the APIs between the old and
+            // new Tokenservers as close as possible, we defer to the error message from
+            // the old Tokenserver.
+            Err(TokenserverError::unsupported(
+                "Unsupported application".to_owned(),
+                "application".to_owned(),
+            ))
+        }
+    }
+
+    #[cfg(not(feature = "py_verifier"))]
+    fn fxa_oauth_verifier(
+        settings: &Settings,
+        _blocking_threadpool: Arc<BlockingThreadpool>,
+    ) -> Box<dyn VerifyToken<Output = oauth::VerifyOutput>> {
+        let mut jwk_verifiers: Vec<JWTVerifierImpl> = Vec::new();
+        if let Some(primary) = &settings.fxa_oauth_primary_jwk {
+            jwk_verifiers.push(
+                primary
+                    .clone()
+                    .try_into()
+                    .expect("Invalid primary key, should either be fixed or removed"),
+            )
+        }
+        if let Some(secondary) = &settings.fxa_oauth_secondary_jwk {
+            jwk_verifiers.push(
+                secondary
+                    .clone()
+                    .try_into()
+                    .expect("Invalid secondary key, should either be fixed or removed"),
+            );
+        }
+        let verifier = oauth::Verifier::new(settings, jwk_verifiers)
+            .expect("failed to create Tokenserver OAuth verifier");
+        // Without configured keys, FxA's are fetched now and kept up to date
+        if let Some(jwks) = verifier.jwks() {
+            jwks.spawn_refresher();
+        }
+        Box::new(verifier)
+    }
+
+    #[cfg(feature = "py_verifier")]
+    fn fxa_oauth_verifier(
+        settings: &Settings,
+        blocking_threadpool: Arc<BlockingThreadpool>,
+    ) -> Box<dyn VerifyToken<Output = oauth::VerifyOutput>> {
+        Box::new(
+            oauth::Verifier::new(settings, blocking_threadpool)
+                .expect("failed to create Tokenserver OAuth verifier"),
+        )
+    }
+
+    /// Initialize the db_pool: run migrations, record the configured services, etc.
     pub async fn init(&mut self) {
         self.db_pool
             .init()
             .await
             .expect("Failed to init Tokenserver pool");
+
+        if self.services.is_empty() {
+            return;
+        }
+        let mut db = self
+            .db_pool
+            .get()
+            .await
+            .expect("Failed to get a Tokenserver db connection");
+        for service in &self.services {
+            let inserted = db
+                .insert_service(params::PostService {
+                    service: service.name(),
+                    pattern: format!("{{node}}/{}/{{uid}}", service.version),
+                })
+                .await
+                .expect("Failed to insert a Tokenserver service");
+            if inserted {
+                info!("Added Tokenserver service {}", service.name());
+            }
+        }
     }
 }
 
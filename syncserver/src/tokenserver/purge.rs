@@ -0,0 +1,561 @@
+//! Purging of replaced (or retired) users, along with the data they left on
+//! their storage node, replacing `tools/tokenserver/purge_old_records.py`.
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use base64::{Engine, engine};
+use cadence::StatsdClient;
+use chrono::{TimeDelta, Utc};
+use hawk::{Credentials, Key, RequestBuilder};
+use reqwest::{Client, StatusCode, Url, header::AUTHORIZATION};
+use syncserver_common::Metrics;
+use thiserror::Error;
+use tokenserver_auth::{MakeTokenPlaintext, Tokenlib, TokenserverOrigin};
+use tokenserver_db::{Db, DbError, DbPool, SYNC_SERVICE_NAME, params, results::OldUser};
+use tokenserver_settings::Settings;
+use tokio::time;
+
+use super::extractors::{fxa_metrics_hash, hash_device_id};
+
+/// The tokens signing storage deletions only need to outlive a single request
+const TOKEN_DURATION: i64 = 300;
+
+#[derive(Debug, Error)]
+pub enum PurgeError {
+    #[error("Database error: {0}")]
+    Db(DbError),
+    #[error("Error deleting storage: {0}")]
+    Storage(String),
+}
+
+impl From<DbError> for PurgeError {
+    fn from(e: DbError) -> Self {
+        PurgeError::Db(e)
+    }
+}
+
+/// Deletes the data a user left on their storage node
+#[async_trait(?Send)]
+pub trait StorageClient {
+    /// Delete all of `user`'s data on `user.node`. Succeeds when there's
+    /// nothing left to delete.
+    async fn delete_storage(&self, user: &OldUser) -> Result<(), PurgeError>;
+}
+
+/// Deletes a user's storage with a `DELETE /1.5/{uid}` request to their node,
+/// signed with a token minted for the user (as if sent by the user's client)
+pub struct HawkStorageClient {
+    client: Client,
+    shared_secret: String,
+    fxa_metrics_hash_secret: String,
+}
+
+impl HawkStorageClient {
+    pub fn new(
+        shared_secret: String,
+        fxa_metrics_hash_secret: String,
+        timeout: Duration,
+    ) -> Result<Self, PurgeError> {
+        let client = Client::builder()
+            .timeout(timeout)
+            .build()
+            .map_err(|e| PurgeError::Storage(e.to_string()))?;
+        Ok(Self {
+            client,
+            shared_secret,
+            fxa_metrics_hash_secret,
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl StorageClient for HawkStorageClient {
+    async fn delete_storage(&self, user: &OldUser) -> Result<(), PurgeError> {
+        let node = user
+            .node
+            .as_deref()
+            .ok_or_else(|| PurgeError::Storage(format!("uid {} has no node", user.uid)))?;
+        let fxa_uid = user.email.split('@').next().unwrap_or_default();
+        let hmac_key = self.fxa_metrics_hash_secret.as_bytes();
+        let hashed_fxa_uid = fxa_metrics_hash(fxa_uid, hmac_key)[0..32].to_owned();
+        let plaintext = MakeTokenPlaintext {
+            node: node.to_owned(),
+            fxa_kid: fxa_kid(user.keys_changed_at, user.generation, &user.client_state)?,
+            fxa_uid: fxa_uid.to_owned(),
+            hashed_device_id: hash_device_id(&hashed_fxa_uid, hmac_key),
+            hashed_fxa_uid,
+            expires: (Utc::now() + TimeDelta::seconds(TOKEN_DURATION)).timestamp() as u64,
+            uid: user.uid,
+            tokenserver_origin: TokenserverOrigin::Rust,
+        };
+        let (token, derived_secret) =
+            Tokenlib::get_token_and_derived_secret(plaintext, &self.shared_secret)
+                .map_err(|e| PurgeError::Storage(e.context))?;
+
+        let url = Url::parse(&format!("{}/1.5/{}", node, user.uid))
+            .map_err(|e| PurgeError::Storage(format!("Invalid node {}: {}", node, e)))?;
+        let credentials = Credentials {
+            id: token,
+            key: Key::new(derived_secret.as_bytes(), hawk::DigestAlgorithm::Sha256)
+                .map_err(|e| PurgeError::Storage(e.to_string()))?,
+        };
+        let header = RequestBuilder::from_url("DELETE", &url)
+            .and_then(|builder| builder.request().make_header(&credentials))
+            .map_err(|e| PurgeError::Storage(e.to_string()))?;
+
+        let response = self
+            .client
+            .delete(url)
+            .header(AUTHORIZATION, format!("Hawk {}", header))
+            .send()
+            .await
+            .map_err(|e| PurgeError::Storage(e.to_string()))?;
+        match response.status() {
+            status if status.is_success() || status == StatusCode::NOT_FOUND => Ok(()),
+            status => Err(PurgeError::Storage(format!(
+                "{} responded with {}",
+                node, status
+            ))),
+        }
+    }
+}
+
+/// The key id storage nodes identify a user's data by (alongside their FxA uid)
+fn fxa_kid(
+    keys_changed_at: Option<i64>,
+    generation: i64,
+    client_state: &str,
+) -> Result<String, PurgeError> {
+    let client_state = hex::decode(client_state)
+        .map_err(|e| PurgeError::Storage(format!("Failed to decode the client state: {}", e)))?;
+    Ok(format!(
+        "{:013}-{:}",
+        keys_changed_at.unwrap_or(generation),
+        engine::general_purpose::URL_SAFE_NO_PAD.encode(client_state)
+    ))
+}
+
+#[derive(Clone, Debug)]
+pub struct PurgeOptions {
+    /// Only purge users replaced at least this long ago
+    pub grace_period: Duration,
+    /// Max number of users fetched at a time
+    pub batch_size: i64,
+    /// Max number of storage deletions issued per second (unlimited when 0)
+    pub max_per_second: u32,
+    /// Only log the users that would be purged
+    pub dry_run: bool,
+}
+
+impl From<&Settings> for PurgeOptions {
+    fn from(settings: &Settings) -> Self {
+        Self {
+            grace_period: Duration::from_secs(settings.purge_replaced_users_grace_period.into()),
+            batch_size: settings.purge_replaced_users_batch_size.into(),
+            max_per_second: settings.purge_replaced_users_max_per_second,
+            dry_run: settings.purge_replaced_users_dry_run,
+        }
+    }
+}
+
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct PurgeSummary {
+    /// Users whose record (and storage, if any) were deleted
+    pub purged: u64,
+    /// Users left in place as their node is down
+    pub skipped: u64,
+    /// Users whose storage couldn't be deleted, deferred by another grace period
+    pub failed: u64,
+}
+
+/// Purge the users replaced (or retired) more than the grace period ago: their
+/// data is deleted from their storage node via `client`, then their record.
+///
+/// Users on a downed node are left until the node is back up (or removed).
+/// When a user's storage can't be deleted, their `replaced_at` is bumped so
+/// they're retried after another grace period, rather than blocking the rest.
+pub async fn purge_replaced_users(
+    db: &mut dyn Db,
+    client: &dyn StorageClient,
+    options: &PurgeOptions,
+) -> Result<PurgeSummary, PurgeError> {
+    let service_id = db
+        .get_service_id(params::GetServiceId {
+            service: SYNC_SERVICE_NAME.to_owned(),
+        })
+        .await?
+        .id;
+    let replaced_before = Utc::now().timestamp_millis() - options.grace_period.as_millis() as i64;
+    let delay =
+        (options.max_per_second > 0).then(|| Duration::from_secs(1) / options.max_per_second);
+    let mut last_deletion: Option<Instant> = None;
+    let mut summary = PurgeSummary::default();
+    // Purged and deferred users drop out of the results, so only page past
+    // the users left in place
+    let mut offset = 0;
+    loop {
+        let users = db
+            .get_old_users(params::GetOldUsers {
+                service_id,
+                replaced_before,
+                limit: options.batch_size,
+                offset,
+            })
+            .await?;
+        for user in &users {
+            let Some(node) = &user.node else {
+                info!("Deleting user record for uid {} (no node)", user.uid);
+                if options.dry_run {
+                    offset += 1;
+                } else {
+                    db.delete_user(params::DeleteUser {
+                        service_id,
+                        uid: user.uid,
+                    })
+                    .await?;
+                }
+                summary.purged += 1;
+                continue;
+            };
+            if user.downed.unwrap_or(0) != 0 {
+                debug!("Skipping uid {} on downed node {}", user.uid, node);
+                offset += 1;
+                summary.skipped += 1;
+                continue;
+            }
+
+            let shared = shares_storage(db, service_id, user).await?;
+            info!(
+                "Purging uid {} on {}{}",
+                user.uid,
+                node,
+                if shared { " (storage shared)" } else { "" }
+            );
+            if options.dry_run {
+                offset += 1;
+                summary.purged += 1;
+                continue;
+            }
+            if !shared {
+                if let (Some(delay), Some(last)) = (delay, last_deletion) {
+                    time::sleep(delay.saturating_sub(last.elapsed())).await;
+                }
+                last_deletion = Some(Instant::now());
+                if let Err(e) = client.delete_storage(user).await {
+                    warn!("Failed to purge uid {}, deferring retry: {}", user.uid, e);
+                    db.replace_user(params::ReplaceUser {
+                        uid: user.uid,
+                        service_id,
+                        replaced_at: Utc::now().timestamp_millis(),
+                    })
+                    .await?;
+                    summary.failed += 1;
+                    continue;
+                }
+            }
+            db.delete_user(params::DeleteUser {
+                service_id,
+                uid: user.uid,
+            })
+            .await?;
+            summary.purged += 1;
+        }
+        if (users.len() as i64) < options.batch_size {
+            return Ok(summary);
+        }
+    }
+}
+
+/// Whether one of the user's current records is on the same node with the
+/// same keys, in which case the storage is still in use (e.g. after their node
+/// was unassigned and they were allocated to it again)
+async fn shares_storage(
+    db: &mut dyn Db,
+    service_id: i32,
+    user: &OldUser,
+) -> Result<bool, PurgeError> {
+    let Ok(kid) = fxa_kid(user.keys_changed_at, user.generation, &user.client_state) else {
+        return Ok(false);
+    };
+    let current = db
+        .get_users(params::GetUsers {
+            service_id,
+            email: user.email.clone(),
+        })
+        .await?;
+    Ok(current.iter().any(|other| {
+        other.replaced_at.is_none()
+            && other.node == user.node
+            && fxa_kid(other.keys_changed_at, other.generation, &other.client_state)
+                .is_ok_and(|other_kid| other_kid == kid)
+    }))
+}
+
+/// Periodically purge the replaced users, every `interval`
+pub fn spawn_replaced_user_purger(
+    interval: Duration,
+    options: PurgeOptions,
+    client: Box<dyn StorageClient>,
+    metrics: &Arc<StatsdClient>,
+    pool: Box<dyn DbPool>,
+) {
+    let metrics = Metrics::from(metrics);
+    // Db futures aren't Send: run on the current thread's runtime
+    actix_web::rt::spawn(async move {
+        loop {
+            time::sleep(interval).await;
+            let mut metrics = metrics.clone();
+            metrics.start_timer("purge_replaced_users", None);
+            let result = match pool.get().await {
+                Ok(mut db) => purge_replaced_users(&mut *db, &*client, &options).await,
+                Err(e) => Err(e.into()),
+            };
+            match result {
+                Ok(summary) => {
+                    debug!(
+                        "Purged {} replaced users ({} skipped, {} failed)",
+                        summary.purged, summary.skipped, summary.failed
+                    );
+                    metrics.count("purge_replaced_users.purged", summary.purged as i64);
+                    metrics.count("purge_replaced_users.failed", summary.failed as i64);
+                }
+                Err(e) => {
+                    error!("⚠️ Purging replaced users failed: {}", e);
+                    metrics.incr("purge_replaced_users.error");
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::HashSet};
+
+    use syncserver_settings::Settings;
+    use tokenserver_db::pool_from_settings;
+
+    use super::*;
+
+    /// Records the deletions rather than sending them, failing for `failing_nodes`
+    #[derive(Default)]
+    struct LocalStorageClient {
+        deleted: RefCell<Vec<i64>>,
+        failing_nodes: HashSet<String>,
+    }
+
+    #[async_trait(?Send)]
+    impl StorageClient for LocalStorageClient {
+        async fn delete_storage(&self, user: &OldUser) -> Result<(), PurgeError> {
+            let node = user.node.clone().unwrap_or_default();
+            if self.failing_nodes.contains(&node) {
+                return Err(PurgeError::Storage(format!("{} is unavailable", node)));
+            }
+            self.deleted.borrow_mut().push(user.uid);
+            Ok(())
+        }
+    }
+
+    async fn db_pool() -> Box<dyn DbPool> {
+        let mut settings = Settings::test_settings();
+        settings.tokenserver.run_migrations = true;
+        let mut pool = pool_from_settings(&settings.tokenserver, &Metrics::noop(), true).unwrap();
+        pool.init().await.unwrap();
+        pool
+    }
+
+    async fn post_node(db: &mut dyn Db, service_id: i32, node: &str, downed: i32) -> i64 {
+        db.post_node(params::PostNode {
+            service_id,
+            node: node.to_owned(),
+            capacity: 100,
+            available: 100,
+            downed,
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .id
+    }
+
+    async fn post_user(
+        db: &mut dyn Db,
+        service_id: i32,
+        email: &str,
+        node_id: i64,
+        client_state: &str,
+        replaced_at: Option<i64>,
+    ) -> i64 {
+        let uid = db
+            .post_user(params::PostUser {
+                service_id,
+                email: email.to_owned(),
+                generation: 1234,
+                client_state: client_state.to_owned(),
+                created_at: 1,
+                node_id,
+                keys_changed_at: Some(1234),
+            })
+            .await
+            .unwrap()
+            .uid;
+        if let Some(replaced_at) = replaced_at {
+            db.replace_user(params::ReplaceUser {
+                uid,
+                service_id,
+                replaced_at,
+            })
+            .await
+            .unwrap();
+        }
+        uid
+    }
+
+    #[actix_web::test]
+    async fn purge_replaced_users() {
+        let pool = db_pool().await;
+        let mut db = pool.get().await.unwrap();
+        let service_id = db
+            .get_service_id(params::GetServiceId {
+                service: SYNC_SERVICE_NAME.to_owned(),
+            })
+            .await
+            .unwrap()
+            .id;
+        let db = &mut *db;
+        let node1 = post_node(db, service_id, "https://purge-node1", 0).await;
+        let node2 = post_node(db, service_id, "https://purge-node2", 0).await;
+        let downed = post_node(db, service_id, "https://purge-downed", 1).await;
+        let failing = post_node(db, service_id, "https://purge-failing", 0).await;
+
+        let an_hour_ago = Utc::now().timestamp_millis() - 3_600_000;
+        // Moved from node1 to node2
+        let moved = post_user(
+            db,
+            service_id,
+            "moved@test",
+            node1,
+            "aaaa",
+            Some(an_hour_ago),
+        )
+        .await;
+        post_user(db, service_id, "moved@test", node2, "aaaa", None).await;
+        // Reallocated to the same node with the same keys: the storage is shared
+        let shared = post_user(
+            db,
+            service_id,
+            "shared@test",
+            node1,
+            "bbbb",
+            Some(an_hour_ago),
+        )
+        .await;
+        post_user(db, service_id, "shared@test", node1, "bbbb", None).await;
+        // Changed keys on the same node
+        let rekeyed = post_user(
+            db,
+            service_id,
+            "rekeyed@test",
+            node1,
+            "cccc",
+            Some(an_hour_ago),
+        )
+        .await;
+        post_user(db, service_id, "rekeyed@test", node1, "dddd", None).await;
+        let on_downed = post_user(
+            db,
+            service_id,
+            "downed@test",
+            downed,
+            "aaaa",
+            Some(an_hour_ago),
+        )
+        .await;
+        let on_failing = post_user(
+            db,
+            service_id,
+            "failing@test",
+            failing,
+            "aaaa",
+            Some(an_hour_ago),
+        )
+        .await;
+        // Within the grace period
+        let recent = post_user(
+            db,
+            service_id,
+            "recent@test",
+            node1,
+            "aaaa",
+            Some(Utc::now().timestamp_millis()),
+        )
+        .await;
+
+        let client = LocalStorageClient {
+            failing_nodes: HashSet::from(["https://purge-failing".to_owned()]),
+            ..Default::default()
+        };
+        let options = PurgeOptions {
+            grace_period: Duration::from_secs(60),
+            batch_size: 2,
+            max_per_second: 0,
+            dry_run: true,
+        };
+        let summary = super::purge_replaced_users(db, &client, &options)
+            .await
+            .unwrap();
+        assert_eq!(
+            summary,
+            PurgeSummary {
+                purged: 4,
+                skipped: 1,
+                failed: 0,
+            }
+        );
+        assert!(client.deleted.borrow().is_empty());
+
+        let options = PurgeOptions {
+            dry_run: false,
+            ..options
+        };
+        let summary = super::purge_replaced_users(db, &client, &options)
+            .await
+            .unwrap();
+        assert_eq!(
+            summary,
+            PurgeSummary {
+                purged: 3,
+                skipped: 1,
+                failed: 1,
+            }
+        );
+        let mut deleted = client.deleted.borrow().clone();
+        deleted.sort();
+        assert_eq!(deleted, vec![moved, rekeyed]);
+
+        let old_users = db
+            .get_old_users(params::GetOldUsers {
+                service_id,
+                replaced_before: Utc::now().timestamp_millis() + 1000,
+                limit: 100,
+                offset: 0,
+            })
+            .await
+            .unwrap();
+        let remaining: Vec<i64> = old_users.iter().map(|user| user.uid).collect();
+        assert!(remaining.contains(&on_downed));
+        assert!(remaining.contains(&recent));
+        assert!(!remaining.contains(&moved));
+        assert!(!remaining.contains(&shared));
+        assert!(!remaining.contains(&rekeyed));
+        // The failed deletion is retried after another grace period
+        let failed = old_users
+            .iter()
+            .find(|user| user.uid == on_failing)
+            .unwrap();
+        assert!(failed.replaced_at > an_hour_ago);
+    }
+}
@@ -6,18 +6,37 @@
     allow(dead_code, unused_imports, unused_variables)
 )]
 
+use std::{
+    fmt::Debug,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
 use base64::{Engine, engine};
-use chrono::{TimeDelta, offset::Utc};
+use chrono::offset::Utc;
 use hawk::{self, Header as HawkHeader, Key, RequestBuilder};
 use hmac::{Hmac, KeyInit, Mac};
+use ipnet::IpNet;
+use lru::LruCache;
 use serde::{Deserialize, Serialize};
-use sha2::Sha256;
+use sha2::{Digest, Sha256};
 use syncserver_common;
-use syncserver_settings::Secrets;
+use syncserver_settings::{Secrets, Settings};
+use syncstorage_db::{Sorting, params};
+use syncstorage_settings::Hawk as HawkSettings;
 use tokenserver_auth::TokenserverOrigin;
 
-use actix_web::dev::ConnectionInfo;
-use actix_web::http::Uri;
+use actix_web::HttpRequest;
+use actix_web::dev::{AppConfig, RequestHead};
+use actix_web::http::{
+    Uri,
+    header::{
+        AUTHORIZATION, FORWARDED, HOST, HeaderMap, X_FORWARDED_FOR, X_FORWARDED_HOST,
+        X_FORWARDED_PROTO,
+    },
+};
 
 use super::{
     error::{HawkErrorKind, ValidationErrorKind},
@@ -66,6 +85,7 @@ impl HawkPayload {
     ///
     /// Assumes that the header string
     /// includes the `Hawk ` prefix.
+    #[allow(clippy::too_many_arguments)]
     fn new(
         header: &str,
         method: &str,
@@ -74,6 +94,7 @@ impl HawkPayload {
         port: u16,
         secrets: &Secrets,
         expiry: u64,
+        replay_guard: &ReplayGuard,
     ) -> ApiResult<HawkPayload> {
         if header.len() < 5 || &header[0..5] != "Hawk " {
             Err(HawkErrorKind::MissingPrefix)?;
@@ -101,24 +122,14 @@ impl HawkPayload {
 
         #[cfg(not(feature = "no_auth"))]
         {
-            let mut duration = TimeDelta::weeks(52)
-                .to_std()
-                .map_err(|_| ApiErrorKind::Internal("Duration::weeks".to_owned()))?;
-            if cfg!(test) {
-                // test cases are valid until 3018. Add millenia as required.
-                duration *= 1000;
-            }
-            if request.validate_header(
-                &header,
-                &Key::new(token_secret.as_bytes(), hawk::DigestAlgorithm::Sha256)?,
-                // Allow plenty of leeway for clock skew, because
-                // client timestamps tend to be all over the shop
-                duration,
-            ) {
-                Ok(payload)
-            } else {
-                Err(HawkErrorKind::InvalidHeader)?
+            let key = Key::new(token_secret.as_bytes(), hawk::DigestAlgorithm::Sha256)?;
+            // The timestamp's checked by the ReplayGuard instead, so stale
+            // (but otherwise valid) requests are sent the server's time
+            if !request.validate_header(&header, &key, Duration::MAX) {
+                Err(HawkErrorKind::InvalidHeader)?;
             }
+            replay_guard.check(id, &header, &key)?;
+            Ok(payload)
         }
     }
 
@@ -163,15 +174,95 @@ impl HawkPayload {
 }
 
 impl HawkPayload {
+    /// Verify the request's Hawk header against the host/port it was sent to
+    /// (see [HawkOrigin::host_port]).
     pub fn extrude(
         header: &str,
         method: &str,
         secrets: &Secrets,
-        ci: &ConnectionInfo,
+        host: &str,
+        port: u16,
         uri: &Uri,
+        replay_guard: &ReplayGuard,
     ) -> ApiResult<Self> {
-        let host_port: Vec<_> = ci.host().splitn(2, ':').collect();
-        let host = host_port[0];
+        let path = uri.path_and_query().ok_or(HawkErrorKind::MissingPath)?;
+        let expiry = if path.path().ends_with("/info/collections") {
+            0
+        } else {
+            Utc::now().timestamp() as u64
+        };
+
+        HawkPayload::new(
+            header,
+            method,
+            path.as_str(),
+            host,
+            port,
+            secrets,
+            expiry,
+            replay_guard,
+        )
+    }
+}
+
+/// Determines the host and port clients sent requests to, which Hawk MACs are
+/// verified against.
+#[derive(Clone, Debug, Default)]
+pub struct HawkOrigin {
+    /// The host and port of `public_url`, overriding the request's own
+    public: Option<(String, u16)>,
+    /// Peers whose `Forwarded`/`X-Forwarded-*` headers are trusted
+    trusted_proxies: Vec<IpNet>,
+}
+
+impl HawkOrigin {
+    pub fn from_settings(settings: &Settings) -> ApiResult<Self> {
+        let public = match &settings.public_url {
+            Some(public_url) => {
+                let uri: Uri = public_url.parse().map_err(|e| {
+                    ApiErrorKind::Internal(format!("Invalid public_url {public_url:?}: {e}"))
+                })?;
+                let host = uri.host().ok_or_else(|| {
+                    ApiErrorKind::Internal(format!("Invalid public_url {public_url:?}: no host"))
+                })?;
+                let port = uri.port_u16().unwrap_or(default_port(uri.scheme_str()));
+                Some((host.to_owned(), port))
+            }
+            None => None,
+        };
+        Ok(Self {
+            public,
+            trusted_proxies: settings.trusted_proxies.clone(),
+        })
+    }
+
+    /// The host and port the request was sent to.
+    ///
+    /// The request's forwarding headers are only considered when its peer is
+    /// a trusted proxy (see [HawkOrigin::forwarded_hop]), otherwise its `Host`
+    /// is used as is. When TLS is terminated by this server
+    /// (`config.secure()`) the scheme is always "https".
+    pub fn host_port(&self, head: &RequestHead, config: &AppConfig) -> ApiResult<(String, u16)> {
+        if let Some((host, port)) = &self.public {
+            return Ok((host.clone(), *port));
+        }
+        let hop = head
+            .peer_addr
+            .filter(|addr| self.is_trusted(addr.ip()))
+            .and_then(|_| self.forwarded_hop(&head.headers));
+        let host = hop
+            .as_ref()
+            .and_then(|hop| hop.host)
+            .or_else(|| head.headers.get(HOST).and_then(|host| host.to_str().ok()))
+            .or_else(|| head.uri.authority().map(|authority| authority.as_str()))
+            .unwrap_or_else(|| config.host());
+        let scheme = if config.secure() {
+            Some("https")
+        } else {
+            hop.and_then(|hop| hop.proto).or(head.uri.scheme_str())
+        };
+
+        let host_port: Vec<_> = host.splitn(2, ':').collect();
         let port = if host_port.len() == 2 {
             host_port[1].parse().map_err(|_| {
                 ValidationErrorKind::FromDetails(
@@ -181,19 +272,274 @@ impl HawkPayload {
                     Some("request.validate.hawk.invalid_port"),
                 )
             })?
-        } else if ci.scheme() == "https" {
-            443
         } else {
-            80
+            default_port(scheme)
         };
-        let path = uri.path_and_query().ok_or(HawkErrorKind::MissingPath)?;
-        let expiry = if path.path().ends_with("/info/collections") {
-            0
+        Ok((host_port[0].to_owned(), port))
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.trusted_proxies.iter().any(|net| net.contains(&ip))
+    }
+
+    /// The entry of the `Forwarded` (or `X-Forwarded-*`) headers describing
+    /// the request as sent by the client.
+    ///
+    /// Each proxy appends an entry for the request it received, so entries are
+    /// read right to left, skipping those received from further trusted
+    /// proxies. Entries left of the first untrusted peer's may have been
+    /// spoofed by the client and are ignored.
+    fn forwarded_hop<'a>(&self, headers: &'a HeaderMap) -> Option<ForwardedHop<'a>> {
+        let mut hops = ForwardedHop::parse(headers);
+        let index = hops
+            .iter()
+            .rposition(|hop| !hop.peer.is_some_and(|ip| self.is_trusted(ip)))
+            .unwrap_or(0);
+        (index < hops.len()).then(|| hops.swap_remove(index))
+    }
+}
+
+/// A proxy's forwarding header entry for the request it received
+#[derive(Debug, Default, PartialEq)]
+struct ForwardedHop<'a> {
+    /// The peer the proxy received the request from
+    peer: Option<IpAddr>,
+    host: Option<&'a str>,
+    proto: Option<&'a str>,
+}
+
+impl<'a> ForwardedHop<'a> {
+    /// The entries of the `Forwarded` header, otherwise the `X-Forwarded-For`,
+    /// `-Host` and `-Proto` headers, from left to right
+    fn parse(headers: &'a HeaderMap) -> Vec<Self> {
+        let values = |name| {
+            headers
+                .get_all(name)
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .collect::<Vec<_>>()
+        };
+
+        let forwarded = values(FORWARDED);
+        if !forwarded.is_empty() {
+            return forwarded
+                .into_iter()
+                .map(|element| {
+                    let mut hop = Self::default();
+                    for pair in element.split(';') {
+                        let Some((name, value)) = pair.split_once('=') else {
+                            continue;
+                        };
+                        let value = value.trim().trim_matches('"');
+                        match name.trim().to_ascii_lowercase().as_str() {
+                            "for" => hop.peer = parse_node(value),
+                            "host" => hop.host = Some(value),
+                            "proto" => hop.proto = Some(value),
+                            _ => (),
+                        }
+                    }
+                    hop
+                })
+                .collect();
+        }
+
+        // Proxies may append to each header or only set some, so the headers
+        // are aligned from the right, with shorter ones' leftmost values
+        // applying to the remaining entries
+        let (peers, hosts, protos) = (
+            values(X_FORWARDED_FOR),
+            values(X_FORWARDED_HOST),
+            values(X_FORWARDED_PROTO),
+        );
+        let len = peers.len().max(hosts.len()).max(protos.len());
+        let nth = |values: &[&'a str], i: usize| {
+            values
+                .len()
+                .checked_sub(len - i)
+                .map_or(values.first(), |i| values.get(i))
+                .copied()
+        };
+        (0..len)
+            .map(|i| Self {
+                peer: peers
+                    .len()
+                    .checked_sub(len - i)
+                    .and_then(|i| parse_node(peers[i])),
+                host: nth(&hosts, i),
+                proto: nth(&protos, i),
+            })
+            .collect()
+    }
+}
+
+/// Parse the IP address of a forwarded node, e.g. `192.0.2.1`,
+/// `192.0.2.1:8080` or `[2001:db8::1]:8080`. Obfuscated and `unknown` nodes
+/// are `None`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    node.parse::<IpAddr>()
+        .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+        .or_else(|| {
+            node.strip_prefix('[')
+                .and_then(|node| node.strip_suffix(']'))
+                .and_then(|node| node.parse().ok())
+        })
+        .map(|ip: IpAddr| ip.to_canonical())
+}
+
+fn default_port(scheme: Option<&str>) -> u16 {
+    if scheme == Some("https") { 443 } else { 80 }
+}
+
+/// Rejects Hawk requests whose timestamps are too far from the server's clock,
+/// or whose nonces have been seen before.
+#[derive(Debug)]
+pub struct ReplayGuard {
+    timestamp_skew: Duration,
+    nonces: Option<Arc<dyn NonceStore>>,
+    /// Whether checked nonces are recorded, or only compared against those
+    /// already recorded
+    record_nonces: bool,
+}
+
+impl ReplayGuard {
+    pub fn new(timestamp_skew: Duration, nonces: Option<Arc<dyn NonceStore>>) -> Self {
+        Self {
+            timestamp_skew,
+            nonces,
+            record_nonces: true,
+        }
+    }
+
+    /// Remember nonces in an in-process [LruNonceStore]
+    pub fn from_settings(settings: &HawkSettings) -> Self {
+        let nonces = NonZeroUsize::new(settings.nonce_cache_size)
+            .map(|size| Arc::new(LruNonceStore::new(size)) as Arc<dyn NonceStore>);
+        Self::new(Duration::from_secs(settings.timestamp_skew.into()), nonces)
+    }
+
+    /// A guard rejecting the same timestamps and replayed nonces as this one,
+    /// but without recording nonces: for authenticating a request ahead of its
+    /// handler, which records its nonce
+    pub fn peeking(&self) -> Self {
+        Self {
+            timestamp_skew: self.timestamp_skew,
+            nonces: self.nonces.clone(),
+            record_nonces: false,
+        }
+    }
+
+    /// Check the timestamp and nonce of a header whose MAC has been verified
+    /// with `key`
+    fn check(&self, id: &str, header: &HawkHeader, key: &Key) -> ApiResult<()> {
+        let (Some(ts), Some(nonce)) = (header.ts, &header.nonce) else {
+            return Err(HawkErrorKind::InvalidHeader.into());
+        };
+        let now = SystemTime::now();
+        let skew = now.duration_since(ts).unwrap_or_else(|e| e.duration());
+        if skew > self.timestamp_skew {
+            Err(HawkErrorKind::StaleTimestamp(stale_timestamp_challenge(
+                now, key,
+            )?))?;
+        }
+
+        let Some(nonces) = &self.nonces else {
+            return Ok(());
+        };
+        // Replays are rejected as stale once this window's passed
+        let expires = ts.checked_add(self.timestamp_skew).unwrap_or(now);
+        let ts = ts.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let nonce_key: [u8; 32] = Sha256::new()
+            .chain_update(id)
+            .chain_update([0])
+            .chain_update(nonce)
+            .chain_update([0])
+            .chain_update(ts.to_be_bytes())
+            .finalize()
+            .into();
+        let fresh = if self.record_nonces {
+            nonces.insert(nonce_key, expires)
         } else {
-            Utc::now().timestamp() as u64
+            !nonces.contains(&nonce_key)
         };
+        if !fresh {
+            Err(HawkErrorKind::Replay)?;
+        }
+        Ok(())
+    }
+}
 
-        HawkPayload::new(header, method, path.as_str(), host, port, secrets, expiry)
+impl Default for ReplayGuard {
+    /// The default timestamp window without nonce checks
+    fn default() -> Self {
+        Self::new(
+            Duration::from_secs(HawkSettings::default().timestamp_skew.into()),
+            None,
+        )
+    }
+}
+
+/// The Hawk `WWW-Authenticate` challenge for a stale timestamp, including the
+/// server's time (and its MAC) so clients may correct their clock skew
+fn stale_timestamp_challenge(now: SystemTime, key: &Key) -> ApiResult<String> {
+    let ts = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let tsm = key.sign(format!("hawk.1.ts\n{ts}\n").as_bytes())?;
+    Ok(format!(
+        "Hawk ts=\"{ts}\", tsm=\"{}\", error=\"Stale timestamp\"",
+        engine::general_purpose::STANDARD.encode(tsm)
+    ))
+}
+
+/// Records the nonces of authenticated Hawk requests (hashed with their token
+/// id and timestamp). [LruNonceStore] keeps them per process: an
+/// implementation backed by a shared store may be used to also reject replays
+/// across nodes.
+pub trait NonceStore: Debug + Send + Sync {
+    /// Record a nonce until `expires`, returning false if it's already
+    /// recorded
+    fn insert(&self, nonce: [u8; 32], expires: SystemTime) -> bool;
+
+    /// Whether a nonce is recorded (and unexpired), without recording it
+    fn contains(&self, nonce: &[u8; 32]) -> bool;
+}
+
+/// An in-process [NonceStore] remembering up to a fixed number of the most
+/// recent nonces
+#[derive(Debug)]
+pub struct LruNonceStore {
+    nonces: Mutex<LruCache<[u8; 32], SystemTime>>,
+}
+
+impl LruNonceStore {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            nonces: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+}
+
+impl NonceStore for LruNonceStore {
+    fn insert(&self, nonce: [u8; 32], expires: SystemTime) -> bool {
+        let mut nonces = self.nonces.lock().expect("LruNonceStore lock poisoned");
+        if nonces
+            .peek(&nonce)
+            .is_some_and(|expires| *expires > SystemTime::now())
+        {
+            return false;
+        }
+        nonces.put(nonce, expires);
+        true
+    }
+
+    fn contains(&self, nonce: &[u8; 32]) -> bool {
+        self.nonces
+            .lock()
+            .expect("LruNonceStore lock poisoned")
+            .peek(nonce)
+            .is_some_and(|expires| *expires > SystemTime::now())
     }
 }
 
@@ -204,11 +550,123 @@ fn verify_hmac(info: &[u8], key: &[u8], expected: &[u8]) -> ApiResult<()> {
     hmac.verify_slice(expected).map_err(From::from)
 }
 
+/// Sign a pagination offset for the `X-Weave-Next-Offset` header.
+///
+/// Keyset cursors are encoded as an opaque `payload.signature` token so
+/// clients can't forge positions, nor reuse them for another listing: the
+/// signature covers the user, collection and sort order they were issued for.
+/// Legacy numeric offsets pass through as is.
+pub fn sign_offset(
+    offset: String,
+    uid: u64,
+    collection: &str,
+    sort: Sorting,
+    secrets: &Secrets,
+) -> String {
+    if !params::Offset::is_cursor(&offset) {
+        return offset;
+    }
+    let signature = offset_hmac(uid, collection, sort, offset.as_bytes(), secrets).finalize();
+    let engine = &engine::general_purpose::URL_SAFE_NO_PAD;
+    format!(
+        "{}.{}",
+        engine.encode(&offset),
+        engine.encode(signature.into_bytes())
+    )
+}
+
+/// Verify an `offset` token produced by [sign_offset] for the same listing,
+/// returning the offset it encodes. Unsigned cursors are rejected.
+pub fn verify_offset(
+    token: &str,
+    uid: u64,
+    collection: &str,
+    sort: Sorting,
+    secrets: &Secrets,
+) -> Option<String> {
+    let Some((payload, signature)) = token.split_once('.') else {
+        return (!params::Offset::is_cursor(token)).then(|| token.to_owned());
+    };
+    let engine = &engine::general_purpose::URL_SAFE_NO_PAD;
+    let offset = engine.decode(payload).ok()?;
+    let signature = engine.decode(signature).ok()?;
+    offset_hmac(uid, collection, sort, &offset, secrets)
+        .verify_slice(&signature)
+        .ok()?;
+    String::from_utf8(offset)
+        .ok()
+        .filter(|offset| params::Offset::is_cursor(offset))
+}
+
+fn offset_hmac(
+    uid: u64,
+    collection: &str,
+    sort: Sorting,
+    offset: &[u8],
+    secrets: &Secrets,
+) -> Hmac<Sha256> {
+    let mut hmac = Hmac::<Sha256>::new_from_slice(&secrets.offset_secret)
+        .expect("HMAC accepts keys of any length");
+    hmac.update(format!("{uid}\0{collection}\0{sort:?}\0").as_bytes());
+    hmac.update(offset);
+    hmac
+}
+
+/// Whether an `admin_token` setting enables the `/__admin__` endpoints. Empty
+/// tokens never do.
+pub fn admin_token_enabled(admin_token: Option<&str>) -> bool {
+    admin_token.is_some_and(|token| !token.trim().is_empty())
+}
+
+/// Whether the request's `Authorization: Bearer` token is the `admin_token`
+/// setting. Used by the `/__admin__` endpoints. Always false when the setting
+/// is unset or empty.
+pub fn admin_token_matches(req: &HttpRequest, admin_token: Option<&str>) -> bool {
+    match admin_token {
+        Some(expected) if admin_token_enabled(admin_token) => bearer_token_matches(req, expected),
+        _ => false,
+    }
+}
+
+/// Whether the request's `Authorization: Bearer` token is `expected`
+fn bearer_token_matches(req: &HttpRequest, expected: &str) -> bool {
+    let Some(provided) = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    // Compare digests rather than the tokens themselves to avoid leaking the
+    // token's prefix through timing
+    Sha256::digest(provided.trim()) == Sha256::digest(expected)
+}
+
 #[cfg(test)]
 mod tests {
-    use std::fmt::{self, Display, Formatter};
-
-    use super::{HawkPayload, Secrets};
+    use std::{
+        fmt::{self, Display, Formatter},
+        num::NonZeroUsize,
+        sync::Arc,
+        time::Duration,
+    };
+
+    use actix_web::{
+        HttpRequest, ResponseError,
+        http::{
+            StatusCode,
+            header::{AUTHORIZATION, WWW_AUTHENTICATE},
+        },
+        test::TestRequest,
+    };
+    use syncserver_settings::Settings;
+    use syncstorage_db::Sorting::{Index, Newest};
+
+    use super::{
+        HawkOrigin, HawkPayload, LruNonceStore, ReplayGuard, Secrets, admin_token_enabled,
+        admin_token_matches, sign_offset, verify_offset,
+    };
 
     #[test]
     fn valid_header() {
@@ -222,6 +680,7 @@ mod tests {
             fixture.request.port,
             &fixture.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            &fixture.replay_guard,
         );
 
         assert!(result.is_ok());
@@ -250,6 +709,7 @@ mod tests {
             fixture.request.port,
             &fixture.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            &fixture.replay_guard,
         );
 
         assert!(result.is_ok());
@@ -270,6 +730,7 @@ mod tests {
             fixture.request.port,
             &fixture.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            &fixture.replay_guard,
         );
 
         assert!(result.is_err());
@@ -287,6 +748,7 @@ mod tests {
             fixture.request.port,
             &fixture.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            &fixture.replay_guard,
         );
 
         assert!(result.is_err());
@@ -304,6 +766,7 @@ mod tests {
             fixture.request.port,
             &Secrets::new("wibble").unwrap(),
             fixture.expected.expires.round() as u64 - 1,
+            &fixture.replay_guard,
         );
 
         assert!(result.is_err());
@@ -326,6 +789,7 @@ mod tests {
             fixture.request.port,
             &fixture.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            &fixture.replay_guard,
         );
 
         assert!(result.is_err());
@@ -343,6 +807,7 @@ mod tests {
             fixture.request.port,
             &fixture.master_secret,
             fixture.expected.expires.round() as u64,
+            &fixture.replay_guard,
         );
 
         assert!(result.is_err());
@@ -361,6 +826,7 @@ mod tests {
             fixture.request.port,
             &fixture.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            &fixture.replay_guard,
         );
 
         assert!(result.is_err());
@@ -379,6 +845,7 @@ mod tests {
             fixture.request.port,
             &fixture.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            &fixture.replay_guard,
         );
 
         assert!(result.is_err());
@@ -397,11 +864,82 @@ mod tests {
             fixture.request.port,
             &fixture.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            &fixture.replay_guard,
         );
 
         assert!(result.is_err());
     }
 
+    #[test]
+    fn replayed_nonce() {
+        let fixture = TestFixture::new();
+        let validate = || {
+            HawkPayload::new(
+                &fixture.header.to_string(),
+                &fixture.request.method,
+                &fixture.request.path,
+                &fixture.request.host,
+                fixture.request.port,
+                &fixture.master_secret,
+                fixture.expected.expires.round() as u64 - 1,
+                &fixture.replay_guard,
+            )
+        };
+
+        assert!(validate().is_ok());
+        let err = validate().unwrap_err();
+        assert!(err.to_string().contains("nonce already used"));
+        assert_eq!(err.error_response().status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn peeked_nonce() {
+        let fixture = TestFixture::new();
+        let validate = |replay_guard: &ReplayGuard| {
+            HawkPayload::new(
+                &fixture.header.to_string(),
+                &fixture.request.method,
+                &fixture.request.path,
+                &fixture.request.host,
+                fixture.request.port,
+                &fixture.master_secret,
+                fixture.expected.expires.round() as u64 - 1,
+                replay_guard,
+            )
+        };
+
+        // Peeking doesn't record the nonce, but rejects it once recorded
+        let peeking = fixture.replay_guard.peeking();
+        assert!(validate(&peeking).is_ok());
+        assert!(validate(&peeking).is_ok());
+        assert!(validate(&fixture.replay_guard).is_ok());
+        let err = validate(&peeking).unwrap_err();
+        assert!(err.to_string().contains("nonce already used"));
+    }
+
+    #[test]
+    fn stale_timestamp() {
+        let fixture = TestFixture::new();
+
+        let err = HawkPayload::new(
+            &fixture.header.to_string(),
+            &fixture.request.method,
+            &fixture.request.path,
+            &fixture.request.host,
+            fixture.request.port,
+            &fixture.master_secret,
+            fixture.expected.expires.round() as u64 - 1,
+            &ReplayGuard::default(),
+        )
+        .unwrap_err();
+        let resp = err.error_response();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let challenge = resp.headers().get(WWW_AUTHENTICATE).unwrap();
+        let challenge = challenge.to_str().unwrap();
+        assert!(challenge.starts_with("Hawk ts=\""));
+        assert!(challenge.contains("tsm=\""));
+    }
+
     #[test]
     fn bad_method() {
         let mut fixture = TestFixture::new();
@@ -415,6 +953,7 @@ mod tests {
             fixture.request.port,
             &fixture.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            &fixture.replay_guard,
         );
 
         assert!(result.is_err());
@@ -436,6 +975,7 @@ mod tests {
             fixture.request.port,
             &fixture.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            &fixture.replay_guard,
         );
 
         assert!(result.is_err());
@@ -454,6 +994,7 @@ mod tests {
             fixture.request.port,
             &fixture.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            &fixture.replay_guard,
         );
 
         assert!(result.is_err());
@@ -472,17 +1013,183 @@ mod tests {
             fixture.request.port,
             &fixture.master_secret,
             fixture.expected.expires.round() as u64 - 1,
+            &fixture.replay_guard,
         );
 
         assert!(result.is_err());
     }
 
+    #[test]
+    fn admin_tokens() {
+        let request = |header: &str| {
+            TestRequest::default()
+                .insert_header((AUTHORIZATION, header))
+                .to_http_request()
+        };
+        assert!(admin_token_matches(
+            &request("Bearer admin-secret"),
+            Some("admin-secret")
+        ));
+        assert!(!admin_token_matches(
+            &request("Bearer wrong"),
+            Some("admin-secret")
+        ));
+        assert!(!admin_token_matches(&request("Bearer admin-secret"), None));
+
+        // Empty tokens never enable nor authenticate the admin endpoints
+        for token in ["", " "] {
+            assert!(!admin_token_enabled(Some(token)));
+            assert!(!admin_token_matches(&request("Bearer "), Some(token)));
+            assert!(!admin_token_matches(&request("Bearer  "), Some(token)));
+        }
+    }
+
+    #[test]
+    fn signed_offsets() {
+        let secrets = Secrets::new("Ted Koppel is a robot").unwrap();
+        let sign =
+            |offset: &str, secrets| sign_offset(offset.to_owned(), 1, "tabs", Index, secrets);
+        let verify = |token, secrets| verify_offset(token, 1, "tabs", Index, secrets);
+        assert_eq!(sign("1234:2", &secrets), "1234:2");
+        assert_eq!(verify("1234:2", &secrets).as_deref(), Some("1234:2"));
+
+        let cursor = "~1234:5:a:b".to_owned();
+        let token = sign(&cursor, &secrets);
+        assert!(!token.contains(':'));
+        assert_eq!(verify(&token, &secrets), Some(cursor.clone()));
+        // Unsigned, tampered or foreign cursors are rejected
+        assert_eq!(verify(&cursor, &secrets), None);
+        let other = Secrets::new("another secret").unwrap();
+        assert_eq!(verify(&token, &other), None);
+        let (payload, signature) = token.split_once('.').unwrap();
+        let forged = sign("~1234:5:a:c", &other);
+        let (forged_payload, _) = forged.split_once('.').unwrap();
+        assert_ne!(payload, forged_payload);
+        let tampered = format!("{}.{}", forged_payload, signature);
+        assert_eq!(verify(&tampered, &secrets), None);
+        // As are cursors issued for another user, collection or sort order
+        assert_eq!(verify_offset(&token, 2, "tabs", Index, &secrets), None);
+        assert_eq!(verify_offset(&token, 1, "history", Index, &secrets), None);
+        assert_eq!(verify_offset(&token, 1, "tabs", Newest, &secrets), None);
+    }
+
+    fn origin_request(peer: &str) -> HttpRequest {
+        TestRequest::with_uri("/1.5/1/info/collections")
+            .peer_addr(peer.parse().unwrap())
+            .insert_header(("host", "node.internal:8000"))
+            .insert_header(("x-forwarded-host", "sync.example.com"))
+            .insert_header(("x-forwarded-proto", "https"))
+            .to_http_request()
+    }
+
+    fn host_port(origin: &HawkOrigin, req: &HttpRequest) -> (String, u16) {
+        origin.host_port(req.head(), req.app_config()).unwrap()
+    }
+
+    #[test]
+    fn hawk_origin_trusted_proxies() {
+        let origin = HawkOrigin::from_settings(&Settings::default()).unwrap();
+        assert_eq!(
+            host_port(&origin, &origin_request("127.0.0.1:1234")),
+            ("sync.example.com".to_owned(), 443)
+        );
+        // Only loopback proxies are trusted by default, forwarding headers
+        // from anyone else are ignored
+        for peer in ["10.0.0.1:1234", "203.0.113.1:1234"] {
+            assert_eq!(
+                host_port(&origin, &origin_request(peer)),
+                ("node.internal".to_owned(), 8000)
+            );
+        }
+        // IPv4-mapped IPv6 peers match IPv4 networks
+        assert_eq!(
+            host_port(&origin, &origin_request("[::ffff:127.0.0.1]:1234")),
+            ("sync.example.com".to_owned(), 443)
+        );
+    }
+
+    #[test]
+    fn hawk_origin_proxy_chain() {
+        let settings = Settings {
+            trusted_proxies: vec!["10.0.0.0/8".parse().unwrap()],
+            ..Default::default()
+        };
+        let origin = HawkOrigin::from_settings(&settings).unwrap();
+        // The client (203.0.113.9) sent its own spoofed forwarding headers to
+        // the outer proxy (10.0.0.2), which appended to them before passing
+        // the request to the inner proxy (10.0.0.3)
+        let req = TestRequest::with_uri("/1.5/1/info/collections")
+            .peer_addr("10.0.0.3:1234".parse().unwrap())
+            .insert_header(("host", "node.internal:8000"))
+            .insert_header(("x-forwarded-for", "192.0.2.1, 203.0.113.9, 10.0.0.2"))
+            .insert_header((
+                "x-forwarded-host",
+                "evil.example.com, sync.example.com, sync.example.com",
+            ))
+            .insert_header(("x-forwarded-proto", "http, https, https"))
+            .to_http_request();
+        assert_eq!(
+            host_port(&origin, &req),
+            ("sync.example.com".to_owned(), 443)
+        );
+
+        let req = TestRequest::with_uri("/1.5/1/info/collections")
+            .peer_addr("10.0.0.3:1234".parse().unwrap())
+            .insert_header(("host", "node.internal:8000"))
+            .insert_header((
+                "forwarded",
+                "for=192.0.2.1;host=evil.example.com;proto=http, \
+                 for=203.0.113.9;host=sync.example.com;proto=https, \
+                 for=\"10.0.0.2:5678\";host=sync.example.com;proto=https",
+            ))
+            .to_http_request();
+        assert_eq!(
+            host_port(&origin, &req),
+            ("sync.example.com".to_owned(), 443)
+        );
+
+        // Every hop being trusted, the leftmost is the client's
+        let req = TestRequest::with_uri("/1.5/1/info/collections")
+            .peer_addr("10.0.0.3:1234".parse().unwrap())
+            .insert_header(("x-forwarded-for", "10.0.0.1, 10.0.0.2"))
+            .insert_header(("x-forwarded-host", "internal.example.com:8443"))
+            .to_http_request();
+        assert_eq!(
+            host_port(&origin, &req),
+            ("internal.example.com".to_owned(), 8443)
+        );
+    }
+
+    #[test]
+    fn hawk_origin_public_url() {
+        let settings = Settings {
+            public_url: Some("https://sync.example.com".to_owned()),
+            ..Default::default()
+        };
+        let origin = HawkOrigin::from_settings(&settings).unwrap();
+        assert_eq!(
+            host_port(&origin, &origin_request("203.0.113.1:1234")),
+            ("sync.example.com".to_owned(), 443)
+        );
+
+        let settings = Settings {
+            public_url: Some("http://sync.example.com:8080/".to_owned()),
+            ..settings
+        };
+        let origin = HawkOrigin::from_settings(&settings).unwrap();
+        assert_eq!(
+            host_port(&origin, &origin_request("10.0.0.1:1234")),
+            ("sync.example.com".to_owned(), 8080)
+        );
+    }
+
     #[derive(Debug)]
     struct TestFixture {
         pub header: HawkHeader,
         pub request: Request,
         pub master_secret: Secrets,
         pub expected: HawkPayload,
+        pub replay_guard: ReplayGuard,
     }
 
     impl TestFixture {
@@ -511,6 +1218,11 @@ mod tests {
                             .to_owned(),
                     tokenserver_origin: Default::default(),
                 },
+                // The fixture's timestamp is from 2019
+                replay_guard: ReplayGuard::new(
+                    Duration::from_secs(u32::MAX.into()),
+                    Some(Arc::new(LruNonceStore::new(NonZeroUsize::new(10).unwrap()))),
+                ),
             }
         }
     }
@@ -45,10 +45,20 @@ impl HawkError {
             HawkErrorKind::MissingId => "request.error.hawk.missing_id",
             HawkErrorKind::MissingPrefix => "request.error.hawk.missing_prefix",
             HawkErrorKind::Parse(_) => "request.error.hawk.parse_error",
+            HawkErrorKind::Replay => "request.error.hawk.replay",
+            HawkErrorKind::StaleTimestamp(_) => "request.error.hawk.stale_timestamp",
             HawkErrorKind::TruncatedId => "request.error.hawk.id_too_short",
             _ => return None,
         })
     }
+
+    /// The `WWW-Authenticate` challenge sent with the error, if any
+    pub fn www_authenticate(&self) -> Option<&str> {
+        match self.kind() {
+            HawkErrorKind::StaleTimestamp(challenge) => Some(challenge),
+            _ => None,
+        }
+    }
 }
 
 /// Causes of HAWK errors.
@@ -90,6 +100,13 @@ pub enum HawkErrorKind {
     #[error("{}", _0)]
     Parse(ParseError),
 
+    #[error("nonce already used")]
+    Replay,
+
+    /// Holds the `WWW-Authenticate` challenge with the server's timestamp
+    #[error("stale timestamp")]
+    StaleTimestamp(String),
+
     #[error("id property is too short")]
     TruncatedId,
 }
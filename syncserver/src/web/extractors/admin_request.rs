@@ -0,0 +1,125 @@
+use actix_web::{
+    Error, FromRequest, HttpRequest,
+    dev::Payload,
+    web::{Data, Query},
+};
+use futures::future::{self, FutureExt, LocalBoxFuture, Ready};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use syncstorage_db::{DbError, DbPool, UserIdentifier};
+
+use super::RequestErrorLocation;
+use crate::{
+    error::{ApiError, ApiErrorKind},
+    server::ServerState,
+    web::{auth::admin_token_matches, error::ValidationErrorKind},
+};
+
+/// Identifies the user an admin request operates on: either by `legacy_id`
+/// (MySQL, Postgres and SQLite) or by `fxa_uid` and `fxa_kid` (Spanner)
+#[derive(Debug, Deserialize)]
+struct AdminUserParams {
+    legacy_id: Option<u64>,
+    fxa_uid: Option<String>,
+    fxa_kid: Option<String>,
+}
+
+impl AdminUserParams {
+    fn into_user_id(self) -> Option<UserIdentifier> {
+        match self {
+            AdminUserParams {
+                legacy_id: Some(legacy_id),
+                fxa_uid: None,
+                fxa_kid: None,
+            } => Some(UserIdentifier {
+                legacy_id,
+                ..Default::default()
+            }),
+            AdminUserParams {
+                legacy_id: None,
+                fxa_uid: Some(fxa_uid),
+                fxa_kid: Some(fxa_kid),
+            } => Some(UserIdentifier {
+                // For logging in place of the fxa_uid
+                hashed_fxa_uid: hex::encode(Sha256::digest(&fxa_uid))[0..32].to_owned(),
+                fxa_uid,
+                fxa_kid,
+                ..Default::default()
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// An `/__admin__` request, authenticated by the `admin_token` setting as a
+/// bearer token
+pub struct AdminRequest {
+    pub state: Data<ServerState>,
+}
+
+impl AdminRequest {
+    fn authenticate(req: &HttpRequest) -> Result<Self, ApiError> {
+        let state = match req.app_data::<Data<ServerState>>() {
+            Some(s) => s,
+            None => {
+                error!("⚠️ Could not load the app state");
+                return Err(ApiErrorKind::NoServerState.into());
+            }
+        };
+        if admin_token_matches(req, state.admin_token.as_deref()) {
+            Ok(AdminRequest {
+                state: state.clone(),
+            })
+        } else {
+            Err(ApiErrorKind::AdminAuth("Invalid bearer token".to_owned()).into())
+        }
+    }
+}
+
+impl FromRequest for AdminRequest {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        future::ready(Self::authenticate(req).map_err(Into::into))
+    }
+}
+
+/// An `/__admin__` request on a single user
+#[derive(Debug)]
+pub struct AdminUserRequest {
+    pub db_pool: Box<dyn DbPool<Error = DbError>>,
+    pub user_id: UserIdentifier,
+}
+
+impl FromRequest for AdminUserRequest {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+
+        async move {
+            let AdminRequest { state } = AdminRequest::authenticate(&req)?;
+
+            let user_id = Query::<AdminUserParams>::from_query(req.query_string())
+                .ok()
+                .and_then(|params| params.into_inner().into_user_id())
+                .ok_or_else(|| {
+                    ValidationErrorKind::FromDetails(
+                        "Expected either legacy_id or fxa_uid and fxa_kid".to_owned(),
+                        RequestErrorLocation::QueryString,
+                        None,
+                        None,
+                    )
+                })?;
+
+            Ok(AdminUserRequest {
+                db_pool: state.db_pool.clone(),
+                user_id,
+            })
+        }
+        .boxed_local()
+    }
+}
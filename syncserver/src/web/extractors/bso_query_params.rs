@@ -1,6 +1,10 @@
-use std::str::FromStr;
+use std::{str::FromStr, sync::Arc};
 
-use actix_web::{Error, FromRequest, HttpRequest, dev::Payload, web::Query};
+use actix_web::{
+    Error, FromRequest, HttpRequest,
+    dev::Payload,
+    web::{Data, Query},
+};
 use futures::future::{LocalBoxFuture, TryFutureExt};
 use serde::{
     Deserialize,
@@ -8,10 +12,14 @@ use serde::{
 };
 use validator::{Validate, ValidationError};
 
+use syncserver_settings::Secrets;
 use syncstorage_db::{Sorting, SyncTimestamp, params};
 
 use super::{BATCH_MAX_IDS, RequestErrorLocation, VALID_ID_REGEX, request_error};
-use crate::web::error::ValidationErrorKind;
+use crate::{
+    error::{ApiError, ApiErrorKind},
+    web::{auth, error::ValidationErrorKind},
+};
 
 /// Verifies that the list of id's is not too long and that the ids are valid
 pub fn validate_qs_ids(ids: &[String]) -> Result<(), ValidationError> {
@@ -48,17 +56,31 @@ where
     }
 }
 
-pub fn deserialize_offset<'de, D>(deserializer: D) -> Result<Option<params::Offset>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let maybe_str: Option<String> = Deserialize::deserialize(deserializer)?;
-    if let Some(val) = maybe_str {
-        return Ok(Some(
-            params::Offset::from_str(&val).map_err(SerdeError::custom)?,
-        ));
-    }
-    Ok(None)
+/// Verify and parse an `offset` token from a previous `X-Weave-Next-Offset`,
+/// which must have been issued for the same listing: the user and collection
+/// of the request's path (the uid being checked against its Hawk token), and
+/// the same sort order
+fn parse_offset(req: &HttpRequest, token: &str, sort: Sorting) -> Result<params::Offset, Error> {
+    let secrets = req
+        .app_data::<Data<Arc<Secrets>>>()
+        .ok_or_else(|| ApiError::from(ApiErrorKind::Internal("No app_data Secrets".to_owned())))?;
+    let uid = req
+        .match_info()
+        .get("uid")
+        .and_then(|uid| uid.parse().ok())
+        .unwrap_or_default();
+    let collection = req.match_info().get("collection").unwrap_or_default();
+    auth::verify_offset(token, uid, collection, sort, secrets)
+        .and_then(|offset| params::Offset::from_str(&offset).ok())
+        .ok_or_else(|| {
+            ValidationErrorKind::FromDetails(
+                "Invalid offset".to_owned(),
+                RequestErrorLocation::QueryString,
+                Some("offset".to_owned()),
+                None,
+            )
+            .into()
+        })
 }
 
 /// Deserialize a comma separated string
@@ -116,9 +138,13 @@ pub struct BsoQueryParams {
     pub limit: Option<u32>,
 
     /// position at which to restart search (string)
-    #[serde(deserialize_with = "deserialize_offset")]
+    #[serde(skip)]
     pub offset: Option<params::Offset>,
 
+    /// the `offset` token as sent, verified into `offset` on extraction
+    #[serde(rename = "offset")]
+    raw_offset: Option<String>,
+
     /// a comma-separated list of BSO ids (list of strings)
     #[serde(deserialize_with = "deserialize_comma_sep_string", default)]
     #[validate(custom(function = "validate_qs_ids"))]
@@ -138,7 +164,7 @@ impl FromRequest for BsoQueryParams {
         let req = req.clone();
         let mut payload = Payload::None;
         Box::pin(async move {
-            let params = Query::<BsoQueryParams>::from_request(&req, &mut payload)
+            let mut params = Query::<BsoQueryParams>::from_request(&req, &mut payload)
                 .map_err(|e| {
                     ValidationErrorKind::FromDetails(
                         e.to_string(),
@@ -156,6 +182,9 @@ impl FromRequest for BsoQueryParams {
                     None,
                 )
             })?;
+            if let Some(token) = params.raw_offset.take() {
+                params.offset = Some(parse_offset(&req, &token, params.sort)?);
+            }
 
             if params.sort != Sorting::Index
                 && let Some(timestamp) = params.offset.as_ref().and_then(|offset| offset.timestamp)
@@ -191,7 +220,7 @@ impl FromRequest for BsoQueryParams {
 
 #[cfg(test)]
 mod tests {
-    use std::str::FromStr;
+    use std::{str::FromStr, sync::Arc};
 
     use actix_web::{FromRequest, HttpResponse, dev::ServiceResponse, test::TestRequest};
     use futures::executor::block_on;
@@ -199,7 +228,10 @@ mod tests {
     use syncstorage_db::{Sorting, SyncTimestamp, params};
 
     use super::{BsoQueryParams, params::Offset};
-    use crate::web::extractors::test_utils::{extract_body_as_str, make_state};
+    use crate::web::{
+        auth::sign_offset,
+        extractors::test_utils::{SECRETS, extract_body_as_str, make_state},
+    };
 
     #[test]
     fn test_invalid_query_args() {
@@ -246,6 +278,7 @@ mod tests {
         let state = make_state();
         let req = TestRequest::with_uri("/?sort=newest&newer=2.22&offset=1111:1")
             .data(state)
+            .data(Arc::clone(&SECRETS))
             .to_http_request();
         let result = block_on(BsoQueryParams::extract(&req));
         assert!(result.is_err());
@@ -258,6 +291,7 @@ mod tests {
         let state = make_state();
         let req = TestRequest::with_uri("/?sort=newest&older=2.22&offset=5858:1")
             .data(state)
+            .data(Arc::clone(&SECRETS))
             .to_http_request();
         let result = block_on(BsoQueryParams::extract(&req));
         assert!(result.is_err());
@@ -270,6 +304,7 @@ mod tests {
         let state = make_state();
         let req = TestRequest::with_uri("/?sort=newest&newer=1.23&older=5.43&offset=3838:1")
             .data(state)
+            .data(Arc::clone(&SECRETS))
             .to_http_request();
         let result = block_on(BsoQueryParams::extract(&req));
         assert!(result.is_ok());
@@ -280,6 +315,7 @@ mod tests {
         let state = make_state();
         let req = TestRequest::with_uri("/?sort=index&newer=2.22&offset=1111:1")
             .data(state)
+            .data(Arc::clone(&SECRETS))
             .to_http_request();
         let result = block_on(BsoQueryParams::extract(&req));
         assert!(result.is_ok());
@@ -290,6 +326,7 @@ mod tests {
         let sample_offset = params::Offset {
             timestamp: Some(SyncTimestamp::default()),
             offset: 1234,
+            cursor: None,
         };
 
         let offset_str = sample_offset.to_string();
@@ -297,4 +334,52 @@ mod tests {
         assert_eq!(parsed.offset, sample_offset.offset);
         assert_eq!(parsed.timestamp, sample_offset.timestamp,);
     }
+
+    #[test]
+    fn test_signed_cursor() {
+        let cursor = params::Cursor {
+            modified: SyncTimestamp::from_seconds(2.22),
+            sortindex: Some(3),
+            id: "abc".to_owned(),
+        };
+        let token = sign_offset(
+            Offset::from(cursor.clone()).to_string(),
+            42,
+            "tabs",
+            Sorting::Index,
+            &SECRETS,
+        );
+        let request = |query: &str, collection| {
+            TestRequest::with_uri(&format!("/?{}", query))
+                .param("uid", "42")
+                .param("collection", collection)
+                .data(make_state())
+                .data(Arc::clone(&SECRETS))
+                .to_http_request()
+        };
+        let req = request(&format!("sort=index&offset={}", token), "tabs");
+        let result = block_on(BsoQueryParams::extract(&req)).unwrap();
+        assert_eq!(result.offset.unwrap().cursor, Some(cursor.clone()));
+
+        // Cursors are only valid for the listing they were issued for
+        for (query, collection) in [
+            (format!("sort=index&offset={}", token), "history"),
+            (format!("sort=newest&offset={}", token), "tabs"),
+        ] {
+            let req = request(&query, collection);
+            let result = block_on(BsoQueryParams::extract(&req));
+            let resp: HttpResponse = result.err().unwrap().into();
+            assert_eq!(resp.status(), 400);
+        }
+
+        // Cursors must carry a valid signature
+        let unsigned = Offset::from(cursor).to_string();
+        let req = TestRequest::with_uri(&format!("/?sort=index&offset={}", unsigned))
+            .data(make_state())
+            .data(Arc::clone(&SECRETS))
+            .to_http_request();
+        let result = block_on(BsoQueryParams::extract(&req));
+        let resp: HttpResponse = result.err().unwrap().into();
+        assert_eq!(resp.status(), 400);
+    }
 }
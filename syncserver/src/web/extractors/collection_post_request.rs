@@ -1,8 +1,11 @@
+use std::sync::Arc;
+
 use actix_web::{Error, FromRequest, HttpRequest, dev::Payload, web::Data};
 use futures::future::LocalBoxFuture;
 
 use syncserver_common::Metrics;
 use syncstorage_db::UserIdentifier;
+use syncstorage_settings::ServerLimits;
 use tokenserver_auth::TokenserverOrigin;
 
 use super::{
@@ -10,6 +13,7 @@ use super::{
     KNOWN_BAD_PAYLOAD_REGEX, RequestErrorLocation,
 };
 use crate::{
+    error::ApiError,
     server::{MetricsWrapper, ServerState},
     web::error::ValidationErrorKind,
 };
@@ -28,6 +32,36 @@ pub struct CollectionPostRequest {
     pub batch: Option<BatchRequest>,
     pub metrics: Metrics,
     pub quota_enabled: bool,
+    pub limits: Arc<ServerLimits>,
+}
+
+impl CollectionPostRequest {
+    /// Ensure this request's BSOs won't take a batch, already holding
+    /// `total_records` records of `total_bytes` bytes, past the advertised
+    /// `max_total_records`/`max_total_bytes` limits
+    pub fn check_batch_totals(&self, total_records: u64, total_bytes: u64) -> Result<(), ApiError> {
+        let records = total_records + self.bsos.valid.len() as u64;
+        let bytes = total_bytes
+            + self
+                .bsos
+                .valid
+                .iter()
+                .filter_map(|bso| bso.payload.as_ref())
+                .map(|payload| payload.len() as u64)
+                .sum::<u64>();
+        if records > u64::from(self.limits.max_total_records)
+            || bytes > u64::from(self.limits.max_total_bytes)
+        {
+            return Err(ValidationErrorKind::FromDetails(
+                "size-limit-exceeded".to_owned(),
+                RequestErrorLocation::Body,
+                None,
+                Some("request.validate.batch.size_exceeded"),
+            )
+            .into());
+        }
+        Ok(())
+    }
 }
 
 impl FromRequest for CollectionPostRequest {
@@ -106,6 +140,7 @@ impl FromRequest for CollectionPostRequest {
                 batch: batch.opt,
                 metrics: MetricsWrapper::extract(&req).await?.0,
                 quota_enabled: state.quota_enabled,
+                limits: Arc::clone(&state.limits),
             })
         })
     }
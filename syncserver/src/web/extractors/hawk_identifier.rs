@@ -2,7 +2,7 @@ use std::{str::FromStr, sync::Arc};
 
 use actix_web::{
     Error, FromRequest, HttpMessage, HttpRequest,
-    dev::{ConnectionInfo, Extensions, Payload},
+    dev::{Extensions, Payload},
     http::Uri,
     web::Data,
 };
@@ -17,9 +17,10 @@ use tokenserver_auth::TokenserverOrigin;
 use super::{RequestErrorLocation, urldecode};
 use crate::{
     error::{ApiError, ApiErrorKind},
+    server::ServerState,
     web::{
         DOCKER_FLOW_ENDPOINTS,
-        auth::HawkPayload,
+        auth::{HawkOrigin, HawkPayload, ReplayGuard},
         error::{HawkErrorKind, ValidationErrorKind},
     },
 };
@@ -96,8 +97,9 @@ impl HawkIdentifier {
         msg: &T,
         method: &str,
         uri: &Uri,
-        ci: &ConnectionInfo,
+        (host, port): (&str, u16),
         secrets: &Secrets,
+        replay_guard: &ReplayGuard,
     ) -> Result<Self, Error>
     where
         T: HttpMessage,
@@ -116,8 +118,9 @@ impl HawkIdentifier {
             secrets,
             method,
             auth_header,
-            ci,
+            (host, port),
             uri,
+            replay_guard,
             &mut msg.extensions_mut(),
         )?;
         msg.extensions_mut().insert(identifier.clone());
@@ -128,11 +131,12 @@ impl HawkIdentifier {
         secrets: &Secrets,
         method: &str,
         header: &str,
-        connection_info: &ConnectionInfo,
+        (host, port): (&str, u16),
         uri: &Uri,
+        replay_guard: &ReplayGuard,
         exts: &mut Extensions,
     ) -> Result<Self, Error> {
-        let payload = HawkPayload::extrude(header, method, secrets, connection_info, uri)?;
+        let payload = HawkPayload::extrude(header, method, secrets, host, port, uri, replay_guard)?;
         let puid = Self::uid_from_path(uri)?;
         if payload.user_id != puid {
             warn!("⚠️ Hawk UID not in URI: {:?} {:?}", payload.user_id, uri);
@@ -183,8 +187,6 @@ impl FromRequest for HawkIdentifier {
         }
         let req = req.clone();
         let uri = req.uri();
-        // NOTE: `connection_info()` will get a mutable reference lock on `extensions()`
-        let connection_info = req.connection_info().clone();
         let method = req.method().clone();
         // Tried collapsing this to a `.or_else` and hit problems with the return resolving
         // to an appropriate error state. Can't use `?` since the function does not return a result.
@@ -196,7 +198,25 @@ impl FromRequest for HawkIdentifier {
             }
         };
 
-        let result = Self::extrude(&req, method.as_str(), uri, &connection_info, secrets);
+        let (default_origin, default_guard) = (HawkOrigin::default(), ReplayGuard::default());
+        let (origin, replay_guard) = req
+            .app_data::<Data<ServerState>>()
+            .map_or((&default_origin, &default_guard), |state| {
+                (&*state.hawk_origin, &*state.replay_guard)
+            });
+        let result = origin
+            .host_port(req.head(), req.app_config())
+            .map_err(Into::into)
+            .and_then(|(host, port)| {
+                Self::extrude(
+                    &req,
+                    method.as_str(),
+                    uri,
+                    (&host, port),
+                    secrets,
+                    replay_guard,
+                )
+            });
 
         if let Ok(ref hawk_id) = result {
             // Store the origin of the token as an extra to be included when emitting a Sentry error
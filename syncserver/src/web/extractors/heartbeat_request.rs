@@ -1,3 +1,5 @@
+use std::sync::atomic::Ordering;
+
 use actix_web::{
     Error, FromRequest, HttpRequest, dev::Payload, http::header::HeaderMap, web::Data,
 };
@@ -21,6 +23,7 @@ pub struct HeartbeatRequest {
     pub headers: HeaderMap,
     pub db_pool: Box<dyn DbPool<Error = DbError>>,
     pub quota: QuotaInfo,
+    pub read_only: bool,
 }
 
 impl FromRequest for HeartbeatRequest {
@@ -55,6 +58,7 @@ impl FromRequest for HeartbeatRequest {
                 headers,
                 db_pool,
                 quota,
+                read_only: state.read_only.load(Ordering::Relaxed),
             })
         }
         .boxed_local()
@@ -36,6 +36,8 @@ pub(crate) use collection_param::*;
 
 mod metrics;
 pub(crate) use metrics::*;
+mod admin_request;
+pub(crate) use admin_request::*;
 mod bso_request;
 pub(crate) use bso_request::*;
 mod bso_put_request;
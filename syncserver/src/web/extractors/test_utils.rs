@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::sync::{Arc, atomic::AtomicBool};
 
 use actix_http::h1;
 use actix_web::{
@@ -23,7 +23,10 @@ use syncstorage_db::mock::{MockDb, MockDbPool};
 use syncstorage_settings::{Deadman, ServerLimits, Settings as SyncstorageSettings};
 
 use super::CollectionPostRequest;
-use crate::{server::ServerState, web::auth::HawkPayload};
+use crate::{
+    server::ServerState,
+    web::auth::{HawkOrigin, HawkPayload, ReplayGuard},
+};
 
 lazy_static! {
     static ref SERVER_LIMITS: Arc<ServerLimits> = Arc::new(ServerLimits::default());
@@ -62,12 +65,20 @@ pub fn make_state() -> ServerState {
             &syncstorage_settings.statsd_label,
             syncserver_settings.statsd_host.as_deref(),
             syncserver_settings.statsd_port,
+            None,
         )
         .unwrap(),
         quota_enabled: syncstorage_settings.enable_quota,
         deadman: Arc::new(RwLock::new(Deadman::default())),
         glean_logger,
         glean_enabled: syncstorage_settings.glean_enabled,
+        admin_token: syncstorage_settings.admin_token,
+        rate_limiter: None,
+        backoff: Arc::new(RwLock::new(syncstorage_settings.backoff)),
+        read_only: Arc::new(AtomicBool::new(syncstorage_settings.read_only)),
+        hawk_origin: Arc::new(HawkOrigin::from_settings(&syncserver_settings).unwrap()),
+        replay_guard: Arc::new(ReplayGuard::from_settings(&syncstorage_settings.hawk)),
+        compression: Arc::new(syncserver_settings.compression),
     }
 }
 
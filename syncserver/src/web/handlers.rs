@@ -1,31 +1,48 @@
 //! API Handlers
 use std::collections::HashMap;
 use std::convert::Into;
+use std::mem;
+use std::pin::pin;
+use std::sync::{Arc, atomic::Ordering};
 use std::thread;
 use std::time::{Duration, Instant};
 
 use crate::server::user_agent::{DeviceInfo, get_device_info};
 use actix_web::{
     HttpRequest, HttpResponse, HttpResponseBuilder,
-    http::{StatusCode, header},
-    web::Data,
+    http::{
+        StatusCode,
+        header::{self, ContentType},
+    },
+    web::{Bytes, Data, Json},
+};
+use futures::{
+    SinkExt, StreamExt,
+    channel::{mpsc, oneshot},
+    sink,
 };
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
 use syncserver_common::{X_LAST_MODIFIED, X_WEAVE_NEXT_OFFSET, X_WEAVE_RECORDS};
+use syncserver_settings::Secrets;
 use syncstorage_db::{
-    Db, DbError, DbErrorIntrospect, params,
+    Db, DbError, DbErrorIntrospect, archive, params,
     results::{CreateBatch, Paginated},
 };
+use syncstorage_settings::Backoff;
+use tokio::time::{timeout, timeout_at};
 use utoipa;
 
 use crate::{
     error::{ApiError, ApiErrorKind},
     server::ServerState,
     web::{
+        auth,
+        error::ValidationErrorKind,
         extractors::{
-            BsoPutRequest, BsoRequest, CollectionPostRequest, CollectionRequest, EmitApiMetric,
-            HeartbeatRequest, MetaRequest, ReplyFormat, TestErrorRequest,
+            AdminRequest, AdminUserRequest, BsoPutRequest, BsoRequest, CollectionPostRequest,
+            CollectionRequest, EmitApiMetric, HeartbeatRequest, MetaRequest, ReplyFormat,
+            RequestErrorLocation, TestErrorRequest,
         },
         transaction::DbTransactionPool,
     },
@@ -35,6 +52,20 @@ use glean::server_events::{EventsPing, RequestInfo, SyncstorageGetCollectionsEve
 
 pub const ONE_KB: f64 = 1024.0;
 
+/// Serialized BSOs are written to streamed `get_collection` responses in
+/// chunks of about this many bytes
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Number of chunks buffered ahead of a slow client
+const STREAM_CHUNK_BUFFER: usize = 4;
+
+/// Max time a streamed response may take to be read by the client, while it
+/// holds the response's db transaction
+const STREAM_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Max number of BSOs read at a time by the export endpoint
+const EXPORT_BATCH_SIZE: u32 = 1000;
+
 #[utoipa::path(
     get,
     path = "/1.5/{uid}/info/collections",
@@ -288,7 +319,7 @@ pub async fn delete_collection(
         ("older" = Option<f64>, Query, description = "Return only items with modified time strictly smaller than this timestamp (ms since epoch)"),
         ("full" = Option<bool>, Query, description = "If present, return full BSO objects rather than just IDs"),
         ("limit" = Option<i64>, Query, description = "Return at most this many objects. If more match, returns X-Weave-Next-Offset header"),
-        ("offset" = Option<String>, Query, description = "String token from a previous X-Weave-Next-Offset header for pagination"),
+        ("offset" = Option<String>, Query, description = "Opaque token from a previous X-Weave-Next-Offset header for pagination (legacy numeric offsets are also accepted)"),
         ("sort" = Option<String>, Query, description = "Sort order: 'newest' (by last-modified, largest first), 'oldest' (by last-modified, smallest first), or 'index' (by sortindex, highest weight first)")
     ),
     responses(
@@ -300,39 +331,182 @@ pub async fn delete_collection(
 pub async fn get_collection(
     coll: CollectionRequest,
     db_pool: DbTransactionPool,
+    secrets: Data<Arc<Secrets>>,
     request: HttpRequest,
 ) -> Result<HttpResponse, ApiError> {
+    let params = params::GetBsos {
+        user_id: coll.user_id.clone(),
+        newer: coll.query.newer,
+        older: coll.query.older,
+        sort: coll.query.sort,
+        limit: coll.query.limit,
+        offset: coll.query.offset.clone(),
+        ids: coll.query.ids.clone(),
+        full: coll.query.full,
+        collection: coll.collection.clone(),
+    };
+    if coll.query.full {
+        return stream_get_collection(coll, params, db_pool, secrets, request).await;
+    }
     db_pool
         .transaction_http(&request, async |db| {
             coll.emit_api_metric("request.get_collection");
-            let params = params::GetBsos {
-                user_id: coll.user_id.clone(),
-                newer: coll.query.newer,
-                older: coll.query.older,
-                sort: coll.query.sort,
-                limit: coll.query.limit,
-                offset: coll.query.offset,
-                ids: coll.query.ids.clone(),
-                full: coll.query.full,
-                collection: coll.collection.clone(),
-            };
-            let response = if coll.query.full {
-                let result = db.get_bsos(params).await;
-                finish_get_collection(&coll, db, result).await?
-            } else {
-                // Changed to be a Paginated list of BSOs, need to extract IDs from them.
-                let result = db.get_bso_ids(params).await;
-                finish_get_collection(&coll, db, result).await?
-            };
-            Ok(response)
+            // Changed to be a Paginated list of BSOs, need to extract IDs from them.
+            let result = db.get_bso_ids(params).await;
+            Ok(finish_get_collection(&coll, db, result, &secrets).await?)
         })
         .await
 }
 
+/// Write a page of full BSOs to the client as they're read from the db.
+///
+/// The transaction runs on its own task for as long as the body is being
+/// written: the page's ids are read first to build the headers, then the
+/// BSOs are serialized in [STREAM_CHUNK_SIZE] chunks through a bounded
+/// channel so a slow client applies backpressure to the db read. A client
+/// that hasn't read the response within [STREAM_TIMEOUT] has it aborted, so
+/// it can't hold the transaction open indefinitely.
+async fn stream_get_collection(
+    coll: CollectionRequest,
+    params: params::GetBsos,
+    db_pool: DbTransactionPool,
+    secrets: Data<Arc<Secrets>>,
+    request: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let (head_tx, head_rx) = oneshot::channel();
+    let (mut body_tx, body_rx) = mpsc::channel::<Result<Bytes, ApiError>>(STREAM_CHUNK_BUFFER);
+
+    let task = actix_web::rt::spawn(async move {
+        let deadline = Instant::now() + STREAM_TIMEOUT;
+        let result = db_pool
+            .transaction_http(&request, async |db| {
+                coll.emit_api_metric("request.get_collection");
+                let ids = db.get_bso_ids(params.clone()).await.or_else(|e| {
+                    if e.is_collection_not_found() {
+                        // For b/w compat, non-existent collections must return an
+                        // empty list
+                        Ok(Paginated::default())
+                    } else {
+                        Err(e)
+                    }
+                })?;
+                let ts = db
+                    .extract_resource(coll.user_id.clone(), Some(coll.collection.clone()), None)
+                    .await?;
+
+                let mut builder = HttpResponse::build(StatusCode::OK);
+                let resp = builder
+                    .insert_header((X_LAST_MODIFIED, ts.as_header()))
+                    .insert_header((X_WEAVE_RECORDS, ids.items.len().to_string()));
+                if let Some(offset) = ids.offset {
+                    let offset = auth::sign_offset(
+                        offset,
+                        coll.user_id.legacy_id,
+                        &coll.collection,
+                        coll.query.sort,
+                        &secrets,
+                    );
+                    resp.insert_header((X_WEAVE_NEXT_OFFSET, offset));
+                }
+                match coll.reply {
+                    ReplyFormat::Json => resp.content_type(ContentType::json()),
+                    ReplyFormat::Newlines => resp.content_type("application/newlines"),
+                };
+                if head_tx.send(resp.streaming(body_rx)).is_err() {
+                    // The client went away before the headers were sent
+                    return Ok(HttpResponse::Ok().finish());
+                }
+
+                let mut chunk = Vec::with_capacity(STREAM_CHUNK_SIZE);
+                if matches!(coll.reply, ReplyFormat::Json) {
+                    chunk.push(b'[');
+                }
+                let mut count = 0;
+                if !ids.items.is_empty() {
+                    // Limit to the page the ids were read from, in case the
+                    // stream runs past it
+                    let mut bsos = db.get_bsos_stream(params).await?.take(ids.items.len());
+                    while let Some(bso) = bsos.next().await {
+                        let bso = bso?;
+                        match coll.reply {
+                            ReplyFormat::Json => {
+                                if count > 0 {
+                                    chunk.push(b',');
+                                }
+                                serde_json::to_writer(&mut chunk, &bso)
+                                    .map_err(|e| ApiErrorKind::Internal(e.to_string()))?;
+                            }
+                            ReplyFormat::Newlines => match serde_json::to_string(&bso) {
+                                Ok(line) if !line.is_empty() => {
+                                    chunk.extend(line.replace('\n', "\\u000a").into_bytes());
+                                    chunk.push(b'\n');
+                                }
+                                _ => {}
+                            },
+                        }
+                        count += 1;
+                        if chunk.len() >= STREAM_CHUNK_SIZE {
+                            let full =
+                                mem::replace(&mut chunk, Vec::with_capacity(STREAM_CHUNK_SIZE));
+                            if !send_chunk(&mut body_tx, Bytes::from(full), deadline).await? {
+                                // The client went away mid-response
+                                return Ok(HttpResponse::Ok().finish());
+                            }
+                        }
+                    }
+                }
+                if matches!(coll.reply, ReplyFormat::Json) {
+                    chunk.push(b']');
+                }
+                if !chunk.is_empty() {
+                    send_chunk(&mut body_tx, Bytes::from(chunk), deadline).await?;
+                }
+                // Only signals the transaction to commit, the client received
+                // the streamed response above
+                Ok(HttpResponse::Ok().finish())
+            })
+            .await;
+        // The body is closed when the headers weren't sent (the error's
+        // returned as the response instead) or the client went away
+        if let Err(e) = &result
+            && !body_tx.is_closed()
+        {
+            // Abort the response rather than end it early
+            warn!("Aborting a streamed get_collection response: {}", e);
+            let abort = Err(ApiErrorKind::Internal(e.to_string()).into());
+            let _ = timeout(STREAM_TIMEOUT, body_tx.send(abort)).await;
+        }
+        result
+    });
+
+    match head_rx.await {
+        Ok(resp) => Ok(resp),
+        // The transaction finished without streaming: it either failed or
+        // answered a precondition itself
+        Err(_) => task
+            .await
+            .map_err(|e| ApiError::from(ApiErrorKind::Internal(e.to_string())))?,
+    }
+}
+
+/// Send a chunk of a streamed response, returning false when the client went
+/// away. Errors when the client doesn't read it by `deadline`
+async fn send_chunk(
+    body_tx: &mut mpsc::Sender<Result<Bytes, ApiError>>,
+    chunk: Bytes,
+    deadline: Instant,
+) -> Result<bool, ApiError> {
+    match timeout_at(deadline.into(), body_tx.send(Ok(chunk))).await {
+        Ok(sent) => Ok(sent.is_ok()),
+        Err(_) => Err(ApiErrorKind::Internal("Timed out streaming the response".to_owned()).into()),
+    }
+}
+
 async fn finish_get_collection<T>(
     coll: &CollectionRequest,
     db: &mut dyn Db<Error = DbError>,
     result: Result<Paginated<T>, DbError>,
+    secrets: &Secrets,
 ) -> Result<HttpResponse, DbError>
 where
     T: Serialize + Default + 'static,
@@ -357,6 +531,13 @@ where
         .insert_header((X_WEAVE_RECORDS, result.items.len().to_string()));
 
     if let Some(offset) = result.offset {
+        let offset = auth::sign_offset(
+            offset,
+            coll.user_id.legacy_id,
+            &coll.collection,
+            coll.query.sort,
+            secrets,
+        );
         resp.insert_header((X_WEAVE_NEXT_OFFSET, offset));
     }
 
@@ -459,45 +640,52 @@ pub async fn post_collection_batch(
         .clone()
         .ok_or_else(|| -> ApiError { ApiErrorKind::Db(DbError::batch_not_found()).into() })?;
 
-    let new_batch = if let Some(id) = breq.id.clone() {
+    let (new_batch, total_records, total_bytes) = if let Some(id) = breq.id.clone() {
         trace!("Batch: Validating {}", &id);
         // Validate the batch before attempting a full append (for efficiency)
-        let is_valid = db
-            .validate_batch(params::ValidateBatch {
+        let batch = db
+            .get_batch(params::GetBatch {
                 user_id: coll.user_id.clone(),
                 collection: coll.collection.clone(),
                 id: id.clone(),
             })
             .await?;
 
-        if is_valid {
+        if let Some(batch) = batch {
             let usage = db
                 .get_quota_usage(params::GetQuotaUsage {
                     user_id: coll.user_id.clone(),
                     collection: coll.collection.clone(),
                 })
                 .await?;
-            CreateBatch {
+            let new_batch = CreateBatch {
                 id: id.clone(),
                 size: if coll.quota_enabled {
                     Some(usage.total_bytes)
                 } else {
                     None
                 },
-            }
+            };
+            (new_batch, batch.total_records, batch.total_bytes)
         } else {
             return Err(ApiErrorKind::Db(DbError::batch_not_found()).into());
         }
     } else {
         trace!("Batch: Creating new batch");
-        db.create_batch(params::CreateBatch {
-            user_id: coll.user_id.clone(),
-            collection: coll.collection.clone(),
-            bsos: vec![],
-        })
-        .await?
+        let new_batch = db
+            .create_batch(params::CreateBatch {
+                user_id: coll.user_id.clone(),
+                collection: coll.collection.clone(),
+                bsos: vec![],
+            })
+            .await?;
+        (new_batch, 0, 0)
     };
 
+    // Whether appended or written with the commit, this request's BSOs count
+    // towards the batch's totals
+    coll.check_batch_totals(total_records, total_bytes)?;
+
     let user_id = coll.user_id.clone();
     let collection = coll.collection.clone();
 
@@ -559,9 +747,6 @@ pub async fn post_collection_batch(
         })
         .await?;
 
-    // TODO: validate *actual* sizes of the batch items
-    // (max_total_records, max_total_bytes)
-    //
     // First, write the pending batch BSO data into the BSO table.
     let modified = if let Some(batch) = batch {
         db.commit_batch(params::CommitBatch {
@@ -785,6 +970,7 @@ pub async fn heartbeat(hb: HeartbeatRequest) -> Result<HttpResponse, ApiError> {
     let mut db = hb.db_pool.get().await?;
 
     checklist.insert("quota".to_owned(), serde_json::to_value(hb.quota)?);
+    checklist.insert("read_only".to_owned(), Value::from(hb.read_only));
 
     match db.check().await {
         Ok(result) => {
@@ -899,6 +1085,110 @@ pub async fn lbheartbeat(req: HttpRequest) -> Result<HttpResponse, ApiError> {
     Ok(HttpResponseBuilder::new(status_code).json(json!(resp)))
 }
 
+/// Export all of a user's collections and BSOs as a JSON lines archive (see
+/// `syncstorage_db::archive`).
+///
+/// The archive is streamed to the client a page of BSOs at a time through a
+/// bounded channel, like full `get_collection` responses. The export is
+/// aborted when the client doesn't read a page within [STREAM_TIMEOUT].
+pub async fn admin_export_user(request: AdminUserRequest) -> Result<HttpResponse, ApiError> {
+    let (mut body_tx, body_rx) = mpsc::channel::<Result<Bytes, ApiError>>(STREAM_CHUNK_BUFFER);
+
+    actix_web::rt::spawn(async move {
+        let AdminUserRequest { db_pool, user_id } = request;
+        let sink = sink::unfold(
+            body_tx.clone(),
+            async |mut tx, chunk: Vec<u8>| match timeout(
+                STREAM_TIMEOUT,
+                tx.send(Ok(Bytes::from(chunk))),
+            )
+            .await
+            {
+                Ok(Ok(())) => Ok(tx),
+                Ok(Err(_)) => Err(DbError::internal(
+                    "The client went away mid-export".to_owned(),
+                )),
+                Err(_) => Err(DbError::internal(
+                    "Timed out streaming the export".to_owned(),
+                )),
+            },
+        );
+        match archive::export_user_to_sink(&*db_pool, &user_id, EXPORT_BATCH_SIZE, pin!(sink)).await
+        {
+            Ok(summary) => info!(
+                "Exported user (legacy_id: {}, hashed_fxa_uid: {}): {} collections, {} bsos",
+                user_id.legacy_id, user_id.hashed_fxa_uid, summary.collections, summary.bsos
+            ),
+            Err(e) => {
+                let e = ApiError::from(e);
+                error!(
+                    "Failed to export user (legacy_id: {}, hashed_fxa_uid: {}): {}",
+                    user_id.legacy_id, user_id.hashed_fxa_uid, e
+                );
+                // Abort the response rather than end it early
+                let _ = body_tx
+                    .send(Err(ApiErrorKind::Internal(e.to_string()).into()))
+                    .await;
+            }
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(body_rx))
+}
+
+/// Report the current client backoff (maintenance mode) settings
+pub async fn admin_get_backoff(request: AdminRequest) -> Result<HttpResponse, ApiError> {
+    let backoff = request.state.backoff.read().await.clone();
+    Ok(HttpResponse::Ok().json(backoff))
+}
+
+/// Replace the client backoff (maintenance mode) settings until the next
+/// restart
+pub async fn admin_put_backoff(
+    request: AdminRequest,
+    backoff: Json<Backoff>,
+) -> Result<HttpResponse, ApiError> {
+    let backoff = backoff.into_inner();
+    if !(0.0..=1.0).contains(&backoff.fraction) {
+        return Err(ValidationErrorKind::FromDetails(
+            "fraction must be between 0.0 and 1.0".to_owned(),
+            RequestErrorLocation::Body,
+            Some("fraction".to_owned()),
+            None,
+        )
+        .into());
+    }
+    info!("Updating client backoff: {:?}", backoff);
+    *request.state.backoff.write().await = backoff.clone();
+    Ok(HttpResponse::Ok().json(backoff))
+}
+
+/// Whether storage is read-only, for `/__admin__/read_only`
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ReadOnly {
+    read_only: bool,
+}
+
+/// Report whether storage is read-only
+pub async fn admin_get_read_only(request: AdminRequest) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(ReadOnly {
+        read_only: request.state.read_only.load(Ordering::Relaxed),
+    }))
+}
+
+/// Toggle whether storage is read-only until the next restart
+pub async fn admin_put_read_only(
+    request: AdminRequest,
+    body: Json<ReadOnly>,
+) -> Result<HttpResponse, ApiError> {
+    let read_only = body.into_inner().read_only;
+    info!("Setting storage read-only: {}", read_only);
+    request.state.read_only.store(read_only, Ordering::Relaxed);
+    Ok(HttpResponse::Ok().json(ReadOnly { read_only }))
+}
+
 // try returning an API error
 pub async fn test_error(
     _req: HttpRequest,
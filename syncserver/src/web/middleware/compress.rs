@@ -0,0 +1,139 @@
+//! Response compression, only available with the `actix-compress` feature
+//! (which also decompresses `Content-Encoding` request bodies before their
+//! size is checked against `max_request_bytes`).
+use std::future::Future;
+
+#[cfg(feature = "actix-compress")]
+use actix_http::encoding::Encoder;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+#[cfg(feature = "actix-compress")]
+use actix_web::{
+    HttpMessage,
+    body::{BodySize, MessageBody},
+    http::{
+        Method,
+        header::{self, AcceptEncoding, ContentEncoding, Encoding, HeaderValue},
+    },
+    web::Data,
+};
+
+#[cfg(feature = "actix-compress")]
+use crate::server::ServerState;
+
+/// Encodings offered to clients (chosen by the client's `Accept-Encoding`
+/// quality values)
+#[cfg(feature = "actix-compress")]
+const SUPPORTED_ENCODINGS: &[Encoding] = &[
+    Encoding::zstd(),
+    Encoding::brotli(),
+    Encoding::gzip(),
+    Encoding::identity(),
+];
+
+/// Middleware compressing `get_collection` and `/info/*` responses with the
+/// `Content-Encoding` negotiated from the request's `Accept-Encoding`.
+///
+/// Responses with a known size under `compression.min_bytes` and routes listed
+/// in `compression.disabled_routes` are passed along uncompressed.
+#[cfg(feature = "actix-compress")]
+pub fn compress<B, S>(
+    request: ServiceRequest,
+    service: &S,
+) -> impl Future<Output = Result<ServiceResponse<Encoder<B>>, actix_web::Error>> + use<B, S>
+where
+    B: MessageBody,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let encoding = negotiate(&request);
+    let fut = service.call(request);
+
+    async move {
+        let resp = fut.await?;
+        let Some((encoding, min_bytes)) = encoding else {
+            return Ok(resp
+                .map_body(|head, body| Encoder::response(ContentEncoding::Identity, head, body)));
+        };
+        Ok(resp.map_body(move |head, body| {
+            head.headers_mut()
+                .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+            let encoding = match body.size() {
+                BodySize::Sized(size) if size < min_bytes as u64 => ContentEncoding::Identity,
+                _ => encoding,
+            };
+            Encoder::response(encoding, head, body)
+        }))
+    }
+}
+
+/// Responses are never compressed without the `actix-compress` feature
+#[cfg(not(feature = "actix-compress"))]
+pub fn compress<B, S>(
+    request: ServiceRequest,
+    service: &S,
+) -> impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>> + use<B, S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    service.call(request)
+}
+
+/// Determine the encoding (and size threshold) for the request's response,
+/// or `None` if it's not to be compressed
+#[cfg(feature = "actix-compress")]
+fn negotiate(request: &ServiceRequest) -> Option<(ContentEncoding, usize)> {
+    if request.method() != Method::GET {
+        return None;
+    }
+    let route = route_name(request.path())?;
+    let compression = &request.app_data::<Data<ServerState>>()?.compression;
+    if !compression.route_enabled(route) {
+        return None;
+    }
+    let encoding = match request.get_header::<AcceptEncoding>() {
+        Some(accept) => match accept.negotiate(SUPPORTED_ENCODINGS.iter())? {
+            Encoding::Known(encoding) => encoding,
+            Encoding::Unknown(_) => ContentEncoding::Identity,
+        },
+        None => ContentEncoding::Identity,
+    };
+    Some((encoding, compression.min_bytes))
+}
+
+/// The name (see `syncserver_settings::COMPRESSIBLE_ROUTES`) of the storage
+/// route the path belongs to, if it's compressible
+#[cfg(feature = "actix-compress")]
+fn route_name(path: &str) -> Option<&'static str> {
+    let mut segments = path.strip_prefix("/1.5/")?.split('/').skip(1);
+    let route = match (segments.next()?, segments.next()?) {
+        ("storage", _) => "get_collection",
+        ("info", "collections") => "info_collections",
+        ("info", "collection_counts") => "info_collection_counts",
+        ("info", "collection_usage") => "info_collection_usage",
+        ("info", "configuration") => "info_configuration",
+        ("info", "quota") => "info_quota",
+        _ => return None,
+    };
+    segments.next().is_none().then_some(route)
+}
+
+#[cfg(all(test, feature = "actix-compress"))]
+mod tests {
+    use super::route_name;
+
+    #[test]
+    fn test_route_name() {
+        assert_eq!(
+            route_name("/1.5/42/storage/bookmarks"),
+            Some("get_collection")
+        );
+        assert_eq!(
+            route_name("/1.5/42/info/collections"),
+            Some("info_collections")
+        );
+        assert_eq!(route_name("/1.5/42/info/quota"), Some("info_quota"));
+        assert_eq!(route_name("/1.5/42/storage/bookmarks/wibble"), None);
+        assert_eq!(route_name("/1.5/42/info/unknown"), None);
+        assert_eq!(route_name("/1.5/42/storage"), None);
+        assert_eq!(route_name("/__heartbeat__"), None);
+    }
+}
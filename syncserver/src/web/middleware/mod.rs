@@ -1,2 +1,4 @@
+pub mod compress;
+pub mod ratelimit;
 pub mod rejectua;
 pub mod weave;
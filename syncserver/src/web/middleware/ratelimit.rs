@@ -0,0 +1,339 @@
+#![allow(clippy::type_complexity)]
+
+use std::{
+    hash::{BuildHasher, RandomState},
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    FromRequest, HttpMessage, HttpResponse,
+    body::EitherBody,
+    dev::{Extensions, Service, ServiceRequest, ServiceResponse},
+    http::{Method, header::RETRY_AFTER},
+    web::Data,
+};
+use futures::future::LocalBoxFuture;
+use lru::LruCache;
+use syncserver_common::X_WEAVE_BACKOFF;
+use syncserver_settings::Secrets;
+use syncstorage_settings::RateLimit;
+
+use crate::{
+    server::{MetricsWrapper, ServerState},
+    web::extractors::{CollectionParam, HawkIdentifier},
+};
+
+/// Buckets are split across this many separately locked shards
+const SHARDS: usize = 16;
+/// Max number of buckets tracked. Past it the least recently used are evicted,
+/// (leniently) resetting those users' limits
+const MAX_BUCKETS: usize = 100_000;
+/// How often each shard drops its refilled buckets
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct BucketKey {
+    uid: u64,
+    collection: Option<String>,
+    method: Option<Method>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+struct Shard {
+    buckets: LruCache<BucketKey, Bucket>,
+    last_pruned: Instant,
+}
+
+/// Token bucket rate limiter for storage requests.
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Tokens regained per second
+    rate: f64,
+    burst: f64,
+    per_collection: bool,
+    per_method: bool,
+    backoff: u32,
+    hasher: RandomState,
+    shards: Vec<Mutex<Shard>>,
+}
+
+impl RateLimiter {
+    /// Build a `RateLimiter`, or `None` when rate limiting is disabled
+    pub fn from_settings(settings: &RateLimit) -> Option<Arc<Self>> {
+        Self::with_max_buckets(settings, MAX_BUCKETS)
+    }
+
+    fn with_max_buckets(settings: &RateLimit, max_buckets: usize) -> Option<Arc<Self>> {
+        let requests_per_minute = settings.requests_per_minute.filter(|rpm| *rpm > 0)?;
+        let capacity = NonZeroUsize::new(max_buckets / SHARDS).unwrap_or(NonZeroUsize::MIN);
+        let now = Instant::now();
+        Some(Arc::new(Self {
+            rate: f64::from(requests_per_minute) / 60.0,
+            burst: f64::from(settings.burst.max(1)),
+            per_collection: settings.per_collection,
+            per_method: settings.per_method,
+            backoff: settings.backoff,
+            hasher: RandomState::new(),
+            shards: (0..SHARDS)
+                .map(|_| {
+                    Mutex::new(Shard {
+                        buckets: LruCache::new(capacity),
+                        last_pruned: now,
+                    })
+                })
+                .collect(),
+        }))
+    }
+
+    fn key(&self, uid: u64, collection: Option<String>, method: &Method) -> BucketKey {
+        BucketKey {
+            uid,
+            collection: collection.filter(|_| self.per_collection),
+            method: self.per_method.then(|| method.clone()),
+        }
+    }
+
+    /// Take a token from the key's bucket, otherwise return how long until
+    /// one's available
+    fn acquire(&self, key: BucketKey, now: Instant) -> Result<(), Duration> {
+        let shard = &self.shards[self.hasher.hash_one(&key) as usize % SHARDS];
+        let mut shard = shard.lock().expect("RateLimiter lock poisoned");
+        if now.duration_since(shard.last_pruned) >= PRUNE_INTERVAL {
+            self.prune(&mut shard.buckets, now);
+            shard.last_pruned = now;
+        }
+        let bucket = shard.buckets.get_or_insert_mut(key, || Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    /// Drop the buckets that have refilled, which are indistinguishable from
+    /// new ones
+    fn prune(&self, buckets: &mut LruCache<BucketKey, Bucket>, now: Instant) {
+        let refilled: Vec<_> = buckets
+            .iter()
+            .filter(|(_, bucket)| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * self.rate
+                    >= self.burst
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in refilled {
+            buckets.pop(&key);
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().buckets.len())
+            .sum()
+    }
+}
+
+/// Middleware throttling storage requests per Hawk authenticated user.
+///
+/// Throttled requests are answered with a 503 including `X-Weave-Backoff`
+/// and `Retry-After` headers. Requests failing Hawk authentication are passed
+/// along untouched so the handler reports the actual error.
+pub fn rate_limit<B>(
+    request: ServiceRequest,
+    service: &(
+         impl Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static
+     ),
+) -> LocalBoxFuture<'static, Result<ServiceResponse<EitherBody<B>>, actix_web::Error>> {
+    match throttled(&request) {
+        Some(wait) => Box::pin(async move {
+            let (req, payload) = request.into_parts();
+            MetricsWrapper::extract(&req).await?.0.incr_with_tag(
+                "request.rate_limited",
+                "method",
+                req.method().as_str(),
+            );
+            let backoff = req
+                .app_data::<Data<ServerState>>()
+                .and_then(|state| state.rate_limiter.as_ref())
+                .map_or(0, |limiter| limiter.backoff);
+            let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            let sreq = ServiceRequest::from_parts(req, payload);
+
+            Ok(sreq.into_response(
+                HttpResponse::ServiceUnavailable()
+                    .insert_header((X_WEAVE_BACKOFF, backoff.to_string()))
+                    .insert_header((RETRY_AFTER, retry_after.max(1).to_string()))
+                    .body("0")
+                    .map_into_right_body(),
+            ))
+        }),
+        None => {
+            let fut = service.call(request);
+            Box::pin(async move { fut.await.map(|resp| resp.map_into_left_body()) })
+        }
+    }
+}
+
+/// Determine if the request exceeds its user's rate limit, returning how long
+/// the client should wait if so
+fn throttled(request: &ServiceRequest) -> Option<Duration> {
+    if !request.path().starts_with("/1.5/") {
+        return None;
+    }
+    let state = request.app_data::<Data<ServerState>>()?;
+    let limiter = state.rate_limiter.clone()?;
+    let secrets = request.app_data::<Data<Arc<Secrets>>>()?;
+    let (host, port) = state
+        .hawk_origin
+        .host_port(request.head(), request.app_config())
+        .ok()?;
+    let auth_header = request.headers().get("authorization")?.to_str().ok()?;
+    // Neither the nonce nor the identifier are recorded: the handler's own
+    // extraction does so, reporting any error. Replays aren't charged to the
+    // user's bucket, so captured requests can't be used to exhaust it
+    let user_id = HawkIdentifier::generate(
+        secrets,
+        request.method().as_str(),
+        auth_header,
+        (&host, port),
+        request.uri(),
+        &state.replay_guard.peeking(),
+        &mut Extensions::new(),
+    )
+    .ok()?;
+    let collection = CollectionParam::extrude(request.uri(), &mut request.extensions_mut())
+        .ok()
+        .flatten()
+        .map(|param| param.collection);
+    let key = limiter.key(user_id.legacy_id, collection, request.method());
+    limiter.acquire(key, Instant::now()).err()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(requests_per_minute: u32, burst: u32) -> Arc<RateLimiter> {
+        RateLimiter::from_settings(&RateLimit {
+            requests_per_minute: Some(requests_per_minute),
+            burst,
+            per_collection: true,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_disabled() {
+        assert!(RateLimiter::from_settings(&RateLimit::default()).is_none());
+    }
+
+    #[test]
+    fn test_burst_then_refill() {
+        let limiter = limiter(60, 2);
+        let now = Instant::now();
+        let key = || limiter.key(1, None, &Method::GET);
+        assert!(limiter.acquire(key(), now).is_ok());
+        assert!(limiter.acquire(key(), now).is_ok());
+        let wait = limiter.acquire(key(), now).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(1));
+        assert!(limiter.acquire(key(), now + Duration::from_secs(1)).is_ok());
+        assert!(
+            limiter
+                .acquire(key(), now + Duration::from_secs(1))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_keys() {
+        let limiter = limiter(60, 1);
+        let now = Instant::now();
+        let key =
+            |uid, collection: &str, method| limiter.key(uid, Some(collection.to_owned()), &method);
+        assert!(
+            limiter
+                .acquire(key(1, "bookmarks", Method::GET), now)
+                .is_ok()
+        );
+        // Methods share a bucket unless `per_method` is set
+        assert!(
+            limiter
+                .acquire(key(1, "bookmarks", Method::POST), now)
+                .is_err()
+        );
+        assert!(limiter.acquire(key(1, "history", Method::GET), now).is_ok());
+        assert!(
+            limiter
+                .acquire(key(2, "bookmarks", Method::GET), now)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_pruning() {
+        let limiter = limiter(60, 1);
+        let now = Instant::now();
+        let key = |uid| limiter.key(uid, None, &Method::GET);
+        for uid in 0..1000 {
+            assert!(limiter.acquire(key(uid), now).is_ok());
+        }
+        assert_eq!(limiter.len(), 1000);
+
+        // Refilled buckets are kept until the prune interval's passed, rather
+        // than swept on every request
+        let later = now + Duration::from_secs(2);
+        for uid in 1000..1010 {
+            assert!(limiter.acquire(key(uid), later).is_ok());
+        }
+        assert_eq!(limiter.len(), 1010);
+        assert!(limiter.acquire(key(0), later).is_ok());
+        assert!(limiter.acquire(key(0), later).is_err());
+
+        // Each shard then drops its refilled buckets on its next request
+        let later = now + PRUNE_INTERVAL + Duration::from_secs(1);
+        for uid in 2000..3000 {
+            assert!(limiter.acquire(key(uid), later).is_ok());
+        }
+        assert_eq!(limiter.len(), 1000);
+    }
+
+    #[test]
+    fn test_max_buckets() {
+        let limiter = RateLimiter::with_max_buckets(
+            &RateLimit {
+                requests_per_minute: Some(60),
+                burst: 1,
+                ..Default::default()
+            },
+            SHARDS * 4,
+        )
+        .unwrap();
+        let now = Instant::now();
+        for uid in 0..1000 {
+            assert!(
+                limiter
+                    .acquire(limiter.key(uid, None, &Method::GET), now)
+                    .is_ok()
+            );
+        }
+        assert!(limiter.len() <= SHARDS * 4);
+    }
+}
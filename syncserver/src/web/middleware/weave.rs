@@ -2,15 +2,22 @@ use std::fmt::Display;
 use std::future::Future;
 
 use actix_web::{
+    HttpMessage,
     dev::{Service, ServiceRequest, ServiceResponse},
-    http::header::{self, HeaderMap},
+    http::{
+        StatusCode,
+        header::{self, HeaderMap, RETRY_AFTER, USER_AGENT},
+    },
+    web::Data,
 };
 
-use syncserver_common::{X_LAST_MODIFIED, X_WEAVE_TIMESTAMP};
+use syncserver_common::{Metrics, X_LAST_MODIFIED, X_WEAVE_BACKOFF, X_WEAVE_TIMESTAMP};
 use syncstorage_db::SyncTimestamp;
+use syncstorage_settings::Backoff;
 
 use crate::error::{ApiError, ApiErrorKind};
-use crate::web::DOCKER_FLOW_ENDPOINTS;
+use crate::server::{ServerState, user_agent::get_device_info};
+use crate::web::{DOCKER_FLOW_ENDPOINTS, extractors::CollectionParam};
 
 /// Middleware to set the X-Weave-Timestamp header on all responses.
 pub fn set_weave_timestamp<B, S>(
@@ -62,6 +69,108 @@ fn insert_weave_timestamp_into_headers(headers: &mut HeaderMap, ts: f64) -> Resu
     Ok(())
 }
 
+/// Middleware to add `X-Weave-Backoff` headers to storage responses while in
+/// backoff (maintenance) mode or while the database pool is saturated.
+pub fn set_weave_backoff<B, S>(
+    request: ServiceRequest,
+    service: &S,
+) -> impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>> + use<B, S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let state = request
+        .app_data::<Data<ServerState>>()
+        .filter(|_| request.path().starts_with("/1.5/"))
+        .cloned();
+    let collection = CollectionParam::extrude(request.uri(), &mut request.extensions_mut())
+        .ok()
+        .flatten()
+        .map(|param| param.collection);
+    let user_agent = request
+        .headers()
+        .get(USER_AGENT)
+        .and_then(|header| header.to_str().ok())
+        .map(ToOwned::to_owned);
+    let fut = service.call(request);
+
+    async move {
+        let mut resp = fut.await?;
+        let Some(state) = state else {
+            return Ok(resp);
+        };
+        let backoff = state.backoff.read().await.clone();
+        let reason = backoff_reason(
+            &backoff,
+            &state.db_pool.status(),
+            collection.as_deref(),
+            user_agent.as_deref(),
+        );
+        if let Some(reason) = reason {
+            let status = resp.status();
+            // Saturation is usually brief: don't keep clients away as long as for maintenance
+            let seconds = if reason == "pool_saturated" {
+                backoff.pool_saturated_seconds
+            } else {
+                backoff.seconds
+            };
+            insert_backoff_into_headers(resp.headers_mut(), status, seconds);
+            Metrics::from(&state.metrics).incr_with_tag("request.backoff", "reason", reason);
+        }
+        Ok(resp)
+    }
+}
+
+/// Determine why (if at all) a response should ask the client to back off
+fn backoff_reason(
+    backoff: &Backoff,
+    pool_status: &deadpool::Status,
+    collection: Option<&str>,
+    user_agent: Option<&str>,
+) -> Option<&'static str> {
+    if backoff.when_pool_saturated
+        && pool_status.max_size > 0
+        && pool_status.size >= pool_status.max_size
+        && pool_status.available == 0
+        && pool_status.waiting > 0
+    {
+        return Some("pool_saturated");
+    }
+    if !backoff.enabled {
+        return None;
+    }
+    if !backoff.collections.is_empty()
+        && !collection.is_some_and(|collection| backoff.collections.iter().any(|c| c == collection))
+    {
+        return None;
+    }
+    if !backoff.platforms.is_empty() {
+        let platform = get_device_info(user_agent.unwrap_or_default())
+            .platform
+            .to_string();
+        if !backoff
+            .platforms
+            .iter()
+            .any(|p| p.eq_ignore_ascii_case(&platform))
+        {
+            return None;
+        }
+    }
+    (rand::random::<f64>() < backoff.fraction).then_some("maintenance")
+}
+
+/// Set the X-Weave-Backoff header (and Retry-After for 503s), leaving any
+/// already set (e.g. by rate limiting) alone
+fn insert_backoff_into_headers(headers: &mut HeaderMap, status: StatusCode, seconds: u32) {
+    let value = header::HeaderValue::from(seconds);
+    let name = header::HeaderName::from_static(X_WEAVE_BACKOFF);
+    if !headers.contains_key(&name) {
+        headers.insert(name, value.clone());
+    }
+    if status == StatusCode::SERVICE_UNAVAILABLE && !headers.contains_key(RETRY_AFTER) {
+        headers.insert(RETRY_AFTER, value);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -128,4 +237,94 @@ mod tests {
             .unwrap();
         assert_eq!(weave_hdr, hts);
     }
+
+    fn pool_status(size: usize, available: usize, waiting: usize) -> deadpool::Status {
+        deadpool::Status {
+            max_size: 10,
+            size,
+            available,
+            waiting,
+        }
+    }
+
+    #[test]
+    fn test_backoff_disabled() {
+        let backoff = Backoff::default();
+        assert_eq!(
+            backoff_reason(&backoff, &pool_status(10, 1, 0), None, None),
+            None
+        );
+        // Every connection in use isn't saturation until requests wait for one
+        assert_eq!(
+            backoff_reason(&backoff, &pool_status(10, 0, 0), None, None),
+            None
+        );
+        assert_eq!(
+            backoff_reason(&backoff, &pool_status(10, 0, 5), None, None),
+            Some("pool_saturated")
+        );
+        let backoff = Backoff {
+            when_pool_saturated: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            backoff_reason(&backoff, &pool_status(10, 0, 5), None, None),
+            None
+        );
+    }
+
+    #[test]
+    fn test_backoff_filters() {
+        let backoff = Backoff {
+            enabled: true,
+            collections: vec!["history".to_owned()],
+            platforms: vec!["FirefoxDesktop".to_owned()],
+            ..Default::default()
+        };
+        let desktop_ua =
+            "Firefox/130.0 (Windows NT 10.0; Win64; x64) FxSync/1.132.0.20240830.desktop";
+        let ios_ua = "Firefox-iOS-Sync/108.1b24234 (iPad; iPhone OS 16.4.1) (Firefox)";
+        let status = pool_status(1, 1, 0);
+        assert_eq!(
+            backoff_reason(&backoff, &status, Some("history"), Some(desktop_ua)),
+            Some("maintenance")
+        );
+        assert_eq!(
+            backoff_reason(&backoff, &status, Some("bookmarks"), Some(desktop_ua)),
+            None
+        );
+        assert_eq!(
+            backoff_reason(&backoff, &status, None, Some(desktop_ua)),
+            None
+        );
+        assert_eq!(
+            backoff_reason(&backoff, &status, Some("history"), Some(ios_ua)),
+            None
+        );
+        let backoff = Backoff {
+            enabled: true,
+            fraction: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(backoff_reason(&backoff, &status, None, None), None);
+    }
+
+    #[test]
+    fn test_backoff_headers() {
+        let mut resp = HttpResponse::build(http::StatusCode::OK).finish();
+        insert_backoff_into_headers(resp.headers_mut(), http::StatusCode::OK, 600);
+        assert_eq!(resp.headers().get(X_WEAVE_BACKOFF).unwrap(), "600");
+        assert!(resp.headers().get(RETRY_AFTER).is_none());
+
+        let mut resp = HttpResponse::build(http::StatusCode::SERVICE_UNAVAILABLE)
+            .insert_header((X_WEAVE_BACKOFF, "60"))
+            .finish();
+        insert_backoff_into_headers(
+            resp.headers_mut(),
+            http::StatusCode::SERVICE_UNAVAILABLE,
+            600,
+        );
+        assert_eq!(resp.headers().get(X_WEAVE_BACKOFF).unwrap(), "60");
+        assert_eq!(resp.headers().get(RETRY_AFTER).unwrap(), "600");
+    }
 }
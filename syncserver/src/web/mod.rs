@@ -7,9 +7,10 @@ pub mod middleware;
 mod transaction;
 
 // Known DockerFlow commands for Ops callbacks
-pub const DOCKER_FLOW_ENDPOINTS: [&str; 4] = [
+pub const DOCKER_FLOW_ENDPOINTS: [&str; 5] = [
     "/__heartbeat__",
     "/__lbheartbeat__",
     "/__version__",
     "/__error__",
+    "/__metrics__",
 ];
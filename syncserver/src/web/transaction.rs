@@ -5,6 +5,7 @@ use actix_web::web::Data;
 use actix_web::{FromRequest, HttpRequest, HttpResponse};
 use futures::FutureExt;
 use futures::future::LocalBoxFuture;
+use std::sync::atomic::Ordering;
 
 use syncserver_common::{Taggable, X_LAST_MODIFIED};
 use syncstorage_db::{Db, DbError, DbPool, UserIdentifier, params, results::ConnectionInfo};
@@ -19,6 +20,7 @@ use crate::server::{MetricsWrapper, ServerState};
 pub struct DbTransactionPool {
     pool: Box<dyn DbPool<Error = DbError>>,
     is_read: bool,
+    read_only: bool,
     user_id: UserIdentifier,
     collection: Option<String>,
     bso_opt: Option<String>,
@@ -50,6 +52,10 @@ impl DbTransactionPool {
     where
         A: AsyncFnOnce(&mut dyn Db<Error = DbError>) -> Result<R, ApiError>,
     {
+        if self.read_only && !self.is_read {
+            return Err(ApiErrorKind::ReadOnly.into());
+        }
+
         // Get connection from pool
         let mut db = self.pool.get().await?;
 
@@ -233,6 +239,7 @@ impl FromRequest for DbTransactionPool {
             let pool = Self {
                 pool: state.db_pool.clone(),
                 is_read,
+                read_only: state.read_only.load(Ordering::Relaxed),
                 user_id: user_id.into(),
                 collection,
                 bso_opt,
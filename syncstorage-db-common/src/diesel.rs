@@ -174,3 +174,43 @@ from_error!(
     DbError,
     |error: std::boxed::Box<dyn std::error::Error>| DbError::internal_error(error.to_string())
 );
+
+/// Keyset predicate matching the BSOs that follow a [crate::params::Cursor]
+/// in `sort` order, given the backend's `sortindex`, `id` and `modified`
+/// columns and the cursor's `modified` converted to the column's type.
+///
+/// Expands to a `match` of boxed expressions, to be coerced to the backend's
+/// `Box<dyn BoxableExpression<_, _, SqlType = Nullable<Bool>>>`. NULL
+/// sortindexes sort last (`sortindex DESC`, or `NULLS LAST` on Postgres).
+#[macro_export]
+macro_rules! after_cursor {
+    ($sort:expr, $cursor:expr, $modified:expr, $sortindex_col:path, $id_col:path, $modified_col:path $(,)?) => {{
+        use ::diesel::{BoolExpressionMethods, ExpressionMethods, NullableExpressionMethods};
+
+        let modified = $modified;
+        let $crate::params::Cursor { sortindex, id, .. } = $cursor;
+        match ($sort, sortindex) {
+            ($crate::Sorting::Index, Some(sortindex)) => Box::new(
+                $sortindex_col
+                    .lt(sortindex)
+                    .or($sortindex_col.eq(sortindex).and($id_col.lt(id)))
+                    .or($sortindex_col.is_null()),
+            ),
+            ($crate::Sorting::Index, None) => {
+                Box::new($sortindex_col.is_null().and($id_col.lt(id)).nullable())
+            }
+            ($crate::Sorting::Oldest, _) => Box::new(
+                $modified_col
+                    .gt(modified)
+                    .or($modified_col.eq(modified).and($id_col.gt(id)))
+                    .nullable(),
+            ),
+            ($crate::Sorting::Newest | $crate::Sorting::None, _) => Box::new(
+                $modified_col
+                    .lt(modified)
+                    .or($modified_col.eq(modified).and($id_col.lt(id)))
+                    .nullable(),
+            ),
+        }
+    }};
+}
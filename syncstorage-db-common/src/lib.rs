@@ -5,9 +5,10 @@ pub mod params;
 pub mod results;
 pub mod util;
 
-use std::fmt::Debug;
+use std::{fmt::Debug, time::Duration};
 
 use async_trait::async_trait;
+use futures::stream::{self, LocalBoxStream, StreamExt};
 use lazy_static::lazy_static;
 use serde::Deserialize;
 use syncserver_db_common::GetPoolStatus;
@@ -60,15 +61,27 @@ pub trait DbPool: Sync + Send + Debug + GetPoolStatus {
 
     fn validate_batch_id(&self, params: params::ValidateBatchId) -> Result<(), Self::Error>;
 
+    /// Spawn a task to periodically evict idle connections. Noop by default.
+    fn spawn_sweeper(&self, _interval: Duration) {}
+
     fn box_clone(&self) -> Box<dyn DbPool<Error = Self::Error>>;
 }
 
+impl<E> GetPoolStatus for Box<dyn DbPool<Error = E>> {
+    fn status(&self) -> deadpool::Status {
+        (**self).status()
+    }
+}
+
 impl<E> Clone for Box<dyn DbPool<Error = E>> {
     fn clone(&self) -> Box<dyn DbPool<Error = E>> {
         self.box_clone()
     }
 }
 
+/// BSOs yielded incrementally by [Db::get_bsos_stream]
+pub type BsoStream<'a, E> = LocalBoxStream<'a, Result<results::GetBso, E>>;
+
 #[async_trait(?Send)]
 pub trait Db: BatchDb {
     async fn lock_for_read(&mut self, params: params::LockCollection) -> Result<(), Self::Error>;
@@ -138,6 +151,19 @@ pub trait Db: BatchDb {
         params: params::GetBsos,
     ) -> Result<results::GetBsoIds, Self::Error>;
 
+    /// Yield the page of BSOs [Db::get_bsos] would return as they're read,
+    /// rather than buffering them. No next offset is computed: callers pair
+    /// this with [Db::get_bso_ids] in the same transaction.
+    ///
+    /// The default implementation buffers the page via [Db::get_bsos].
+    async fn get_bsos_stream(
+        &mut self,
+        params: params::GetBsos,
+    ) -> Result<BsoStream<'_, Self::Error>, Self::Error> {
+        let bsos = self.get_bsos(params).await?;
+        Ok(stream::iter(bsos.items.into_iter().map(Ok)).boxed_local())
+    }
+
     async fn post_bsos(&mut self, params: params::PostBsos) -> Result<SyncTimestamp, Self::Error>;
 
     async fn delete_bso(
@@ -161,6 +187,33 @@ pub trait Db: BatchDb {
 
     fn get_connection_info(&self) -> results::ConnectionInfo;
 
+    /// Page through the users with stored data, ordered by the backend's
+    /// user key, starting after `params.after`. Used by offline tools that
+    /// walk every user.
+    async fn get_user_ids(
+        &mut self,
+        params: params::GetUserIds,
+    ) -> Result<results::GetUserIds, Self::Error>;
+
+    /// Write BSOs verbatim, keeping their `modified` and `expiry`, and set the
+    /// collection's timestamp to `params.modified`. Unlike `post_bsos` this
+    /// neither checks quota nor uses the session's timestamp: it's intended
+    /// for copying data between backends.
+    async fn import_bsos(
+        &mut self,
+        params: params::ImportBsos,
+    ) -> Result<results::ImportBsos, Self::Error>;
+
+    /// Delete up to `params.limit` expired BSOs and up to `params.limit`
+    /// expired batches (along with their pending items), across all users.
+    ///
+    /// Callers should repeat this until fewer than `params.limit` rows of
+    /// each are deleted.
+    async fn purge_expired(
+        &mut self,
+        params: params::PurgeExpired,
+    ) -> Result<results::PurgeExpired, Self::Error>;
+
     /// Retrieve the timestamp for an item/collection
     async fn extract_resource(
         &mut self,
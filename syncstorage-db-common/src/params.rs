@@ -60,14 +60,88 @@ uid_data! {
     DeleteStorage,
 }
 
-#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+/// Marks an offset as a keyset [Cursor] rather than a legacy numeric offset
+const CURSOR_PREFIX: char = '~';
+
+/// Keyset position of the last BSO of a page: the following page starts
+/// immediately after it in the query's sort order.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Cursor {
+    pub modified: SyncTimestamp,
+    pub sortindex: Option<i32>,
+    pub id: String,
+}
+
+impl Display for Cursor {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(fmt, "{}{}:", CURSOR_PREFIX, self.modified.as_i64())?;
+        if let Some(sortindex) = self.sortindex {
+            write!(fmt, "{}", sortindex)?;
+        }
+        // BSO ids may contain colons, so the id comes last
+        write!(fmt, ":{}", self.id)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = ParseIntError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.strip_prefix(CURSOR_PREFIX).unwrap_or(s).splitn(3, ':');
+        let modified = SyncTimestamp::from_milliseconds(parts.next().unwrap_or("").parse()?);
+        let sortindex = match parts.next().unwrap_or("") {
+            "" => None,
+            sortindex => Some(sortindex.parse()?),
+        };
+        Ok(Cursor {
+            modified,
+            sortindex,
+            id: parts.next().unwrap_or("").to_owned(),
+        })
+    }
+}
+
+impl From<&results::GetBso> for Cursor {
+    fn from(bso: &results::GetBso) -> Self {
+        Cursor {
+            modified: bso.modified,
+            sortindex: bso.sortindex,
+            id: bso.id.clone(),
+        }
+    }
+}
+
+/// Where to resume a BSO listing.
+///
+/// Either a legacy `timestamp:skip` (or plain numeric) offset, which backends
+/// resolve with an SQL `OFFSET`, or a keyset [Cursor].
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
 pub struct Offset {
     pub timestamp: Option<SyncTimestamp>,
     pub offset: u64,
+    pub cursor: Option<Cursor>,
+}
+
+impl Offset {
+    /// Whether the string is an encoded keyset [Cursor]
+    pub fn is_cursor(s: &str) -> bool {
+        s.starts_with(CURSOR_PREFIX)
+    }
+}
+
+impl From<Cursor> for Offset {
+    fn from(cursor: Cursor) -> Self {
+        Offset {
+            cursor: Some(cursor),
+            ..Default::default()
+        }
+    }
 }
 
 impl Display for Offset {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        if let Some(cursor) = &self.cursor {
+            return write!(fmt, "{}", cursor);
+        }
         match self.timestamp {
             None => write!(fmt, "{}", self.offset),
             Some(ts) => write!(fmt, "{}:{}", ts.as_i64(), self.offset),
@@ -78,10 +152,13 @@ impl Display for Offset {
 impl FromStr for Offset {
     type Err = ParseIntError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if Offset::is_cursor(s) {
+            return Ok(Cursor::from_str(s)?.into());
+        }
         let result = match s.chars().position(|c| c == ':') {
             None => Offset {
-                timestamp: None,
                 offset: s.parse::<u64>()?,
+                ..Default::default()
             },
             Some(_colon_position) => {
                 let mut parts = s.split(':');
@@ -91,6 +168,7 @@ impl FromStr for Offset {
                 Offset {
                     timestamp: Some(timestamp),
                     offset,
+                    cursor: None,
                 }
             }
         };
@@ -102,7 +180,7 @@ impl FromStr for Offset {
 mod tests {
     use std::str::FromStr;
 
-    use super::Offset;
+    use super::{Cursor, Offset};
     use crate::util::SyncTimestamp;
 
     #[test]
@@ -110,6 +188,7 @@ mod tests {
         let offset = Offset {
             timestamp: None,
             offset: 50,
+            cursor: None,
         };
         assert_eq!(offset.to_string(), "50");
     }
@@ -119,6 +198,7 @@ mod tests {
         let offset = Offset {
             timestamp: Some(SyncTimestamp::from_milliseconds(676760)),
             offset: 2,
+            cursor: None,
         };
         assert_eq!(offset.to_string(), "676760:2");
     }
@@ -128,6 +208,7 @@ mod tests {
         let original = Offset {
             timestamp: None,
             offset: 99,
+            cursor: None,
         };
         let parsed = Offset::from_str(&original.to_string()).unwrap();
         assert_eq!(parsed.offset, original.offset);
@@ -139,6 +220,7 @@ mod tests {
         let original = Offset {
             timestamp: Some(SyncTimestamp::from_milliseconds(71138383830)),
             offset: 3,
+            cursor: None,
         };
         let parsed = Offset::from_str(&original.to_string()).unwrap();
         assert_eq!(parsed.offset, original.offset);
@@ -149,6 +231,35 @@ mod tests {
     fn offset_fromstr_malformed_returns_error() {
         assert!(Offset::from_str("quux").is_err());
         assert!(Offset::from_str("wibble:buzz").is_err());
+        assert!(Offset::from_str("~wibble:1:id").is_err());
+        assert!(Offset::from_str("~1:buzz:id").is_err());
+    }
+
+    #[test]
+    fn cursor_roundtrip() {
+        let cursor = Cursor {
+            modified: SyncTimestamp::from_milliseconds(71138383830),
+            sortindex: Some(-3),
+            id: "a:b".to_owned(),
+        };
+        let offset = Offset::from(cursor.clone());
+        assert_eq!(offset.to_string(), "~71138383830:-3:a:b");
+        assert!(Offset::is_cursor(&offset.to_string()));
+        let parsed = Offset::from_str(&offset.to_string()).unwrap();
+        assert_eq!(parsed.cursor, Some(cursor));
+        assert_eq!(parsed.offset, 0);
+        assert!(parsed.timestamp.is_none());
+    }
+
+    #[test]
+    fn cursor_without_sortindex() {
+        let cursor = Cursor {
+            modified: SyncTimestamp::from_milliseconds(676760),
+            sortindex: None,
+            id: "xyz".to_owned(),
+        };
+        assert_eq!(cursor.to_string(), "~676760::xyz");
+        assert_eq!(Cursor::from_str(&cursor.to_string()).unwrap(), cursor);
     }
 }
 
@@ -194,6 +305,10 @@ collection_data! {
     },
     GetQuotaUsage {
     },
+    ImportBsos {
+        bsos: Vec<results::GetBso>,
+        modified: SyncTimestamp,
+    },
 }
 
 impl From<ValidateBatch> for GetBatch {
@@ -218,6 +333,10 @@ bso_data! {
 #[derive(Clone, Debug, Default, Queryable)]
 pub struct Batch {
     pub id: String,
+    /// The number of records appended to the batch so far
+    pub total_records: u64,
+    /// The combined size of the payloads appended to the batch so far
+    pub total_bytes: u64,
 }
 
 pub struct PutBso {
@@ -239,6 +358,19 @@ pub struct PostCollectionBso {
     pub ttl: Option<u32>,
 }
 
+data! {
+    GetUserIds {
+        after: Option<UserIdentifier>,
+        limit: u32,
+    }
+}
+
+data! {
+    PurgeExpired {
+        limit: u32,
+    }
+}
+
 data! {
     UpdateCollection {
         user_id: UserIdentifier,
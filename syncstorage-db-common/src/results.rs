@@ -8,7 +8,7 @@ use diesel::{
 use serde::{Deserialize, Serialize};
 
 use super::params;
-use crate::util::SyncTimestamp;
+use crate::{UserIdentifier, util::SyncTimestamp};
 
 pub type LockCollection = ();
 pub type GetBsoTimestamp = SyncTimestamp;
@@ -24,6 +24,7 @@ pub type DeleteBsos = SyncTimestamp;
 pub type DeleteBso = SyncTimestamp;
 pub type PutBso = SyncTimestamp;
 pub type PostBsos = SyncTimestamp;
+pub type ImportBsos = SyncTimestamp;
 
 #[derive(Debug, Default, Clone)]
 pub struct CreateBatch {
@@ -45,7 +46,7 @@ pub struct GetQuotaUsage {
     pub count: i32,
 }
 
-#[derive(Debug, Default, Deserialize, Queryable, QueryableByName, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Queryable, QueryableByName, Serialize)]
 pub struct GetBso {
     #[diesel(sql_type = Text)]
     pub id: String,
@@ -57,8 +58,7 @@ pub struct GetBso {
     #[diesel(sql_type = Nullable<Integer>)]
     pub sortindex: Option<i32>,
     // NOTE: expiry (ttl) is never rendered to clients and only loaded for
-    // tests: this and its associated queries/loading could be wrapped in
-    // #[cfg(test)]
+    // tests and for copying BSOs between backends (see `Db::import_bsos`)
     #[serde(skip_serializing)]
     #[serde(skip_deserializing)]
     #[diesel(sql_type = BigInt)]
@@ -76,6 +76,18 @@ where
 
 pub type GetBsos = Paginated<GetBso>;
 pub type GetBsoIds = Paginated<String>;
+pub type GetUserIds = Vec<UserIdentifier>;
+
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct PurgeExpired {
+    /// Number of expired BSOs deleted
+    pub bsos: u64,
+    /// Number of expired batches deleted
+    pub batches: u64,
+    /// Number of expired batch items deleted separately from their batch (0
+    /// when they're deleted along with it)
+    pub batch_items: u64,
+}
 
 #[derive(Debug, Default)]
 pub struct ConnectionInfo {
@@ -0,0 +1,346 @@
+//! Adapters converting a backend's `DbPool`/`Db` into ones returning the
+//! unified [DbError], so they can be boxed as a `dyn DbPool<Error = DbError>`.
+use std::{fmt, time::Duration};
+
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use syncserver_db_common::GetPoolStatus;
+use syncstorage_db_common::{
+    BatchDb, BsoStream, Db, DbPool, error::DbErrorIntrospect, params, results, util::SyncTimestamp,
+};
+
+use crate::DbError;
+
+pub(crate) struct DbPoolAdapter<P>(pub P);
+
+impl<P, E> DbPoolAdapter<P>
+where
+    P: DbPool<Error = E> + Clone + 'static,
+    E: DbErrorIntrospect + Into<DbError> + 'static,
+{
+    pub fn boxed(pool: P) -> Box<dyn DbPool<Error = DbError>> {
+        Box::new(Self(pool))
+    }
+}
+
+#[async_trait]
+impl<P, E> DbPool for DbPoolAdapter<P>
+where
+    P: DbPool<Error = E> + Clone + 'static,
+    E: DbErrorIntrospect + Into<DbError> + 'static,
+{
+    type Error = DbError;
+
+    async fn init(&mut self) -> Result<(), Self::Error> {
+        self.0.init().await.map_err(Into::into)
+    }
+
+    async fn get(&self) -> Result<Box<dyn Db<Error = Self::Error>>, Self::Error> {
+        let db = self.0.get().await.map_err(Into::into)?;
+        Ok(Box::new(DbAdapter(db)))
+    }
+
+    fn validate_batch_id(&self, params: params::ValidateBatchId) -> Result<(), Self::Error> {
+        self.0.validate_batch_id(params).map_err(Into::into)
+    }
+
+    fn spawn_sweeper(&self, interval: Duration) {
+        self.0.spawn_sweeper(interval)
+    }
+
+    fn box_clone(&self) -> Box<dyn DbPool<Error = Self::Error>> {
+        Self::boxed(self.0.clone())
+    }
+}
+
+impl<P: GetPoolStatus> GetPoolStatus for DbPoolAdapter<P> {
+    fn status(&self) -> deadpool::Status {
+        self.0.status()
+    }
+}
+
+impl<P: fmt::Debug> fmt::Debug for DbPoolAdapter<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+struct DbAdapter<E>(Box<dyn Db<Error = E>>);
+
+impl<E> fmt::Debug for DbAdapter<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[async_trait(?Send)]
+impl<E> Db for DbAdapter<E>
+where
+    E: DbErrorIntrospect + Into<DbError> + 'static,
+{
+    async fn lock_for_read(&mut self, params: params::LockCollection) -> Result<(), Self::Error> {
+        self.0.lock_for_read(params).await.map_err(Into::into)
+    }
+
+    async fn lock_for_write(&mut self, params: params::LockCollection) -> Result<(), Self::Error> {
+        self.0.lock_for_write(params).await.map_err(Into::into)
+    }
+
+    async fn begin(&mut self, for_write: bool) -> Result<(), Self::Error> {
+        self.0.begin(for_write).await.map_err(Into::into)
+    }
+
+    async fn commit(&mut self) -> Result<(), Self::Error> {
+        self.0.commit().await.map_err(Into::into)
+    }
+
+    async fn rollback(&mut self) -> Result<(), Self::Error> {
+        self.0.rollback().await.map_err(Into::into)
+    }
+
+    async fn get_collection_timestamps(
+        &mut self,
+        params: params::GetCollectionTimestamps,
+    ) -> Result<results::GetCollectionTimestamps, Self::Error> {
+        self.0
+            .get_collection_timestamps(params)
+            .await
+            .map_err(Into::into)
+    }
+
+    async fn get_collection_timestamp(
+        &mut self,
+        params: params::GetCollectionTimestamp,
+    ) -> Result<results::GetCollectionTimestamp, Self::Error> {
+        self.0
+            .get_collection_timestamp(params)
+            .await
+            .map_err(Into::into)
+    }
+
+    async fn get_collection_counts(
+        &mut self,
+        params: params::GetCollectionCounts,
+    ) -> Result<results::GetCollectionCounts, Self::Error> {
+        self.0
+            .get_collection_counts(params)
+            .await
+            .map_err(Into::into)
+    }
+
+    async fn get_collection_usage(
+        &mut self,
+        params: params::GetCollectionUsage,
+    ) -> Result<results::GetCollectionUsage, Self::Error> {
+        self.0
+            .get_collection_usage(params)
+            .await
+            .map_err(Into::into)
+    }
+
+    async fn get_storage_timestamp(
+        &mut self,
+        params: params::GetStorageTimestamp,
+    ) -> Result<results::GetStorageTimestamp, Self::Error> {
+        self.0
+            .get_storage_timestamp(params)
+            .await
+            .map_err(Into::into)
+    }
+
+    async fn get_storage_usage(
+        &mut self,
+        params: params::GetStorageUsage,
+    ) -> Result<results::GetStorageUsage, Self::Error> {
+        self.0.get_storage_usage(params).await.map_err(Into::into)
+    }
+
+    async fn get_quota_usage(
+        &mut self,
+        params: params::GetQuotaUsage,
+    ) -> Result<results::GetQuotaUsage, Self::Error> {
+        self.0.get_quota_usage(params).await.map_err(Into::into)
+    }
+
+    async fn delete_storage(
+        &mut self,
+        params: params::DeleteStorage,
+    ) -> Result<results::DeleteStorage, Self::Error> {
+        self.0.delete_storage(params).await.map_err(Into::into)
+    }
+
+    async fn delete_collection(
+        &mut self,
+        params: params::DeleteCollection,
+    ) -> Result<results::DeleteCollection, Self::Error> {
+        self.0.delete_collection(params).await.map_err(Into::into)
+    }
+
+    async fn delete_bsos(
+        &mut self,
+        params: params::DeleteBsos,
+    ) -> Result<results::DeleteBsos, Self::Error> {
+        self.0.delete_bsos(params).await.map_err(Into::into)
+    }
+
+    async fn get_bsos(&mut self, params: params::GetBsos) -> Result<results::GetBsos, Self::Error> {
+        self.0.get_bsos(params).await.map_err(Into::into)
+    }
+
+    async fn get_bso_ids(
+        &mut self,
+        params: params::GetBsos,
+    ) -> Result<results::GetBsoIds, Self::Error> {
+        self.0.get_bso_ids(params).await.map_err(Into::into)
+    }
+
+    async fn get_bsos_stream(
+        &mut self,
+        params: params::GetBsos,
+    ) -> Result<BsoStream<'_, Self::Error>, Self::Error> {
+        let bsos = self.0.get_bsos_stream(params).await.map_err(Into::into)?;
+        Ok(bsos.map_err(Into::into).boxed_local())
+    }
+
+    async fn post_bsos(&mut self, params: params::PostBsos) -> Result<SyncTimestamp, Self::Error> {
+        self.0.post_bsos(params).await.map_err(Into::into)
+    }
+
+    async fn delete_bso(
+        &mut self,
+        params: params::DeleteBso,
+    ) -> Result<results::DeleteBso, Self::Error> {
+        self.0.delete_bso(params).await.map_err(Into::into)
+    }
+
+    async fn get_bso(
+        &mut self,
+        params: params::GetBso,
+    ) -> Result<Option<results::GetBso>, Self::Error> {
+        self.0.get_bso(params).await.map_err(Into::into)
+    }
+
+    async fn get_bso_timestamp(
+        &mut self,
+        params: params::GetBsoTimestamp,
+    ) -> Result<results::GetBsoTimestamp, Self::Error> {
+        self.0.get_bso_timestamp(params).await.map_err(Into::into)
+    }
+
+    async fn put_bso(&mut self, params: params::PutBso) -> Result<results::PutBso, Self::Error> {
+        self.0.put_bso(params).await.map_err(Into::into)
+    }
+
+    async fn check(&mut self) -> Result<results::Check, Self::Error> {
+        self.0.check().await.map_err(Into::into)
+    }
+
+    fn get_connection_info(&self) -> results::ConnectionInfo {
+        self.0.get_connection_info()
+    }
+
+    async fn get_user_ids(
+        &mut self,
+        params: params::GetUserIds,
+    ) -> Result<results::GetUserIds, Self::Error> {
+        self.0.get_user_ids(params).await.map_err(Into::into)
+    }
+
+    async fn import_bsos(
+        &mut self,
+        params: params::ImportBsos,
+    ) -> Result<results::ImportBsos, Self::Error> {
+        self.0.import_bsos(params).await.map_err(Into::into)
+    }
+
+    async fn purge_expired(
+        &mut self,
+        params: params::PurgeExpired,
+    ) -> Result<results::PurgeExpired, Self::Error> {
+        self.0.purge_expired(params).await.map_err(Into::into)
+    }
+
+    #[cfg(debug_assertions)]
+    async fn get_collection_id(&mut self, name: &str) -> Result<i32, Self::Error> {
+        self.0.get_collection_id(name).await.map_err(Into::into)
+    }
+
+    #[cfg(debug_assertions)]
+    async fn create_collection(&mut self, name: &str) -> Result<i32, Self::Error> {
+        self.0.create_collection(name).await.map_err(Into::into)
+    }
+
+    async fn update_collection(
+        &mut self,
+        params: params::UpdateCollection,
+    ) -> Result<SyncTimestamp, Self::Error> {
+        self.0.update_collection(params).await.map_err(Into::into)
+    }
+
+    #[cfg(debug_assertions)]
+    fn timestamp(&self) -> SyncTimestamp {
+        self.0.timestamp()
+    }
+
+    #[cfg(debug_assertions)]
+    fn set_timestamp(&mut self, timestamp: SyncTimestamp) {
+        self.0.set_timestamp(timestamp)
+    }
+
+    #[cfg(debug_assertions)]
+    async fn clear_coll_cache(&mut self) -> Result<(), Self::Error> {
+        self.0.clear_coll_cache().await.map_err(Into::into)
+    }
+
+    #[cfg(debug_assertions)]
+    fn set_quota(&mut self, enabled: bool, limit: usize, enforce: bool) {
+        self.0.set_quota(enabled, limit, enforce)
+    }
+}
+
+#[async_trait(?Send)]
+impl<E> BatchDb for DbAdapter<E>
+where
+    E: DbErrorIntrospect + Into<DbError> + 'static,
+{
+    type Error = DbError;
+
+    async fn create_batch(
+        &mut self,
+        params: params::CreateBatch,
+    ) -> Result<results::CreateBatch, Self::Error> {
+        self.0.create_batch(params).await.map_err(Into::into)
+    }
+
+    async fn validate_batch(
+        &mut self,
+        params: params::ValidateBatch,
+    ) -> Result<results::ValidateBatch, Self::Error> {
+        self.0.validate_batch(params).await.map_err(Into::into)
+    }
+
+    async fn append_to_batch(
+        &mut self,
+        params: params::AppendToBatch,
+    ) -> Result<results::AppendToBatch, Self::Error> {
+        self.0.append_to_batch(params).await.map_err(Into::into)
+    }
+
+    async fn get_batch(
+        &mut self,
+        params: params::GetBatch,
+    ) -> Result<Option<results::GetBatch>, Self::Error> {
+        self.0.get_batch(params).await.map_err(Into::into)
+    }
+
+    async fn commit_batch(
+        &mut self,
+        params: params::CommitBatch,
+    ) -> Result<results::CommitBatch, Self::Error> {
+        self.0.commit_batch(params).await.map_err(Into::into)
+    }
+
+    async fn delete_batch(&mut self, params: params::DeleteBatch) -> Result<(), Self::Error> {
+        self.0.delete_batch(params).await.map_err(Into::into)
+    }
+}
@@ -0,0 +1,390 @@
+//! Portable per-user archives of Sync data.
+//!
+//! An archive is JSON lines: a [ArchiveRecord::Header], then each collection
+//! as a [ArchiveRecord::Collection] followed by its [ArchiveRecord::Bso]s.
+//! Payloads are exported as stored, i.e. still encrypted by the client.
+use std::{
+    io::{BufRead, Write},
+    mem,
+    pin::pin,
+};
+
+use futures::{Sink, SinkExt, sink};
+use serde::{Deserialize, Serialize};
+use syncstorage_db_common::{
+    Db, DbPool, Sorting, UserIdentifier, params, results, util::SyncTimestamp,
+};
+
+use crate::DbError;
+
+/// The current archive format version
+pub const ARCHIVE_VERSION: u32 = 1;
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ArchiveRecord {
+    Header {
+        version: u32,
+        /// When the archive was written, in milliseconds. BSO ttls are
+        /// relative to this.
+        exported_at: i64,
+    },
+    Collection {
+        name: String,
+        /// In milliseconds
+        modified: i64,
+    },
+    Bso {
+        collection: String,
+        id: String,
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        sortindex: Option<i32>,
+        payload: String,
+        /// In milliseconds
+        modified: i64,
+        /// Remaining time to live, in seconds
+        ttl: u32,
+    },
+}
+
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct ArchiveSummary {
+    pub collections: u64,
+    pub bsos: u64,
+}
+
+fn io_error(e: impl std::fmt::Display) -> DbError {
+    DbError::internal(format!("Archive error: {e}"))
+}
+
+fn write_record(out: &mut impl Write, record: &ArchiveRecord) -> Result<(), DbError> {
+    serde_json::to_writer(&mut *out, record).map_err(io_error)?;
+    out.write_all(b"\n").map_err(io_error)
+}
+
+/// Write all of a user's collections and BSOs to `out`, reading
+/// `batch_size` BSOs at a time
+pub async fn export_user(
+    pool: &dyn DbPool<Error = DbError>,
+    user_id: &UserIdentifier,
+    batch_size: u32,
+    out: &mut impl Write,
+) -> Result<ArchiveSummary, DbError> {
+    let sink = sink::unfold(out, |out, chunk: Vec<u8>| async move {
+        out.write_all(&chunk).map_err(io_error)?;
+        Ok::<_, DbError>(out)
+    });
+    export_user_to_sink(pool, user_id, batch_size, pin!(sink)).await
+}
+
+/// Send all of a user's collections and BSOs to `out` as chunks of archive
+/// lines, one per page of `batch_size` BSOs.
+///
+/// The whole export is read in one (read only) transaction, for a consistent
+/// archive of a user still syncing.
+pub async fn export_user_to_sink(
+    pool: &dyn DbPool<Error = DbError>,
+    user_id: &UserIdentifier,
+    batch_size: u32,
+    mut out: impl Sink<Vec<u8>, Error = DbError> + Unpin,
+) -> Result<ArchiveSummary, DbError> {
+    let mut db = pool.get().await?;
+    db.begin(false).await?;
+    match export_pages(&mut *db, user_id, batch_size, &mut out).await {
+        Ok(summary) => {
+            db.commit().await?;
+            Ok(summary)
+        }
+        Err(e) => {
+            db.rollback().await?;
+            Err(e)
+        }
+    }
+}
+
+async fn export_pages(
+    db: &mut dyn Db<Error = DbError>,
+    user_id: &UserIdentifier,
+    batch_size: u32,
+    out: &mut (impl Sink<Vec<u8>, Error = DbError> + Unpin),
+) -> Result<ArchiveSummary, DbError> {
+    let exported_at = SyncTimestamp::default().as_i64();
+    let mut chunk = vec![];
+    write_record(
+        &mut chunk,
+        &ArchiveRecord::Header {
+            version: ARCHIVE_VERSION,
+            exported_at,
+        },
+    )?;
+
+    let mut summary = ArchiveSummary::default();
+    let timestamps = db.get_collection_timestamps(user_id.clone()).await?;
+    for (collection, modified) in timestamps {
+        write_record(
+            &mut chunk,
+            &ArchiveRecord::Collection {
+                name: collection.clone(),
+                modified: modified.as_i64(),
+            },
+        )?;
+        summary.collections += 1;
+
+        let mut offset = None;
+        loop {
+            let page = db
+                .get_bsos(params::GetBsos {
+                    user_id: user_id.clone(),
+                    collection: collection.clone(),
+                    newer: None,
+                    older: None,
+                    sort: Sorting::Oldest,
+                    limit: Some(batch_size),
+                    offset,
+                    ids: vec![],
+                    full: true,
+                })
+                .await?;
+
+            for bso in page.items {
+                let ttl = (bso.expiry - exported_at).max(0) / 1000;
+                write_record(
+                    &mut chunk,
+                    &ArchiveRecord::Bso {
+                        collection: collection.clone(),
+                        id: bso.id,
+                        sortindex: bso.sortindex,
+                        payload: bso.payload,
+                        modified: bso.modified.as_i64(),
+                        ttl: u32::try_from(ttl).unwrap_or(u32::MAX),
+                    },
+                )?;
+                summary.bsos += 1;
+            }
+            out.send(mem::take(&mut chunk)).await?;
+            match page.offset {
+                Some(next) => {
+                    offset =
+                        Some(next.parse().map_err(|e| {
+                            DbError::internal(format!("Invalid offset {next:?}: {e}"))
+                        })?);
+                }
+                None => break,
+            }
+        }
+    }
+    if !chunk.is_empty() {
+        out.send(chunk).await?;
+    }
+    Ok(summary)
+}
+
+/// A collection's records read from an archive, pending write
+struct PendingCollection {
+    name: String,
+    modified: i64,
+    bsos: Vec<results::GetBso>,
+    /// Whether any of the collection has been written yet
+    written: bool,
+    /// Without `preserve_timestamps`, the transaction the whole collection is
+    /// posted in: posting successive pages in their own transactions would
+    /// conflict on the collection's timestamp
+    db: Option<Box<dyn Db<Error = DbError>>>,
+}
+
+/// Read an archive written by [export_user] into a user's storage, writing
+/// `batch_size` BSOs at a time.
+///
+/// With `preserve_timestamps` the BSOs keep their `modified` and expiry, and
+/// the collections their timestamps (via `Db::import_bsos`). Otherwise each
+/// collection is written as a new change via `Db::post_bsos`, which clients
+/// will download again.
+pub async fn import_user(
+    pool: &dyn DbPool<Error = DbError>,
+    user_id: &UserIdentifier,
+    batch_size: u32,
+    preserve_timestamps: bool,
+    input: impl BufRead,
+) -> Result<ArchiveSummary, DbError> {
+    let mut lines = input.lines();
+    let exported_at = match lines.next() {
+        Some(line) => match serde_json::from_str(&line.map_err(io_error)?).map_err(io_error)? {
+            ArchiveRecord::Header {
+                version: ARCHIVE_VERSION,
+                exported_at,
+            } => exported_at,
+            ArchiveRecord::Header { version, .. } => {
+                return Err(io_error(format!("unsupported version {version}")));
+            }
+            _ => return Err(io_error("missing header")),
+        },
+        None => return Err(io_error("empty archive")),
+    };
+
+    let importer = Importer {
+        pool,
+        user_id,
+        preserve_timestamps,
+        now: SyncTimestamp::default().as_i64(),
+    };
+    let mut summary = ArchiveSummary::default();
+    let mut pending: Option<PendingCollection> = None;
+    for line in lines {
+        let line = line.map_err(io_error)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line).map_err(io_error)? {
+            ArchiveRecord::Header { .. } => return Err(io_error("unexpected header")),
+            ArchiveRecord::Collection { name, modified } => {
+                if let Some(collection) = pending.take() {
+                    importer.flush(collection, &mut summary).await?;
+                }
+                summary.collections += 1;
+                pending = Some(PendingCollection {
+                    name,
+                    modified,
+                    bsos: vec![],
+                    written: false,
+                    db: None,
+                });
+            }
+            ArchiveRecord::Bso {
+                collection,
+                id,
+                sortindex,
+                payload,
+                modified,
+                ttl,
+            } => {
+                let Some(current) = pending.as_mut().filter(|c| c.name == collection) else {
+                    return Err(io_error(format!(
+                        "bso {id:?} outside of its collection {collection:?}"
+                    )));
+                };
+                current.bsos.push(results::GetBso {
+                    id,
+                    modified: SyncTimestamp::from_i64(modified)?,
+                    payload,
+                    sortindex,
+                    expiry: exported_at.saturating_add(i64::from(ttl) * 1000),
+                });
+                if current.bsos.len() >= batch_size as usize {
+                    importer.write(current, &mut summary).await?;
+                }
+            }
+        }
+    }
+    if let Some(collection) = pending {
+        importer.flush(collection, &mut summary).await?;
+    }
+    Ok(summary)
+}
+
+struct Importer<'a> {
+    pool: &'a dyn DbPool<Error = DbError>,
+    user_id: &'a UserIdentifier,
+    preserve_timestamps: bool,
+    now: i64,
+}
+
+impl Importer<'_> {
+    /// Write the rest of a collection
+    async fn flush(
+        &self,
+        mut collection: PendingCollection,
+        summary: &mut ArchiveSummary,
+    ) -> Result<(), DbError> {
+        // An empty collection is still imported when preserving timestamps, to
+        // carry over its timestamp
+        if !collection.bsos.is_empty() || (self.preserve_timestamps && !collection.written) {
+            self.write(&mut collection, summary).await?;
+        }
+        if let Some(mut db) = collection.db {
+            db.commit().await?;
+        }
+        Ok(())
+    }
+
+    /// Write (and clear) a collection's pending BSOs
+    async fn write(
+        &self,
+        collection: &mut PendingCollection,
+        summary: &mut ArchiveSummary,
+    ) -> Result<(), DbError> {
+        let bsos: Vec<_> = collection
+            .bsos
+            .drain(..)
+            .filter(|bso| bso.expiry > self.now)
+            .collect();
+        summary.bsos += bsos.len() as u64;
+        collection.written = true;
+
+        if self.preserve_timestamps {
+            let mut db = self.pool.get().await?;
+            db.begin(true).await?;
+            let result = db
+                .import_bsos(params::ImportBsos {
+                    user_id: self.user_id.clone(),
+                    collection: collection.name.clone(),
+                    bsos,
+                    modified: SyncTimestamp::from_i64(collection.modified)?,
+                })
+                .await;
+            return finish(db, result).await;
+        }
+
+        let db = match &mut collection.db {
+            Some(db) => db,
+            None => {
+                let mut db = self.pool.get().await?;
+                db.lock_for_write(params::LockCollection {
+                    user_id: self.user_id.clone(),
+                    collection: collection.name.clone(),
+                })
+                .await?;
+                collection.db.insert(db)
+            }
+        };
+        let bsos = bsos
+            .into_iter()
+            .map(|bso| params::PostCollectionBso {
+                id: bso.id,
+                sortindex: bso.sortindex,
+                payload: Some(bso.payload),
+                ttl: u32::try_from((bso.expiry - self.now) / 1000).ok(),
+            })
+            .collect();
+        let result = db
+            .post_bsos(params::PostBsos {
+                user_id: self.user_id.clone(),
+                collection: collection.name.clone(),
+                bsos,
+                for_batch: false,
+            })
+            .await;
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                if let Some(mut db) = collection.db.take() {
+                    db.rollback().await?;
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Commit a write's transaction, or roll it back on error
+async fn finish<T>(
+    mut db: Box<dyn Db<Error = DbError>>,
+    result: Result<T, DbError>,
+) -> Result<(), DbError> {
+    match result {
+        Ok(_) => db.commit().await,
+        Err(e) => {
+            db.rollback().await?;
+            Err(e)
+        }
+    }
+}
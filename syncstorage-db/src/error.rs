@@ -0,0 +1,170 @@
+use std::fmt;
+
+use backtrace::Backtrace;
+use http::StatusCode;
+use syncserver_common::{InternalError, ReportableError, from_error, impl_fmt_display};
+use syncstorage_db_common::error::{DbErrorIntrospect, SyncstorageDbError};
+use thiserror::Error;
+
+/// An error from any of the compiled in syncstorage database backends.
+///
+/// The backend is chosen at runtime (see [crate::pool_from_settings]), so this
+/// wraps each backend's own error type.
+#[derive(Debug)]
+pub struct DbError {
+    kind: DbErrorKind,
+    pub status: StatusCode,
+    pub backtrace: Box<Backtrace>,
+}
+
+impl DbError {
+    pub fn batch_not_found() -> Self {
+        DbErrorKind::Common(SyncstorageDbError::batch_not_found()).into()
+    }
+
+    pub fn bso_not_found() -> Self {
+        DbErrorKind::Common(SyncstorageDbError::bso_not_found()).into()
+    }
+
+    pub fn collection_not_found() -> Self {
+        DbErrorKind::Common(SyncstorageDbError::collection_not_found()).into()
+    }
+
+    pub fn conflict() -> Self {
+        DbErrorKind::Common(SyncstorageDbError::conflict()).into()
+    }
+
+    pub fn internal(msg: String) -> Self {
+        DbErrorKind::Common(SyncstorageDbError::internal(msg)).into()
+    }
+
+    pub fn quota() -> Self {
+        DbErrorKind::Common(SyncstorageDbError::quota()).into()
+    }
+
+    /// The backend error wrapped by this error
+    fn inner(&self) -> &(dyn ReportableError + 'static) {
+        match &self.kind {
+            DbErrorKind::Common(e) => e,
+            DbErrorKind::Diesel(e) => e,
+            #[cfg(feature = "spanner")]
+            DbErrorKind::Spanner(e) => e,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+enum DbErrorKind {
+    #[error("{}", _0)]
+    Common(SyncstorageDbError),
+
+    /// The MySQL, Postgres and SQLite backends share diesel's error type
+    #[error("{}", _0)]
+    Diesel(syncstorage_db_common::diesel::DbError),
+
+    #[cfg(feature = "spanner")]
+    #[error("{}", _0)]
+    Spanner(syncstorage_spanner::DbError),
+}
+
+impl From<DbErrorKind> for DbError {
+    fn from(kind: DbErrorKind) -> Self {
+        let (status, backtrace) = match &kind {
+            DbErrorKind::Common(e) => (e.status, Box::new(e.backtrace.clone())),
+            DbErrorKind::Diesel(e) => (e.status, e.backtrace.clone()),
+            #[cfg(feature = "spanner")]
+            DbErrorKind::Spanner(e) => (e.status, e.backtrace.clone()),
+        };
+        Self {
+            kind,
+            status,
+            backtrace,
+        }
+    }
+}
+
+impl DbErrorIntrospect for DbError {
+    fn is_batch_not_found(&self) -> bool {
+        match &self.kind {
+            DbErrorKind::Common(e) => e.is_batch_not_found(),
+            DbErrorKind::Diesel(e) => e.is_batch_not_found(),
+            #[cfg(feature = "spanner")]
+            DbErrorKind::Spanner(e) => e.is_batch_not_found(),
+        }
+    }
+
+    fn is_bso_not_found(&self) -> bool {
+        match &self.kind {
+            DbErrorKind::Common(e) => e.is_bso_not_found(),
+            DbErrorKind::Diesel(e) => e.is_bso_not_found(),
+            #[cfg(feature = "spanner")]
+            DbErrorKind::Spanner(e) => e.is_bso_not_found(),
+        }
+    }
+
+    fn is_collection_not_found(&self) -> bool {
+        match &self.kind {
+            DbErrorKind::Common(e) => e.is_collection_not_found(),
+            DbErrorKind::Diesel(e) => e.is_collection_not_found(),
+            #[cfg(feature = "spanner")]
+            DbErrorKind::Spanner(e) => e.is_collection_not_found(),
+        }
+    }
+
+    fn is_conflict(&self) -> bool {
+        match &self.kind {
+            DbErrorKind::Common(e) => e.is_conflict(),
+            DbErrorKind::Diesel(e) => e.is_conflict(),
+            #[cfg(feature = "spanner")]
+            DbErrorKind::Spanner(e) => e.is_conflict(),
+        }
+    }
+
+    fn is_quota(&self) -> bool {
+        match &self.kind {
+            DbErrorKind::Common(e) => e.is_quota(),
+            DbErrorKind::Diesel(e) => e.is_quota(),
+            #[cfg(feature = "spanner")]
+            DbErrorKind::Spanner(e) => e.is_quota(),
+        }
+    }
+}
+
+impl ReportableError for DbError {
+    fn reportable_source(&self) -> Option<&(dyn ReportableError + 'static)> {
+        Some(self.inner())
+    }
+
+    fn is_sentry_event(&self) -> bool {
+        self.inner().is_sentry_event()
+    }
+
+    fn metric_label(&self) -> Option<&str> {
+        self.inner().metric_label()
+    }
+
+    fn backtrace(&self) -> Option<&Backtrace> {
+        self.inner().backtrace()
+    }
+
+    fn tags(&self) -> Vec<(&str, String)> {
+        self.inner().tags()
+    }
+}
+
+impl InternalError for DbError {
+    fn internal_error(message: String) -> Self {
+        DbErrorKind::Common(SyncstorageDbError::internal(message)).into()
+    }
+}
+
+impl_fmt_display!(DbError, DbErrorKind);
+
+from_error!(
+    syncstorage_db_common::diesel::DbError,
+    DbError,
+    DbErrorKind::Diesel
+);
+#[cfg(feature = "spanner")]
+from_error!(syncstorage_spanner::DbError, DbError, DbErrorKind::Spanner);
+from_error!(SyncstorageDbError, DbError, DbErrorKind::Common);
@@ -4,49 +4,77 @@
 #[macro_use]
 extern crate slog_scope;
 
+mod adapter;
+pub mod archive;
+mod error;
 pub mod mock;
 #[cfg(test)]
 mod tests;
 
-#[cfg(feature = "mysql")]
-pub type DbPoolImpl = syncstorage_mysql::MysqlDbPool;
-#[cfg(feature = "mysql")]
-pub use syncstorage_mysql::DbError;
-#[cfg(feature = "mysql")]
-pub type DbImpl = syncstorage_mysql::MysqlDb;
-
-#[cfg(feature = "postgres")]
-pub type DbPoolImpl = syncstorage_postgres::PgDbPool;
-#[cfg(feature = "postgres")]
-pub use syncstorage_postgres::DbError;
-#[cfg(feature = "postgres")]
-pub type DbImpl = syncstorage_postgres::PgDb;
-
-#[cfg(feature = "spanner")]
-pub type DbPoolImpl = syncstorage_spanner::SpannerDbPool;
-#[cfg(feature = "spanner")]
-pub use syncstorage_spanner::DbError;
-#[cfg(feature = "spanner")]
-pub type DbImpl = syncstorage_spanner::SpannerDb;
+use std::sync::Arc;
+
+use syncserver_common::{BlockingThreadpool, Metrics};
+use syncstorage_settings::Settings;
+use url::Url;
+
+use adapter::DbPoolAdapter;
+pub use error::DbError;
 
 pub use syncserver_db_common::GetPoolStatus;
 pub use syncstorage_db_common::error::DbErrorIntrospect;
 
 pub use syncstorage_db_common::{
-    Db, DbPool, Sorting, UserIdentifier, params, results,
+    BsoStream, Db, DbPool, Sorting, UserIdentifier, params, results,
     util::{SyncTimestamp, to_rfc3339},
 };
 
-#[cfg(all(feature = "mysql", feature = "spanner"))]
-compile_error!("only one of the \"mysql\" and \"spanner\" features can be enabled at a time");
-
-#[cfg(all(feature = "mysql", feature = "postgres"))]
-compile_error!("only one of the \"mysql\" and \"postgres\" features can be enabled at a time");
-
-#[cfg(all(feature = "postgres", feature = "spanner"))]
-compile_error!("only one of the \"postgres\" and \"spanner\" features can be enabled at a time");
+/// Build the storage pool for the backend named by the `database_url`'s scheme
+pub fn pool_from_settings(
+    settings: &Settings,
+    metrics: &Metrics,
+    blocking_threadpool: Arc<BlockingThreadpool>,
+) -> Result<Box<dyn DbPool<Error = DbError>>, DbError> {
+    let url = Url::parse(&settings.database_url)
+        .map_err(|e| DbError::internal(format!("Invalid SYNC_SYNCSTORAGE__DATABASE_URL: {e}")))?;
+    Ok(match url.scheme() {
+        #[cfg(feature = "mysql")]
+        "mysql" => DbPoolAdapter::boxed(syncstorage_mysql::MysqlDbPool::new(
+            settings,
+            metrics,
+            blocking_threadpool,
+        )?),
+        #[cfg(feature = "postgres")]
+        "postgres" => DbPoolAdapter::boxed(syncstorage_postgres::PgDbPool::new(
+            settings,
+            metrics,
+            blocking_threadpool,
+        )?),
+        #[cfg(feature = "spanner")]
+        "spanner" => DbPoolAdapter::boxed(syncstorage_spanner::SpannerDbPool::new(
+            settings,
+            metrics,
+            blocking_threadpool,
+        )?),
+        #[cfg(feature = "sqlite")]
+        "sqlite" => DbPoolAdapter::boxed(syncstorage_sqlite::SqliteDbPool::new(
+            settings,
+            metrics,
+            blocking_threadpool,
+        )?),
+        invalid_scheme => {
+            return Err(DbError::internal(format!(
+                "Invalid SYNC_SYNCSTORAGE__DATABASE_URL scheme: {invalid_scheme}://"
+            )));
+        }
+    })
+}
 
-#[cfg(not(any(feature = "mysql", feature = "postgres", feature = "spanner")))]
+#[cfg(not(any(
+    feature = "mysql",
+    feature = "postgres",
+    feature = "spanner",
+    feature = "sqlite"
+)))]
 compile_error!(
-    "exactly one of the \"mysql\", \"postgres\" and \"spanner\" features must be enabled"
+    "at least one of the \"mysql\", \"postgres\", \"spanner\" or \"sqlite\" features must be enabled"
 );
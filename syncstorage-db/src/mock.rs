@@ -206,6 +206,27 @@ impl Db for MockDb {
         Default::default()
     }
 
+    async fn get_user_ids(
+        &mut self,
+        _params: params::GetUserIds,
+    ) -> Result<results::GetUserIds, Self::Error> {
+        Ok(Default::default())
+    }
+
+    async fn import_bsos(
+        &mut self,
+        _params: params::ImportBsos,
+    ) -> Result<results::ImportBsos, Self::Error> {
+        Ok(Default::default())
+    }
+
+    async fn purge_expired(
+        &mut self,
+        _params: params::PurgeExpired,
+    ) -> Result<results::PurgeExpired, Self::Error> {
+        Ok(Default::default())
+    }
+
     #[cfg(debug_assertions)]
     async fn get_collection_id(
         &mut self,
@@ -92,6 +92,34 @@ async fn expiry() -> Result<(), DbError> {
     .await
 }
 
+#[tokio::test]
+async fn purge_expired() -> Result<(), DbError> {
+    with_test_transaction(None, async |db: &mut dyn Db<Error = DbError>| {
+        let uid = 1;
+        let coll = "clients";
+        let delta = -(BATCH_LIFETIME + 11);
+        let bsos = vec![postbso("b0", Some("payload 0"), Some(10), None)];
+        let expired = with_delta!(db, delta, { db.create_batch(cb(uid, coll, bsos)).await })?;
+        let live = db.create_batch(cb(uid, coll, vec![])).await?;
+        let batch = with_delta!(db, delta, {
+            db.get_batch(gb(uid, coll, expired.id.clone())).await
+        })?;
+        assert!(batch.is_some());
+
+        let purged = db
+            .purge_expired(params::PurgeExpired { limit: 1000 })
+            .await?;
+        assert!(purged.batches >= 1);
+        let batch = with_delta!(db, delta, {
+            db.get_batch(gb(uid, coll, expired.id.clone())).await
+        })?;
+        assert!(batch.is_none());
+        assert!(db.get_batch(gb(uid, coll, live.id)).await?.is_some());
+        Ok(())
+    })
+    .await
+}
+
 #[tokio::test]
 async fn update() -> Result<(), DbError> {
     with_test_transaction(None, async |db: &mut dyn Db<Error = DbError>| {
@@ -160,6 +188,38 @@ async fn append_commit() -> Result<(), DbError> {
     .await
 }
 
+#[tokio::test]
+async fn running_totals() -> Result<(), DbError> {
+    with_test_transaction(None, async |db: &mut dyn Db<Error = DbError>| {
+        let uid = 1;
+        let coll = "clients";
+        let bsos1 = vec![
+            postbso("b0", Some("payload 0"), Some(10), None),
+            postbso("b1", None, Some(1), None),
+        ];
+        let new_batch = db.create_batch(cb(uid, coll, bsos1)).await?;
+        let batch = db
+            .get_batch(gb(uid, coll, new_batch.id.clone()))
+            .await?
+            .unwrap();
+        assert_eq!((batch.total_records, batch.total_bytes), (2, 9));
+
+        // Re-sent records count towards the totals again
+        let bsos2 = vec![
+            postbso("b0", Some("payload 00"), None, None),
+            postbso("b2", Some("payload 2"), None, None),
+        ];
+        db.append_to_batch(ab(uid, coll, new_batch.clone(), bsos2))
+            .await?;
+        db.append_to_batch(ab(uid, coll, new_batch.clone(), vec![]))
+            .await?;
+        let batch = db.get_batch(gb(uid, coll, new_batch.id)).await?.unwrap();
+        assert_eq!((batch.total_records, batch.total_bytes), (4, 28));
+        Ok(())
+    })
+    .await
+}
+
 #[tokio::test]
 async fn quota_test_create_batch() -> Result<(), DbError> {
     let mut settings = Settings::test_settings().syncstorage;
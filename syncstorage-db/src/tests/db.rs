@@ -1,4 +1,5 @@
 #![allow(clippy::cognitive_complexity)]
+use futures::TryStreamExt;
 use lazy_static::lazy_static;
 use rand::rng;
 use rand::{RngExt, distr::Alphanumeric};
@@ -229,6 +230,51 @@ async fn get_bsos_limit_offset() -> Result<(), DbError> {
     .await
 }
 
+#[tokio::test]
+async fn get_bsos_stream_matches_get_bsos() -> Result<(), DbError> {
+    with_test_transaction(None, async |db: &mut dyn Db<Error = DbError>| {
+        let uid = *UID;
+        let coll = "clients";
+        for i in 0..7 {
+            let bso = pbso(
+                uid,
+                coll,
+                &i.to_string(),
+                Some(&format!("payload-{}", i)),
+                Some(i % 3),
+                Some(DEFAULT_BSO_TTL),
+            );
+            with_delta!(db, i64::from(i) * 10, { db.put_bso(bso).await })?;
+        }
+
+        for sort in [Sorting::Index, Sorting::Newest, Sorting::Oldest] {
+            let mut offset = "0".to_owned();
+            loop {
+                let params = gbsos(uid, coll, &[], MAX_TIMESTAMP, 0, sort, 3, &offset);
+                let streamed: Vec<_> = db
+                    .get_bsos_stream(params.clone())
+                    .await?
+                    .map_ok(|bso| (bso.id, bso.payload))
+                    .try_collect()
+                    .await?;
+                let page = db.get_bsos(params).await?;
+                let expected: Vec<_> = page
+                    .items
+                    .into_iter()
+                    .map(|bso| (bso.id, bso.payload))
+                    .collect();
+                assert_eq!(streamed, expected);
+                match page.offset {
+                    Some(next) => offset = next,
+                    None => break,
+                }
+            }
+        }
+        Ok(())
+    })
+    .await
+}
+
 #[tokio::test]
 async fn get_bsos_newer() -> Result<(), DbError> {
     with_test_transaction(None, async |db: &mut dyn Db<Error = DbError>| {
@@ -912,9 +958,107 @@ async fn get_bsos() -> Result<(), DbError> {
             ))
             .await?;
         assert_eq!(bsos.items.len(), 2);
-        assert_eq!(bsos.offset, Some("2".to_string()));
         assert_eq!(bsos.items[0].id, "b2");
         assert_eq!(bsos.items[1].id, "b1");
+
+        let offset = bsos.offset.unwrap();
+        assert!(params::Offset::is_cursor(&offset));
+        let bsos = db
+            .get_bsos(gbsos(
+                uid,
+                coll,
+                &[],
+                MAX_TIMESTAMP,
+                0,
+                Sorting::Index,
+                2,
+                &offset,
+            ))
+            .await?;
+        assert_eq!(bsos.items.len(), 2);
+        assert_eq!(bsos.items[0].id, "b3");
+        assert_eq!(bsos.items[1].id, "b0");
+        Ok(())
+    })
+    .await
+}
+
+#[tokio::test]
+async fn get_bsos_cursor() -> Result<(), DbError> {
+    with_test_transaction(None, async |db: &mut dyn Db<Error = DbError>| {
+        let uid = *UID;
+        let coll = "clients";
+        // Every BSO shares a modified timestamp and half lack a sortindex
+        for i in 0..6 {
+            let sortindex = (i % 2 == 0).then_some(i);
+            let bso = pbso(
+                uid,
+                coll,
+                &format!("b{}", i),
+                Some("Hello"),
+                sortindex,
+                None,
+            );
+            db.put_bso(bso).await?;
+        }
+
+        for (sort, expected) in [
+            (Sorting::Newest, ["b5", "b4", "b3", "b2", "b1", "b0"]),
+            (Sorting::Oldest, ["b0", "b1", "b2", "b3", "b4", "b5"]),
+            (Sorting::Index, ["b4", "b2", "b0", "b5", "b3", "b1"]),
+        ] {
+            let mut offset = "0".to_owned();
+            let (mut ids, mut bso_ids) = (vec![], vec![]);
+            loop {
+                let page = db
+                    .get_bso_ids(gbsos(uid, coll, &[], MAX_TIMESTAMP, 0, sort, 4, &offset))
+                    .await?;
+                ids.extend(page.items);
+                let bsos = db
+                    .get_bsos(gbsos(uid, coll, &[], MAX_TIMESTAMP, 0, sort, 4, &offset))
+                    .await?;
+                bso_ids.extend(bsos.items.into_iter().map(|bso| bso.id));
+                assert_eq!(page.offset, bsos.offset);
+                let Some(next) = page.offset else {
+                    break;
+                };
+                assert!(params::Offset::is_cursor(&next));
+                offset = next;
+            }
+            assert_eq!(ids, expected);
+            assert_eq!(bso_ids, expected);
+        }
+
+        // Legacy numeric offsets are still accepted
+        let bsos = db
+            .get_bsos(gbsos(
+                uid,
+                coll,
+                &[],
+                MAX_TIMESTAMP,
+                0,
+                Sorting::Index,
+                2,
+                "2",
+            ))
+            .await?;
+        assert_eq!(bsos.items[0].id, "b0");
+        assert_eq!(bsos.items[1].id, "b5");
+        let bsos = db
+            .get_bsos(gbsos(
+                uid,
+                coll,
+                &[],
+                MAX_TIMESTAMP,
+                0,
+                Sorting::Index,
+                2,
+                &bsos.offset.unwrap(),
+            ))
+            .await?;
+        assert_eq!(bsos.items[0].id, "b3");
+        assert_eq!(bsos.items[1].id, "b1");
+        assert_eq!(bsos.offset, None);
         Ok(())
     })
     .await
@@ -1001,19 +1145,42 @@ async fn usage_stats() -> Result<(), DbError> {
 }
 
 #[tokio::test]
-async fn purge_expired() -> Result<(), DbError> {
+async fn optimize() -> Result<(), DbError> {
     let pool = db_pool(None).await?;
     let mut db = test_db(pool).await?;
     Ok(())
 }
+*/
 
 #[tokio::test]
-async fn optimize() -> Result<(), DbError> {
-    let pool = db_pool(None).await?;
-    let mut db = test_db(pool).await?;
-    Ok(())
+async fn purge_expired() -> Result<(), DbError> {
+    with_test_transaction(None, async |db: &mut dyn Db<Error = DbError>| {
+        let uid = *UID;
+        let coll = "clients";
+        // Expired 10 seconds ago
+        let bso1 = pbso(uid, coll, "expired", Some("hello"), None, Some(10));
+        with_delta!(db, -20_000, { db.put_bso(bso1).await })?;
+        let bso2 = pbso(uid, coll, "live", Some("hello"), None, None);
+        db.put_bso(bso2).await?;
+        let expired = with_delta!(db, -20_000, {
+            db.get_bso(gbso(uid, coll, "expired")).await
+        })?;
+        assert!(expired.is_some());
+
+        let purged = db
+            .purge_expired(params::PurgeExpired { limit: 1000 })
+            .await?;
+        assert!(purged.bsos >= 1);
+        // Still wouldn't be visible even at the time it was live
+        let expired = with_delta!(db, -20_000, {
+            db.get_bso(gbso(uid, coll, "expired")).await
+        })?;
+        assert!(expired.is_none());
+        assert!(db.get_bso(gbso(uid, coll, "live")).await?.is_some());
+        Ok(())
+    })
+    .await
 }
-*/
 
 #[tokio::test]
 async fn delete_storage() -> Result<(), DbError> {
@@ -5,10 +5,12 @@ use syncserver_settings::Settings as SyncserverSettings;
 use syncstorage_db_common::{Db, DbPool, Sorting, UserIdentifier, params, util::SyncTimestamp};
 use syncstorage_settings::Settings as SyncstorageSettings;
 
-use crate::{DbError, DbPoolImpl};
+use crate::{DbError, pool_from_settings};
 
 #[cfg(test)]
-pub async fn db_pool(settings: Option<SyncstorageSettings>) -> Result<DbPoolImpl, DbError> {
+pub async fn db_pool(
+    settings: Option<SyncstorageSettings>,
+) -> Result<Box<dyn DbPool<Error = DbError>>, DbError> {
     let _ = env_logger::try_init();
     // The default for SYNC_SYNCSTORAGE__DATABASE_USE_TEST_TRANSACTIONS is
     // false, but we want the mysql default to be true, so let's check
@@ -23,12 +25,14 @@ pub async fn db_pool(settings: Option<SyncstorageSettings>) -> Result<DbPoolImpl
     settings.database_use_test_transactions = use_test_transactions;
 
     let metrics = Metrics::noop();
-    let mut pool = DbPoolImpl::new(&settings, &metrics, Arc::new(BlockingThreadpool::new(512)))?;
+    let mut pool = pool_from_settings(&settings, &metrics, Arc::new(BlockingThreadpool::new(512)))?;
     pool.init().await?;
     Ok(pool)
 }
 
-pub async fn test_db(pool: DbPoolImpl) -> Result<Box<dyn Db<Error = DbError>>, DbError> {
+pub async fn test_db(
+    pool: Box<dyn DbPool<Error = DbError>>,
+) -> Result<Box<dyn Db<Error = DbError>>, DbError> {
     let mut db = pool.get().await?;
     // Spanner won't have a timestamp until lock_for_xxx are called: fill one
     // in for it
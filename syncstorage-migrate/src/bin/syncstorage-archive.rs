@@ -0,0 +1,120 @@
+//! Export a user's Sync data to an archive, or import one
+#[macro_use]
+extern crate slog_scope;
+
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use docopt::Docopt;
+use serde::Deserialize;
+
+use syncserver_common::BlockingThreadpool;
+use syncserver_settings::Settings;
+use syncstorage_db::{
+    UserIdentifier,
+    archive::{export_user, import_user},
+};
+use syncstorage_migrate::{MigrateError, init_logging, pool};
+
+const USAGE: &str = "
+Usage: syncstorage-archive export [options] (--uid=ID | --fxa-uid=UID --fxa-kid=KID) [<file>]
+       syncstorage-archive import [options] (--uid=ID | --fxa-uid=UID --fxa-kid=KID) [<file>]
+
+Exports a user's collections and BSOs from the configured syncstorage
+database to a JSON lines archive, or imports one. The archive is written to
+(or read from) <file>, or stdout (stdin) when omitted.
+
+Users are identified by --uid (the tokenserver's legacy id) for MySQL,
+Postgres and SQLite, or by --fxa-uid and --fxa-kid for Spanner.
+
+Options:
+    -h, --help               Show this message.
+    --config=CONFIGFILE      Syncstorage configuration file path.
+    --uid=ID                 The user's legacy id.
+    --fxa-uid=UID            The user's FxA uid.
+    --fxa-kid=KID            The user's FxA kid.
+    --batch-size=N           Max number of BSOs read or written at a time [default: 500].
+    --preserve-timestamps    Import BSOs and collections with their original
+                             timestamps, rather than as new changes.
+";
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    cmd_export: bool,
+    cmd_import: bool,
+    arg_file: Option<PathBuf>,
+    flag_config: Option<String>,
+    flag_uid: Option<u64>,
+    flag_fxa_uid: Option<String>,
+    flag_fxa_kid: Option<String>,
+    flag_batch_size: u32,
+    flag_preserve_timestamps: bool,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+    let settings = Settings::with_env_and_config_file(args.flag_config.as_deref())?;
+    init_logging();
+
+    let user_id = UserIdentifier {
+        legacy_id: args.flag_uid.unwrap_or_default(),
+        fxa_uid: args.flag_fxa_uid.clone().unwrap_or_default(),
+        fxa_kid: args.flag_fxa_kid.clone().unwrap_or_default(),
+        ..Default::default()
+    };
+    let blocking_threadpool = Arc::new(BlockingThreadpool::new(
+        settings.worker_max_blocking_threads,
+    ));
+    let pool = pool(
+        &settings,
+        &settings.syncstorage.database_url,
+        blocking_threadpool,
+    )
+    .await?;
+
+    let summary = if args.cmd_export {
+        let mut out: Box<dyn Write> = match &args.arg_file {
+            Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+            None => Box::new(BufWriter::new(io::stdout().lock())),
+        };
+        let summary = export_user(&*pool, &user_id, args.flag_batch_size, &mut out)
+            .await
+            .map_err(MigrateError::from)?;
+        out.flush()?;
+        summary
+    } else {
+        debug_assert!(args.cmd_import);
+        let input: Box<dyn BufRead> = match &args.arg_file {
+            Some(path) => Box::new(BufReader::new(File::open(path)?)),
+            None => Box::new(io::stdin().lock()),
+        };
+        import_user(
+            &*pool,
+            &user_id,
+            args.flag_batch_size,
+            args.flag_preserve_timestamps,
+            input,
+        )
+        .await
+        .map_err(MigrateError::from)?
+    };
+    info!(
+        "{} {} collections, {} bsos",
+        if args.cmd_export {
+            "Exported"
+        } else {
+            "Imported"
+        },
+        summary.collections,
+        summary.bsos
+    );
+    Ok(())
+}
@@ -0,0 +1,424 @@
+//! Offline copy of users' data between syncstorage backends.
+//!
+//! Users are walked in the source backend's user key order. Each user's
+//! collections and BSOs (including their `modified` and `expiry`) are copied
+//! through the `Db` trait, then verified by comparing the collection counts
+//! and timestamps of both sides.
+#[macro_use]
+extern crate slog_scope;
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use slog::{self, Drain};
+use syncserver_common::{BlockingThreadpool, Metrics};
+use syncserver_settings::Settings;
+use syncstorage_db::{
+    DbError, DbPool, Sorting, SyncTimestamp, UserIdentifier, params, pool_from_settings,
+    results::GetCollectionTimestamps,
+};
+use thiserror::Error;
+
+/// How many user ids to fetch from the source at a time
+const USER_PAGE_SIZE: u32 = 100;
+
+#[derive(Debug, Error)]
+pub enum MigrateError {
+    #[error("Database error: {0}")]
+    Db(DbError),
+    #[error("Checkpoint error: {0}")]
+    Checkpoint(io::Error),
+    #[error("Invalid user map: {0}")]
+    UserMap(String),
+    #[error("Verification failed for user {user}: {reason}")]
+    Verification { user: String, reason: String },
+}
+
+impl From<DbError> for MigrateError {
+    fn from(e: DbError) -> Self {
+        MigrateError::Db(e)
+    }
+}
+
+pub type MigrateResult<T> = Result<T, MigrateError>;
+
+/// Render a user for logs, checkpoints and user maps
+fn format_user(user: &UserIdentifier) -> String {
+    format!("{},{},{}", user.legacy_id, user.fxa_uid, user.fxa_kid)
+}
+
+/// Parse a `legacy_id,fxa_uid,fxa_kid` line
+fn parse_user(line: &str) -> Option<UserIdentifier> {
+    let mut fields = line.trim().splitn(3, ',');
+    let legacy_id = fields.next()?.trim().parse().ok()?;
+    let fxa_uid = fields.next()?.trim().to_owned();
+    let fxa_kid = fields.next()?.trim().to_owned();
+    Some(UserIdentifier {
+        legacy_id,
+        fxa_uid,
+        fxa_kid,
+        ..Default::default()
+    })
+}
+
+/// Maps users between backends that key them differently: MySQL, Postgres
+/// and SQLite by `legacy_id`, Spanner by `(fxa_uid, fxa_kid)`.
+///
+/// Read from a CSV file of `legacy_id,fxa_uid,fxa_kid` lines (e.g. exported
+/// from the tokenserver's `users` table).
+#[derive(Debug, Default)]
+pub struct UserMap {
+    by_legacy_id: HashMap<u64, UserIdentifier>,
+    by_fxa: HashMap<(String, String), UserIdentifier>,
+}
+
+impl UserMap {
+    pub fn from_file(path: &Path) -> MigrateResult<Self> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| MigrateError::UserMap(format!("{}: {e}", path.display())))?;
+        Self::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> MigrateResult<Self> {
+        let mut map = Self::default();
+        for (i, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let user = parse_user(line)
+                .ok_or_else(|| MigrateError::UserMap(format!("line {}: {line:?}", i + 1)))?;
+            map.by_fxa
+                .insert((user.fxa_uid.clone(), user.fxa_kid.clone()), user.clone());
+            map.by_legacy_id.insert(user.legacy_id, user);
+        }
+        Ok(map)
+    }
+
+    /// Find the full identifier for a user as returned from a backend
+    fn get(&self, user: &UserIdentifier) -> Option<&UserIdentifier> {
+        if user.fxa_uid.is_empty() {
+            self.by_legacy_id.get(&user.legacy_id)
+        } else {
+            self.by_fxa
+                .get(&(user.fxa_uid.clone(), user.fxa_kid.clone()))
+        }
+    }
+}
+
+/// Records the last fully migrated user so an interrupted run can resume
+/// after it.
+#[derive(Debug)]
+pub struct Checkpoint {
+    path: PathBuf,
+}
+
+impl Checkpoint {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn load(&self) -> MigrateResult<Option<UserIdentifier>> {
+        match fs::read_to_string(&self.path) {
+            Ok(contents) => parse_user(&contents).map(Some).ok_or_else(|| {
+                MigrateError::Checkpoint(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: {contents:?}", self.path.display()),
+                ))
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(MigrateError::Checkpoint(e)),
+        }
+    }
+
+    pub fn save(&self, user: &UserIdentifier) -> MigrateResult<()> {
+        // Write then rename so the checkpoint is never left half written
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, format_user(user)).map_err(MigrateError::Checkpoint)?;
+        fs::rename(&tmp, &self.path).map_err(MigrateError::Checkpoint)
+    }
+}
+
+#[derive(Debug)]
+pub struct Options {
+    /// Max number of BSOs read and written at a time
+    pub batch_size: u32,
+    /// Only read from the source, reporting what would be copied
+    pub dry_run: bool,
+    pub checkpoint: Option<Checkpoint>,
+    pub user_map: Option<UserMap>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            batch_size: 500,
+            dry_run: false,
+            checkpoint: None,
+            user_map: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Summary {
+    pub users: u64,
+    pub skipped_users: u64,
+    pub collections: u64,
+    pub bsos: u64,
+}
+
+pub struct Migrator {
+    source: Box<dyn DbPool<Error = DbError>>,
+    dest: Box<dyn DbPool<Error = DbError>>,
+    options: Options,
+}
+
+impl Migrator {
+    pub fn new(
+        source: Box<dyn DbPool<Error = DbError>>,
+        dest: Box<dyn DbPool<Error = DbError>>,
+        options: Options,
+    ) -> Self {
+        Self {
+            source,
+            dest,
+            options,
+        }
+    }
+
+    /// Migrate every user, resuming after the checkpoint (if any)
+    pub async fn run(&self) -> MigrateResult<Summary> {
+        let mut summary = Summary::default();
+        let mut after = match &self.options.checkpoint {
+            Some(checkpoint) => checkpoint.load()?,
+            None => None,
+        };
+        if let Some(user) = &after {
+            info!("Resuming after user {}", format_user(user));
+        }
+
+        loop {
+            let users = self
+                .source
+                .get()
+                .await?
+                .get_user_ids(params::GetUserIds {
+                    after: after.clone(),
+                    limit: USER_PAGE_SIZE,
+                })
+                .await?;
+            if users.is_empty() {
+                break;
+            }
+            for user in users {
+                self.migrate_user(&user, &mut summary).await?;
+                if !self.options.dry_run
+                    && let Some(checkpoint) = &self.options.checkpoint
+                {
+                    checkpoint.save(&user)?;
+                }
+                after = Some(user);
+            }
+        }
+        Ok(summary)
+    }
+
+    async fn migrate_user(
+        &self,
+        source_user: &UserIdentifier,
+        summary: &mut Summary,
+    ) -> MigrateResult<()> {
+        let dest_user = match &self.options.user_map {
+            Some(user_map) => match user_map.get(source_user) {
+                Some(user) => user.clone(),
+                None => {
+                    warn!("Skipping unmapped user {}", format_user(source_user));
+                    summary.skipped_users += 1;
+                    return Ok(());
+                }
+            },
+            None => source_user.clone(),
+        };
+
+        let timestamps = self
+            .source
+            .get()
+            .await?
+            .get_collection_timestamps(source_user.clone())
+            .await?;
+
+        if !self.options.dry_run {
+            // Replace anything left over from a previous, interrupted run
+            let mut db = self.dest.get().await?;
+            db.begin(true).await?;
+            db.delete_storage(dest_user.clone()).await?;
+            db.commit().await?;
+        }
+
+        let mut bsos = 0;
+        for (collection, modified) in &timestamps {
+            bsos += self
+                .copy_collection(source_user, &dest_user, collection, *modified)
+                .await?;
+        }
+
+        let collections = timestamps.len();
+        if !self.options.dry_run {
+            self.verify_user(source_user, &dest_user, timestamps)
+                .await?;
+        }
+        info!(
+            "{} user {} -> {}: {} collections, {} bsos",
+            if self.options.dry_run {
+                "Would copy"
+            } else {
+                "Copied"
+            },
+            format_user(source_user),
+            format_user(&dest_user),
+            collections,
+            bsos
+        );
+        summary.users += 1;
+        summary.collections += collections as u64;
+        summary.bsos += bsos;
+        Ok(())
+    }
+
+    /// Copy a collection a page at a time, returning the number of BSOs
+    /// copied
+    async fn copy_collection(
+        &self,
+        source_user: &UserIdentifier,
+        dest_user: &UserIdentifier,
+        collection: &str,
+        modified: SyncTimestamp,
+    ) -> MigrateResult<u64> {
+        let mut copied = 0;
+        let mut offset = None;
+        loop {
+            let page = {
+                let mut db = self.source.get().await?;
+                db.lock_for_read(params::LockCollection {
+                    user_id: source_user.clone(),
+                    collection: collection.to_owned(),
+                })
+                .await?;
+                let page = db
+                    .get_bsos(params::GetBsos {
+                        user_id: source_user.clone(),
+                        collection: collection.to_owned(),
+                        newer: None,
+                        older: None,
+                        sort: Sorting::Oldest,
+                        limit: Some(self.options.batch_size),
+                        offset,
+                        ids: vec![],
+                        full: true,
+                    })
+                    .await?;
+                db.commit().await?;
+                page
+            };
+            copied += page.items.len() as u64;
+
+            // Always import the first page, even when empty, to carry over
+            // the collection's timestamp
+            if !self.options.dry_run {
+                let mut db = self.dest.get().await?;
+                db.begin(true).await?;
+                db.import_bsos(params::ImportBsos {
+                    user_id: dest_user.clone(),
+                    collection: collection.to_owned(),
+                    bsos: page.items,
+                    modified,
+                })
+                .await?;
+                db.commit().await?;
+            }
+
+            match page.offset {
+                Some(next) => {
+                    offset =
+                        Some(next.parse().map_err(|e| {
+                            DbError::internal(format!("Invalid offset {next:?}: {e}"))
+                        })?);
+                }
+                None => break,
+            }
+        }
+        Ok(copied)
+    }
+
+    /// Compare the collection counts and timestamps of both sides
+    async fn verify_user(
+        &self,
+        source_user: &UserIdentifier,
+        dest_user: &UserIdentifier,
+        source_timestamps: GetCollectionTimestamps,
+    ) -> MigrateResult<()> {
+        let failed = |reason: String| MigrateError::Verification {
+            user: format_user(source_user),
+            reason,
+        };
+
+        let mut dest_db = self.dest.get().await?;
+        let dest_timestamps = dest_db.get_collection_timestamps(dest_user.clone()).await?;
+        if dest_timestamps != source_timestamps {
+            return Err(failed(format!(
+                "collection timestamps differ: {source_timestamps:?} != {dest_timestamps:?}"
+            )));
+        }
+
+        let source_counts = self
+            .source
+            .get()
+            .await?
+            .get_collection_counts(source_user.clone())
+            .await?;
+        let dest_counts = dest_db.get_collection_counts(dest_user.clone()).await?;
+        if dest_counts != source_counts {
+            return Err(failed(format!(
+                "collection counts differ: {source_counts:?} != {dest_counts:?}"
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Whether the backend named by a database URL's scheme keys users by
+/// `legacy_id` (rather than Spanner's `(fxa_uid, fxa_kid)`)
+pub fn keyed_by_legacy_id(database_url: &str) -> bool {
+    !database_url.starts_with("spanner://")
+}
+
+/// Log to stderr, leaving stdout free for output (e.g. archives)
+pub fn init_logging() {
+    let decorator = slog_term::TermDecorator::new().stderr().build();
+    let drain = slog_term::FullFormat::new(decorator).build().fuse();
+    let drain = slog_envlogger::new(drain);
+    let drain = slog_async::Async::new(drain).build().fuse();
+    let logger = slog::Logger::root(drain, slog::o!());
+    slog_scope::set_global_logger(logger).cancel_reset();
+}
+
+/// Build and initialize a pool for `database_url`, otherwise configured by
+/// `settings`
+pub async fn pool(
+    settings: &Settings,
+    database_url: &str,
+    blocking_threadpool: Arc<BlockingThreadpool>,
+) -> MigrateResult<Box<dyn DbPool<Error = DbError>>> {
+    let mut syncstorage = settings.syncstorage.clone();
+    syncstorage.database_url = database_url.to_owned();
+    let mut pool = pool_from_settings(&syncstorage, &Metrics::noop(), blocking_threadpool)?;
+    pool.init().await?;
+    Ok(pool)
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod test;
@@ -0,0 +1,80 @@
+//! Copy users' data from one syncstorage backend to another
+#[macro_use]
+extern crate slog_scope;
+
+use std::{error::Error, path::PathBuf, sync::Arc};
+
+use docopt::Docopt;
+use serde::Deserialize;
+
+use syncserver_common::BlockingThreadpool;
+use syncserver_settings::Settings;
+use syncstorage_migrate::{
+    Checkpoint, Migrator, Options, UserMap, init_logging, keyed_by_legacy_id, pool,
+};
+
+const USAGE: &str = "
+Usage: syncstorage-migrate [options] <source-url> <dest-url>
+
+Copies every user's collections and BSOs from the syncstorage database at
+<source-url> to the one at <dest-url>, preserving their timestamps.
+
+Options:
+    -h, --help               Show this message.
+    --config=CONFIGFILE      Syncstorage configuration file path.
+    --checkpoint=FILE        Record progress to FILE, resuming from it if it exists.
+    --user-map=FILE          CSV of legacy_id,fxa_uid,fxa_kid lines mapping users
+                             between Spanner and the other backends.
+    --batch-size=N           Max number of BSOs copied at a time [default: 500].
+    --dry-run                Only read from the source, reporting what would be copied.
+";
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    arg_source_url: String,
+    arg_dest_url: String,
+    flag_config: Option<String>,
+    flag_checkpoint: Option<PathBuf>,
+    flag_user_map: Option<PathBuf>,
+    flag_batch_size: u32,
+    flag_dry_run: bool,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+    let settings = Settings::with_env_and_config_file(args.flag_config.as_deref())?;
+    init_logging();
+
+    let user_map = args
+        .flag_user_map
+        .as_deref()
+        .map(UserMap::from_file)
+        .transpose()?;
+    if user_map.is_none()
+        && keyed_by_legacy_id(&args.arg_source_url) != keyed_by_legacy_id(&args.arg_dest_url)
+    {
+        return Err("--user-map is required when migrating to or from Spanner".into());
+    }
+
+    let blocking_threadpool = Arc::new(BlockingThreadpool::new(
+        settings.worker_max_blocking_threads,
+    ));
+    let source = pool(&settings, &args.arg_source_url, blocking_threadpool.clone()).await?;
+    let dest = pool(&settings, &args.arg_dest_url, blocking_threadpool).await?;
+
+    let options = Options {
+        batch_size: args.flag_batch_size,
+        dry_run: args.flag_dry_run,
+        checkpoint: args.flag_checkpoint.map(Checkpoint::new),
+        user_map,
+    };
+    let summary = Migrator::new(source, dest, options).run().await?;
+    info!(
+        "Migration complete: {} users ({} skipped), {} collections, {} bsos",
+        summary.users, summary.skipped_users, summary.collections, summary.bsos
+    );
+    Ok(())
+}
@@ -0,0 +1,265 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use syncserver_common::{BlockingThreadpool, Metrics};
+use syncstorage_db::{
+    Db, DbError, DbPool, SyncTimestamp, UserIdentifier,
+    archive::{ArchiveSummary, export_user, import_user},
+    params, pool_from_settings, results,
+};
+use syncstorage_settings::Settings;
+
+use crate::{Checkpoint, MigrateResult, Migrator, Options, Summary, UserMap};
+
+fn temp_path(name: &str) -> PathBuf {
+    let path =
+        std::env::temp_dir().join(format!("syncstorage-migrate-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+async fn pool(path: &Path) -> Result<Box<dyn DbPool<Error = DbError>>, DbError> {
+    let settings = Settings {
+        database_url: format!("sqlite://{}", path.display()),
+        database_use_test_transactions: false,
+        ..Default::default()
+    };
+    let mut pool = pool_from_settings(
+        &settings,
+        &Metrics::noop(),
+        Arc::new(BlockingThreadpool::new(512)),
+    )?;
+    pool.init().await?;
+    Ok(pool)
+}
+
+fn user(legacy_id: u64) -> UserIdentifier {
+    UserIdentifier {
+        legacy_id,
+        ..Default::default()
+    }
+}
+
+fn bso(id: &str, modified: i64, expiry: i64) -> results::GetBso {
+    results::GetBso {
+        id: id.to_owned(),
+        modified: SyncTimestamp::from_i64(modified).unwrap(),
+        payload: format!("payload-{id}"),
+        sortindex: Some(1),
+        expiry,
+    }
+}
+
+async fn import(
+    pool: &dyn DbPool<Error = DbError>,
+    user_id: UserIdentifier,
+    collection: &str,
+    bsos: Vec<results::GetBso>,
+    modified: i64,
+) -> Result<(), DbError> {
+    let mut db = pool.get().await?;
+    db.begin(true).await?;
+    db.import_bsos(params::ImportBsos {
+        user_id,
+        collection: collection.to_owned(),
+        bsos,
+        modified: SyncTimestamp::from_i64(modified).unwrap(),
+    })
+    .await?;
+    db.commit().await
+}
+
+async fn get_bsos(
+    db: &mut dyn Db<Error = DbError>,
+    user_id: UserIdentifier,
+    collection: &str,
+) -> Result<Vec<results::GetBso>, DbError> {
+    Ok(db
+        .get_bsos(params::GetBsos {
+            user_id,
+            collection: collection.to_owned(),
+            newer: None,
+            older: None,
+            sort: syncstorage_db::Sorting::Oldest,
+            limit: None,
+            offset: None,
+            ids: vec![],
+            full: true,
+        })
+        .await?
+        .items)
+}
+
+#[tokio::test]
+async fn migrate_sqlite_to_sqlite() -> MigrateResult<()> {
+    let source_path = temp_path("source.db");
+    let dest_path = temp_path("dest.db");
+    let checkpoint_path = temp_path("checkpoint");
+    let source = pool(&source_path).await?;
+    let dest = pool(&dest_path).await?;
+
+    // Far enough in the future to not expire during the test
+    let expiry = SyncTimestamp::default().as_i64() + 86_400_000;
+    let bsos: Vec<_> = (0..5)
+        .map(|i| bso(&format!("b{i}"), 1_000_000 + i, expiry + i))
+        .collect();
+    import(&*source, user(1), "bookmarks", bsos.clone(), 1_000_010).await?;
+    import(&*source, user(1), "history", vec![], 1_000_020).await?;
+    import(&*source, user(2), "tabs", bsos[..1].to_vec(), 1_000_030).await?;
+
+    // Stale data in the destination is replaced
+    import(&*dest, user(1), "prefs", bsos.clone(), 1_000_040).await?;
+
+    let options = Options {
+        batch_size: 2,
+        checkpoint: Some(Checkpoint::new(checkpoint_path.clone())),
+        ..Default::default()
+    };
+    let summary = Migrator::new(source.box_clone(), dest.box_clone(), options)
+        .run()
+        .await?;
+    assert_eq!(
+        summary,
+        Summary {
+            users: 2,
+            skipped_users: 0,
+            collections: 3,
+            bsos: 6,
+        }
+    );
+
+    let mut db = dest.get().await?;
+    let timestamps = db.get_collection_timestamps(user(1)).await?;
+    assert_eq!(timestamps.len(), 2);
+    assert_eq!(timestamps["bookmarks"].as_i64(), 1_000_010);
+    assert_eq!(timestamps["history"].as_i64(), 1_000_020);
+    let copied = get_bsos(&mut *db, user(1), "bookmarks").await?;
+    assert_eq!(copied.len(), 5);
+    for (copied, original) in copied.iter().zip(&bsos) {
+        assert_eq!(copied.id, original.id);
+        assert_eq!(copied.modified, original.modified);
+        assert_eq!(copied.expiry, original.expiry);
+        assert_eq!(copied.payload, original.payload);
+    }
+
+    assert_eq!(
+        Checkpoint::new(checkpoint_path.clone()).load()?,
+        Some(user(2))
+    );
+    // Resuming after the last user has nothing left to do
+    let options = Options {
+        checkpoint: Some(Checkpoint::new(checkpoint_path.clone())),
+        ..Default::default()
+    };
+    let summary = Migrator::new(source.box_clone(), dest.box_clone(), options)
+        .run()
+        .await?;
+    assert_eq!(summary, Summary::default());
+
+    for path in [source_path, dest_path, checkpoint_path] {
+        let _ = std::fs::remove_file(path);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn archive_roundtrip() -> MigrateResult<()> {
+    let source_path = temp_path("archive-source.db");
+    let dest_path = temp_path("archive-dest.db");
+    let source = pool(&source_path).await?;
+    let dest = pool(&dest_path).await?;
+
+    let expiry = SyncTimestamp::default().as_i64() + 86_400_000;
+    let mut bsos: Vec<_> = (0..5)
+        .map(|i| bso(&format!("b{i}"), 1_000_000 + i, expiry + i * 1000))
+        .collect();
+    import(&*source, user(1), "bookmarks", bsos.clone(), 1_000_010).await?;
+    import(&*source, user(1), "history", vec![], 1_000_020).await?;
+    // Expired by the time it's imported
+    let expired = bso(
+        "expired",
+        1_000_005,
+        SyncTimestamp::default().as_i64() - 1000,
+    );
+    import(&*source, user(1), "tabs", vec![expired], 1_000_030).await?;
+
+    let mut archive = vec![];
+    let summary = export_user(&*source, &user(1), 2, &mut archive).await?;
+    assert_eq!(
+        summary,
+        ArchiveSummary {
+            collections: 3,
+            bsos: 5,
+        }
+    );
+
+    let summary = import_user(&*dest, &user(2), 2, true, &archive[..]).await?;
+    assert_eq!(
+        summary,
+        ArchiveSummary {
+            collections: 3,
+            bsos: 5,
+        }
+    );
+    let mut db = dest.get().await?;
+    let timestamps = db.get_collection_timestamps(user(2)).await?;
+    assert_eq!(timestamps["bookmarks"].as_i64(), 1_000_010);
+    assert_eq!(timestamps["history"].as_i64(), 1_000_020);
+    let imported = get_bsos(&mut *db, user(2), "bookmarks").await?;
+    assert_eq!(imported.len(), 5);
+    for (imported, original) in imported.iter().zip(&bsos) {
+        assert_eq!(imported.id, original.id);
+        assert_eq!(imported.modified, original.modified);
+        assert_eq!(imported.payload, original.payload);
+        // ttls are exported in whole seconds
+        assert!((0..1000).contains(&(original.expiry - imported.expiry)));
+    }
+    assert!(get_bsos(&mut *db, user(2), "tabs").await?.is_empty());
+
+    // Otherwise imported as new changes
+    let before = SyncTimestamp::default();
+    import_user(&*dest, &user(3), 2, false, &archive[..]).await?;
+    let imported = get_bsos(&mut *db, user(3), "bookmarks").await?;
+    assert_eq!(imported.len(), 5);
+    bsos.sort_by(|a, b| a.id.cmp(&b.id));
+    for (imported, original) in imported.iter().zip(&bsos) {
+        assert_eq!(imported.payload, original.payload);
+        assert!(imported.modified >= before);
+    }
+
+    assert!(
+        import_user(
+            &*dest,
+            &user(4),
+            2,
+            true,
+            &b"{\"type\":\"header\",\"version\":2,\"exported_at\":0}\n"[..]
+        )
+        .await
+        .is_err()
+    );
+
+    for path in [source_path, dest_path] {
+        let _ = std::fs::remove_file(path);
+    }
+    Ok(())
+}
+
+#[test]
+fn user_map() -> MigrateResult<()> {
+    let map = UserMap::parse("1,uid1,kid1\n\n2, uid2, kid2\n")?;
+    let mapped = map.get(&user(2)).unwrap();
+    assert_eq!(mapped.fxa_uid, "uid2");
+    assert_eq!(mapped.fxa_kid, "kid2");
+    let spanner_user = UserIdentifier {
+        fxa_uid: "uid1".to_owned(),
+        fxa_kid: "kid1".to_owned(),
+        ..Default::default()
+    };
+    assert_eq!(map.get(&spanner_user).unwrap().legacy_id, 1);
+    assert!(map.get(&user(3)).is_none());
+    assert!(UserMap::parse("not,a").is_err());
+    Ok(())
+}
@@ -0,0 +1,3 @@
+ALTER TABLE `batch_uploads`
+  DROP COLUMN `total_records`,
+  DROP COLUMN `total_bytes`;
@@ -0,0 +1,3 @@
+ALTER TABLE `batch_uploads`
+  ADD COLUMN `total_records` bigint(20) NOT NULL DEFAULT 0,
+  ADD COLUMN `total_bytes` bigint(20) NOT NULL DEFAULT 0;
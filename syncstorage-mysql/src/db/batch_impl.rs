@@ -3,9 +3,7 @@ use std::collections::HashSet;
 
 use async_trait::async_trait;
 use diesel::{
-    self, ExpressionMethods, OptionalExtension, QueryDsl,
-    dsl::sql,
-    insert_into,
+    self, ExpressionMethods, OptionalExtension, QueryDsl, insert_into,
     result::{DatabaseErrorKind::UniqueViolation, Error as DieselError},
     sql_query,
     sql_types::{BigInt, Integer},
@@ -77,23 +75,7 @@ impl BatchDb for MysqlDb {
     }
 
     async fn validate_batch(&mut self, params: params::ValidateBatch) -> DbResult<bool> {
-        let batch_id = decode_id(&params.id)?;
-        // Avoid hitting the db for batches that are obviously too old.  Recall
-        // that the batchid is a millisecond timestamp.
-        if (batch_id + BATCH_LIFETIME) < self.session.timestamp.as_i64() {
-            return Ok(false);
-        }
-
-        let user_id = params.user_id.legacy_id as i64;
-        let collection_id = self._get_collection_id(&params.collection).await?;
-        let exists = batch_uploads::table
-            .select(sql::<Integer>("1"))
-            .filter(batch_uploads::batch_id.eq(&batch_id))
-            .filter(batch_uploads::user_id.eq(&user_id))
-            .filter(batch_uploads::collection_id.eq(&collection_id))
-            .get_result::<i32>(&mut self.conn)
-            .await
-            .optional()?;
+        let exists = self.get_batch(params.into()).await?;
         Ok(exists.is_some())
     }
 
@@ -117,19 +99,30 @@ impl BatchDb for MysqlDb {
     }
 
     async fn get_batch(&mut self, params: params::GetBatch) -> DbResult<Option<results::GetBatch>> {
-        let is_valid = self
-            .validate_batch(params::ValidateBatch {
-                user_id: params.user_id,
-                collection: params.collection,
-                id: params.id.clone(),
-            })
-            .await?;
-        let batch = if is_valid {
-            Some(results::GetBatch { id: params.id })
-        } else {
-            None
-        };
-        Ok(batch)
+        let batch_id = decode_id(&params.id)?;
+        // Avoid hitting the db for batches that are obviously too old.  Recall
+        // that the batchid is a millisecond timestamp.
+        if (batch_id + BATCH_LIFETIME) < self.session.timestamp.as_i64() {
+            return Ok(None);
+        }
+
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self._get_collection_id(&params.collection).await?;
+        let totals = batch_uploads::table
+            .select((batch_uploads::total_records, batch_uploads::total_bytes))
+            .filter(batch_uploads::batch_id.eq(&batch_id))
+            .filter(batch_uploads::user_id.eq(&user_id))
+            .filter(batch_uploads::collection_id.eq(&collection_id))
+            .get_result::<(i64, i64)>(&mut self.conn)
+            .await
+            .optional()?;
+        Ok(
+            totals.map(|(total_records, total_bytes)| results::GetBatch {
+                id: params.id,
+                total_records: total_records as u64,
+                total_bytes: total_bytes as u64,
+            }),
+        )
     }
 
     async fn delete_batch(&mut self, params: params::DeleteBatch) -> DbResult<()> {
@@ -227,6 +220,8 @@ pub async fn do_append(
     }
 
     let mut existing = HashSet::new();
+    let total_records = bsos.len() as i64;
+    let mut total_bytes = 0;
 
     // pre-load the "existing" hashset with any batched uploads that are already in the table.
     for item in sql_query(
@@ -245,6 +240,7 @@ pub async fn do_append(
 
     for bso in bsos {
         let payload_size = bso.payload.as_ref().map(|p| p.len() as i64);
+        total_bytes += payload_size.unwrap_or(0);
         let exist_idx = exist_idx(user_id.legacy_id, batch_id, &bso.id);
 
         if existing.contains(&exist_idx) {
@@ -278,6 +274,21 @@ pub async fn do_append(
         }
     }
 
+    if total_records > 0 {
+        // Keep the batch's running totals (enforced against the
+        // `max_total_records`/`max_total_bytes` limits)
+        diesel::update(
+            batch_uploads::table
+                .filter(batch_uploads::batch_id.eq(batch_id))
+                .filter(batch_uploads::user_id.eq(user_id.legacy_id as i64)),
+        )
+        .set((
+            batch_uploads::total_records.eq(batch_uploads::total_records + total_records),
+            batch_uploads::total_bytes.eq(batch_uploads::total_bytes + total_bytes),
+        ))
+        .execute(&mut db.conn)
+        .await?;
+    }
     Ok(())
 }
 
@@ -9,17 +9,16 @@ use diesel::{
     sql_types::{BigInt, Integer, Nullable, Text},
 };
 use diesel_async::{AsyncConnection, RunQueryDsl, TransactionManager};
+use futures::{StreamExt, TryStreamExt};
 use syncstorage_db_common::{
-    DEFAULT_BSO_TTL, Db, Sorting, UserIdentifier,
-    error::DbErrorIntrospect,
-    params, results,
-    util::{SyncTimestamp, encode_next_offset},
+    BATCH_LIFETIME, BsoStream, DEFAULT_BSO_TTL, Db, Sorting, UserIdentifier,
+    error::DbErrorIntrospect, params, results, util::SyncTimestamp,
 };
 use syncstorage_settings::DEFAULT_MAX_TOTAL_RECORDS;
 
 use super::{
     COLLECTION_ID, COUNT, CollectionLock, EXPIRY, LAST_MODIFIED, MODIFIED, MysqlDb, TOMBSTONE,
-    TOTAL_BYTES, USER_ID,
+    TOTAL_BYTES, USER_ID, after_cursor,
     diesel_ext::LockInShareModeDsl,
     schema::{bso, user_collections},
 };
@@ -287,52 +286,7 @@ impl Db for MysqlDb {
     }
 
     async fn get_bsos(&mut self, params: params::GetBsos) -> DbResult<results::GetBsos> {
-        let user_id = params.user_id.legacy_id as i64;
         let collection_id = self._get_collection_id(&params.collection).await?;
-        let now = self.session.timestamp.as_i64();
-        let mut query = bso::table
-            .select((
-                bso::id,
-                bso::modified,
-                bso::payload,
-                bso::sortindex,
-                bso::expiry,
-            ))
-            .filter(bso::user_id.eq(user_id))
-            .filter(bso::collection_id.eq(collection_id))
-            .filter(bso::expiry.gt(now))
-            .into_boxed();
-
-        if let Some(ts) = params.offset.as_ref().and_then(|o| o.timestamp) {
-            match params.sort {
-                Sorting::Oldest => query = query.filter(bso::modified.ge(ts.as_i64())),
-                Sorting::Newest | Sorting::None => {
-                    query = query.filter(bso::modified.le(ts.as_i64()))
-                }
-                Sorting::Index => {}
-            }
-        }
-        if let Some(older) = params.older {
-            query = query.filter(bso::modified.lt(older.as_i64()));
-        }
-        if let Some(newer) = params.newer {
-            query = query.filter(bso::modified.gt(newer.as_i64()));
-        }
-
-        if !params.ids.is_empty() {
-            query = query.filter(bso::id.eq_any(params.ids));
-        }
-
-        // it's possible for two BSOs to be inserted with the same `modified` date,
-        // since there's no guarantee of order when doing a get, pagination can return
-        // an error. We "fudge" a bit here by taking the id order as a secondary, since
-        // that is guaranteed to be unique by the client.
-        query = match params.sort {
-            Sorting::Index => query.order(bso::sortindex.desc()),
-            Sorting::Newest | Sorting::None => query.order((bso::modified.desc(), bso::id.desc())),
-            Sorting::Oldest => query.order((bso::modified.asc(), bso::id.asc())),
-        };
-
         let limit = params
             .limit
             .map(i64::from)
@@ -340,20 +294,11 @@ impl Db for MysqlDb {
             .max(0);
         // fetch an extra row to detect if there are more rows that
         // match the query conditions
-        query = query.limit(if limit > 0 { limit + 1 } else { limit });
-
-        let prev_ts = params
-            .offset
-            .as_ref()
-            .and_then(|o| o.timestamp)
-            .map(|t| t.as_i64());
-        let numeric_offset = params.offset.map_or(0, |offset| offset.offset as i64);
-
-        if numeric_offset > 0 {
-            // XXX: copy over this optimization:
-            // https://github.com/mozilla-services/server-syncstorage/blob/a0f8117/syncstorage/storage/sql/__init__.py#L404
-            query = query.offset(numeric_offset);
-        }
+        let query = self.bsos_query(
+            params,
+            collection_id,
+            if limit > 0 { limit + 1 } else { limit },
+        );
         let mut bsos = query.load::<results::GetBso>(&mut self.conn).await?;
 
         // XXX: an additional get_collection_timestamp is done here in
@@ -363,13 +308,8 @@ impl Db for MysqlDb {
 
         let next_offset = if limit >= 0 && bsos.len() > limit as usize {
             bsos.pop();
-            let modified_timestamps: Vec<i64> = bsos.iter().map(|b| b.modified.as_i64()).collect();
-            Some(encode_next_offset(
-                params.sort,
-                numeric_offset as u64,
-                prev_ts,
-                &modified_timestamps,
-            ))
+            bsos.last()
+                .map(|bso| params::Offset::from(params::Cursor::from(bso)).to_string())
         } else {
             // if an explicit "limit=0" is sent, return the offset of "0"
             // Otherwise, this would break at least the db::tests::db::get_bsos_limit_offset
@@ -387,16 +327,45 @@ impl Db for MysqlDb {
         })
     }
 
+    async fn get_bsos_stream(
+        &mut self,
+        params: params::GetBsos,
+    ) -> DbResult<BsoStream<'_, DbError>> {
+        let collection_id = self._get_collection_id(&params.collection).await?;
+        let limit = params
+            .limit
+            .map(i64::from)
+            .unwrap_or(DEFAULT_LIMIT as i64)
+            .max(0);
+        let bsos = self
+            .bsos_query(params, collection_id, limit)
+            .load_stream::<results::GetBso>(&mut self.conn)
+            .await?;
+        Ok(bsos.map_err(Into::into).boxed_local())
+    }
+
     async fn get_bso_ids(&mut self, params: params::GetBsos) -> DbResult<results::GetBsoIds> {
         let user_id = params.user_id.legacy_id as i64;
         let collection_id = self._get_collection_id(&params.collection).await?;
         let mut query = bso::table
-            .select(bso::id)
+            .select((bso::id, bso::modified, bso::sortindex))
             .filter(bso::user_id.eq(user_id))
             .filter(bso::collection_id.eq(collection_id))
             .filter(bso::expiry.gt(self.session.timestamp.as_i64()))
             .into_boxed();
 
+        let cursor = params.offset.as_ref().and_then(|o| o.cursor.clone());
+        if let Some(cursor) = cursor {
+            query = query.filter(after_cursor(params.sort, cursor));
+        } else if let Some(ts) = params.offset.as_ref().and_then(|o| o.timestamp) {
+            match params.sort {
+                Sorting::Oldest => query = query.filter(bso::modified.ge(ts.as_i64())),
+                Sorting::Newest | Sorting::None => {
+                    query = query.filter(bso::modified.le(ts.as_i64()))
+                }
+                Sorting::Index => {}
+            }
+        }
         if let Some(older) = params.older {
             query = query.filter(bso::modified.lt(older.as_i64()));
         }
@@ -409,10 +378,9 @@ impl Db for MysqlDb {
         }
 
         query = match params.sort {
-            Sorting::Index => query.order(bso::sortindex.desc()),
-            Sorting::Newest => query.order((bso::modified.desc(), bso::id.desc())),
+            Sorting::Index => query.order((bso::sortindex.desc(), bso::id.desc())),
+            Sorting::Newest | Sorting::None => query.order((bso::modified.desc(), bso::id.desc())),
             Sorting::Oldest => query.order((bso::modified.asc(), bso::id.asc())),
-            _ => query,
         };
 
         // negative limits are no longer allowed by mysql.
@@ -424,25 +392,34 @@ impl Db for MysqlDb {
         // fetch an extra row to detect if there are more rows that
         // match the query conditions. Negative limits will cause an error.
         query = query.limit(if limit == 0 { limit } else { limit + 1 });
+        // Legacy offsets still skip rows, cursors already filtered them out
         let numeric_offset = params.offset.map_or(0, |offset| offset.offset as i64);
         if numeric_offset != 0 {
-            // XXX: copy over this optimization:
-            // https://github.com/mozilla-services/server-syncstorage/blob/a0f8117/syncstorage/storage/sql/__init__.py#L404
             query = query.offset(numeric_offset);
         }
-        let mut ids = query.load::<String>(&mut self.conn).await?;
+        let mut rows = query
+            .load::<(String, SyncTimestamp, Option<i32>)>(&mut self.conn)
+            .await?;
 
         // XXX: an additional get_collection_timestamp is done here in
         // python to trigger potential CollectionNotFoundErrors
         //if bsos.len() == 0 {
         //}
 
-        let next_offset = if limit >= 0 && ids.len() > limit as usize {
-            ids.pop();
-            Some((limit + numeric_offset).to_string())
+        let next_offset = if limit >= 0 && rows.len() > limit as usize {
+            rows.pop();
+            rows.last().map(|(id, modified, sortindex)| {
+                params::Offset::from(params::Cursor {
+                    modified: *modified,
+                    sortindex: *sortindex,
+                    id: id.clone(),
+                })
+                .to_string()
+            })
         } else {
             None
         };
+        let ids = rows.into_iter().map(|(id, ..)| id).collect();
 
         Ok(results::GetBsoIds {
             items: ids,
@@ -742,6 +719,101 @@ impl Db for MysqlDb {
         results::ConnectionInfo::default()
     }
 
+    async fn get_user_ids(&mut self, params: params::GetUserIds) -> DbResult<results::GetUserIds> {
+        let mut query = user_collections::table
+            .select(user_collections::user_id)
+            .distinct()
+            .order(user_collections::user_id)
+            .limit(i64::from(params.limit))
+            .into_boxed();
+        if let Some(after) = params.after {
+            query = query.filter(user_collections::user_id.gt(after.legacy_id as i64));
+        }
+        Ok(query
+            .load::<i64>(&mut self.conn)
+            .await?
+            .into_iter()
+            .map(|user_id| UserIdentifier {
+                legacy_id: user_id as u64,
+                ..Default::default()
+            })
+            .collect())
+    }
+
+    async fn import_bsos(&mut self, params: params::ImportBsos) -> DbResult<results::ImportBsos> {
+        let collection_id = self.get_or_create_collection_id(&params.collection).await?;
+        let user_id = params.user_id.legacy_id as i64;
+        let q = format!(
+            r#"
+            INSERT INTO bso ({user_id}, {collection_id}, id, sortindex, payload, {modified}, {expiry})
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+                ON DUPLICATE KEY UPDATE
+                   sortindex = VALUES(sortindex),
+                   payload = VALUES(payload),
+                   {modified} = VALUES({modified}),
+                   {expiry} = VALUES({expiry})
+            "#,
+            user_id = USER_ID,
+            modified = MODIFIED,
+            collection_id = COLLECTION_ID,
+            expiry = EXPIRY
+        );
+        for bso in params.bsos {
+            sql_query(&q)
+                .bind::<BigInt, _>(user_id)
+                .bind::<Integer, _>(&collection_id)
+                .bind::<Text, _>(&bso.id)
+                .bind::<Nullable<Integer>, _>(bso.sortindex)
+                .bind::<Text, _>(&bso.payload)
+                .bind::<BigInt, _>(bso.modified.as_i64())
+                .bind::<BigInt, _>(bso.expiry)
+                .execute(&mut self.conn)
+                .await?;
+        }
+        // update_collection records the session's timestamp
+        self.session.timestamp = params.modified;
+        self.update_collection(params::UpdateCollection {
+            user_id: params.user_id,
+            collection_id,
+            collection: params.collection,
+        })
+        .await
+    }
+
+    async fn purge_expired(
+        &mut self,
+        params: params::PurgeExpired,
+    ) -> DbResult<results::PurgeExpired> {
+        let now = self.session.timestamp.as_i64();
+        let limit = i64::from(params.limit);
+        let bsos = sql_query(format!(
+            "DELETE FROM bso WHERE {expiry} < ? LIMIT ?",
+            expiry = EXPIRY
+        ))
+        .bind::<BigInt, _>(now)
+        .bind::<BigInt, _>(limit)
+        .execute(&mut self.conn)
+        .await?;
+
+        // Batch ids are their creation timestamps
+        let batch_expiry = now - BATCH_LIFETIME;
+        let batch_items = sql_query("DELETE FROM batch_upload_items WHERE batch < ? LIMIT ?")
+            .bind::<BigInt, _>(batch_expiry)
+            .bind::<BigInt, _>(limit)
+            .execute(&mut self.conn)
+            .await?;
+        let batches = sql_query("DELETE FROM batch_uploads WHERE batch < ? LIMIT ?")
+            .bind::<BigInt, _>(batch_expiry)
+            .bind::<BigInt, _>(limit)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(results::PurgeExpired {
+            bsos: bsos as u64,
+            batches: batches as u64,
+            batch_items: batch_items as u64,
+        })
+    }
+
     #[cfg(debug_assertions)]
     async fn create_collection(&mut self, name: &str) -> Result<i32, Self::Error> {
         self._create_collection(name).await
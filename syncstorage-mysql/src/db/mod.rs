@@ -1,16 +1,17 @@
 use std::{collections::HashMap, fmt, sync::Arc};
 
 use diesel::{
-    ExpressionMethods, OptionalExtension, QueryDsl,
-    dsl::sql,
+    BoxableExpression, ExpressionMethods, OptionalExtension, QueryDsl,
+    dsl::{IntoBoxed, Select, sql},
+    mysql::Mysql,
     sql_query,
-    sql_types::{BigInt, Integer, Text},
+    sql_types::{BigInt, Bool, Integer, Nullable, Text},
 };
 use diesel_async::RunQueryDsl;
 use syncserver_common::Metrics;
 use syncstorage_db_common::{
-    FIRST_CUSTOM_COLLECTION_ID, UserIdentifier, error::DbErrorIntrospect, results,
-    util::SyncTimestamp,
+    FIRST_CUSTOM_COLLECTION_ID, Sorting, UserIdentifier, after_cursor, error::DbErrorIntrospect,
+    params, results, util::SyncTimestamp,
 };
 use syncstorage_settings::Quota;
 
@@ -78,7 +79,74 @@ impl fmt::Debug for MysqlDb {
     }
 }
 
+/// Columns of a BSO listing, as loaded into a [results::GetBso]
+type GetBsoColumns = (
+    bso::id,
+    bso::modified,
+    bso::payload,
+    bso::sortindex,
+    bso::expiry,
+);
+type BsosQuery = IntoBoxed<'static, Select<bso::table, GetBsoColumns>, Mysql>;
+
 impl MysqlDb {
+    /// Build the query for a page of at most `limit` BSOs
+    fn bsos_query(&self, params: params::GetBsos, collection_id: i32, limit: i64) -> BsosQuery {
+        let mut query = bso::table
+            .select((
+                bso::id,
+                bso::modified,
+                bso::payload,
+                bso::sortindex,
+                bso::expiry,
+            ))
+            .filter(bso::user_id.eq(params.user_id.legacy_id as i64))
+            .filter(bso::collection_id.eq(collection_id))
+            .filter(bso::expiry.gt(self.session.timestamp.as_i64()))
+            .into_boxed();
+
+        let cursor = params.offset.as_ref().and_then(|o| o.cursor.clone());
+        if let Some(cursor) = cursor {
+            query = query.filter(after_cursor(params.sort, cursor));
+        } else if let Some(ts) = params.offset.as_ref().and_then(|o| o.timestamp) {
+            match params.sort {
+                Sorting::Oldest => query = query.filter(bso::modified.ge(ts.as_i64())),
+                Sorting::Newest | Sorting::None => {
+                    query = query.filter(bso::modified.le(ts.as_i64()))
+                }
+                Sorting::Index => {}
+            }
+        }
+        if let Some(older) = params.older {
+            query = query.filter(bso::modified.lt(older.as_i64()));
+        }
+        if let Some(newer) = params.newer {
+            query = query.filter(bso::modified.gt(newer.as_i64()));
+        }
+
+        if !params.ids.is_empty() {
+            query = query.filter(bso::id.eq_any(params.ids));
+        }
+
+        // it's possible for two BSOs to be inserted with the same `modified` date,
+        // since there's no guarantee of order when doing a get, pagination can return
+        // an error. We "fudge" a bit here by taking the id order as a secondary, since
+        // that is guaranteed to be unique by the client.
+        query = match params.sort {
+            Sorting::Index => query.order((bso::sortindex.desc(), bso::id.desc())),
+            Sorting::Newest | Sorting::None => query.order((bso::modified.desc(), bso::id.desc())),
+            Sorting::Oldest => query.order((bso::modified.asc(), bso::id.asc())),
+        };
+        query = query.limit(limit);
+
+        // Legacy offsets still skip rows, cursors already filtered them out
+        let numeric_offset = params.offset.map_or(0, |offset| offset.offset as i64);
+        if numeric_offset > 0 {
+            query = query.offset(numeric_offset);
+        }
+        query
+    }
+
     pub(super) fn new(
         conn: Conn,
         coll_cache: Arc<CollectionCache>,
@@ -251,3 +319,18 @@ struct IdResult {
     #[diesel(sql_type = Integer)]
     id: i32,
 }
+
+/// Keyset predicate matching the BSOs that follow `cursor` in `sort` order
+fn after_cursor(
+    sort: Sorting,
+    cursor: params::Cursor,
+) -> Box<dyn BoxableExpression<bso::table, Mysql, SqlType = Nullable<Bool>>> {
+    after_cursor!(
+        sort,
+        cursor,
+        cursor.modified.as_i64(),
+        bso::sortindex,
+        bso::id,
+        bso::modified
+    )
+}
@@ -6,6 +6,8 @@ table! {
         user_id -> Bigint,
         #[sql_name="collection"]
         collection_id -> Integer,
+        total_records -> Bigint,
+        total_bytes -> Bigint,
     }
 }
 
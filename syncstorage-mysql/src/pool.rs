@@ -100,13 +100,6 @@ impl MysqlDbPool {
         })
     }
 
-    /// Spawn a task to periodically evict idle connections. Calls wrapper sweeper fn
-    ///  to use pool.retain, retaining objects only if they are shorter in duration than
-    ///  defined max_idle. Noop for mysql impl.
-    pub fn spawn_sweeper(&self, _interval: Duration) {
-        sweeper()
-    }
-
     async fn get_conn(&self) -> DbResult<Conn> {
         self.pool.get().await.map_err(|e| match e {
             PoolError::Backend(be) => match be {
@@ -128,13 +121,6 @@ impl MysqlDbPool {
     }
 }
 
-/// Sweeper to retain only the objects specified within the closure.
-/// In this context, if a Spanner connection is unutilized, we want it
-/// to release the given connections.
-/// See: https://docs.rs/deadpool/latest/deadpool/managed/struct.Pool.html#method.retain
-/// Noop for mysql impl
-fn sweeper() {}
-
 #[async_trait]
 impl DbPool for MysqlDbPool {
     type Error = DbError;
@@ -0,0 +1,3 @@
+ALTER TABLE batches
+    DROP COLUMN total_records,
+    DROP COLUMN total_bytes;
@@ -0,0 +1,3 @@
+ALTER TABLE batches
+    ADD COLUMN total_records BIGINT NOT NULL DEFAULT 0,
+    ADD COLUMN total_bytes BIGINT NOT NULL DEFAULT 0;
@@ -1,7 +1,7 @@
 use async_trait::async_trait;
 use diesel::{
     self, ExpressionMethods, OptionalExtension, QueryDsl, delete,
-    dsl::{now, sql},
+    dsl::now,
     insert_into, sql_query,
     sql_types::{BigInt, Integer, Nullable, Text, Timestamptz, Uuid as SqlUuid},
 };
@@ -64,21 +64,7 @@ impl BatchDb for PgDb {
         &mut self,
         params: params::ValidateBatch,
     ) -> DbResult<results::ValidateBatch> {
-        let batch_id = Uuid::parse_str(&params.id)
-            .map_err(|e| DbError::internal(format!("Invalid batch_id: {}", e)))?;
-        let user_id = params.user_id.legacy_id as i64;
-        let collection_id = self.get_or_create_collection_id(&params.collection).await?;
-
-        let exists = batches::table
-            .select(sql::<Integer>("1"))
-            .filter(batches::batch_id.eq(&batch_id))
-            .filter(batches::user_id.eq(user_id))
-            .filter(batches::collection_id.eq(collection_id))
-            .filter(batches::expiry.gt(now))
-            .first::<i32>(&mut self.conn)
-            .await
-            .optional()?;
-
+        let exists = self.get_batch(params.into()).await?;
         Ok(exists.is_some())
     }
 
@@ -113,14 +99,27 @@ impl BatchDb for PgDb {
     }
 
     async fn get_batch(&mut self, params: params::GetBatch) -> DbResult<Option<results::GetBatch>> {
-        let is_valid = self
-            .validate_batch(params::ValidateBatch {
-                user_id: params.user_id,
-                collection: params.collection,
-                id: params.id.clone(),
-            })
-            .await?;
-        Ok(is_valid.then_some(results::GetBatch { id: params.id }))
+        let batch_id = validate_batch_id(&params.id)?;
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self.get_or_create_collection_id(&params.collection).await?;
+
+        let totals = batches::table
+            .select((batches::total_records, batches::total_bytes))
+            .filter(batches::batch_id.eq(&batch_id))
+            .filter(batches::user_id.eq(user_id))
+            .filter(batches::collection_id.eq(collection_id))
+            .filter(batches::expiry.gt(now))
+            .first::<(i64, i64)>(&mut self.conn)
+            .await
+            .optional()?;
+
+        Ok(
+            totals.map(|(total_records, total_bytes)| results::GetBatch {
+                id: params.id,
+                total_records: total_records as u64,
+                total_bytes: total_bytes as u64,
+            }),
+        )
     }
 
     async fn commit_batch(
@@ -197,11 +196,14 @@ pub async fn do_append(
 ) -> DbResult<()> {
     let batch_id = Uuid::parse_str(&batch.id)
         .map_err(|e| DbError::internal(format!("Invalid batch_id in batch: {}", e)))?;
+    let total_records = bsos.len() as i64;
+    let mut total_bytes = 0;
 
     for bso in bsos {
         let ttl = bso.ttl.map(|t| t as i64);
         let sortindex = bso.sortindex;
         let user_id_i64 = user_id.legacy_id as i64;
+        total_bytes += bso.payload.as_ref().map_or(0, |p| p.len() as i64);
 
         sql_query(
             "INSERT INTO batch_bsos (user_id, collection_id, batch_id, batch_bso_id, sortindex, payload, ttl)
@@ -222,6 +224,22 @@ pub async fn do_append(
         .await?;
     }
 
+    if total_records > 0 {
+        // Keep the batch's running totals (enforced against the
+        // `max_total_records`/`max_total_bytes` limits)
+        diesel::update(
+            batches::table
+                .filter(batches::user_id.eq(user_id.legacy_id as i64))
+                .filter(batches::collection_id.eq(collection_id))
+                .filter(batches::batch_id.eq(&batch_id)),
+        )
+        .set((
+            batches::total_records.eq(batches::total_records + total_records),
+            batches::total_bytes.eq(batches::total_bytes + total_bytes),
+        ))
+        .execute(&mut db.conn)
+        .await?;
+    }
     Ok(())
 }
 
@@ -1,23 +1,23 @@
 use async_trait::async_trait;
 use chrono::{DateTime, TimeDelta, offset::Utc};
 use diesel::{
-    ExpressionMethods, IntoSql, OptionalExtension, QueryDsl, SelectableHelper, delete,
+    ExpressionMethods, IntoSql, OptionalExtension, PgSortExpressionMethods, QueryDsl,
+    SelectableHelper, delete,
     dsl::{count, max, now, sql},
+    sql_query,
     sql_types::{Array, BigInt, Integer, Nullable, Timestamptz},
     upsert::excluded,
 };
 use diesel_async::{AsyncConnection, RunQueryDsl, TransactionManager};
-use futures::TryStreamExt;
+use futures::{StreamExt, TryStreamExt};
 use syncstorage_db_common::{
-    DEFAULT_BSO_TTL, Db, Sorting,
-    error::DbErrorIntrospect,
-    params, results,
-    util::{SyncTimestamp, encode_next_offset},
+    BsoStream, DEFAULT_BSO_TTL, Db, Sorting, UserIdentifier, error::DbErrorIntrospect, params,
+    results, util::SyncTimestamp,
 };
 
-use super::{PgDb, TOMBSTONE};
+use super::{PgDb, TOMBSTONE, after_cursor};
 use crate::{
-    DbError, DbResult, bsos_query,
+    DbError, DbResult, bsos_query, bsos_select,
     db::{CollectionLock, PRETOUCH_DT},
     orm_models::{BsoChangeset, sql_types::PostBso},
     pool::Conn,
@@ -342,38 +342,51 @@ impl Db for PgDb {
     }
 
     async fn get_bsos(&mut self, params: params::GetBsos) -> DbResult<results::GetBsos> {
-        let (bsos, did_overflow, _limit, numeric_offset) =
-            bsos_query!(self, params, GetBso::as_select());
+        let (bsos, did_overflow) = bsos_query!(self, params, GetBso::as_select());
         let items: Vec<results::GetBso> = bsos
             .into_iter()
             .map(TryInto::try_into)
             .collect::<DbResult<_>>()?;
         let offset = if did_overflow {
-            let modified_timestamps: Vec<i64> = items.iter().map(|b| b.modified.as_i64()).collect();
-            let prev_ts = params
-                .offset
-                .as_ref()
-                .and_then(|o| o.timestamp)
-                .map(|t| t.as_i64());
-            Some(encode_next_offset(
-                params.sort,
-                numeric_offset as u64,
-                prev_ts,
-                &modified_timestamps,
-            ))
+            items
+                .last()
+                .map(|bso| params::Offset::from(params::Cursor::from(bso)).to_string())
         } else {
             None
         };
         Ok(results::GetBsos { items, offset })
     }
 
+    async fn get_bsos_stream(
+        &mut self,
+        params: params::GetBsos,
+    ) -> DbResult<BsoStream<'_, DbError>> {
+        let bsos = bsos_select!(self, params, GetBso::as_select(), 0)
+            .load_stream::<GetBso>(&mut self.conn)
+            .await?;
+        Ok(bsos
+            .map(|bso| bso.map_err(Into::into).and_then(TryInto::try_into))
+            .boxed_local())
+    }
+
     async fn get_bso_ids(&mut self, params: params::GetBsoIds) -> DbResult<results::GetBsoIds> {
-        let (items, did_overflow, limit, numeric_offset) = bsos_query!(self, params, bsos::bso_id);
-        let offset = if did_overflow {
-            Some((limit + numeric_offset).to_string())
-        } else {
-            None
+        let (rows, did_overflow): (Vec<(String, DateTime<Utc>, Option<i32>)>, _) = bsos_query!(
+            self,
+            params,
+            (bsos::bso_id, bsos::modified, bsos::sortindex)
+        );
+        let offset = match rows.last() {
+            Some((id, modified, sortindex)) if did_overflow => Some(
+                params::Offset::from(params::Cursor {
+                    modified: SyncTimestamp::from_datetime(*modified)?,
+                    sortindex: *sortindex,
+                    id: id.clone(),
+                })
+                .to_string(),
+            ),
+            _ => None,
         };
+        let items = rows.into_iter().map(|(id, ..)| id).collect();
         Ok(results::GetBsoIds { items, offset })
     }
 
@@ -512,6 +525,104 @@ impl Db for PgDb {
         results::ConnectionInfo::default()
     }
 
+    async fn get_user_ids(&mut self, params: params::GetUserIds) -> DbResult<results::GetUserIds> {
+        let mut query = user_collections::table
+            .select(user_collections::user_id)
+            .distinct()
+            .order(user_collections::user_id)
+            .limit(i64::from(params.limit))
+            .into_boxed();
+        if let Some(after) = params.after {
+            query = query.filter(user_collections::user_id.gt(after.legacy_id as i64));
+        }
+        Ok(query
+            .load::<i64>(&mut self.conn)
+            .await?
+            .into_iter()
+            .map(|user_id| UserIdentifier {
+                legacy_id: user_id as u64,
+                ..Default::default()
+            })
+            .collect())
+    }
+
+    async fn import_bsos(&mut self, params: params::ImportBsos) -> DbResult<results::ImportBsos> {
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self.get_or_create_collection_id(&params.collection).await?;
+        self.ensure_user_collection(user_id, collection_id).await?;
+
+        if !params.bsos.is_empty() {
+            let rows = params
+                .bsos
+                .into_iter()
+                .map(|bso| {
+                    let expiry = DateTime::from_timestamp_millis(bso.expiry)
+                        .ok_or_else(|| DbError::internal("Invalid expiry".to_owned()))?;
+                    Ok((
+                        bsos::user_id.eq(user_id),
+                        bsos::collection_id.eq(collection_id),
+                        bsos::bso_id.eq(bso.id),
+                        bsos::sortindex.eq(bso.sortindex),
+                        bsos::payload.eq(bso.payload),
+                        bsos::modified.eq(bso.modified.as_datetime()?),
+                        bsos::expiry.eq(expiry),
+                    ))
+                })
+                .collect::<DbResult<Vec<_>>>()?;
+            diesel::insert_into(bsos::table)
+                .values(rows)
+                .on_conflict((bsos::user_id, bsos::collection_id, bsos::bso_id))
+                .do_update()
+                .set((
+                    bsos::sortindex.eq(excluded(bsos::sortindex)),
+                    bsos::payload.eq(excluded(bsos::payload)),
+                    bsos::modified.eq(excluded(bsos::modified)),
+                    bsos::expiry.eq(excluded(bsos::expiry)),
+                ))
+                .execute(&mut self.conn)
+                .await?;
+        }
+
+        // update_collection records the session's timestamp
+        self.session.timestamp = Some(params.modified);
+        self.update_collection(params::UpdateCollection {
+            user_id: params.user_id,
+            collection_id,
+            collection: params.collection,
+        })
+        .await
+    }
+
+    async fn purge_expired(
+        &mut self,
+        params: params::PurgeExpired,
+    ) -> DbResult<results::PurgeExpired> {
+        let limit = i64::from(params.limit);
+        // Postgres' DELETE has no LIMIT, so select a batch of rows by ctid
+        let bsos = sql_query(
+            "DELETE FROM bsos WHERE ctid IN (
+                 SELECT ctid FROM bsos WHERE expiry < CURRENT_TIMESTAMP LIMIT $1
+             )",
+        )
+        .bind::<BigInt, _>(limit)
+        .execute(&mut self.conn)
+        .await?;
+        // Also deletes child batch_bsos rows (ON DELETE CASCADE)
+        let batches = sql_query(
+            "DELETE FROM batches WHERE ctid IN (
+                 SELECT ctid FROM batches WHERE expiry < CURRENT_TIMESTAMP LIMIT $1
+             )",
+        )
+        .bind::<BigInt, _>(limit)
+        .execute(&mut self.conn)
+        .await?;
+        Ok(results::PurgeExpired {
+            bsos: bsos as u64,
+            batches: batches as u64,
+            ..Default::default()
+        })
+    }
+
     /// Updates a given collection entry, when provided the `user_id`, `collection_id`,
     /// and `collection` string. This is an insertion operation should the
     /// `user_id` and `collection_id` keys not exist, but will update with the Postgres
@@ -1,8 +1,9 @@
 use chrono::{DateTime, NaiveDate, Utc};
 use diesel::{
-    ExpressionMethods, OptionalExtension, QueryDsl,
+    BoxableExpression, ExpressionMethods, OptionalExtension, QueryDsl,
     dsl::{now, sql},
-    sql_types::BigInt,
+    pg::Pg,
+    sql_types::{BigInt, Bool, Nullable},
     upsert::excluded,
 };
 use diesel_async::RunQueryDsl;
@@ -10,8 +11,8 @@ use std::{collections::HashMap, fmt, sync::Arc};
 
 use syncserver_common::Metrics;
 use syncstorage_db_common::{
-    Db, FIRST_CUSTOM_COLLECTION_ID, UserIdentifier, diesel::DbError, error::DbErrorIntrospect,
-    params, results, util::SyncTimestamp,
+    Db, FIRST_CUSTOM_COLLECTION_ID, Sorting, UserIdentifier, after_cursor, diesel::DbError,
+    error::DbErrorIntrospect, params, results, util::SyncTimestamp,
 };
 use syncstorage_settings::Quota;
 
@@ -287,67 +288,103 @@ impl PgDb {
 }
 
 #[macro_export]
-macro_rules! bsos_query {
-    ($self:expr, $params:expr, $selection:expr) => {
-        {
-            let user_id = $params.user_id.legacy_id as i64;
-            let collection_id = $self._get_collection_id(&$params.collection).await?;
-            let limit = $params.limit.map(i64::from);
-
-            let mut query = bsos::table
-                .select($selection)
-                .filter(bsos::user_id.eq(user_id))
-                .filter(bsos::collection_id.eq(collection_id))
-                .filter(bsos::expiry.gt(now))
-                .into_boxed();
-
-            if let Some(ts) = $params.offset.as_ref().and_then(|o| o.timestamp) {
-                match $params.sort {
-                    Sorting::Oldest => query = query.filter(bsos::modified.ge(ts.as_datetime()?)),
-                    Sorting::Newest | Sorting::None => query = query.filter(bsos::modified.le(ts.as_datetime()?)),
-                    Sorting::Index => {}
+macro_rules! bsos_select {
+    ($self:expr, $params:expr, $selection:expr, $extra_rows:expr) => {{
+        let user_id = $params.user_id.legacy_id as i64;
+        let collection_id = $self._get_collection_id(&$params.collection).await?;
+        let limit = $params.limit.map(i64::from);
+
+        let mut query = bsos::table
+            .select($selection)
+            .filter(bsos::user_id.eq(user_id))
+            .filter(bsos::collection_id.eq(collection_id))
+            .filter(bsos::expiry.gt(now))
+            .into_boxed();
+
+        let cursor = $params.offset.as_ref().and_then(|o| o.cursor.clone());
+        if let Some(cursor) = cursor {
+            query = query.filter(after_cursor($params.sort, cursor)?);
+        } else if let Some(ts) = $params.offset.as_ref().and_then(|o| o.timestamp) {
+            match $params.sort {
+                Sorting::Oldest => query = query.filter(bsos::modified.ge(ts.as_datetime()?)),
+                Sorting::Newest | Sorting::None => {
+                    query = query.filter(bsos::modified.le(ts.as_datetime()?))
                 }
+                Sorting::Index => {}
             }
-            if let Some(older) = $params.older {
-                query = query.filter(bsos::modified.lt(older.as_datetime()?));
-            }
-            if let Some(newer) = $params.newer {
-                query = query.filter(bsos::modified.gt(newer.as_datetime()?));
-            }
-
-            if !$params.ids.is_empty() {
-                query = query.filter(bsos::bso_id.eq_any($params.ids));
-            }
+        }
+        if let Some(older) = $params.older {
+            query = query.filter(bsos::modified.lt(older.as_datetime()?));
+        }
+        if let Some(newer) = $params.newer {
+            query = query.filter(bsos::modified.gt(newer.as_datetime()?));
+        }
 
-            query = match $params.sort {
-                Sorting::Index => query.order((bsos::sortindex.desc(), bsos::bso_id.desc())),
-                Sorting::Newest | Sorting::None => query.order((bsos::modified.desc(), bsos::bso_id.desc())),
-                Sorting::Oldest => query.order((bsos::modified.asc(), bsos::bso_id.asc())),
-            };
+        if !$params.ids.is_empty() {
+            query = query.filter(bsos::bso_id.eq_any($params.ids));
+        }
 
-            // fetch an extra row to detect if there are more rows that
-            // match the query conditions. Negative limits will cause an error.
-            if let Some(limit) = limit {
-                query = query.limit(limit + 1);
+        query = match $params.sort {
+            Sorting::Index => {
+                query.order((bsos::sortindex.desc().nulls_last(), bsos::bso_id.desc()))
             }
-            let numeric_offset = $params.offset.as_ref().map_or(0, |offset| offset.offset as i64);
-            if numeric_offset != 0 {
-                // XXX: copy over this optimization:
-                // https://github.com/mozilla-services/server-syncstorage/blob/a0f8117/syncstorage/storage/sql/__init__.py#L404
-                query = query.offset(numeric_offset);
+            Sorting::Newest | Sorting::None => {
+                query.order((bsos::modified.desc(), bsos::bso_id.desc()))
             }
-            let mut items = query.load(&mut $self.conn).await?;
+            Sorting::Oldest => query.order((bsos::modified.asc(), bsos::bso_id.asc())),
+        };
 
-            // Note that "Non-existent collections do not trigger a 404 Not
-            // Found for backwards-compatibility reasons.": an empty list is
-            // returned in those cases
+        if let Some(limit) = limit {
+            query = query.limit(limit + $extra_rows);
+        }
+        // Legacy offsets still skip rows, cursors already filtered them out
+        let numeric_offset = $params
+            .offset
+            .as_ref()
+            .map_or(0, |offset| offset.offset as i64);
+        if numeric_offset != 0 {
+            query = query.offset(numeric_offset);
+        }
+        query
+    }};
+}
 
-            let limit = limit.unwrap_or(-1);
-            let did_overflow = limit >= 0 && items.len() > limit as usize;
-            if did_overflow {
-                items.pop();
-            }
-            (items, did_overflow, limit, numeric_offset)
+#[macro_export]
+macro_rules! bsos_query {
+    ($self:expr, $params:expr, $selection:expr) => {{
+        let limit = $params.limit.map(i64::from);
+        // fetch an extra row to detect if there are more rows that
+        // match the query conditions. Negative limits will cause an error.
+        let mut items = $crate::bsos_select!($self, $params, $selection, 1)
+            .load(&mut $self.conn)
+            .await?;
+
+        // Note that "Non-existent collections do not trigger a 404 Not
+        // Found for backwards-compatibility reasons.": an empty list is
+        // returned in those cases
+
+        let limit = limit.unwrap_or(-1);
+        let did_overflow = limit >= 0 && items.len() > limit as usize;
+        if did_overflow {
+            items.pop();
         }
-    }
+        (items, did_overflow)
+    }};
+}
+
+/// Keyset predicate matching the BSOs that follow `cursor` in `sort` order
+fn after_cursor(
+    sort: Sorting,
+    cursor: params::Cursor,
+) -> DbResult<Box<dyn BoxableExpression<bsos::table, Pg, SqlType = Nullable<Bool>>>> {
+    let modified = cursor.modified.as_datetime()?;
+    let predicate: Box<dyn BoxableExpression<bsos::table, Pg, SqlType = Nullable<Bool>>> = after_cursor!(
+        sort,
+        cursor,
+        modified,
+        bsos::sortindex,
+        bsos::bso_id,
+        bsos::modified
+    );
+    Ok(predicate)
 }
@@ -24,6 +24,8 @@ pub struct Batch {
     pub collection_id: i32,
     pub batch_id: Uuid,
     pub expiry: DateTime<Utc>,
+    pub total_records: i64,
+    pub total_bytes: i64,
 }
 
 #[derive(Queryable, Debug, Identifiable, Insertable)]
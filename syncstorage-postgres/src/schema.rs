@@ -16,6 +16,8 @@ diesel::table! {
         collection_id -> Int4,
         batch_id -> Uuid,
         expiry -> Timestamptz,
+        total_records -> Int8,
+        total_bytes -> Int8,
     }
 }
 
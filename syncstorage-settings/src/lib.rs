@@ -92,6 +92,15 @@ pub struct Settings {
     /// Server-enforced limits for request payloads.
     pub limits: ServerLimits,
 
+    /// Per-user request rate limiting.
+    pub rate_limit: RateLimit,
+
+    /// Server-driven client backoff (maintenance mode).
+    pub backoff: Backoff,
+
+    /// Hawk timestamp and nonce checks.
+    pub hawk: Hawk,
+
     pub statsd_label: String,
 
     pub enable_quota: bool,
@@ -109,6 +118,20 @@ pub struct Settings {
     /// Percentage of `lbheartbeat_ttl` time to "jitter" (adds additional,
     /// randomized time)
     pub lbheartbeat_ttl_jitter: u32,
+
+    /// Interval for the background task purging expired BSOs and batches, in
+    /// seconds. Disabled when unset (e.g. when purged by an external job).
+    pub purge_expired_interval: Option<u32>,
+    /// Max number of rows the purge task deletes from a table per transaction.
+    pub purge_expired_batch_size: u32,
+
+    /// Bearer token for the `/__admin__` endpoints (e.g. user export).
+    /// Disabled when unset.
+    pub admin_token: Option<String>,
+
+    /// Refuse writes while continuing to serve reads (e.g. while the node's
+    /// being migrated). May be toggled at runtime via `/__admin__/read_only`.
+    pub read_only: bool,
 }
 
 impl Default for Settings {
@@ -126,6 +149,9 @@ impl Default for Settings {
             database_use_test_transactions: false,
             database_spanner_route_to_leader: false,
             limits: ServerLimits::default(),
+            rate_limit: RateLimit::default(),
+            backoff: Backoff::default(),
+            hawk: Hawk::default(),
             statsd_label: "syncstorage".to_string(),
             enable_quota: false,
             enforce_quota: false,
@@ -134,6 +160,10 @@ impl Default for Settings {
             enabled: true,
             lbheartbeat_ttl: None,
             lbheartbeat_ttl_jitter: 25,
+            purge_expired_interval: None,
+            purge_expired_batch_size: 1000,
+            admin_token: None,
+            read_only: false,
         }
     }
 }
@@ -210,3 +240,104 @@ impl Default for ServerLimits {
         }
     }
 }
+
+/// Per-user request rate limiting, applied as a token bucket keyed on the
+/// Hawk authenticated uid (and optionally the collection and method).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RateLimit {
+    /// Sustained number of requests allowed per minute. Disabled when unset.
+    pub requests_per_minute: Option<u32>,
+
+    /// Number of requests allowed in a burst before throttling starts.
+    pub burst: u32,
+
+    /// Whether each collection is limited separately.
+    pub per_collection: bool,
+
+    /// Whether each HTTP method is limited separately.
+    pub per_method: bool,
+
+    /// `X-Weave-Backoff` (in seconds) sent with throttled responses.
+    pub backoff: u32,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            requests_per_minute: None,
+            burst: 60,
+            per_collection: false,
+            per_method: false,
+            backoff: 60,
+        }
+    }
+}
+
+/// Server-driven `X-Weave-Backoff`, asking clients to sync less often (e.g.
+/// for maintenance). May be adjusted at runtime via `/__admin__/backoff`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Backoff {
+    /// Whether backoff headers are sent with matching responses.
+    pub enabled: bool,
+
+    /// `X-Weave-Backoff` (and `Retry-After` for 503s) value, in seconds.
+    pub seconds: u32,
+
+    /// Fraction (0.0 - 1.0) of matching responses sent backoff headers.
+    pub fraction: f64,
+
+    /// Only send backoff headers for these collections (all when empty).
+    pub collections: Vec<String>,
+
+    /// Only send backoff headers to these client platforms (all when empty):
+    /// `firefoxdesktop`, `fenix`, `firefoxios` or `other`.
+    pub platforms: Vec<String>,
+
+    /// Send backoff headers with every response while the database pool is
+    /// saturated (requests are waiting for a connection), regardless of
+    /// `enabled`.
+    pub when_pool_saturated: bool,
+
+    /// `X-Weave-Backoff` value, in seconds, sent while the pool is saturated.
+    pub pool_saturated_seconds: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            seconds: 1800,
+            fraction: 1.0,
+            collections: vec![],
+            platforms: vec![],
+            when_pool_saturated: true,
+            pool_saturated_seconds: 60,
+        }
+    }
+}
+
+/// Hawk replay protection: requests must be timestamped close to the server's
+/// clock, and their nonces are remembered for as long as that timestamp is
+/// accepted.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Hawk {
+    /// Max difference between a request's timestamp and the server's clock,
+    /// in seconds.
+    pub timestamp_skew: u32,
+
+    /// Number of recently seen nonces remembered (per node) to reject
+    /// replayed requests. Disabled when 0.
+    pub nonce_cache_size: usize,
+}
+
+impl Default for Hawk {
+    fn default() -> Self {
+        Self {
+            timestamp_skew: 60,
+            nonce_cache_size: 100_000,
+        }
+    }
+}
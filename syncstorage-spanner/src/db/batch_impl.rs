@@ -126,7 +126,7 @@ impl BatchDb for SpannerDb {
         };
         let batch = self
             .sql(
-                "SELECT 1
+                "SELECT COALESCE(total_records, 0), COALESCE(total_bytes, 0)
                    FROM batches
                   WHERE fxa_uid = @fxa_uid
                     AND fxa_kid = @fxa_kid
@@ -139,9 +139,21 @@ impl BatchDb for SpannerDb {
             .param_types(sqlparam_types)
             .execute(&self.conn)?
             .one_or_none()
-            .await?
-            .map(move |_| params::Batch { id: params.id });
-        Ok(batch)
+            .await?;
+        let Some(row) = batch else {
+            return Ok(None);
+        };
+        let total = |i: usize| {
+            row[i]
+                .get_string_value()
+                .parse::<u64>()
+                .map_err(|e| DbError::integrity(e.to_string()))
+        };
+        Ok(Some(params::Batch {
+            id: params.id,
+            total_records: total(0)?,
+            total_bytes: total(1)?,
+        }))
     }
 
     async fn delete_batch(&mut self, params: params::DeleteBatch) -> DbResult<()> {
@@ -357,6 +369,32 @@ pub async fn do_append(
     .param_types(sqlparam_types)
     .execute_dml(&db.conn)
     .await?;
+
+    // Keep the batch's running totals (enforced against the
+    // `max_total_records`/`max_total_bytes` limits). These columns were added
+    // after the table, so treat NULL as 0
+    let (sqlparams, sqlparam_types) = params! {
+        "fxa_uid" => user_id.fxa_uid.clone(),
+        "fxa_kid" => user_id.fxa_kid.clone(),
+        "collection_id" => collection_id,
+        "batch_id" => batch.id.clone(),
+        "total_records" => row_count as i64,
+        "total_bytes" => running_size as i64,
+    };
+    db.sql(
+        "UPDATE batches
+            SET total_records = COALESCE(total_records, 0) + @total_records,
+                total_bytes = COALESCE(total_bytes, 0) + @total_bytes
+          WHERE fxa_uid = @fxa_uid
+            AND fxa_kid = @fxa_kid
+            AND collection_id = @collection_id
+            AND batch_id = @batch_id",
+    )
+    .await?
+    .params(sqlparams)
+    .param_types(sqlparam_types)
+    .execute_dml(&db.conn)
+    .await?;
     db.metrics
         .count_with_tags("storage.spanner.batch.upsert", row_count as i64, tags);
 
@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 
 use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
 use google_cloud_rust_raw::spanner::v1::{
     spanner::{BeginTransactionRequest, CommitRequest, RollbackRequest},
     transaction::{
@@ -10,7 +11,9 @@ use google_cloud_rust_raw::spanner::v1::{
     type_pb::TypeCode,
 };
 use syncserver_common::MAX_SPANNER_LOAD_SIZE;
-use syncstorage_db_common::{Db, error::DbErrorIntrospect, params, results, util::SyncTimestamp};
+use syncstorage_db_common::{
+    BsoStream, Db, UserIdentifier, error::DbErrorIntrospect, params, results, util::SyncTimestamp,
+};
 
 use super::{
     CollectionLock, SpannerDb, TOMBSTONE,
@@ -646,8 +649,6 @@ impl Db for SpannerDb {
                AND collection_id = @collection_id
                AND expiry > CURRENT_TIMESTAMP()";
         let limit = params.limit.map(i64::from).unwrap_or(-1);
-        let params::Offset { offset, timestamp } = params.offset.unwrap_or_default();
-        let sort = params.sort;
 
         let mut streaming = self.bsos_query(query, params).await?;
         let mut bsos = vec![];
@@ -664,8 +665,8 @@ impl Db for SpannerDb {
 
         let next_offset = if limit >= 0 && bsos.len() > limit as usize {
             bsos.pop();
-            let modifieds: Vec<i64> = bsos.iter().map(|r| r.modified.as_i64()).collect();
-            self.encode_next_offset(sort, offset, timestamp.map(|t| t.as_i64()), modifieds)
+            bsos.last()
+                .map(|bso| params::Offset::from(params::Cursor::from(bso)).to_string())
         } else {
             None
         };
@@ -676,13 +677,35 @@ impl Db for SpannerDb {
         })
     }
 
+    async fn get_bsos_stream(
+        &mut self,
+        params: params::GetBsos,
+    ) -> DbResult<BsoStream<'_, DbError>> {
+        let query = "\
+            SELECT bso_id, sortindex, payload, modified, expiry
+              FROM bsos
+             WHERE fxa_uid = @fxa_uid
+               AND fxa_kid = @fxa_kid
+               AND collection_id = @collection_id
+               AND expiry > CURRENT_TIMESTAMP()";
+        // bsos_query fetches an extra row to detect overflow: drop it here
+        let limit = params.limit.map_or(usize::MAX, |limit| limit as usize);
+
+        let streaming = self.bsos_query(query, params).await?;
+        let bsos = stream::try_unfold(streaming, |mut streaming| async move {
+            match streaming.try_next().await? {
+                Some(row) => Ok(Some((bso_from_row(row)?, streaming))),
+                None => Ok(None),
+            }
+        });
+        Ok(bsos.take(limit).boxed_local())
+    }
+
     async fn get_bso_ids(&mut self, params: params::GetBsos) -> DbResult<results::GetBsoIds> {
         let limit = params.limit.map(i64::from).unwrap_or(-1);
-        let params::Offset { offset, timestamp } = params.offset.unwrap_or_default();
-        let sort = params.sort;
 
         let query = "\
-            SELECT bso_id, modified
+            SELECT bso_id, sortindex, modified
               FROM bsos
              WHERE fxa_uid = @fxa_uid
                AND fxa_kid = @fxa_kid
@@ -690,11 +713,22 @@ impl Db for SpannerDb {
                AND expiry > CURRENT_TIMESTAMP()";
         let mut stream = self.bsos_query(query, params).await?;
 
-        let mut ids = vec![];
-        let mut modifieds = vec![];
+        let mut cursors = vec![];
         while let Some(mut row) = stream.try_next().await? {
-            ids.push(row[0].take_string_value());
-            modifieds.push(sync_timestamp_from_rfc3339(row[1].get_string_value())?.as_i64());
+            cursors.push(params::Cursor {
+                sortindex: if row[1].has_null_value() {
+                    None
+                } else {
+                    Some(
+                        row[1]
+                            .get_string_value()
+                            .parse::<i32>()
+                            .map_err(|e| DbError::integrity(e.to_string()))?,
+                    )
+                },
+                modified: sync_timestamp_from_rfc3339(row[2].get_string_value())?,
+                id: row[0].take_string_value(),
+            });
         }
         // NOTE: when bsos.len() == 0, server-syncstorage (the Python impl)
         // makes an additional call to get_collection_timestamp to potentially
@@ -703,13 +737,15 @@ impl Db for SpannerDb {
         // backwards compat.:
         // https://bugzilla.mozilla.org/show_bug.cgi?id=963332
 
-        let next_offset = if limit >= 0 && ids.len() > limit as usize {
-            ids.pop();
-            modifieds.pop();
-            self.encode_next_offset(sort, offset, timestamp.map(|t| t.as_i64()), modifieds)
+        let next_offset = if limit >= 0 && cursors.len() > limit as usize {
+            cursors.pop();
+            cursors
+                .last()
+                .map(|cursor| params::Offset::from(cursor.clone()).to_string())
         } else {
             None
         };
+        let ids = cursors.into_iter().map(|cursor| cursor.id).collect();
 
         Ok(results::GetBsoIds {
             items: ids,
@@ -881,6 +917,111 @@ impl Db for SpannerDb {
         }
     }
 
+    async fn get_user_ids(&mut self, params: params::GetUserIds) -> DbResult<results::GetUserIds> {
+        let after = params.after.unwrap_or_default();
+        let (sqlparams, mut sqlparam_types) = params! {
+            "fxa_uid" => after.fxa_uid,
+            "fxa_kid" => after.fxa_kid,
+            "limit" => params.limit,
+            "pretouch_ts" => PRETOUCH_TS.to_owned(),
+        };
+        sqlparam_types.insert("pretouch_ts".to_owned(), as_type(TypeCode::TIMESTAMP));
+        let mut streaming = self
+            .sql(
+                "SELECT DISTINCT fxa_uid, fxa_kid
+                   FROM user_collections
+                  WHERE (fxa_uid > @fxa_uid OR (fxa_uid = @fxa_uid AND fxa_kid > @fxa_kid))
+                    AND modified > @pretouch_ts
+                  ORDER BY fxa_uid, fxa_kid
+                  LIMIT @limit",
+            )
+            .await?
+            .params(sqlparams)
+            .param_types(sqlparam_types)
+            .execute(&self.conn)?;
+        let mut user_ids = vec![];
+        while let Some(mut row) = streaming.try_next().await? {
+            user_ids.push(UserIdentifier {
+                fxa_uid: row[0].take_string_value(),
+                fxa_kid: row[1].take_string_value(),
+                ..Default::default()
+            });
+        }
+        Ok(user_ids)
+    }
+
+    async fn import_bsos(&mut self, params: params::ImportBsos) -> DbResult<results::ImportBsos> {
+        let user_id = params.user_id;
+        let collection_id = self.get_or_create_collection_id(&params.collection).await?;
+
+        // update_user_collection_quotas records the session's timestamp. It
+        // also ensures the parent user_collections row exists before writing
+        // to bsos
+        self.session.timestamp = Some(params.modified);
+        self.update_user_collection_quotas(&user_id, collection_id)
+            .await?;
+        self.import_bsos_dml(&user_id, collection_id, params.bsos)
+            .await?;
+        if self.quota.enabled {
+            self.update_user_collection_quotas(&user_id, collection_id)
+                .await?;
+        }
+        Ok(params.modified)
+    }
+
+    async fn purge_expired(
+        &mut self,
+        params: params::PurgeExpired,
+    ) -> DbResult<results::PurgeExpired> {
+        let bso_key = [
+            ("fxa_uid", TypeCode::STRING),
+            ("fxa_kid", TypeCode::STRING),
+            ("collection_id", TypeCode::INT64),
+            ("bso_id", TypeCode::STRING),
+        ];
+        let bsos = self
+            .purge_expired_rows(
+                "SELECT fxa_uid, fxa_kid, collection_id, bso_id
+                   FROM bsos
+                  WHERE expiry < CURRENT_TIMESTAMP()
+                  LIMIT @limit",
+                "DELETE FROM bsos
+                  WHERE STRUCT<fxa_uid STRING, fxa_kid STRING, collection_id INT64, bso_id STRING>
+                        (fxa_uid, fxa_kid, collection_id, bso_id) IN UNNEST(@keys)",
+                &bso_key,
+                params.limit,
+            )
+            .await?;
+
+        let batch_key = [
+            ("fxa_uid", TypeCode::STRING),
+            ("fxa_kid", TypeCode::STRING),
+            ("collection_id", TypeCode::INT64),
+            ("batch_id", TypeCode::STRING),
+        ];
+        // Also deletes child batch_bsos rows (INTERLEAVE IN PARENT batches ON
+        // DELETE CASCADE)
+        let batches = self
+            .purge_expired_rows(
+                "SELECT fxa_uid, fxa_kid, collection_id, batch_id
+                   FROM batches
+                  WHERE expiry < CURRENT_TIMESTAMP()
+                  LIMIT @limit",
+                "DELETE FROM batches
+                  WHERE STRUCT<fxa_uid STRING, fxa_kid STRING, collection_id INT64, batch_id STRING>
+                        (fxa_uid, fxa_kid, collection_id, batch_id) IN UNNEST(@keys)",
+                &batch_key,
+                params.limit,
+            )
+            .await?;
+        self.metrics.incr("storage.spanner.purge_expired");
+        Ok(results::PurgeExpired {
+            bsos,
+            batches,
+            ..Default::default()
+        })
+    }
+
     #[cfg(debug_assertions)]
     async fn create_collection(&mut self, name: &str) -> DbResult<i32> {
         self._create_collection(name).await
@@ -13,7 +13,7 @@ use syncserver_common::Metrics;
 use syncstorage_db_common::{
     DEFAULT_BSO_TTL, Db, FIRST_CUSTOM_COLLECTION_ID, Sorting, UserIdentifier,
     error::DbErrorIntrospect,
-    params,
+    params, results,
     util::{SyncTimestamp, to_rfc3339},
 };
 use syncstorage_settings::Quota;
@@ -25,7 +25,7 @@ use crate::{
 };
 use support::{
     ExecuteSqlRequestBuilder, IntoSpannerValue, StreamedResultSetAsync, as_type, null_value,
-    struct_type_field,
+    struct_array_param, struct_type_field,
 };
 
 mod batch_impl;
@@ -391,6 +391,41 @@ impl SpannerDb {
             sqlparams.insert("ids".to_owned(), params.ids.into_spanner_value());
         }
 
+        if let Some(cursor) = params.offset.as_ref().and_then(|o| o.cursor.clone()) {
+            let condition = match (params.sort, cursor.sortindex) {
+                (Sorting::Index, Some(sortindex)) => {
+                    sqlparams.insert(
+                        "cursor_sortindex".to_owned(),
+                        sortindex.into_spanner_value(),
+                    );
+                    sqlparam_types.insert("cursor_sortindex".to_owned(), sortindex.spanner_type());
+                    // NULL sortindexes sort last under `sortindex DESC`
+                    "(sortindex < @cursor_sortindex
+                      OR (sortindex = @cursor_sortindex AND bso_id < @cursor_id)
+                      OR sortindex IS NULL)"
+                }
+                (Sorting::Index, None) => "(sortindex IS NULL AND bso_id < @cursor_id)",
+                (Sorting::Oldest, _) => {
+                    "(modified > @cursor_modified
+                      OR (modified = @cursor_modified AND bso_id > @cursor_id))"
+                }
+                (Sorting::Newest | Sorting::None, _) => {
+                    "(modified < @cursor_modified
+                      OR (modified = @cursor_modified AND bso_id < @cursor_id))"
+                }
+            };
+            if params.sort != Sorting::Index {
+                sqlparams.insert(
+                    "cursor_modified".to_owned(),
+                    cursor.modified.as_rfc3339()?.into_spanner_value(),
+                );
+                sqlparam_types.insert("cursor_modified".to_owned(), as_type(TypeCode::TIMESTAMP));
+            }
+            sqlparam_types.insert("cursor_id".to_owned(), cursor.id.spanner_type());
+            sqlparams.insert("cursor_id".to_owned(), cursor.id.into_spanner_value());
+            query = format!("{} AND {}", query, condition);
+        }
+
         // issue559: Dead code (timestamp always None)
         /*
         if let Some(timestamp) = offset.clone().unwrap_or_default().timestamp {
@@ -432,22 +467,14 @@ impl SpannerDb {
             sqlparam_types.insert("newer".to_string(), as_type(TypeCode::TIMESTAMP));
         }
 
-        if self.stabilize_bsos_sort_order() {
-            query = match params.sort {
-                Sorting::Index => format!("{} ORDER BY sortindex DESC, bso_id DESC", query),
-                Sorting::Newest | Sorting::None => {
-                    format!("{} ORDER BY modified DESC, bso_id DESC", query)
-                }
-                Sorting::Oldest => format!("{} ORDER BY modified ASC, bso_id ASC", query),
-            };
-        } else {
-            query = match params.sort {
-                Sorting::Index => format!("{} ORDER BY sortindex DESC", query),
-                Sorting::Newest => format!("{} ORDER BY modified DESC", query),
-                Sorting::Oldest => format!("{} ORDER BY modified ASC", query),
-                _ => query,
-            };
-        }
+        // Keyset cursors rely on a total order, so bso_id always breaks ties
+        query = match params.sort {
+            Sorting::Index => format!("{} ORDER BY sortindex DESC, bso_id DESC", query),
+            Sorting::Newest | Sorting::None => {
+                format!("{} ORDER BY modified DESC, bso_id DESC", query)
+            }
+            Sorting::Oldest => format!("{} ORDER BY modified ASC, bso_id ASC", query),
+        };
 
         if let Some(limit) = params.limit {
             // fetch an extra row to detect if there are more rows that match
@@ -462,7 +489,8 @@ impl SpannerDb {
             query = format!("{} LIMIT {}", query, i64::MAX - offset.offset as i64);
         };
 
-        if let Some(offset) = params.offset {
+        // Legacy offsets still skip rows, cursors already filtered them out
+        if let Some(offset) = params.offset.filter(|offset| offset.offset > 0) {
             query = format!("{} OFFSET {}", query, offset.offset);
         }
         self.sql(&query)
@@ -472,64 +500,6 @@ impl SpannerDb {
             .execute(&self.conn)
     }
 
-    /// Whether to stabilize the sort order for get_bsos_async
-    fn stabilize_bsos_sort_order(&self) -> bool {
-        self.conn.settings.using_spanner_emulator()
-    }
-
-    pub fn encode_next_offset(
-        &self,
-        _sort: Sorting,
-        offset: u64,
-        _timestamp: Option<i64>,
-        modifieds: Vec<i64>,
-    ) -> Option<String> {
-        // issue559: Use a simple numeric offset everwhere as previously for
-        // now: was previously a value of "limit + offset", modifieds.len()
-        // always equals limit
-        Some(
-            params::Offset {
-                offset: offset + modifieds.len() as u64,
-                timestamp: None,
-            }
-            .to_string(),
-        )
-        /*
-        let mut calc_offset = 1;
-        let mut i = (modifieds.len() as i64) - 2;
-
-        let prev_bound = match sort {
-            Sorting::Index => {
-                // Use a simple numeric offset for sortindex ordering.
-                return Some(
-                    params::Offset {
-                        offset: offset + modifieds.len() as u64,
-                        timestamp: None,
-                    }
-                    .to_string(),
-                );
-            }
-            Sorting::None => timestamp,
-            Sorting::Newest => timestamp,
-            Sorting::Oldest => timestamp,
-        };
-        // Find an appropriate upper bound for faster timestamp ordering.
-        let bound = *modifieds.last().unwrap_or(&0);
-        // Count how many previous items have that same timestamp, and hence
-        // will need to be skipped over.  The number of matches here is limited
-        // by upload batch size.
-        while i >= 0 && modifieds[i as usize] == bound {
-            calc_offset += 1;
-            i -= 1;
-        }
-        if i < 0 && prev_bound.is_some() && prev_bound.unwrap() == bound {
-            calc_offset += offset;
-        }
-
-        Some(format!("{}:{}", bound, calc_offset))
-        */
-    }
-
     pub fn quota_error(&self, collection: &str) -> DbError {
         // return the over quota error.
         let mut tags = HashMap::default();
@@ -645,6 +615,120 @@ impl SpannerDb {
         Ok(())
     }
 
+    /// Delete a batch of expired rows by their primary keys.
+    ///
+    /// Spanner's DELETE has no LIMIT: `select_sql` selects up to `@limit`
+    /// keys (of `key_fields`), which `delete_sql` then deletes via
+    /// `UNNEST(@keys)`.
+    async fn purge_expired_rows(
+        &mut self,
+        select_sql: &str,
+        delete_sql: &str,
+        key_fields: &[(&str, TypeCode)],
+        limit: u32,
+    ) -> DbResult<u64> {
+        let (sqlparams, sqlparam_types) = params! {
+            "limit" => limit,
+        };
+        let mut streaming = self
+            .sql(select_sql)
+            .await?
+            .params(sqlparams)
+            .param_types(sqlparam_types)
+            .execute(&self.conn)?;
+        let mut keys = vec![];
+        while let Some(row) = streaming.try_next().await? {
+            keys.push(row);
+        }
+        if keys.is_empty() {
+            return Ok(0);
+        }
+
+        let (values, param_type) = struct_array_param(key_fields, keys);
+        let mut sqlparams = HashMap::new();
+        let mut sqlparam_types = HashMap::new();
+        sqlparams.insert("keys".to_owned(), values);
+        sqlparam_types.insert("keys".to_owned(), param_type);
+        let deleted = self
+            .sql(delete_sql)
+            .await?
+            .params(sqlparams)
+            .param_types(sqlparam_types)
+            .execute_dml(&self.conn)
+            .await?;
+        Ok(deleted as u64)
+    }
+
+    /// Write N bsos verbatim to the same `(fxa_uid, fxa_kid, collection_id)`
+    /// in an `INSERT OR UPDATE`
+    async fn import_bsos_dml(
+        &mut self,
+        user_id: &UserIdentifier,
+        collection_id: i32,
+        bsos: Vec<results::GetBso>,
+    ) -> DbResult<()> {
+        if bsos.is_empty() {
+            return Ok(());
+        }
+
+        let rows = bsos
+            .into_iter()
+            .map(|bso| {
+                let sortindex = bso
+                    .sortindex
+                    .map(IntoSpannerValue::into_spanner_value)
+                    .unwrap_or_else(null_value);
+                Ok(vec![
+                    bso.id.into_spanner_value(),
+                    sortindex,
+                    bso.payload.into_spanner_value(),
+                    bso.modified.as_rfc3339()?.into_spanner_value(),
+                    to_rfc3339(bso.expiry)?.into_spanner_value(),
+                ])
+            })
+            .collect::<DbResult<Vec<_>>>()?;
+        let (values, param_type) = struct_array_param(
+            &[
+                ("bso_id", TypeCode::STRING),
+                ("sortindex", TypeCode::INT64),
+                ("payload", TypeCode::STRING),
+                ("modified", TypeCode::TIMESTAMP),
+                ("expiry", TypeCode::TIMESTAMP),
+            ],
+            rows,
+        );
+
+        let (mut sqlparams, mut sqlparam_types) = params! {
+            "fxa_uid" => user_id.fxa_uid.clone(),
+            "fxa_kid" => user_id.fxa_kid.clone(),
+            "collection_id" => collection_id,
+        };
+        sqlparams.insert("bsos".to_owned(), values);
+        sqlparam_types.insert("bsos".to_owned(), param_type);
+
+        self.sql(
+            "INSERT OR UPDATE INTO bsos
+                 (fxa_uid, fxa_kid, collection_id, bso_id,
+                  sortindex, payload, modified, expiry)
+             SELECT
+                 @fxa_uid,
+                 @fxa_kid,
+                 @collection_id,
+                 incoming.bso_id,
+                 incoming.sortindex,
+                 incoming.payload,
+                 incoming.modified,
+                 incoming.expiry
+               FROM UNNEST(@bsos) AS incoming",
+        )
+        .await?
+        .params(sqlparams)
+        .param_types(sqlparam_types)
+        .execute_dml(&self.conn)
+        .await?;
+        Ok(())
+    }
+
     /// Write N bsos to the same `(fxa_uid, fxa_kid, collection_id)` in an `INSERT OR UPDATE`
     async fn post_bsos_dml(
         &mut self,
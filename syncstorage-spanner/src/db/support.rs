@@ -2,7 +2,7 @@ use std::collections::HashMap;
 
 use google_cloud_rust_raw::spanner::v1::{
     spanner::ExecuteSqlRequest,
-    type_pb::{StructType_Field, Type, TypeCode},
+    type_pb::{StructType, StructType_Field, Type, TypeCode},
 };
 
 use protobuf::{
@@ -44,6 +44,14 @@ impl IntoSpannerValue for i32 {
     }
 }
 
+impl IntoSpannerValue for i64 {
+    const TYPE_CODE: TypeCode = TypeCode::INT64;
+
+    fn into_spanner_value(self) -> Value {
+        self.to_string().into_spanner_value()
+    }
+}
+
 impl IntoSpannerValue for u32 {
     const TYPE_CODE: TypeCode = TypeCode::INT64;
 
@@ -118,6 +126,40 @@ pub fn null_value() -> Value {
     value
 }
 
+/// Build an `ARRAY<STRUCT<..>>` param (and its type) from rows of field
+/// values, e.g. for use with `UNNEST(@param)`
+pub fn struct_array_param(fields: &[(&str, TypeCode)], rows: Vec<Vec<Value>>) -> (Value, Type) {
+    let rows = rows
+        .into_iter()
+        .map(|row| {
+            let mut list_value = ListValue::new();
+            list_value.set_values(RepeatedField::from_vec(row));
+            let mut value = Value::new();
+            value.set_list_value(list_value);
+            value
+        })
+        .collect();
+    let mut list_values = ListValue::new();
+    list_values.set_values(RepeatedField::from_vec(rows));
+    let mut values = Value::new();
+    values.set_list_value(list_values);
+
+    let mut struct_type = StructType::new();
+    struct_type.set_fields(RepeatedField::from_vec(
+        fields
+            .iter()
+            .map(|(name, field_type)| struct_type_field(name, *field_type))
+            .collect(),
+    ));
+    let mut array_type = Type::new();
+    array_type.set_code(TypeCode::STRUCT);
+    array_type.set_struct_type(struct_type);
+    let mut param_type = Type::new();
+    param_type.set_code(TypeCode::ARRAY);
+    param_type.set_array_element_type(array_type);
+    (values, param_type)
+}
+
 #[derive(Default)]
 pub struct ExecuteSqlRequestBuilder {
     execute_sql: ExecuteSqlRequest,
@@ -87,22 +87,6 @@ impl SpannerDbPool {
             self.quota,
         ))
     }
-
-    /// Spawn a task to periodically evict idle connections. Calls wrapper sweeper fn
-    ///  to use pool.retain, retaining objects only if they are shorter in duration than
-    ///  defined max_idle.
-    pub fn spawn_sweeper(&self, interval: Duration) {
-        let Some(max_idle) = self.pool.manager().settings.max_idle else {
-            return;
-        };
-        let pool = self.pool.clone();
-        rt::spawn(async move {
-            loop {
-                sweeper(&pool, Duration::from_secs(max_idle.into()));
-                rt::time::sleep(interval).await;
-            }
-        });
-    }
 }
 
 /// Sweeper to retain only the objects specified within the closure.
@@ -130,6 +114,22 @@ impl DbPool for SpannerDbPool {
         super::db::validate_batch_id(&id)
     }
 
+    /// Spawn a task to periodically evict idle connections. Calls wrapper sweeper fn
+    ///  to use pool.retain, retaining objects only if they are shorter in duration than
+    ///  defined max_idle.
+    fn spawn_sweeper(&self, interval: Duration) {
+        let Some(max_idle) = self.pool.manager().settings.max_idle else {
+            return;
+        };
+        let pool = self.pool.clone();
+        rt::spawn(async move {
+            loop {
+                sweeper(&pool, Duration::from_secs(max_idle.into()));
+                rt::time::sleep(interval).await;
+            }
+        });
+    }
+
     fn box_clone(&self) -> Box<dyn DbPool<Error = Self::Error>> {
         Box::new(self.clone())
     }
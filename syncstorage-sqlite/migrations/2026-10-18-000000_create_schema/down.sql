@@ -0,0 +1,7 @@
+DROP TABLE batch_upload_items;
+DROP TABLE batch_uploads;
+DROP TABLE user_collections;
+DROP TABLE collections;
+DROP INDEX bso_usr_col_mod_idx;
+DROP INDEX bso_ttl_idx;
+DROP TABLE bso;
@@ -0,0 +1,77 @@
+-- The SQLite schema mirrors the MySQL one (including its legacy column
+-- names) so the two backends can share query shapes.
+
+CREATE TABLE bso (
+    userid INTEGER NOT NULL,
+    collection INTEGER NOT NULL,
+    id TEXT NOT NULL,
+
+    sortindex INTEGER,
+
+    payload TEXT NOT NULL,
+
+    -- last modified time in milliseconds since epoch
+    modified INTEGER NOT NULL,
+    -- expiration in milliseconds since epoch
+    ttl INTEGER NOT NULL DEFAULT 3153600000000,
+
+    PRIMARY KEY (userid, collection, id)
+);
+
+CREATE INDEX bso_ttl_idx ON bso (ttl);
+CREATE INDEX bso_usr_col_mod_idx ON bso (userid, collection, modified);
+
+CREATE TABLE collections (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    name TEXT UNIQUE NOT NULL
+);
+
+-- Insert Standard Collections.
+-- These are the 13 standard collections that are expected to exist by clients.
+-- The IDs are fixed.
+INSERT INTO collections (id, name) VALUES
+    ( 1, 'clients'),
+    ( 2, 'crypto'),
+    ( 3, 'forms'),
+    ( 4, 'history'),
+    ( 5, 'keys'),
+    ( 6, 'meta'),
+    ( 7, 'bookmarks'),
+    ( 8, 'prefs'),
+    ( 9, 'tabs'),
+    (10, 'passwords'),
+    (11, 'addons'),
+    (12, 'addresses'),
+    (13, 'creditcards');
+
+-- Reserve space for additions to the standard collections: custom
+-- collections are allocated ids beginning at 101.
+UPDATE sqlite_sequence SET seq = 100 WHERE name = 'collections';
+
+CREATE TABLE user_collections (
+    userid INTEGER NOT NULL,
+    collection INTEGER NOT NULL,
+    -- last modified time in milliseconds since epoch
+    last_modified INTEGER NOT NULL,
+    count INTEGER NOT NULL DEFAULT 0,
+    total_bytes INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (userid, collection)
+);
+
+CREATE TABLE batch_uploads (
+    batch INTEGER NOT NULL,
+    userid INTEGER NOT NULL,
+    collection INTEGER NOT NULL,
+    PRIMARY KEY (batch, userid)
+);
+
+CREATE TABLE batch_upload_items (
+    batch INTEGER NOT NULL,
+    userid INTEGER NOT NULL,
+    id TEXT NOT NULL,
+    sortindex INTEGER DEFAULT NULL,
+    payload TEXT,
+    payload_size INTEGER DEFAULT NULL,
+    ttl_offset INTEGER DEFAULT NULL,
+    PRIMARY KEY (batch, userid, id)
+);
@@ -0,0 +1,2 @@
+ALTER TABLE batch_uploads DROP COLUMN total_bytes;
+ALTER TABLE batch_uploads DROP COLUMN total_records;
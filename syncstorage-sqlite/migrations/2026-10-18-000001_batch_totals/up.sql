@@ -0,0 +1,2 @@
+ALTER TABLE batch_uploads ADD COLUMN total_records INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE batch_uploads ADD COLUMN total_bytes INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,13 @@
+INSERT INTO bso (userid, collection, id, modified, sortindex, ttl, payload)
+SELECT
+       userid,
+       ?,
+       id,
+       ?,
+       sortindex,
+       COALESCE((ttl_offset * 1000) + ?, ?),
+       COALESCE(payload, '')
+  FROM batch_upload_items
+ WHERE batch = ?
+   AND userid = ?
+    ON CONFLICT (userid, collection, id) DO NOTHING
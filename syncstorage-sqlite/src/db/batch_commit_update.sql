@@ -0,0 +1,11 @@
+UPDATE bso
+   SET modified = ?,
+       sortindex = COALESCE(batch_upload_items.sortindex, bso.sortindex),
+       ttl = COALESCE((batch_upload_items.ttl_offset * 1000) + ?, bso.ttl),
+       payload = COALESCE(batch_upload_items.payload, bso.payload)
+  FROM batch_upload_items
+ WHERE batch_upload_items.batch = ?
+   AND batch_upload_items.userid = ?
+   AND bso.userid = batch_upload_items.userid
+   AND bso.collection = ?
+   AND bso.id = batch_upload_items.id
@@ -0,0 +1,252 @@
+use base64::Engine;
+
+use async_trait::async_trait;
+use diesel::{
+    self, ExpressionMethods, OptionalExtension, QueryDsl, insert_into,
+    result::{DatabaseErrorKind::UniqueViolation, Error as DieselError},
+    sql_query,
+    sql_types::{BigInt, Integer, Nullable, Text},
+};
+use diesel_async::RunQueryDsl;
+use syncstorage_db_common::{
+    BATCH_LIFETIME, BatchDb, DEFAULT_BSO_TTL, Db, UserIdentifier, params, results,
+};
+
+use super::{
+    SqliteDb,
+    schema::{batch_upload_items, batch_uploads},
+};
+use crate::{DbError, DbResult};
+
+const MAX_BATCH_CREATE_RETRY: u8 = 5;
+
+#[async_trait(?Send)]
+impl BatchDb for SqliteDb {
+    type Error = DbError;
+
+    async fn create_batch(
+        &mut self,
+        params: params::CreateBatch,
+    ) -> DbResult<results::CreateBatch> {
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self._get_collection_id(&params.collection).await?;
+        // As w/ MySQL, batch ids are millisecond timestamps with the lowest
+        // digit of the uid mixed in (Sync timestamps always end in zero)
+        let mut batch_id = self.session.timestamp.as_i64() + (user_id % 10);
+        // Occasionally batch_ids clash (usually during unit testing), so also
+        // retry w/ increments
+        for i in 1..=MAX_BATCH_CREATE_RETRY {
+            let result = insert_into(batch_uploads::table)
+                .values((
+                    batch_uploads::batch_id.eq(&batch_id),
+                    batch_uploads::user_id.eq(&user_id),
+                    batch_uploads::collection_id.eq(&collection_id),
+                ))
+                .execute(&mut self.conn)
+                .await;
+            match result {
+                Ok(_) => break,
+                Err(DieselError::DatabaseError(UniqueViolation, _)) => {
+                    if i == MAX_BATCH_CREATE_RETRY {
+                        return Err(DbError::conflict());
+                    }
+                    batch_id += 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        do_append(self, batch_id, params.user_id, params.bsos).await?;
+        Ok(results::CreateBatch {
+            id: encode_id(batch_id),
+            size: None,
+        })
+    }
+
+    async fn validate_batch(&mut self, params: params::ValidateBatch) -> DbResult<bool> {
+        let exists = self.get_batch(params.into()).await?;
+        Ok(exists.is_some())
+    }
+
+    async fn append_to_batch(&mut self, params: params::AppendToBatch) -> DbResult<()> {
+        let exists = self
+            .validate_batch(params::ValidateBatch {
+                user_id: params.user_id.clone(),
+                collection: params.collection.clone(),
+                id: params.batch.id.clone(),
+            })
+            .await?;
+
+        if !exists {
+            return Err(DbError::batch_not_found());
+        }
+
+        let batch_id = decode_id(&params.batch.id)?;
+        do_append(self, batch_id, params.user_id, params.bsos).await?;
+        Ok(())
+    }
+
+    async fn get_batch(&mut self, params: params::GetBatch) -> DbResult<Option<results::GetBatch>> {
+        let batch_id = decode_id(&params.id)?;
+        // Avoid hitting the db for batches that are obviously too old.  Recall
+        // that the batchid is a millisecond timestamp.
+        if (batch_id + BATCH_LIFETIME) < self.session.timestamp.as_i64() {
+            return Ok(None);
+        }
+
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self._get_collection_id(&params.collection).await?;
+        let totals = batch_uploads::table
+            .select((batch_uploads::total_records, batch_uploads::total_bytes))
+            .filter(batch_uploads::batch_id.eq(&batch_id))
+            .filter(batch_uploads::user_id.eq(&user_id))
+            .filter(batch_uploads::collection_id.eq(&collection_id))
+            .get_result::<(i64, i64)>(&mut self.conn)
+            .await
+            .optional()?;
+        Ok(
+            totals.map(|(total_records, total_bytes)| results::GetBatch {
+                id: params.id,
+                total_records: total_records as u64,
+                total_bytes: total_bytes as u64,
+            }),
+        )
+    }
+
+    async fn delete_batch(&mut self, params: params::DeleteBatch) -> DbResult<()> {
+        let batch_id = decode_id(&params.id)?;
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self._get_collection_id(&params.collection).await?;
+        diesel::delete(batch_uploads::table)
+            .filter(batch_uploads::batch_id.eq(&batch_id))
+            .filter(batch_uploads::user_id.eq(&user_id))
+            .filter(batch_uploads::collection_id.eq(&collection_id))
+            .execute(&mut self.conn)
+            .await?;
+        diesel::delete(batch_upload_items::table)
+            .filter(batch_upload_items::batch_id.eq(&batch_id))
+            .filter(batch_upload_items::user_id.eq(&user_id))
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Commits a batch to the bsos table, deleting the batch when succesful
+    async fn commit_batch(
+        &mut self,
+        params: params::CommitBatch,
+    ) -> DbResult<results::CommitBatch> {
+        let batch_id = decode_id(&params.batch.id)?;
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self._get_collection_id(&params.collection).await?;
+        let timestamp = self.session.timestamp.as_i64();
+        // SQLite's upsert can't reference the source rows of an INSERT ...
+        // SELECT (only `excluded`, where a NULL payload/sortindex is
+        // indistinguishable from "unchanged"), so existing BSOs are updated
+        // first and the remaining ones inserted afterwards
+        sql_query(include_str!("batch_commit_update.sql"))
+            .bind::<BigInt, _>(timestamp)
+            .bind::<BigInt, _>(timestamp)
+            .bind::<BigInt, _>(batch_id)
+            .bind::<BigInt, _>(user_id)
+            .bind::<Integer, _>(collection_id)
+            .execute(&mut self.conn)
+            .await?;
+        sql_query(include_str!("batch_commit_insert.sql"))
+            .bind::<Integer, _>(collection_id)
+            .bind::<BigInt, _>(timestamp)
+            .bind::<BigInt, _>(timestamp)
+            .bind::<BigInt, _>(timestamp + i64::from(DEFAULT_BSO_TTL) * 1000)
+            .bind::<BigInt, _>(batch_id)
+            .bind::<BigInt, _>(user_id)
+            .execute(&mut self.conn)
+            .await?;
+
+        let timestamp = self
+            .update_collection(params::UpdateCollection {
+                user_id: params.user_id.clone(),
+                collection_id,
+                collection: params.collection.clone(),
+            })
+            .await?;
+
+        self.delete_batch(params::DeleteBatch {
+            user_id: params.user_id,
+            collection: params.collection,
+            id: params.batch.id,
+        })
+        .await?;
+        Ok(timestamp)
+    }
+}
+
+pub async fn do_append(
+    db: &mut SqliteDb,
+    batch_id: i64,
+    user_id: UserIdentifier,
+    bsos: Vec<params::PostCollectionBso>,
+) -> DbResult<()> {
+    let user_id = user_id.legacy_id as i64;
+    let total_records = bsos.len() as i64;
+    let mut total_bytes = 0;
+    // The same BSO may be appended more than once (in the same or a later
+    // append): later values override earlier ones, fields omitted by the
+    // later append are preserved
+    for bso in bsos {
+        let payload_size = bso.payload.as_ref().map(|p| p.len() as i64);
+        total_bytes += payload_size.unwrap_or(0);
+        sql_query(
+            "INSERT INTO batch_upload_items (batch, userid, id, sortindex, payload, payload_size, ttl_offset)
+             VALUES (?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT (batch, userid, id) DO UPDATE SET
+                 sortindex = COALESCE(excluded.sortindex, batch_upload_items.sortindex),
+                 payload = COALESCE(excluded.payload, batch_upload_items.payload),
+                 payload_size = COALESCE(excluded.payload_size, batch_upload_items.payload_size),
+                 ttl_offset = COALESCE(excluded.ttl_offset, batch_upload_items.ttl_offset)",
+        )
+        .bind::<BigInt, _>(batch_id)
+        .bind::<BigInt, _>(user_id)
+        .bind::<Text, _>(&bso.id)
+        .bind::<Nullable<Integer>, _>(bso.sortindex)
+        .bind::<Nullable<Text>, _>(&bso.payload)
+        .bind::<Nullable<BigInt>, _>(payload_size)
+        .bind::<Nullable<Integer>, _>(bso.ttl.map(|ttl| ttl as i32))
+        .execute(&mut db.conn)
+        .await?;
+    }
+
+    if total_records > 0 {
+        // Keep the batch's running totals (enforced against the
+        // `max_total_records`/`max_total_bytes` limits)
+        diesel::update(
+            batch_uploads::table
+                .filter(batch_uploads::batch_id.eq(batch_id))
+                .filter(batch_uploads::user_id.eq(user_id)),
+        )
+        .set((
+            batch_uploads::total_records.eq(batch_uploads::total_records + total_records),
+            batch_uploads::total_bytes.eq(batch_uploads::total_bytes + total_bytes),
+        ))
+        .execute(&mut db.conn)
+        .await?;
+    }
+    Ok(())
+}
+
+pub fn validate_batch_id(id: &str) -> DbResult<()> {
+    decode_id(id).map(|_| ())
+}
+
+fn encode_id(id: i64) -> String {
+    base64::engine::general_purpose::STANDARD.encode(id.to_string())
+}
+
+fn decode_id(id: &str) -> DbResult<i64> {
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(id)
+        .unwrap_or_else(|_| id.as_bytes().to_vec());
+    let decoded = std::str::from_utf8(&bytes).unwrap_or(id);
+    decoded
+        .parse::<i64>()
+        .map_err(|e| DbError::internal(format!("Invalid batch_id: {}", e)))
+}
@@ -0,0 +1,849 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use diesel::{
+    ExpressionMethods, OptionalExtension, QueryDsl,
+    connection::{AnsiTransactionManager, TransactionManager},
+    delete,
+    dsl::max,
+    dsl::sql,
+    sql_query,
+    sql_types::{BigInt, Integer, Nullable, Text},
+};
+use diesel_async::RunQueryDsl;
+use futures::{StreamExt, TryStreamExt};
+use syncstorage_db_common::{
+    BATCH_LIFETIME, BsoStream, DEFAULT_BSO_TTL, Db, Sorting, UserIdentifier,
+    error::DbErrorIntrospect, params, results, util::SyncTimestamp,
+};
+use syncstorage_settings::DEFAULT_MAX_TOTAL_RECORDS;
+
+use super::{
+    COLLECTION_ID, COUNT, CollectionLock, EXPIRY, LAST_MODIFIED, MODIFIED, PAYLOAD_BYTES_SUM,
+    SqliteDb, TOMBSTONE, TOTAL_BYTES, USER_ID, after_cursor,
+    schema::{bso, user_collections},
+};
+use crate::{DbError, DbResult};
+
+// this is the max number of records we will return.
+static DEFAULT_LIMIT: u32 = DEFAULT_MAX_TOTAL_RECORDS;
+
+#[async_trait(?Send)]
+impl Db for SqliteDb {
+    /// APIs for collection-level locking
+    ///
+    /// SQLite only locks at the database level: a read transaction sees a
+    /// consistent snapshot of the db and only one write transaction may run at
+    /// a time. Write locks are acquired upfront (see `begin`) so concurrent
+    /// writers queue on the `busy_timeout` instead of failing when upgrading
+    /// from a read lock mid transaction.
+    async fn lock_for_read(&mut self, params: params::LockCollection) -> DbResult<()> {
+        // Lock the db
+        self.begin(false).await?;
+        let collection_id = self
+            ._get_collection_id(&params.collection)
+            .await
+            .or_else(|e| {
+                if e.is_collection_not_found() {
+                    // If the collection doesn't exist, we still want to start a
+                    // transaction so it will continue to not exist.
+                    Ok(0)
+                } else {
+                    Err(e)
+                }
+            })?;
+
+        let user_id = params.user_id.legacy_id as i64;
+        let key = (params.user_id, collection_id);
+        // If we already have a read or write lock then it's safe to
+        // use it as-is.
+        if self.session.coll_locks.contains_key(&key) {
+            return Ok(());
+        }
+
+        let modified = user_collections::table
+            .select(user_collections::modified)
+            .filter(user_collections::user_id.eq(user_id))
+            .filter(user_collections::collection_id.eq(collection_id))
+            .first(&mut self.conn)
+            .await
+            .optional()?;
+        if let Some(modified) = modified {
+            let modified = SyncTimestamp::from_i64(modified)?;
+            self.session
+                .coll_modified_cache
+                .insert(key.clone(), modified);
+        }
+        // XXX: who's responsible for unlocking (removing the entry)
+        self.session.coll_locks.insert(key, CollectionLock::Read);
+        Ok(())
+    }
+
+    async fn lock_for_write(&mut self, params: params::LockCollection) -> DbResult<()> {
+        // Lock the db
+        self.begin(true).await?;
+        let collection_id = self.get_or_create_collection_id(&params.collection).await?;
+        let user_id = params.user_id.legacy_id as i64;
+        let key = (params.user_id, collection_id);
+
+        if let Some(CollectionLock::Read) = self.session.coll_locks.get(&key) {
+            return Err(DbError::internal(
+                "Can't escalate read-lock to write-lock".to_owned(),
+            ));
+        }
+
+        let modified = user_collections::table
+            .select(user_collections::modified)
+            .filter(user_collections::user_id.eq(user_id))
+            .filter(user_collections::collection_id.eq(collection_id))
+            .first(&mut self.conn)
+            .await
+            .optional()?;
+        if let Some(modified) = modified {
+            let modified = SyncTimestamp::from_i64(modified)?;
+            // Forbid the write if it would not properly incr the timestamp
+            if modified >= self.session.timestamp {
+                return Err(DbError::conflict());
+            }
+            self.session
+                .coll_modified_cache
+                .insert(key.clone(), modified);
+        }
+        self.session.coll_locks.insert(key, CollectionLock::Write);
+        Ok(())
+    }
+
+    async fn begin(&mut self, for_write: bool) -> DbResult<()> {
+        self.conn
+            .spawn_blocking(move |conn| {
+                // Write transactions take the db's write lock immediately.
+                // Nested transactions (e.g. within a test transaction) are
+                // savepoints that can't specify a lock mode
+                let in_transaction = AnsiTransactionManager::transaction_manager_status_mut(conn)
+                    .transaction_depth()?
+                    .is_some();
+                if for_write && !in_transaction {
+                    AnsiTransactionManager::begin_transaction_sql(conn, "BEGIN IMMEDIATE")
+                } else {
+                    AnsiTransactionManager::begin_transaction(conn)
+                }
+            })
+            .await?;
+        self.session.in_transaction = true;
+        if for_write {
+            self.session.in_write_transaction = true;
+        }
+        Ok(())
+    }
+
+    async fn commit(&mut self) -> DbResult<()> {
+        if self.session.in_transaction {
+            self.conn
+                .spawn_blocking(AnsiTransactionManager::commit_transaction)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn rollback(&mut self) -> DbResult<()> {
+        if self.session.in_transaction {
+            self.conn
+                .spawn_blocking(AnsiTransactionManager::rollback_transaction)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn delete_storage(&mut self, user_id: UserIdentifier) -> DbResult<()> {
+        let user_id = user_id.legacy_id as i64;
+        // Delete user data.
+        delete(bso::table)
+            .filter(bso::user_id.eq(user_id))
+            .execute(&mut self.conn)
+            .await?;
+        // Delete user collections.
+        delete(user_collections::table)
+            .filter(user_collections::user_id.eq(user_id))
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
+    // Deleting the collection should result in:
+    //  - collection does not appear in /info/collections
+    //  - X-Last-Modified timestamp at the storage level changing
+    async fn delete_collection(
+        &mut self,
+        params: params::DeleteCollection,
+    ) -> DbResult<SyncTimestamp> {
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self._get_collection_id(&params.collection).await?;
+        let mut count = delete(bso::table)
+            .filter(bso::user_id.eq(user_id))
+            .filter(bso::collection_id.eq(&collection_id))
+            .execute(&mut self.conn)
+            .await?;
+        count += delete(user_collections::table)
+            .filter(user_collections::user_id.eq(user_id))
+            .filter(user_collections::collection_id.eq(&collection_id))
+            .execute(&mut self.conn)
+            .await?;
+        if count == 0 {
+            return Err(DbError::collection_not_found());
+        } else {
+            self.erect_tombstone(user_id).await?;
+        }
+        self.get_storage_timestamp(params.user_id).await
+    }
+
+    async fn put_bso(&mut self, bso: params::PutBso) -> DbResult<results::PutBso> {
+        let collection_id = self.get_or_create_collection_id(&bso.collection).await?;
+        let user_id: u64 = bso.user_id.legacy_id;
+        let timestamp = self.session.timestamp.as_i64();
+        if self.quota.enabled {
+            let usage = self
+                .get_quota_usage(params::GetQuotaUsage {
+                    user_id: bso.user_id.clone(),
+                    collection: bso.collection.clone(),
+                })
+                .await?;
+            if usage.total_bytes >= self.quota.size {
+                let mut tags = HashMap::default();
+                tags.insert("collection".to_owned(), bso.collection.clone());
+                self.metrics.incr_with_tags("storage.quota.at_limit", tags);
+                if self.quota.enforced {
+                    return Err(DbError::quota());
+                } else {
+                    warn!("Quota at limit for user's collection ({} bytes)", usage.total_bytes; "collection"=>bso.collection.clone());
+                }
+            }
+        }
+
+        let payload = bso.payload.as_deref().unwrap_or_default();
+        let sortindex = bso.sortindex;
+        let ttl = bso.ttl.map_or(DEFAULT_BSO_TTL, |ttl| ttl);
+
+        // Only the provided fields are updated for existing BSOs: the
+        // modified timestamp is only bumped when the payload or sortindex
+        // change (a lone ttl update doesn't count as a modification)
+        let mut updates = vec![];
+        if bso.sortindex.is_some() {
+            updates.push("sortindex = excluded.sortindex".to_owned());
+        }
+        if bso.payload.is_some() {
+            updates.push("payload = excluded.payload".to_owned());
+        }
+        if bso.ttl.is_some() {
+            updates.push(format!("{expiry} = excluded.{expiry}", expiry = EXPIRY));
+        }
+        if bso.payload.is_some() || bso.sortindex.is_some() {
+            updates.push(format!(
+                "{modified} = excluded.{modified}",
+                modified = MODIFIED
+            ));
+        }
+        let on_conflict = if updates.is_empty() {
+            "DO NOTHING".to_owned()
+        } else {
+            format!("DO UPDATE SET {}", updates.join(", "))
+        };
+        let q = format!(
+            r#"
+            INSERT INTO bso ({user_id}, {collection_id}, id, sortindex, payload, {modified}, {expiry})
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+                ON CONFLICT ({user_id}, {collection_id}, id) {on_conflict}
+            "#,
+            user_id = USER_ID,
+            modified = MODIFIED,
+            collection_id = COLLECTION_ID,
+            expiry = EXPIRY
+        );
+        sql_query(q)
+            .bind::<BigInt, _>(user_id as i64)
+            .bind::<Integer, _>(&collection_id)
+            .bind::<Text, _>(&bso.id)
+            .bind::<Nullable<Integer>, _>(sortindex)
+            .bind::<Text, _>(payload)
+            .bind::<BigInt, _>(timestamp)
+            .bind::<BigInt, _>(timestamp + (i64::from(ttl) * 1000)) // remember: this is in millis
+            .execute(&mut self.conn)
+            .await?;
+        self.update_collection(params::UpdateCollection {
+            user_id: bso.user_id,
+            collection_id,
+            collection: bso.collection,
+        })
+        .await
+    }
+
+    async fn get_bsos(&mut self, params: params::GetBsos) -> DbResult<results::GetBsos> {
+        let collection_id = self._get_collection_id(&params.collection).await?;
+        let limit = params
+            .limit
+            .map(i64::from)
+            .unwrap_or(DEFAULT_LIMIT as i64)
+            .max(0);
+        // fetch an extra row to detect if there are more rows that
+        // match the query conditions
+        let query = self.bsos_query(
+            params,
+            collection_id,
+            if limit > 0 { limit + 1 } else { limit },
+        );
+        let mut bsos = query.load::<results::GetBso>(&mut self.conn).await?;
+
+        let next_offset = if limit >= 0 && bsos.len() > limit as usize {
+            bsos.pop();
+            bsos.last()
+                .map(|bso| params::Offset::from(params::Cursor::from(bso)).to_string())
+        } else {
+            // if an explicit "limit=0" is sent, return the offset of "0"
+            // Otherwise, this would break at least the db::tests::db::get_bsos_limit_offset
+            // unit test.
+            if limit == 0 {
+                Some(0.to_string())
+            } else {
+                None
+            }
+        };
+
+        Ok(results::GetBsos {
+            items: bsos,
+            offset: next_offset,
+        })
+    }
+
+    async fn get_bsos_stream(
+        &mut self,
+        params: params::GetBsos,
+    ) -> DbResult<BsoStream<'_, DbError>> {
+        let collection_id = self._get_collection_id(&params.collection).await?;
+        let limit = params
+            .limit
+            .map(i64::from)
+            .unwrap_or(DEFAULT_LIMIT as i64)
+            .max(0);
+        let bsos = self
+            .bsos_query(params, collection_id, limit)
+            .load_stream::<results::GetBso>(&mut self.conn)
+            .await?;
+        Ok(bsos.map_err(Into::into).boxed_local())
+    }
+
+    async fn get_bso_ids(&mut self, params: params::GetBsos) -> DbResult<results::GetBsoIds> {
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self._get_collection_id(&params.collection).await?;
+        let mut query = bso::table
+            .select((bso::id, bso::modified, bso::sortindex))
+            .filter(bso::user_id.eq(user_id))
+            .filter(bso::collection_id.eq(collection_id))
+            .filter(bso::expiry.gt(self.session.timestamp.as_i64()))
+            .into_boxed();
+
+        let cursor = params.offset.as_ref().and_then(|o| o.cursor.clone());
+        if let Some(cursor) = cursor {
+            query = query.filter(after_cursor(params.sort, cursor));
+        } else if let Some(ts) = params.offset.as_ref().and_then(|o| o.timestamp) {
+            match params.sort {
+                Sorting::Oldest => query = query.filter(bso::modified.ge(ts.as_i64())),
+                Sorting::Newest | Sorting::None => {
+                    query = query.filter(bso::modified.le(ts.as_i64()))
+                }
+                Sorting::Index => {}
+            }
+        }
+        if let Some(older) = params.older {
+            query = query.filter(bso::modified.lt(older.as_i64()));
+        }
+        if let Some(newer) = params.newer {
+            query = query.filter(bso::modified.gt(newer.as_i64()));
+        }
+
+        if !params.ids.is_empty() {
+            query = query.filter(bso::id.eq_any(params.ids));
+        }
+
+        query = match params.sort {
+            Sorting::Index => query.order((bso::sortindex.desc(), bso::id.desc())),
+            Sorting::Newest | Sorting::None => query.order((bso::modified.desc(), bso::id.desc())),
+            Sorting::Oldest => query.order((bso::modified.asc(), bso::id.asc())),
+        };
+
+        let limit = params
+            .limit
+            .map(i64::from)
+            .unwrap_or(DEFAULT_LIMIT as i64)
+            .max(0);
+        // fetch an extra row to detect if there are more rows that
+        // match the query conditions.
+        query = query.limit(if limit == 0 { limit } else { limit + 1 });
+        // Legacy offsets still skip rows, cursors already filtered them out
+        let numeric_offset = params.offset.map_or(0, |offset| offset.offset as i64);
+        if numeric_offset != 0 {
+            query = query.offset(numeric_offset);
+        }
+        let mut rows = query
+            .load::<(String, SyncTimestamp, Option<i32>)>(&mut self.conn)
+            .await?;
+
+        let next_offset = if limit >= 0 && rows.len() > limit as usize {
+            rows.pop();
+            rows.last().map(|(id, modified, sortindex)| {
+                params::Offset::from(params::Cursor {
+                    modified: *modified,
+                    sortindex: *sortindex,
+                    id: id.clone(),
+                })
+                .to_string()
+            })
+        } else {
+            None
+        };
+        let ids = rows.into_iter().map(|(id, ..)| id).collect();
+
+        Ok(results::GetBsoIds {
+            items: ids,
+            offset: next_offset,
+        })
+    }
+
+    async fn get_bso(&mut self, params: params::GetBso) -> DbResult<Option<results::GetBso>> {
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self._get_collection_id(&params.collection).await?;
+        Ok(bso::table
+            .select((
+                bso::id,
+                bso::modified,
+                bso::payload,
+                bso::sortindex,
+                bso::expiry,
+            ))
+            .filter(bso::user_id.eq(user_id))
+            .filter(bso::collection_id.eq(&collection_id))
+            .filter(bso::id.eq(&params.id))
+            .filter(bso::expiry.gt(self.session.timestamp.as_i64()))
+            .get_result::<results::GetBso>(&mut self.conn)
+            .await
+            .optional()?)
+    }
+
+    async fn delete_bso(&mut self, params: params::DeleteBso) -> DbResult<results::DeleteBso> {
+        let user_id = params.user_id.legacy_id;
+        let collection_id = self._get_collection_id(&params.collection).await?;
+        let affected_rows = delete(bso::table)
+            .filter(bso::user_id.eq(user_id as i64))
+            .filter(bso::collection_id.eq(&collection_id))
+            .filter(bso::id.eq(params.id))
+            .filter(bso::expiry.gt(&self.session.timestamp.as_i64()))
+            .execute(&mut self.conn)
+            .await?;
+        if affected_rows == 0 {
+            return Err(DbError::bso_not_found());
+        }
+        self.update_collection(params::UpdateCollection {
+            user_id: params.user_id,
+            collection_id,
+            collection: params.collection,
+        })
+        .await
+    }
+
+    async fn delete_bsos(&mut self, params: params::DeleteBsos) -> DbResult<results::DeleteBsos> {
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self._get_collection_id(&params.collection).await?;
+        delete(bso::table)
+            .filter(bso::user_id.eq(user_id))
+            .filter(bso::collection_id.eq(&collection_id))
+            .filter(bso::id.eq_any(params.ids))
+            .execute(&mut self.conn)
+            .await?;
+        self.update_collection(params::UpdateCollection {
+            user_id: params.user_id,
+            collection_id,
+            collection: params.collection,
+        })
+        .await
+    }
+
+    async fn post_bsos(&mut self, input: params::PostBsos) -> DbResult<SyncTimestamp> {
+        let collection_id = self.get_or_create_collection_id(&input.collection).await?;
+        let modified = self.session.timestamp;
+
+        for pbso in input.bsos {
+            self.put_bso(params::PutBso {
+                user_id: input.user_id.clone(),
+                collection: input.collection.clone(),
+                id: pbso.id.clone(),
+                payload: pbso.payload,
+                sortindex: pbso.sortindex,
+                ttl: pbso.ttl,
+            })
+            .await?;
+        }
+        self.update_collection(params::UpdateCollection {
+            user_id: input.user_id,
+            collection_id,
+            collection: input.collection,
+        })
+        .await?;
+
+        Ok(modified)
+    }
+
+    async fn get_storage_timestamp(&mut self, user_id: UserIdentifier) -> DbResult<SyncTimestamp> {
+        let user_id = user_id.legacy_id as i64;
+        let modified = user_collections::table
+            .select(max(user_collections::modified))
+            .filter(user_collections::user_id.eq(user_id))
+            .first::<Option<i64>>(&mut self.conn)
+            .await?
+            .unwrap_or_default();
+        SyncTimestamp::from_i64(modified).map_err(Into::into)
+    }
+
+    async fn get_collection_timestamp(
+        &mut self,
+        params: params::GetCollectionTimestamp,
+    ) -> DbResult<SyncTimestamp> {
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self._get_collection_id(&params.collection).await?;
+        if let Some(modified) = self
+            .session
+            .coll_modified_cache
+            .get(&(params.user_id, collection_id))
+        {
+            return Ok(*modified);
+        }
+        user_collections::table
+            .select(user_collections::modified)
+            .filter(user_collections::user_id.eq(user_id))
+            .filter(user_collections::collection_id.eq(collection_id))
+            .first(&mut self.conn)
+            .await
+            .optional()?
+            .ok_or_else(DbError::collection_not_found)
+    }
+
+    async fn get_bso_timestamp(
+        &mut self,
+        params: params::GetBsoTimestamp,
+    ) -> DbResult<SyncTimestamp> {
+        let user_id = params.user_id.legacy_id as i64;
+        let collection_id = self._get_collection_id(&params.collection).await?;
+        let modified = bso::table
+            .select(bso::modified)
+            .filter(bso::user_id.eq(user_id))
+            .filter(bso::collection_id.eq(&collection_id))
+            .filter(bso::id.eq(&params.id))
+            .first::<i64>(&mut self.conn)
+            .await
+            .optional()?
+            .unwrap_or_default();
+        SyncTimestamp::from_i64(modified).map_err(Into::into)
+    }
+
+    async fn get_collection_timestamps(
+        &mut self,
+        user_id: UserIdentifier,
+    ) -> DbResult<results::GetCollectionTimestamps> {
+        let modifieds = sql_query(format!(
+            "SELECT {collection_id}, {modified}
+               FROM user_collections
+              WHERE {user_id} = ?
+               AND {collection_id} != ?",
+            collection_id = COLLECTION_ID,
+            user_id = USER_ID,
+            modified = LAST_MODIFIED
+        ))
+        .bind::<BigInt, _>(user_id.legacy_id as i64)
+        .bind::<Integer, _>(TOMBSTONE)
+        .load::<UserCollectionsResult>(&mut self.conn)
+        .await?
+        .into_iter()
+        .map(|cr| {
+            SyncTimestamp::from_i64(cr.last_modified)
+                .map(|ts| (cr.collection, ts))
+                .map_err(Into::into)
+        })
+        .collect::<DbResult<HashMap<_, _>>>()?;
+        self.map_collection_names(modifieds).await
+    }
+
+    async fn check(&mut self) -> DbResult<results::Check> {
+        sql_query("SELECT 1").execute(&mut self.conn).await?;
+        Ok(true)
+    }
+
+    async fn update_collection(
+        &mut self,
+        params: params::UpdateCollection,
+    ) -> DbResult<SyncTimestamp> {
+        let quota = if self.quota.enabled {
+            self.calc_quota_usage(params.user_id.legacy_id as i64, params.collection_id)
+                .await?
+        } else {
+            results::GetQuotaUsage {
+                count: 0,
+                total_bytes: 0,
+            }
+        };
+        let upsert = format!(
+            r#"
+                INSERT INTO user_collections ({user_id}, {collection_id}, {modified}, {total_bytes}, {count})
+                VALUES (?, ?, ?, ?, ?)
+                    ON CONFLICT ({user_id}, {collection_id}) DO UPDATE SET
+                       {modified} = excluded.{modified},
+                       {total_bytes} = excluded.{total_bytes},
+                       {count} = excluded.{count}
+        "#,
+            user_id = USER_ID,
+            collection_id = COLLECTION_ID,
+            modified = LAST_MODIFIED,
+            count = COUNT,
+            total_bytes = TOTAL_BYTES,
+        );
+        let total_bytes = quota.total_bytes as i64;
+        let modified = self.session.timestamp;
+        sql_query(upsert)
+            .bind::<BigInt, _>(params.user_id.legacy_id as i64)
+            .bind::<Integer, _>(&params.collection_id)
+            .bind::<BigInt, _>(&modified.as_i64())
+            .bind::<BigInt, _>(&total_bytes)
+            .bind::<Integer, _>(&quota.count)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(modified)
+    }
+
+    // Perform a lighter weight "read only" storage size check
+    async fn get_storage_usage(
+        &mut self,
+        user_id: UserIdentifier,
+    ) -> DbResult<results::GetStorageUsage> {
+        let uid = user_id.legacy_id as i64;
+        let total_bytes = bso::table
+            .select(sql::<BigInt>(PAYLOAD_BYTES_SUM))
+            .filter(bso::user_id.eq(uid))
+            .filter(bso::expiry.gt(&self.session.timestamp.as_i64()))
+            .get_result::<i64>(&mut self.conn)
+            .await?;
+        Ok(total_bytes as u64)
+    }
+
+    // Perform a lighter weight "read only" quota storage check
+    async fn get_quota_usage(
+        &mut self,
+        params: params::GetQuotaUsage,
+    ) -> DbResult<results::GetQuotaUsage> {
+        let uid = params.user_id.legacy_id as i64;
+        let collection_id = self._get_collection_id(&params.collection).await?;
+        let (total_bytes, count): (i64, i32) = user_collections::table
+            .select((
+                sql::<BigInt>("COALESCE(SUM(COALESCE(total_bytes, 0)), 0)"),
+                sql::<Integer>("COALESCE(SUM(COALESCE(count, 0)), 0)"),
+            ))
+            .filter(user_collections::user_id.eq(uid))
+            .filter(user_collections::collection_id.eq(collection_id))
+            .get_result(&mut self.conn)
+            .await
+            .optional()?
+            .unwrap_or_default();
+        Ok(results::GetQuotaUsage {
+            total_bytes: total_bytes as usize,
+            count,
+        })
+    }
+
+    async fn get_collection_usage(
+        &mut self,
+        user_id: UserIdentifier,
+    ) -> DbResult<results::GetCollectionUsage> {
+        let counts = bso::table
+            .select((bso::collection_id, sql::<BigInt>(PAYLOAD_BYTES_SUM)))
+            .filter(bso::user_id.eq(user_id.legacy_id as i64))
+            .filter(bso::expiry.gt(&self.session.timestamp.as_i64()))
+            .group_by(bso::collection_id)
+            .load(&mut self.conn)
+            .await?
+            .into_iter()
+            .collect();
+        self.map_collection_names(counts).await
+    }
+
+    async fn get_collection_counts(
+        &mut self,
+        user_id: UserIdentifier,
+    ) -> DbResult<results::GetCollectionCounts> {
+        let counts = bso::table
+            .select((
+                bso::collection_id,
+                sql::<BigInt>(&format!(
+                    "COUNT({collection_id})",
+                    collection_id = COLLECTION_ID
+                )),
+            ))
+            .filter(bso::user_id.eq(user_id.legacy_id as i64))
+            .filter(bso::expiry.gt(&self.session.timestamp.as_i64()))
+            .group_by(bso::collection_id)
+            .load(&mut self.conn)
+            .await?
+            .into_iter()
+            .collect();
+        self.map_collection_names(counts).await
+    }
+
+    fn get_connection_info(&self) -> results::ConnectionInfo {
+        results::ConnectionInfo::default()
+    }
+
+    async fn get_user_ids(&mut self, params: params::GetUserIds) -> DbResult<results::GetUserIds> {
+        let mut query = user_collections::table
+            .select(user_collections::user_id)
+            .distinct()
+            .order(user_collections::user_id)
+            .limit(i64::from(params.limit))
+            .into_boxed();
+        if let Some(after) = params.after {
+            query = query.filter(user_collections::user_id.gt(after.legacy_id as i64));
+        }
+        Ok(query
+            .load::<i64>(&mut self.conn)
+            .await?
+            .into_iter()
+            .map(|user_id| UserIdentifier {
+                legacy_id: user_id as u64,
+                ..Default::default()
+            })
+            .collect())
+    }
+
+    async fn import_bsos(&mut self, params: params::ImportBsos) -> DbResult<results::ImportBsos> {
+        let collection_id = self.get_or_create_collection_id(&params.collection).await?;
+        let user_id = params.user_id.legacy_id as i64;
+        let q = format!(
+            r#"
+            INSERT INTO bso ({user_id}, {collection_id}, id, sortindex, payload, {modified}, {expiry})
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+                ON CONFLICT ({user_id}, {collection_id}, id) DO UPDATE SET
+                   sortindex = excluded.sortindex,
+                   payload = excluded.payload,
+                   {modified} = excluded.{modified},
+                   {expiry} = excluded.{expiry}
+            "#,
+            user_id = USER_ID,
+            modified = MODIFIED,
+            collection_id = COLLECTION_ID,
+            expiry = EXPIRY
+        );
+        for bso in params.bsos {
+            sql_query(&q)
+                .bind::<BigInt, _>(user_id)
+                .bind::<Integer, _>(&collection_id)
+                .bind::<Text, _>(&bso.id)
+                .bind::<Nullable<Integer>, _>(bso.sortindex)
+                .bind::<Text, _>(&bso.payload)
+                .bind::<BigInt, _>(bso.modified.as_i64())
+                .bind::<BigInt, _>(bso.expiry)
+                .execute(&mut self.conn)
+                .await?;
+        }
+        // update_collection records the session's timestamp
+        self.session.timestamp = params.modified;
+        self.update_collection(params::UpdateCollection {
+            user_id: params.user_id,
+            collection_id,
+            collection: params.collection,
+        })
+        .await
+    }
+
+    async fn purge_expired(
+        &mut self,
+        params: params::PurgeExpired,
+    ) -> DbResult<results::PurgeExpired> {
+        let now = self.session.timestamp.as_i64();
+        let limit = i64::from(params.limit);
+        // SQLite's DELETE only accepts LIMIT when built with
+        // SQLITE_ENABLE_UPDATE_DELETE_LIMIT, so select the rows instead
+        let bsos = sql_query(format!(
+            "DELETE FROM bso WHERE rowid IN (
+                 SELECT rowid FROM bso WHERE {expiry} < ? LIMIT ?
+             )",
+            expiry = EXPIRY
+        ))
+        .bind::<BigInt, _>(now)
+        .bind::<BigInt, _>(limit)
+        .execute(&mut self.conn)
+        .await?;
+
+        // Batch ids are their creation timestamps
+        let batch_expiry = now - BATCH_LIFETIME;
+        let batch_items = sql_query(
+            "DELETE FROM batch_upload_items WHERE rowid IN (
+                 SELECT rowid FROM batch_upload_items WHERE batch < ? LIMIT ?
+             )",
+        )
+        .bind::<BigInt, _>(batch_expiry)
+        .bind::<BigInt, _>(limit)
+        .execute(&mut self.conn)
+        .await?;
+        let batches = sql_query(
+            "DELETE FROM batch_uploads WHERE rowid IN (
+                 SELECT rowid FROM batch_uploads WHERE batch < ? LIMIT ?
+             )",
+        )
+        .bind::<BigInt, _>(batch_expiry)
+        .bind::<BigInt, _>(limit)
+        .execute(&mut self.conn)
+        .await?;
+        Ok(results::PurgeExpired {
+            bsos: bsos as u64,
+            batches: batches as u64,
+            batch_items: batch_items as u64,
+        })
+    }
+
+    #[cfg(debug_assertions)]
+    async fn create_collection(&mut self, name: &str) -> Result<i32, Self::Error> {
+        self._create_collection(name).await
+    }
+
+    #[cfg(debug_assertions)]
+    async fn get_collection_id(&mut self, name: &str) -> DbResult<i32> {
+        self._get_collection_id(name).await
+    }
+
+    #[cfg(debug_assertions)]
+    fn timestamp(&self) -> SyncTimestamp {
+        self.session.timestamp
+    }
+
+    #[cfg(debug_assertions)]
+    fn set_timestamp(&mut self, timestamp: SyncTimestamp) {
+        self.session.timestamp = timestamp;
+    }
+
+    #[cfg(debug_assertions)]
+    async fn clear_coll_cache(&mut self) -> Result<(), Self::Error> {
+        self.coll_cache.clear();
+        Ok(())
+    }
+
+    #[cfg(debug_assertions)]
+    fn set_quota(&mut self, enabled: bool, limit: usize, enforced: bool) {
+        self.quota = syncstorage_settings::Quota {
+            size: limit,
+            enabled,
+            enforced,
+        }
+    }
+}
+
+#[derive(Debug, QueryableByName)]
+struct UserCollectionsResult {
+    // Can't substitute column names here.
+    #[diesel(sql_type = Integer)]
+    collection: i32, // COLLECTION_ID
+    #[diesel(sql_type = BigInt)]
+    last_modified: i64, // LAST_MODIFIED
+}
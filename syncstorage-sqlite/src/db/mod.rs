@@ -0,0 +1,328 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use diesel::{
+    BoxableExpression, ExpressionMethods, OptionalExtension, QueryDsl,
+    dsl::{IntoBoxed, Select, sql},
+    sql_query,
+    sql_types::{BigInt, Bool, Integer, Nullable, Text},
+    sqlite::Sqlite,
+};
+use diesel_async::RunQueryDsl;
+use syncserver_common::Metrics;
+use syncstorage_db_common::{
+    FIRST_CUSTOM_COLLECTION_ID, Sorting, UserIdentifier, after_cursor, error::DbErrorIntrospect,
+    params, results, util::SyncTimestamp,
+};
+use syncstorage_settings::Quota;
+
+use crate::{
+    DbError, DbResult,
+    pool::{CollectionCache, Conn},
+};
+use schema::{bso, collections};
+
+mod batch_impl;
+mod db_impl;
+pub(crate) mod schema;
+
+pub use batch_impl::validate_batch_id;
+
+const TOMBSTONE: i32 = 0;
+/// SQL Variable remapping
+/// These names are the legacy values mapped to the new names.
+const COLLECTION_ID: &str = "collection";
+const USER_ID: &str = "userid";
+const MODIFIED: &str = "modified";
+const EXPIRY: &str = "ttl";
+const LAST_MODIFIED: &str = "last_modified";
+const COUNT: &str = "count";
+const TOTAL_BYTES: &str = "total_bytes";
+
+#[derive(Debug)]
+enum CollectionLock {
+    Read,
+    Write,
+}
+
+/// Per session Db metadata
+#[derive(Debug, Default)]
+struct SqliteDbSession {
+    /// The "current time" on the server used for this session's operations
+    timestamp: SyncTimestamp,
+    /// Cache of collection modified timestamps per (user_id, collection_id)
+    coll_modified_cache: HashMap<(UserIdentifier, i32), SyncTimestamp>,
+    /// Currently locked collections
+    coll_locks: HashMap<(UserIdentifier, i32), CollectionLock>,
+    /// Whether a transaction was started (begin() called)
+    in_transaction: bool,
+    in_write_transaction: bool,
+}
+
+pub struct SqliteDb {
+    pub(super) conn: Conn,
+    session: SqliteDbSession,
+    /// Pool level cache of collection_ids and their names
+    coll_cache: Arc<CollectionCache>,
+    metrics: Metrics,
+    quota: Quota,
+}
+
+impl fmt::Debug for SqliteDb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SqliteDb")
+            .field("session", &self.session)
+            .field("coll_cache", &self.coll_cache)
+            .field("metrics", &self.metrics)
+            .field("quota", &self.quota)
+            .finish()
+    }
+}
+
+/// Columns of a BSO listing, as loaded into a [results::GetBso]
+type GetBsoColumns = (
+    bso::id,
+    bso::modified,
+    bso::payload,
+    bso::sortindex,
+    bso::expiry,
+);
+type BsosQuery = IntoBoxed<'static, Select<bso::table, GetBsoColumns>, Sqlite>;
+
+impl SqliteDb {
+    /// Build the query for a page of at most `limit` BSOs
+    fn bsos_query(&self, params: params::GetBsos, collection_id: i32, limit: i64) -> BsosQuery {
+        let mut query = bso::table
+            .select((
+                bso::id,
+                bso::modified,
+                bso::payload,
+                bso::sortindex,
+                bso::expiry,
+            ))
+            .filter(bso::user_id.eq(params.user_id.legacy_id as i64))
+            .filter(bso::collection_id.eq(collection_id))
+            .filter(bso::expiry.gt(self.session.timestamp.as_i64()))
+            .into_boxed();
+
+        let cursor = params.offset.as_ref().and_then(|o| o.cursor.clone());
+        if let Some(cursor) = cursor {
+            query = query.filter(after_cursor(params.sort, cursor));
+        } else if let Some(ts) = params.offset.as_ref().and_then(|o| o.timestamp) {
+            match params.sort {
+                Sorting::Oldest => query = query.filter(bso::modified.ge(ts.as_i64())),
+                Sorting::Newest | Sorting::None => {
+                    query = query.filter(bso::modified.le(ts.as_i64()))
+                }
+                Sorting::Index => {}
+            }
+        }
+        if let Some(older) = params.older {
+            query = query.filter(bso::modified.lt(older.as_i64()));
+        }
+        if let Some(newer) = params.newer {
+            query = query.filter(bso::modified.gt(newer.as_i64()));
+        }
+
+        if !params.ids.is_empty() {
+            query = query.filter(bso::id.eq_any(params.ids));
+        }
+
+        // it's possible for two BSOs to be inserted with the same `modified` date,
+        // since there's no guarantee of order when doing a get, pagination can return
+        // an error. We "fudge" a bit here by taking the id order as a secondary, since
+        // that is guaranteed to be unique by the client.
+        query = match params.sort {
+            Sorting::Index => query.order((bso::sortindex.desc(), bso::id.desc())),
+            Sorting::Newest | Sorting::None => query.order((bso::modified.desc(), bso::id.desc())),
+            Sorting::Oldest => query.order((bso::modified.asc(), bso::id.asc())),
+        };
+        query = query.limit(limit);
+
+        // Legacy offsets still skip rows, cursors already filtered them out
+        let numeric_offset = params.offset.map_or(0, |offset| offset.offset as i64);
+        if numeric_offset > 0 {
+            query = query.offset(numeric_offset);
+        }
+        query
+    }
+
+    pub(super) fn new(
+        conn: Conn,
+        coll_cache: Arc<CollectionCache>,
+        metrics: &Metrics,
+        quota: &Quota,
+    ) -> Self {
+        SqliteDb {
+            conn,
+            session: Default::default(),
+            coll_cache,
+            metrics: metrics.clone(),
+            quota: *quota,
+        }
+    }
+
+    async fn erect_tombstone(&mut self, user_id: i64) -> DbResult<()> {
+        sql_query(format!(
+            r#"INSERT INTO user_collections ({user_id}, {collection_id}, {modified})
+               VALUES (?, ?, ?)
+                   ON CONFLICT ({user_id}, {collection_id}) DO UPDATE SET
+                      {modified} = excluded.{modified}"#,
+            user_id = USER_ID,
+            collection_id = COLLECTION_ID,
+            modified = LAST_MODIFIED
+        ))
+        .bind::<BigInt, _>(user_id)
+        .bind::<Integer, _>(TOMBSTONE)
+        .bind::<BigInt, _>(self.session.timestamp.as_i64())
+        .execute(&mut self.conn)
+        .await?;
+        Ok(())
+    }
+
+    pub(super) async fn get_or_create_collection_id(&mut self, name: &str) -> DbResult<i32> {
+        match self._get_collection_id(name).await {
+            Err(e) if e.is_collection_not_found() => self._create_collection(name).await,
+            result => result,
+        }
+    }
+
+    async fn _create_collection(&mut self, name: &str) -> DbResult<i32> {
+        if !cfg!(debug_assertions) && !self.session.in_write_transaction {
+            return Err(DbError::internal(
+                "Can't escalate read-lock to write-lock".to_owned(),
+            ));
+        }
+        let collection_id = diesel::insert_into(collections::table)
+            .values(collections::name.eq(name))
+            .returning(collections::id)
+            .get_result::<i32>(&mut self.conn)
+            .await?;
+        if collection_id < FIRST_CUSTOM_COLLECTION_ID {
+            // DDL ensures this should never occur
+            return Err(DbError::internal(
+                "create_collection < {FIRST_CUSTOM_COLLECTION_ID}".to_owned(),
+            ));
+        }
+        Ok(collection_id)
+    }
+
+    async fn _get_collection_id(&mut self, name: &str) -> DbResult<i32> {
+        if let Some(id) = self.coll_cache.get_id(name)? {
+            return Ok(id);
+        }
+
+        let id = sql_query(
+            "SELECT id
+               FROM collections
+              WHERE name = ?",
+        )
+        .bind::<Text, _>(name)
+        .get_result::<IdResult>(&mut self.conn)
+        .await
+        .optional()?
+        .ok_or_else(DbError::collection_not_found)?
+        .id;
+        if !self.session.in_write_transaction {
+            self.coll_cache.put(id, name.to_owned())?;
+        }
+        Ok(id)
+    }
+
+    async fn map_collection_names<T>(
+        &mut self,
+        by_id: HashMap<i32, T>,
+    ) -> DbResult<HashMap<String, T>> {
+        let mut names = self.load_collection_names(by_id.keys()).await?;
+        by_id
+            .into_iter()
+            .map(|(id, value)| {
+                names.remove(&id).map(|name| (name, value)).ok_or_else(|| {
+                    DbError::internal("load_collection_names unknown collection id".to_owned())
+                })
+            })
+            .collect()
+    }
+
+    async fn load_collection_names<'a>(
+        &mut self,
+        collection_ids: impl Iterator<Item = &'a i32>,
+    ) -> DbResult<HashMap<i32, String>> {
+        let mut names = HashMap::new();
+        let mut uncached = Vec::new();
+        for &id in collection_ids {
+            if let Some(name) = self.coll_cache.get_name(id)? {
+                names.insert(id, name);
+            } else {
+                uncached.push(id);
+            }
+        }
+
+        if !uncached.is_empty() {
+            let result = collections::table
+                .select((collections::id, collections::name))
+                .filter(collections::id.eq_any(uncached))
+                .load::<(i32, String)>(&mut self.conn)
+                .await?;
+
+            for (id, name) in result {
+                names.insert(id, name.clone());
+                if !self.session.in_write_transaction {
+                    self.coll_cache.put(id, name)?;
+                }
+            }
+        }
+
+        Ok(names)
+    }
+
+    // perform a heavier weight quota calculation
+    async fn calc_quota_usage(
+        &mut self,
+        user_id: i64,
+        collection_id: i32,
+    ) -> DbResult<results::GetQuotaUsage> {
+        let (total_bytes, count): (i64, i32) = bso::table
+            .select((
+                sql::<BigInt>(PAYLOAD_BYTES_SUM),
+                sql::<Integer>("COALESCE(COUNT(*),0)"),
+            ))
+            .filter(bso::user_id.eq(user_id))
+            .filter(bso::expiry.gt(self.session.timestamp.as_i64()))
+            .filter(bso::collection_id.eq(collection_id))
+            .get_result(&mut self.conn)
+            .await
+            .optional()?
+            .unwrap_or_default();
+        Ok(results::GetQuotaUsage {
+            total_bytes: total_bytes as usize,
+            count,
+        })
+    }
+}
+
+/// Total size of the selected payloads in bytes.
+///
+/// SQLite's `LENGTH` counts characters for TEXT values, so the payload is cast
+/// to a BLOB to count bytes instead.
+const PAYLOAD_BYTES_SUM: &str = "COALESCE(SUM(LENGTH(CAST(payload AS BLOB))), 0)";
+
+#[derive(Debug, QueryableByName)]
+struct IdResult {
+    #[diesel(sql_type = Integer)]
+    id: i32,
+}
+
+/// Keyset predicate matching the BSOs that follow `cursor` in `sort` order
+fn after_cursor(
+    sort: Sorting,
+    cursor: params::Cursor,
+) -> Box<dyn BoxableExpression<bso::table, Sqlite, SqlType = Nullable<Bool>>> {
+    after_cursor!(
+        sort,
+        cursor,
+        cursor.modified.as_i64(),
+        bso::sortindex,
+        bso::id,
+        bso::modified
+    )
+}
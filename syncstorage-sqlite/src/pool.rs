@@ -0,0 +1,268 @@
+use async_trait::async_trait;
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use deadpool::managed::PoolError;
+use diesel::{ConnectionError, ConnectionResult, SqliteConnection};
+use diesel_async::{
+    SimpleAsyncConnection,
+    pooled_connection::{
+        AsyncDieselConnectionManager, ManagerConfig,
+        deadpool::{Object, Pool},
+    },
+    sync_connection_wrapper::SyncConnectionWrapper,
+};
+use diesel_migrations::{EmbeddedMigrations, embed_migrations};
+use syncserver_common::{BlockingThreadpool, Metrics};
+#[cfg(debug_assertions)]
+use syncserver_db_common::test::test_transaction_hook;
+use syncserver_db_common::{
+    GetPoolStatus, establish_connection_with_logging, run_embedded_migrations,
+};
+use syncstorage_db_common::{Db, DbPool, STD_COLLS};
+use syncstorage_settings::{Quota, Settings};
+
+use super::{DbError, DbResult, db::SqliteDb};
+
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
+
+/// diesel's `SqliteConnection` is synchronous: diesel-async runs its queries
+/// on the blocking threadpool.
+pub(crate) type AsyncSqliteConnection = SyncConnectionWrapper<SqliteConnection>;
+
+pub(crate) type Conn = Object<AsyncSqliteConnection>;
+
+/// Applied to every new connection.
+///
+/// WAL allows readers to proceed concurrently with the (single) writer and
+/// `busy_timeout` makes writers wait on each other rather than immediately
+/// failing with `SQLITE_BUSY`.
+const CONNECTION_PRAGMAS: &str = "
+    PRAGMA journal_mode = WAL;
+    PRAGMA synchronous = NORMAL;
+    PRAGMA busy_timeout = 30000;
+";
+
+#[derive(Clone)]
+pub struct SqliteDbPool {
+    /// Pool of db connections
+    pool: Pool<AsyncSqliteConnection>,
+    /// In-memory cache of collection_ids and their names
+    coll_cache: Arc<CollectionCache>,
+
+    metrics: Metrics,
+    quota: Quota,
+    database_path: String,
+}
+
+impl SqliteDbPool {
+    /// Creates a new pool of SQLite db connections.
+    ///
+    /// Doesn't initialize the db (does not run migrations).
+    pub fn new(
+        settings: &Settings,
+        metrics: &Metrics,
+        _blocking_threadpool: Arc<BlockingThreadpool>,
+    ) -> DbResult<Self> {
+        let database_path = database_path(&settings.database_url)?.to_owned();
+        let mut manager_config = ManagerConfig::<AsyncSqliteConnection>::default();
+        manager_config.custom_setup = Box::new(|path| Box::pin(establish_connection(path)));
+        let manager = AsyncDieselConnectionManager::<AsyncSqliteConnection>::new_with_config(
+            &database_path,
+            manager_config,
+        );
+
+        let wait = settings
+            .database_pool_connection_timeout
+            .map(|seconds| Duration::from_secs(seconds as u64));
+        let timeouts = deadpool::managed::Timeouts {
+            wait,
+            ..Default::default()
+        };
+        let config = deadpool::managed::PoolConfig {
+            max_size: settings.database_pool_max_size as usize,
+            timeouts,
+            ..Default::default()
+        };
+
+        let builder = Pool::builder(manager)
+            .config(config)
+            .runtime(deadpool::Runtime::Tokio1);
+        #[cfg(debug_assertions)]
+        let builder = if settings.database_use_test_transactions {
+            builder.post_create(deadpool::managed::Hook::async_fn(|conn, _| {
+                Box::pin(async { test_transaction_hook(conn).await })
+            }))
+        } else {
+            builder
+        };
+        let pool = builder
+            .build()
+            .map_err(|e| DbError::internal(format!("Couldn't build Db Pool: {e}")))?;
+
+        Ok(Self {
+            pool,
+            coll_cache: Default::default(),
+            metrics: metrics.clone(),
+            quota: Quota {
+                size: settings.limits.max_quota_limit as usize,
+                enabled: settings.enable_quota,
+                enforced: settings.enforce_quota,
+            },
+            database_path,
+        })
+    }
+
+    async fn get_conn(&self) -> DbResult<Conn> {
+        self.pool.get().await.map_err(|e| match e {
+            PoolError::Backend(be) => match be {
+                diesel_async::pooled_connection::PoolError::ConnectionError(ce) => ce.into(),
+                diesel_async::pooled_connection::PoolError::QueryError(dbe) => dbe.into(),
+            },
+            PoolError::Timeout(timeout_type) => DbError::pool_timeout(timeout_type),
+            _ => DbError::internal(format!("deadpool PoolError: {e}")),
+        })
+    }
+
+    pub async fn get_sqlite_db(&self) -> DbResult<SqliteDb> {
+        Ok(SqliteDb::new(
+            self.get_conn().await?,
+            Arc::clone(&self.coll_cache),
+            &self.metrics,
+            &self.quota,
+        ))
+    }
+}
+
+/// Convert a `sqlite://` `database_url` into the file path (or `file:` URI)
+/// handed to SQLite, e.g. `sqlite:///var/lib/syncstorage.db` ->
+/// `/var/lib/syncstorage.db`.
+pub(crate) fn database_path(database_url: &str) -> DbResult<&str> {
+    database_url
+        .strip_prefix("sqlite://")
+        .filter(|path| !path.is_empty())
+        .ok_or_else(|| {
+            DbError::internal(format!(
+                "Invalid SQLite database_url (expected sqlite://<path>): {database_url}"
+            ))
+        })
+}
+
+/// Establish a connection configured via [CONNECTION_PRAGMAS]
+async fn establish_connection(path: &str) -> ConnectionResult<AsyncSqliteConnection> {
+    let mut conn = establish_connection_with_logging::<AsyncSqliteConnection>(path).await?;
+    conn.batch_execute(CONNECTION_PRAGMAS)
+        .await
+        .map_err(ConnectionError::CouldntSetupConfiguration)?;
+    Ok(conn)
+}
+
+#[async_trait]
+impl DbPool for SqliteDbPool {
+    type Error = DbError;
+
+    async fn init(&mut self) -> Result<(), Self::Error> {
+        // Run on a separate conn so the migrations aren't wrapped in the
+        // pool's test transactions
+        let conn = establish_connection(&self.database_path).await?;
+        run_embedded_migrations(conn, MIGRATIONS).await?;
+        Ok(())
+    }
+
+    async fn get<'a>(&'a self) -> DbResult<Box<dyn Db<Error = Self::Error>>> {
+        Ok(Box::new(self.get_sqlite_db().await?) as Box<dyn Db<Error = Self::Error>>)
+    }
+
+    fn validate_batch_id(&self, id: String) -> DbResult<()> {
+        super::db::validate_batch_id(&id)
+    }
+
+    fn box_clone(&self) -> Box<dyn DbPool<Error = Self::Error>> {
+        Box::new(self.clone())
+    }
+}
+
+impl fmt::Debug for SqliteDbPool {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("SqliteDbPool")
+            .field("coll_cache", &self.coll_cache)
+            .finish()
+    }
+}
+
+impl GetPoolStatus for SqliteDbPool {
+    fn status(&self) -> deadpool::Status {
+        self.pool.status()
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct CollectionCache {
+    pub by_name: RwLock<HashMap<String, i32>>,
+    pub by_id: RwLock<HashMap<i32, String>>,
+}
+
+impl CollectionCache {
+    pub fn put(&self, id: i32, name: String) -> DbResult<()> {
+        // XXX: should this emit a metric?
+        // XXX: should probably either lock both simultaneously during
+        // writes or use an RwLock alternative
+        self.by_name
+            .write()
+            .map_err(|_| DbError::internal("by_name write".to_owned()))?
+            .insert(name.clone(), id);
+        self.by_id
+            .write()
+            .map_err(|_| DbError::internal("by_id write".to_owned()))?
+            .insert(id, name);
+        Ok(())
+    }
+
+    pub fn get_id(&self, name: &str) -> DbResult<Option<i32>> {
+        Ok(self
+            .by_name
+            .read()
+            .map_err(|_| DbError::internal("by_name read".to_owned()))?
+            .get(name)
+            .cloned())
+    }
+
+    pub fn get_name(&self, id: i32) -> DbResult<Option<String>> {
+        Ok(self
+            .by_id
+            .read()
+            .map_err(|_| DbError::internal("by_id read".to_owned()))?
+            .get(&id)
+            .cloned())
+    }
+
+    #[allow(dead_code)]
+    pub fn clear(&self) {
+        self.by_name.write().expect("by_name write").clear();
+        self.by_id.write().expect("by_id write").clear();
+    }
+}
+
+impl Default for CollectionCache {
+    fn default() -> Self {
+        Self {
+            by_name: RwLock::new(
+                STD_COLLS
+                    .iter()
+                    .map(|(k, v)| ((*v).to_owned(), *k))
+                    .collect(),
+            ),
+            by_id: RwLock::new(
+                STD_COLLS
+                    .iter()
+                    .map(|(k, v)| (*k, (*v).to_owned()))
+                    .collect(),
+            ),
+        }
+    }
+}
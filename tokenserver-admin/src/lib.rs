@@ -0,0 +1,474 @@
+//! Administrative operations on the tokenserver database, replacing the
+//! `tools/tokenserver` scripts.
+#[macro_use]
+extern crate slog_scope;
+
+use std::{
+    io::{self, BufRead},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use slog::{self, Drain};
+use syncserver_common::Metrics;
+use thiserror::Error;
+use tokenserver_db::{
+    Db, DbError, DbPool, MAX_GENERATION, SYNC_SERVICE_NAME, params, pool_from_settings, results,
+};
+use tokenserver_settings::Settings;
+
+#[derive(Debug, Error)]
+pub enum AdminError {
+    #[error("Database error: {0}")]
+    Db(DbError),
+    #[error("Unknown node: {0}")]
+    UnknownNode(String),
+    #[error("User {0} is retired")]
+    RetiredUser(String),
+    #[error("Error reading account events: {0}")]
+    Io(#[from] io::Error),
+}
+
+impl From<DbError> for AdminError {
+    fn from(e: DbError) -> Self {
+        AdminError::Db(e)
+    }
+}
+
+pub type AdminResult<T> = Result<T, AdminError>;
+
+/// The `sync_count_users` record emitted by `count-users`
+#[derive(Debug, Serialize)]
+pub struct UserCounts {
+    pub op: &'static str,
+    /// The users' creation cutoff, in RFC 3339 format
+    pub time: String,
+    pub total_users: i64,
+    pub nodes: Vec<results::NodeUserCount>,
+}
+
+/// Count the current users allocated to each node, created at or before
+/// `created_before` (in milliseconds)
+pub async fn count_users(db: &mut dyn Db, created_before: i64) -> AdminResult<UserCounts> {
+    let service_id = sync_service_id(db).await?;
+    let nodes = db
+        .count_users(params::CountUsers {
+            service_id,
+            created_before,
+        })
+        .await?;
+    Ok(UserCounts {
+        op: "sync_count_users",
+        time: DateTime::from_timestamp_millis(created_before)
+            .unwrap_or_default()
+            .to_rfc3339(),
+        total_users: nodes.iter().map(|node| node.count).sum(),
+        nodes,
+    })
+}
+
+/// Allocate a user to `node`, or the best available node when `None`.
+///
+/// A user that already exists is moved to the node: a new record is created
+/// with their current generation, keys_changed_at and client state, replacing
+/// their old records.
+pub async fn allocate_user(
+    db: &mut dyn Db,
+    email: &str,
+    node: Option<&str>,
+) -> AdminResult<results::AllocateUser> {
+    let service_id = sync_service_id(db).await?;
+    let node = match node {
+        Some(node) => {
+            let id = db
+                .get_node_id(params::GetNodeId {
+                    service_id,
+                    node: node.to_owned(),
+                })
+                .await
+                .map_err(|e| {
+                    if e.is_diesel_not_found() {
+                        AdminError::UnknownNode(node.to_owned())
+                    } else {
+                        e.into()
+                    }
+                })?
+                .id;
+            results::GetBestNode {
+                id,
+                node: node.to_owned(),
+            }
+        }
+        None => {
+            db.get_best_node(params::GetBestNode {
+                service_id,
+                capacity_release_rate: None,
+            })
+            .await?
+        }
+    };
+
+    // The new record replaces the user's old ones atomically, so they're never
+    // left without a current record (or with two)
+    db.begin().await?;
+    match allocate_user_to_node(db, service_id, email, node).await {
+        Ok(user) => {
+            db.commit().await?;
+            Ok(user)
+        }
+        Err(e) => {
+            db.rollback().await?;
+            Err(e)
+        }
+    }
+}
+
+async fn allocate_user_to_node(
+    db: &mut dyn Db,
+    service_id: i32,
+    email: &str,
+    node: results::GetBestNode,
+) -> AdminResult<results::AllocateUser> {
+    // The user with the greatest `generation` and `created_at` is the current user
+    let users = db
+        .get_users(params::GetUsers {
+            service_id,
+            email: email.to_owned(),
+        })
+        .await?;
+    let current_user = users
+        .iter()
+        .max_by_key(|user| (user.generation, user.created_at));
+    if current_user.is_some_and(|user| user.generation == MAX_GENERATION) {
+        return Err(AdminError::RetiredUser(email.to_owned()));
+    }
+
+    db.add_user_to_node(params::AddUserToNode {
+        service_id,
+        node: node.node.clone(),
+    })
+    .await?;
+    let created_at = Utc::now().timestamp_millis();
+    let uid = db
+        .post_user(params::PostUser {
+            service_id,
+            email: email.to_owned(),
+            generation: current_user.map_or(0, |user| user.generation),
+            client_state: current_user
+                .map(|user| user.client_state.clone())
+                .unwrap_or_default(),
+            created_at,
+            node_id: node.id,
+            keys_changed_at: current_user.and_then(|user| user.keys_changed_at),
+        })
+        .await?
+        .uid;
+    for old_user in users.iter().filter(|user| user.replaced_at.is_none()) {
+        db.replace_user(params::ReplaceUser {
+            uid: old_user.uid,
+            service_id,
+            replaced_at: created_at,
+        })
+        .await?;
+    }
+
+    Ok(results::AllocateUser {
+        uid,
+        node: node.node,
+        created_at,
+    })
+}
+
+#[derive(Clone, Debug)]
+pub struct PurgeOptions {
+    /// Only purge users replaced at least this many seconds ago
+    pub grace_period: i64,
+    /// Max number of user records fetched at a time
+    pub max_per_loop: i64,
+    /// Stop after purging this many user records
+    pub max_records: Option<u64>,
+    /// Only report the user records that would be purged
+    pub dry_run: bool,
+}
+
+impl Default for PurgeOptions {
+    fn default() -> Self {
+        Self {
+            grace_period: 86400,
+            max_per_loop: 10,
+            max_records: None,
+            dry_run: false,
+        }
+    }
+}
+
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct PurgeSummary {
+    /// Records deleted
+    pub purged: u64,
+    /// Records left in place as they may still have data on their node
+    pub skipped: u64,
+}
+
+/// Delete the user records replaced (or retired) more than the grace period
+/// ago whose node has since been removed.
+///
+/// Records still allocated to a node are skipped: their data on the node has
+/// to be deleted first, which is left to the server's purger (see
+/// `SYNC_TOKENSERVER__PURGE_REPLACED_USERS_INTERVAL`).
+pub async fn purge_old_users(db: &mut dyn Db, options: &PurgeOptions) -> AdminResult<PurgeSummary> {
+    let service_id = sync_service_id(db).await?;
+    let replaced_before = Utc::now().timestamp_millis() - options.grace_period * 1000;
+    let mut summary = PurgeSummary::default();
+    // Purged records drop out of the results, so only page past the records
+    // left in place
+    let mut offset = 0;
+    loop {
+        let users = db
+            .get_old_users(params::GetOldUsers {
+                service_id,
+                replaced_before,
+                limit: options.max_per_loop,
+                offset,
+            })
+            .await?;
+        for user in &users {
+            if let Some(node) = &user.node {
+                debug!("Skipping uid {} on {}", user.uid, node);
+                offset += 1;
+                summary.skipped += 1;
+                continue;
+            }
+            if options.max_records.is_some_and(|max| summary.purged >= max) {
+                info!("Reached max_records, stopping");
+                return Ok(summary);
+            }
+            info!("Purging uid {} (no node)", user.uid);
+            if options.dry_run {
+                offset += 1;
+            } else {
+                db.delete_user(params::DeleteUser {
+                    service_id,
+                    uid: user.uid,
+                })
+                .await?;
+            }
+            summary.purged += 1;
+        }
+        if (users.len() as i64) < options.max_per_loop {
+            return Ok(summary);
+        }
+    }
+}
+
+/// An account event as sent by FxA, see
+/// https://github.com/mozilla/fxa/blob/main/packages/fxa-auth-server/docs/service_notifications.md
+#[derive(Debug, Deserialize)]
+struct AccountEvent {
+    event: String,
+    uid: String,
+    iss: Option<String>,
+    generation: Option<i64>,
+}
+
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct EventSummary {
+    pub processed: u64,
+    pub dropped: u64,
+    pub invalid: u64,
+}
+
+/// Process the account events in `input`, one per line. Each line is either
+/// an SQS message body (whose `Message` is the JSON encoded event) or the
+/// event itself.
+///
+/// Invalid or unknown events are logged and skipped.
+pub async fn process_account_events(
+    db: &mut dyn Db,
+    input: impl BufRead,
+) -> AdminResult<EventSummary> {
+    let service_id = sync_service_id(db).await?;
+    let mut summary = EventSummary::default();
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event = match parse_account_event(&line) {
+            Ok(event) => event,
+            Err(e) => {
+                warn!("Invalid account message: {}", e);
+                summary.invalid += 1;
+                continue;
+            }
+        };
+        // Older versions of FxA sent an email-like identifier in the `uid`
+        // field, rather than including the issuer separately
+        let email = match &event.iss {
+            Some(iss) => format!("{}@{}", event.uid, iss),
+            None if event.uid.contains('@') => event.uid.clone(),
+            None => {
+                warn!("Invalid account message: uid field does not contain issuer info");
+                summary.invalid += 1;
+                continue;
+            }
+        };
+        match (event.event.as_str(), event.generation) {
+            ("delete", _) => {
+                info!("Processing account delete for {}", email);
+                db.retire_user(params::RetireUser { service_id, email })
+                    .await?;
+            }
+            ("reset" | "passwordChange", Some(generation)) => {
+                info!("Processing {} for {}", event.event, email);
+                // Locks out devices with an older generation, while still
+                // accepting the new generation with its new client state
+                db.update_user_generation(params::UpdateUserGeneration {
+                    service_id,
+                    email,
+                    generation: Some(generation - 1),
+                    keys_changed_at: None,
+                })
+                .await?;
+            }
+            ("reset" | "passwordChange", None) => {
+                warn!(
+                    "Invalid account message: {} without a generation",
+                    event.event
+                );
+                summary.invalid += 1;
+                continue;
+            }
+            (event_type, _) => {
+                warn!("Dropping unknown event type {:?}", event_type);
+                summary.dropped += 1;
+                continue;
+            }
+        }
+        summary.processed += 1;
+    }
+    Ok(summary)
+}
+
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct ReplaySummary {
+    pub replayed: u64,
+    pub dropped: u64,
+    pub failed: u64,
+}
+
+/// Reprocess up to `limit` of the FxA webhook events that failed to process,
+/// oldest first.
+///
+/// Events that now succeed (or are dropped as unhandled) are deleted. Those
+/// that fail again are kept, recording the new error.
+pub async fn replay_failed_events(db: &mut dyn Db, limit: i64) -> AdminResult<ReplaySummary> {
+    let service_id = sync_service_id(db).await?;
+    let mut summary = ReplaySummary::default();
+    for event in db
+        .get_failed_events(params::GetFailedEvents { limit })
+        .await?
+    {
+        let payload = serde_json::from_str(&event.payload).unwrap_or(Value::Null);
+        // The event's deleted in the transaction applying it, so it's never applied twice
+        db.begin().await?;
+        let result = match db
+            .apply_fxa_event(params::ApplyFxaEvent {
+                service_id,
+                email: event.email.clone(),
+                event_type: event.event_type.clone(),
+                payload,
+            })
+            .await
+        {
+            Ok(handled) => db
+                .delete_failed_event(params::DeleteFailedEvent { id: event.id })
+                .await
+                .map(|_| handled),
+            Err(e) => Err(e),
+        };
+        if result.is_ok() {
+            db.commit().await?;
+        } else {
+            db.rollback().await?;
+        }
+        match result {
+            Ok(handled) => {
+                if handled {
+                    summary.replayed += 1;
+                } else {
+                    warn!("Dropping unhandled event type {:?}", event.event_type);
+                    summary.dropped += 1;
+                }
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to replay {} for {} (attempt {}): {}",
+                    event.event_type,
+                    event.email,
+                    event.attempts + 1,
+                    e
+                );
+                db.update_failed_event(params::UpdateFailedEvent {
+                    id: event.id,
+                    error: e.to_string(),
+                    failed_at: Utc::now().timestamp_millis(),
+                })
+                .await?;
+                summary.failed += 1;
+            }
+        }
+    }
+    Ok(summary)
+}
+
+/// Forget the FxA webhook events processed more than `grace_period` seconds
+/// ago, returning how many were deleted. Redeliveries of these events are no
+/// longer recognized as such.
+pub async fn purge_processed_events(db: &mut dyn Db, grace_period: i64) -> AdminResult<usize> {
+    Ok(db
+        .delete_processed_events(params::DeleteProcessedEvents {
+            processed_before: Utc::now().timestamp_millis() - grace_period * 1000,
+        })
+        .await?)
+}
+
+fn parse_account_event(line: &str) -> serde_json::Result<AccountEvent> {
+    let body: Value = serde_json::from_str(line)?;
+    match body.get("Message").and_then(Value::as_str) {
+        Some(message) => serde_json::from_str(message),
+        None => serde_json::from_value(body),
+    }
+}
+
+async fn sync_service_id(db: &mut dyn Db) -> AdminResult<i32> {
+    Ok(db
+        .get_service_id(params::GetServiceId {
+            service: SYNC_SERVICE_NAME.to_owned(),
+        })
+        .await?
+        .id)
+}
+
+/// Log to stderr, leaving stdout for the commands' output. Logging is
+/// synchronous so that nothing is lost when a command exits.
+pub fn init_logging() {
+    let decorator = slog_term::TermDecorator::new().stderr().build();
+    let drain = slog_term::FullFormat::new(decorator).build().fuse();
+    let drain = slog_envlogger::new(drain);
+    let drain = Mutex::new(drain).fuse();
+    let logger = slog::Logger::root(drain, slog::o!());
+    slog_scope::set_global_logger(logger).cancel_reset();
+}
+
+/// Build and initialize a pool for the configured tokenserver database
+pub async fn pool(settings: &Settings) -> AdminResult<Box<dyn DbPool>> {
+    let mut pool = pool_from_settings(settings, &Metrics::noop(), false)?;
+    pool.init().await?;
+    Ok(pool)
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod test;
@@ -0,0 +1,153 @@
+//! Administrative commands for the tokenserver database
+#[macro_use]
+extern crate slog_scope;
+
+use std::{
+    error::Error,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::PathBuf,
+};
+
+use chrono::{Datelike, TimeZone, Utc};
+use docopt::Docopt;
+use serde::Deserialize;
+
+use syncserver_settings::Settings;
+use tokenserver_admin::{
+    AdminError, PurgeOptions, allocate_user, count_users, init_logging, pool,
+    process_account_events, purge_old_users, purge_processed_events, replay_failed_events,
+};
+
+const USAGE: &str = "
+Usage: tokenserver-admin count-users [options] [--timestamp=MS] [--output=FILE]
+       tokenserver-admin allocate-user [options] <email> [<node>]
+       tokenserver-admin purge-old-records [options] [--grace-period=SECS] [--max-per-loop=N] [--max-records=N] [--dry-run]
+       tokenserver-admin process-account-events [options] [<file>]
+       tokenserver-admin replay-failed-events [options] [--limit=N]
+       tokenserver-admin purge-processed-events [options] [--grace-period=SECS]
+
+Administrative commands for the configured tokenserver database (see
+SYNC_TOKENSERVER__DATABASE_URL).
+
+Commands:
+    count-users              Write a JSON record of the number of current users
+                             allocated to each node.
+    allocate-user            Allocate a user to <node>, or the best available
+                             node. Existing users are moved to the node.
+    purge-old-records        Delete the user records replaced more than the grace
+                             period ago whose node was removed. Records still on
+                             a node are left to the server's purger.
+    process-account-events   Apply the FxA account events in <file> (or stdin),
+                             one SQS message body per line.
+    replay-failed-events     Reprocess the FxA webhook events that failed to
+                             process, deleting those that now succeed.
+    purge-processed-events   Forget the FxA webhook events processed more than
+                             the grace period ago.
+
+Options:
+    -h, --help               Show this message.
+    --config=CONFIGFILE      Configuration file path.
+    --timestamp=MS           Only count users created at or before this time
+                             (in milliseconds). Defaults to the previous midnight (UTC).
+    --output=FILE            Append the count to FILE, rather than writing to stdout.
+    --grace-period=SECS      Only purge records replaced (or events processed) at
+                             least this many seconds ago [default: 86400].
+    --max-per-loop=N         Max number of records fetched at a time [default: 10].
+    --max-records=N          Stop after purging N records.
+    --dry-run                Only report the records that would be purged.
+    --limit=N                Max number of failed events replayed [default: 100].
+";
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    cmd_count_users: bool,
+    cmd_allocate_user: bool,
+    cmd_purge_old_records: bool,
+    cmd_process_account_events: bool,
+    cmd_replay_failed_events: bool,
+    cmd_purge_processed_events: bool,
+    arg_email: Option<String>,
+    arg_node: Option<String>,
+    arg_file: Option<PathBuf>,
+    flag_config: Option<String>,
+    flag_timestamp: Option<i64>,
+    flag_output: Option<PathBuf>,
+    flag_grace_period: i64,
+    flag_max_per_loop: i64,
+    flag_max_records: Option<u64>,
+    flag_dry_run: bool,
+    flag_limit: i64,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+    let settings = Settings::with_env_and_config_file(args.flag_config.as_deref())?;
+    init_logging();
+
+    let pool = pool(&settings.tokenserver).await?;
+    let mut db = pool.get().await.map_err(AdminError::from)?;
+
+    if args.cmd_count_users {
+        let timestamp = args.flag_timestamp.unwrap_or_else(|| {
+            let today = Utc::now().date_naive();
+            Utc.with_ymd_and_hms(today.year(), today.month(), today.day(), 0, 0, 0)
+                .unwrap()
+                .timestamp_millis()
+        });
+        let counts = count_users(&mut *db, timestamp).await?;
+        info!("Found {} users", counts.total_users);
+        let mut out: Box<dyn Write> = match &args.flag_output {
+            Some(path) => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+            None => Box::new(io::stdout().lock()),
+        };
+        writeln!(out, "{}", serde_json::to_string(&counts)?)?;
+    } else if args.cmd_allocate_user {
+        let email = args.arg_email.as_deref().unwrap_or_default();
+        let user = allocate_user(&mut *db, email, args.arg_node.as_deref()).await?;
+        info!("Allocated {} (uid {}) to {}", email, user.uid, user.node);
+        println!("{}", user.node);
+    } else if args.cmd_purge_old_records {
+        let options = PurgeOptions {
+            grace_period: args.flag_grace_period,
+            max_per_loop: args.flag_max_per_loop,
+            max_records: args.flag_max_records,
+            dry_run: args.flag_dry_run,
+        };
+        let summary = purge_old_users(&mut *db, &options).await?;
+        info!(
+            "{} {} old user records ({} skipped, still on a node)",
+            if options.dry_run {
+                "Would purge"
+            } else {
+                "Purged"
+            },
+            summary.purged,
+            summary.skipped
+        );
+    } else if args.cmd_replay_failed_events {
+        let summary = replay_failed_events(&mut *db, args.flag_limit).await?;
+        info!(
+            "Replayed {} failed events ({} dropped, {} failed again)",
+            summary.replayed, summary.dropped, summary.failed
+        );
+    } else if args.cmd_purge_processed_events {
+        let purged = purge_processed_events(&mut *db, args.flag_grace_period).await?;
+        info!("Purged {} processed events", purged);
+    } else {
+        debug_assert!(args.cmd_process_account_events);
+        let input: Box<dyn BufRead> = match &args.arg_file {
+            Some(path) => Box::new(BufReader::new(File::open(path)?)),
+            None => Box::new(io::stdin().lock()),
+        };
+        let summary = process_account_events(&mut *db, input).await?;
+        info!(
+            "Processed {} account events ({} dropped, {} invalid)",
+            summary.processed, summary.dropped, summary.invalid
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,338 @@
+use std::path::PathBuf;
+
+use chrono::Utc;
+use tokenserver_db::{Db, DbPool, SYNC_SERVICE_NAME, fxa_events, params};
+use tokenserver_settings::Settings;
+
+use crate::{
+    AdminError, EventSummary, PurgeOptions, PurgeSummary, ReplaySummary, allocate_user,
+    count_users, pool, process_account_events, purge_old_users, purge_processed_events,
+    replay_failed_events,
+};
+
+fn temp_path(name: &str) -> PathBuf {
+    let path =
+        std::env::temp_dir().join(format!("tokenserver-admin-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+async fn test_pool(name: &str) -> Box<dyn DbPool> {
+    let settings = Settings {
+        database_url: format!("sqlite://{}", temp_path(name).display()),
+        run_migrations: true,
+        ..Default::default()
+    };
+    pool(&settings).await.unwrap()
+}
+
+async fn post_node(db: &mut dyn Db, node: &str) -> i64 {
+    let service_id = db
+        .get_service_id(params::GetServiceId {
+            service: SYNC_SERVICE_NAME.to_owned(),
+        })
+        .await
+        .unwrap()
+        .id;
+    db.post_node(params::PostNode {
+        service_id,
+        node: node.to_owned(),
+        capacity: 100,
+        available: 100,
+        ..Default::default()
+    })
+    .await
+    .unwrap()
+    .id
+}
+
+#[tokio::test]
+async fn allocate_and_count_users() {
+    let pool = test_pool("allocate.db").await;
+    let mut db = pool.get().await.unwrap();
+    post_node(&mut *db, "https://node1").await;
+    let node2_id = post_node(&mut *db, "https://node2").await;
+
+    let user1 = allocate_user(&mut *db, "test1@test.com", Some("https://node2"))
+        .await
+        .unwrap();
+    assert_eq!(user1.node, "https://node2");
+    allocate_user(&mut *db, "test2@test.com", Some("https://node2"))
+        .await
+        .unwrap();
+    let node2 = db.get_node(params::GetNode { id: node2_id }).await.unwrap();
+    assert_eq!(node2.current_load, 2);
+
+    assert!(matches!(
+        allocate_user(&mut *db, "test3@test.com", Some("https://node3")).await,
+        Err(AdminError::UnknownNode(_))
+    ));
+
+    // Moving an existing user replaces their old record
+    let moved = allocate_user(&mut *db, "test1@test.com", Some("https://node1"))
+        .await
+        .unwrap();
+    assert_ne!(moved.uid, user1.uid);
+    assert_eq!(moved.node, "https://node1");
+
+    let counts = count_users(&mut *db, Utc::now().timestamp_millis())
+        .await
+        .unwrap();
+    assert_eq!(counts.total_users, 2);
+    assert_eq!(
+        counts
+            .nodes
+            .iter()
+            .map(|node| (node.node.as_str(), node.count))
+            .collect::<Vec<_>>(),
+        vec![("https://node1", 1), ("https://node2", 1)]
+    );
+
+    // Users created after the timestamp aren't counted
+    let counts = count_users(&mut *db, user1.created_at - 1).await.unwrap();
+    assert_eq!(counts.total_users, 0);
+}
+
+#[tokio::test]
+async fn purge_old_records() {
+    let pool = test_pool("purge.db").await;
+    let mut db = pool.get().await.unwrap();
+    let node1_id = post_node(&mut *db, "https://node1").await;
+    post_node(&mut *db, "https://node2").await;
+
+    for (email, node) in [
+        ("test1@test.com", "https://node1"),
+        ("test2@test.com", "https://node1"),
+        ("test3@test.com", "https://node2"),
+    ] {
+        allocate_user(&mut *db, email, Some(node)).await.unwrap();
+        // Replace the user's first record
+        allocate_user(&mut *db, email, Some(node)).await.unwrap();
+    }
+
+    // Nothing has been replaced for longer than the grace period
+    let options = PurgeOptions {
+        max_per_loop: 2,
+        ..Default::default()
+    };
+    assert_eq!(
+        purge_old_users(&mut *db, &options).await.unwrap(),
+        PurgeSummary::default()
+    );
+
+    // The replaced records may still have data on their node
+    let options = PurgeOptions {
+        grace_period: -1,
+        max_per_loop: 2,
+        dry_run: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        purge_old_users(&mut *db, &options).await.unwrap(),
+        PurgeSummary {
+            purged: 0,
+            skipped: 3,
+        }
+    );
+
+    db.remove_node(params::RemoveNode { node_id: node1_id })
+        .await
+        .unwrap();
+    assert_eq!(
+        purge_old_users(&mut *db, &options).await.unwrap(),
+        PurgeSummary {
+            purged: 2,
+            skipped: 1,
+        }
+    );
+
+    let options = PurgeOptions {
+        max_records: Some(1),
+        dry_run: false,
+        ..options
+    };
+    assert_eq!(purge_old_users(&mut *db, &options).await.unwrap().purged, 1);
+    let options = PurgeOptions {
+        max_records: None,
+        ..options
+    };
+    assert_eq!(purge_old_users(&mut *db, &options).await.unwrap().purged, 1);
+    assert_eq!(
+        purge_old_users(&mut *db, &options).await.unwrap(),
+        PurgeSummary {
+            purged: 0,
+            skipped: 1,
+        }
+    );
+
+    // The current records, and the replaced record still on a node, remain
+    let service_id = db
+        .get_service_id(params::GetServiceId {
+            service: SYNC_SERVICE_NAME.to_owned(),
+        })
+        .await
+        .unwrap()
+        .id;
+    for (email, records) in [
+        ("test1@test.com", 1),
+        ("test2@test.com", 1),
+        ("test3@test.com", 2),
+    ] {
+        let users = db
+            .get_users(params::GetUsers {
+                service_id,
+                email: email.to_owned(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(users.len(), records);
+    }
+}
+
+#[tokio::test]
+async fn account_events() {
+    let pool = test_pool("events.db").await;
+    let mut db = pool.get().await.unwrap();
+    post_node(&mut *db, "https://node1").await;
+    let service_id = db
+        .get_service_id(params::GetServiceId {
+            service: SYNC_SERVICE_NAME.to_owned(),
+        })
+        .await
+        .unwrap()
+        .id;
+    for email in ["deleted@test.com", "reset@test.com"] {
+        db.get_or_create_user(params::GetOrCreateUser {
+            service_id,
+            email: email.to_owned(),
+            generation: 1234,
+            client_state: "aaaa".to_owned(),
+            keys_changed_at: Some(1234),
+            capacity_release_rate: None,
+        })
+        .await
+        .unwrap();
+    }
+
+    let events = [
+        r#"{"Message": "{\"event\": \"delete\", \"uid\": \"deleted\", \"iss\": \"test.com\"}"}"#,
+        r#"{"event": "reset", "uid": "reset@test.com", "generation": 5678}"#,
+        r#"{"event": "verified", "uid": "reset", "iss": "test.com"}"#,
+        r#"{"event": "passwordChange", "uid": "reset"}"#,
+        "",
+        "not json",
+    ]
+    .join("\n");
+    let summary = process_account_events(&mut *db, events.as_bytes())
+        .await
+        .unwrap();
+    assert_eq!(
+        summary,
+        EventSummary {
+            processed: 2,
+            dropped: 1,
+            invalid: 2,
+        }
+    );
+
+    let deleted = db
+        .get_users(params::GetUsers {
+            service_id,
+            email: "deleted@test.com".to_owned(),
+        })
+        .await
+        .unwrap();
+    assert!(deleted[0].replaced_at.is_some());
+    assert_eq!(deleted[0].generation, tokenserver_db::MAX_GENERATION);
+    let reset = db
+        .get_users(params::GetUsers {
+            service_id,
+            email: "reset@test.com".to_owned(),
+        })
+        .await
+        .unwrap();
+    assert_eq!(reset[0].generation, 5677);
+}
+
+#[tokio::test]
+async fn replay_and_purge_webhook_events() {
+    let pool = test_pool("webhook.db").await;
+    let mut db = pool.get().await.unwrap();
+    post_node(&mut *db, "https://node1").await;
+    let service_id = db
+        .get_service_id(params::GetServiceId {
+            service: SYNC_SERVICE_NAME.to_owned(),
+        })
+        .await
+        .unwrap()
+        .id;
+    db.get_or_create_user(params::GetOrCreateUser {
+        service_id,
+        email: "deleted@test.com".to_owned(),
+        generation: 1234,
+        client_state: "aaaa".to_owned(),
+        keys_changed_at: Some(1234),
+        capacity_release_rate: None,
+    })
+    .await
+    .unwrap();
+
+    let now = Utc::now().timestamp_millis();
+    for event_type in [
+        fxa_events::DELETE_USER,
+        "https://schemas.accounts.firefox.com/event/unknown-event",
+    ] {
+        db.post_failed_event(params::PostFailedEvent {
+            jti: "wibble".to_owned(),
+            email: "deleted@test.com".to_owned(),
+            event_type: event_type.to_owned(),
+            payload: "{}".to_owned(),
+            error: "Database error".to_owned(),
+            failed_at: now,
+        })
+        .await
+        .unwrap();
+    }
+    let failed = db
+        .get_failed_events(params::GetFailedEvents { limit: 10 })
+        .await
+        .unwrap();
+    assert_eq!(failed.len(), 2);
+    assert_eq!(failed[0].event_type, fxa_events::DELETE_USER);
+    assert_eq!(failed[0].attempts, 1);
+
+    let summary = replay_failed_events(&mut *db, 10).await.unwrap();
+    assert_eq!(
+        summary,
+        ReplaySummary {
+            replayed: 1,
+            dropped: 1,
+            failed: 0,
+        }
+    );
+    let deleted = db
+        .get_users(params::GetUsers {
+            service_id,
+            email: "deleted@test.com".to_owned(),
+        })
+        .await
+        .unwrap();
+    assert_eq!(deleted[0].generation, tokenserver_db::MAX_GENERATION);
+    assert!(
+        db.get_failed_events(params::GetFailedEvents { limit: 10 })
+            .await
+            .unwrap()
+            .is_empty()
+    );
+
+    // Processed events are recorded once, and forgotten after the grace period
+    let wibble = || params::MarkEventProcessed {
+        jti: "wibble".to_owned(),
+        processed_at: now - 10_000,
+    };
+    assert!(db.mark_event_processed(wibble()).await.unwrap());
+    assert!(!db.mark_event_processed(wibble()).await.unwrap());
+    assert_eq!(purge_processed_events(&mut *db, 60).await.unwrap(), 0);
+    assert_eq!(purge_processed_events(&mut *db, 5).await.unwrap(), 1);
+    assert!(db.mark_event_processed(wibble()).await.unwrap());
+}
@@ -120,12 +120,18 @@ pub trait JWTVerifier: TryFrom<Self::Key, Error = JWTVerifyError> + Sync + Send
     type Key: DeserializeOwned;
 
     fn verify<T: DeserializeOwned>(&self, token: &str) -> Result<T, JWTVerifyError>;
+
+    /// The id (`kid`) of the key used to verify tokens, if it has one
+    fn key_id(&self) -> Option<&str> {
+        None
+    }
 }
 
 /// An implementation of the JWT verifier using the jsonwebtoken crate
 #[derive(Clone)]
 pub struct JWTVerifierImpl {
     key: DecodingKey,
+    key_id: Option<String>,
     validation: Validation,
 }
 
@@ -153,6 +159,10 @@ impl JWTVerifier for JWTVerifierImpl {
             })?;
         Ok(token_data.claims)
     }
+
+    fn key_id(&self) -> Option<&str> {
+        self.key_id.as_deref()
+    }
 }
 
 impl TryFrom<Jwk> for JWTVerifierImpl {
@@ -169,6 +179,7 @@ impl TryFrom<Jwk> for JWTVerifierImpl {
 
         Ok(Self {
             key: decoding_key,
+            key_id: value.common.key_id,
             validation,
         })
     }
@@ -179,6 +190,8 @@ impl TryFrom<Jwk> for JWTVerifierImpl {
 pub struct FxaWebhookClaims {
     pub sub: String,
     pub iss: String,
+    /// The token's unique id, identifying redeliveries of the same events
+    pub jti: String,
     pub events: serde_json::Value,
 }
 
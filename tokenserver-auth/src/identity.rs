@@ -0,0 +1,69 @@
+use async_trait::async_trait;
+use syncserver_common::Metrics;
+use tokenserver_common::TokenserverError;
+
+use crate::{
+    VerifyToken,
+    oauth::{JwksStatus, VerifyOutput},
+};
+
+mod oidc;
+
+pub use oidc::OidcIdentityProvider;
+
+/// An identity provider Sync clients authenticate against: verifies the OAuth tokens they send
+/// Tokenserver and maps the users they identify to Tokenserver's user records.
+pub trait IdentityProvider: VerifyToken<Output = VerifyOutput> {
+    /// The email address the user with the given id is recorded under
+    fn email(&self, user_id: &str) -> String;
+
+    /// Whether clients must send FxA's `X-KeyID` header. Without it, the client state is read
+    /// from `X-Client-State` (when sent) and no keys_changed_at is tracked.
+    fn requires_key_id(&self) -> bool {
+        true
+    }
+}
+
+dyn_clone::clone_trait_object!(IdentityProvider);
+
+/// Firefox Accounts: the users of its OAuth tokens are recorded as `[FxA uid]@[email domain]`.
+#[derive(Clone)]
+pub struct FxaIdentityProvider {
+    verifier: Box<dyn VerifyToken<Output = VerifyOutput>>,
+    email_domain: String,
+}
+
+impl FxaIdentityProvider {
+    pub fn new(
+        verifier: Box<dyn VerifyToken<Output = VerifyOutput>>,
+        email_domain: impl Into<String>,
+    ) -> Self {
+        Self {
+            verifier,
+            email_domain: email_domain.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl VerifyToken for FxaIdentityProvider {
+    type Output = VerifyOutput;
+
+    async fn verify(
+        &self,
+        token: String,
+        metrics: &Metrics,
+    ) -> Result<VerifyOutput, TokenserverError> {
+        self.verifier.verify(token, metrics).await
+    }
+
+    fn jwks_status(&self) -> Option<JwksStatus> {
+        self.verifier.jwks_status()
+    }
+}
+
+impl IdentityProvider for FxaIdentityProvider {
+    fn email(&self, user_id: &str) -> String {
+        format!("{}@{}", user_id, self.email_domain)
+    }
+}
@@ -4,9 +4,12 @@ mod crypto;
 pub mod test_utils;
 
 pub use crypto::{FxaWebhookClaims, JWTVerifyError, SETVerifierImpl};
+pub use identity::{FxaIdentityProvider, IdentityProvider, OidcIdentityProvider};
 #[cfg(not(feature = "py"))]
 pub use crypto::{JWTVerifier, JWTVerifierImpl};
 
+#[allow(clippy::result_large_err)]
+mod identity;
 #[allow(clippy::result_large_err)]
 pub mod oauth;
 #[allow(clippy::result_large_err)]
@@ -65,6 +68,11 @@ pub trait VerifyToken: DynClone + Sync + Send {
         token: String,
         metrics: &Metrics,
     ) -> Result<Self::Output, TokenserverError>;
+
+    /// Reports the state of the verifier's cached JWKS, if it keeps one.
+    fn jwks_status(&self) -> Option<oauth::JwksStatus> {
+        None
+    }
 }
 
 dyn_clone::clone_trait_object!(<T> VerifyToken<Output=T>);
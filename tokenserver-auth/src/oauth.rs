@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
+use tokenserver_common::TokenserverError;
 
+mod jwks;
 #[cfg(not(feature = "py"))]
 mod native;
 #[cfg(feature = "py")]
@@ -11,6 +13,8 @@ pub type Verifier = py::Verifier;
 #[cfg(not(feature = "py"))]
 pub type Verifier<J> = native::Verifier<J>;
 
+pub use jwks::JwksCache;
+
 /// The information extracted from a valid OAuth token.
 #[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
 pub struct VerifyOutput {
@@ -18,3 +22,26 @@ pub struct VerifyOutput {
     pub fxa_uid: String,
     pub generation: Option<i64>,
 }
+
+/// The state of a verifier's cached JWKS, reported by Tokenserver's `/__heartbeat__`.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct JwksStatus {
+    /// The ids of the cached keys.
+    pub key_ids: Vec<String>,
+    /// When the keys were last fetched, in seconds since the epoch.
+    pub last_refresh: Option<u64>,
+}
+
+pub(crate) fn unauthorized_err_with_ctx<E: std::fmt::Display>(err: E) -> TokenserverError {
+    TokenserverError {
+        context: err.to_string(),
+        ..TokenserverError::invalid_credentials("Unauthorized".to_string())
+    }
+}
+
+pub(crate) fn internal_err_with_ctx<E: std::fmt::Display>(err: E) -> TokenserverError {
+    TokenserverError {
+        context: err.to_string(),
+        ..TokenserverError::internal_error()
+    }
+}
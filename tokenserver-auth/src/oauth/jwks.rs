@@ -0,0 +1,322 @@
+use std::{
+    sync::{Arc, RwLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use reqwest::{
+    Url,
+    header::{CACHE_CONTROL, HeaderMap},
+};
+use serde::Deserialize;
+use tokenserver_common::TokenserverError;
+use tokio::{sync::Mutex, time};
+
+use super::{JwksStatus, internal_err_with_ctx};
+use crate::crypto::JWTVerifier;
+
+/// A cache of an OAuth server's public keys (JWKS), e.g. those on FxA's `/v1/jwks` endpoint.
+///
+/// The keys are refetched on a schedule (honoring the response's `Cache-Control` max-age) and
+/// whenever a token signed by an unknown key id is received. The last successfully fetched keys
+/// are kept when the server can't be reached.
+pub struct JwksCache<J> {
+    jwks_url: Url,
+    http_client: reqwest::Client,
+    refresh_interval: Duration,
+    min_refresh_interval: Duration,
+    keys: RwLock<KeySet<J>>,
+    /// Held while fetching, so concurrent refreshes only fetch once
+    fetching: Mutex<()>,
+}
+
+struct KeySet<J> {
+    verifiers: Vec<J>,
+    /// When the keys were last successfully fetched
+    refreshed_at: Option<SystemTime>,
+    /// The max-age of the last successful response
+    max_age: Option<Duration>,
+    /// When the keys were last fetched, successfully or not
+    attempted_at: Option<Instant>,
+}
+
+impl<J> JwksCache<J>
+where
+    J: JWTVerifier,
+{
+    pub fn new(
+        jwks_url: Url,
+        http_client: reqwest::Client,
+        refresh_interval: Duration,
+        min_refresh_interval: Duration,
+    ) -> Self {
+        Self {
+            jwks_url,
+            http_client,
+            refresh_interval,
+            min_refresh_interval,
+            keys: RwLock::new(KeySet {
+                verifiers: vec![],
+                refreshed_at: None,
+                max_age: None,
+                attempted_at: None,
+            }),
+            fetching: Mutex::new(()),
+        }
+    }
+
+    /// The cached keys
+    pub fn verifiers(&self) -> Vec<J> {
+        self.keys
+            .read()
+            .expect("JwksCache lock poisoned")
+            .verifiers
+            .clone()
+    }
+
+    /// The cached keys for verifying a token signed by the `kid` key, first refetching them if
+    /// none are cached or `kid` is unknown (at most once per `min_refresh_interval`)
+    pub async fn verifiers_for(&self, kid: Option<&str>) -> Vec<J> {
+        let stale = {
+            let keys = self.keys.read().expect("JwksCache lock poisoned");
+            let unknown = keys.verifiers.is_empty()
+                || kid.is_some_and(|kid| {
+                    !keys
+                        .verifiers
+                        .iter()
+                        .any(|verifier| verifier.key_id() == Some(kid))
+                });
+            unknown
+                && keys
+                    .attempted_at
+                    .is_none_or(|at| at.elapsed() >= self.min_refresh_interval)
+        };
+        if stale {
+            // Failures are logged and the last good keys are kept
+            let _ = self.refresh().await;
+        }
+        self.verifiers()
+    }
+
+    /// Refetch the keys, keeping the cached ones on failure
+    pub async fn refresh(&self) -> Result<(), TokenserverError> {
+        let requested_at = Instant::now();
+        let _fetching = self.fetching.lock().await;
+        {
+            let keys = self.keys.read().expect("JwksCache lock poisoned");
+            if keys.attempted_at.is_some_and(|at| at >= requested_at) {
+                // Refreshed by another task while waiting for the lock
+                return Ok(());
+            }
+        }
+
+        let result = self.fetch().await;
+        let mut keys = self.keys.write().expect("JwksCache lock poisoned");
+        keys.attempted_at = Some(Instant::now());
+        match result {
+            Ok((verifiers, max_age)) => {
+                keys.verifiers = verifiers;
+                keys.refreshed_at = Some(SystemTime::now());
+                keys.max_age = max_age;
+                Ok(())
+            }
+            Err(e) => {
+                slog_scope::warn!("Error requesting remote jwks: {}", e.context);
+                Err(e)
+            }
+        }
+    }
+
+    async fn fetch(&self) -> Result<(Vec<J>, Option<Duration>), TokenserverError> {
+        #[derive(Deserialize)]
+        struct KeysResponse<K> {
+            keys: Vec<K>,
+        }
+
+        let response = self
+            .http_client
+            .get(self.jwks_url.clone())
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(internal_err_with_ctx)?;
+        let max_age = max_age(response.headers());
+        let verifiers = response
+            .json::<KeysResponse<J::Key>>()
+            .await
+            .map_err(internal_err_with_ctx)?
+            .keys
+            .into_iter()
+            .map(|key| key.try_into().map_err(internal_err_with_ctx))
+            .collect::<Result<Vec<J>, _>>()?;
+        if verifiers.is_empty() {
+            return Err(internal_err_with_ctx("Empty remote jwks"));
+        }
+        Ok((verifiers, max_age))
+    }
+
+    /// How long until the keys should next be refetched
+    fn next_refresh(&self) -> Duration {
+        let keys = self.keys.read().expect("JwksCache lock poisoned");
+        keys.max_age
+            .unwrap_or(self.refresh_interval)
+            .max(self.min_refresh_interval)
+    }
+
+    /// The ids of the cached keys and when they were fetched
+    pub fn status(&self) -> JwksStatus {
+        let keys = self.keys.read().expect("JwksCache lock poisoned");
+        JwksStatus {
+            key_ids: keys
+                .verifiers
+                .iter()
+                .filter_map(|verifier| verifier.key_id().map(ToOwned::to_owned))
+                .collect(),
+            last_refresh: keys
+                .refreshed_at
+                .map(|at| at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()),
+        }
+    }
+}
+
+impl<J> JwksCache<J>
+where
+    J: JWTVerifier + 'static,
+{
+    /// Fetch the keys now, then keep refetching them in the background: when their max-age
+    /// expires (or every `refresh_interval`), or after `min_refresh_interval` on failure
+    pub fn spawn_refresher(self: &Arc<Self>) {
+        let cache = Arc::clone(self);
+        tokio::spawn(async move {
+            loop {
+                let delay = match cache.refresh().await {
+                    Ok(()) => cache.next_refresh(),
+                    Err(_) => cache.min_refresh_interval,
+                };
+                time::sleep(delay).await;
+            }
+        });
+    }
+}
+
+/// The `max-age` of a `Cache-Control` header (0 for `no-cache` or `no-store`)
+fn max_age(headers: &HeaderMap) -> Option<Duration> {
+    let cache_control = headers.get(CACHE_CONTROL)?.to_str().ok()?;
+    cache_control.split(',').find_map(|directive| {
+        let directive = directive.trim();
+        if directive.eq_ignore_ascii_case("no-cache") || directive.eq_ignore_ascii_case("no-store")
+        {
+            return Some(Duration::ZERO);
+        }
+        let (name, value) = directive.split_once('=')?;
+        if !name.trim().eq_ignore_ascii_case("max-age") {
+            return None;
+        }
+        value.trim().parse().ok().map(Duration::from_secs)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{crypto::JWTVerifierImpl, test_utils::test_jwk};
+
+    fn jwks_body(kid: &str) -> String {
+        let mut jwk = serde_json::to_value(test_jwk()).unwrap();
+        jwk["kid"] = json!(kid);
+        json!({ "keys": [jwk] }).to_string()
+    }
+
+    fn cache(server: &mockito::Server) -> JwksCache<JWTVerifierImpl> {
+        JwksCache::new(
+            Url::parse(&server.url()).unwrap().join("v1/jwks").unwrap(),
+            reqwest::Client::new(),
+            Duration::from_secs(3600),
+            Duration::from_secs(60),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_refresh_honors_cache_control() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/v1/jwks")
+            .with_header("content-type", "application/json")
+            .with_header("cache-control", "public, max-age=600")
+            .with_body(jwks_body("20260101"))
+            .create_async()
+            .await;
+        let cache = cache(&server);
+
+        cache.refresh().await.unwrap();
+        mock.assert_async().await;
+        let status = cache.status();
+        assert_eq!(status.key_ids, vec!["20260101".to_owned()]);
+        assert!(status.last_refresh.is_some());
+        assert_eq!(cache.next_refresh(), Duration::from_secs(600));
+    }
+
+    #[tokio::test]
+    async fn test_failed_refresh_keeps_last_keys() {
+        let mut server = mockito::Server::new_async().await;
+        let ok = server
+            .mock("GET", "/v1/jwks")
+            .with_header("content-type", "application/json")
+            .with_body(jwks_body("20260101"))
+            .create_async()
+            .await;
+        let cache = cache(&server);
+        cache.refresh().await.unwrap();
+        ok.remove_async().await;
+        let status = cache.status();
+
+        let failing = server
+            .mock("GET", "/v1/jwks")
+            .with_status(503)
+            .create_async()
+            .await;
+        assert!(cache.refresh().await.is_err());
+        failing.assert_async().await;
+        assert_eq!(cache.status(), status);
+        assert_eq!(cache.verifiers().len(), 1);
+        assert_eq!(cache.next_refresh(), Duration::from_secs(3600));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_kid_refreshes() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/v1/jwks")
+            .with_header("content-type", "application/json")
+            .with_body(jwks_body("20260101"))
+            .expect(1)
+            .create_async()
+            .await;
+        let cache = cache(&server);
+
+        // Fetches the initially empty key set
+        assert_eq!(cache.verifiers_for(Some("20260101")).await.len(), 1);
+        // Known key ids are served from the cache
+        assert_eq!(cache.verifiers_for(Some("20260101")).await.len(), 1);
+        // Unknown key ids are refetched, at most once per min_refresh_interval
+        assert_eq!(cache.verifiers_for(Some("20261231")).await.len(), 1);
+        mock.assert_async().await;
+    }
+
+    #[test]
+    fn test_max_age() {
+        let headers = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(CACHE_CONTROL, value.parse().unwrap());
+            headers
+        };
+        assert_eq!(
+            max_age(&headers("public, max-age=600")),
+            Some(Duration::from_secs(600))
+        );
+        assert_eq!(max_age(&headers("no-cache")), Some(Duration::ZERO));
+        assert_eq!(max_age(&headers("public")), None);
+        assert_eq!(max_age(&HeaderMap::new()), None);
+    }
+}
@@ -1,11 +1,11 @@
-use super::VerifyOutput;
+use super::{JwksCache, JwksStatus, VerifyOutput, unauthorized_err_with_ctx};
 use crate::VerifyToken;
 pub use crate::crypto::JWTVerifier;
 use crate::crypto::JWTVerifyError;
 use async_trait::async_trait;
 use reqwest::Url;
 use serde::{Deserialize, Serialize};
-use std::{borrow::Cow, time::Duration};
+use std::{borrow::Cow, sync::Arc, time::Duration};
 use syncserver_common::Metrics;
 use tokenserver_common::TokenserverError;
 use tokenserver_settings::Settings;
@@ -45,8 +45,9 @@ impl From<TokenClaims> for VerifyOutput {
 #[derive(Clone)]
 pub struct Verifier<J> {
     verify_url: Url,
-    jwks_url: Url,
     jwk_verifiers: Vec<J>,
+    /// FxA's keys, used when none are configured
+    jwks: Option<Arc<JwksCache<J>>>,
     http_client: reqwest::Client,
 }
 
@@ -68,15 +69,28 @@ where
             .use_rustls_tls()
             .build()
             .map_err(|_| TokenserverError::internal_error())?;
+        let jwks = jwk_verifiers.is_empty().then(|| {
+            Arc::new(JwksCache::new(
+                jwks_url,
+                http_client.clone(),
+                Duration::from_secs(settings.fxa_oauth_jwks_refresh_interval),
+                Duration::from_secs(settings.fxa_oauth_jwks_min_refresh_interval),
+            ))
+        });
 
         Ok(Self {
             verify_url,
-            jwks_url,
             jwk_verifiers,
+            jwks,
             http_client,
         })
     }
 
+    /// The cache of FxA's keys, when no keys are configured
+    pub fn jwks(&self) -> Option<&Arc<JwksCache<J>>> {
+        self.jwks.as_ref()
+    }
+
     async fn remote_verify_token(&self, token: &str) -> Result<TokenClaims, TokenserverError> {
         #[derive(Serialize)]
         struct VerifyRequest<'a> {
@@ -123,25 +137,6 @@ where
             .into())
     }
 
-    async fn get_remote_jwks(&self) -> Result<Vec<J>, TokenserverError> {
-        #[derive(Deserialize)]
-        struct KeysResponse<K> {
-            keys: Vec<K>,
-        }
-        self.http_client
-            .get(self.jwks_url.clone())
-            .send()
-            .await
-            .map_err(internal_err_with_ctx)?
-            .json::<KeysResponse<J::Key>>()
-            .await
-            .map_err(internal_err_with_ctx)?
-            .keys
-            .into_iter()
-            .map(|key| key.try_into().map_err(internal_err_with_ctx))
-            .collect()
-    }
-
     fn verify_jwt_locally(
         &self,
         verifiers: &[Cow<'_, J>],
@@ -180,8 +175,8 @@ where
     /// The verifier will first attempt to verify the token using FxA's public keys, which were
     /// provided as environment variables.
     ///
-    /// If FxA's public keys were not supplied, then the verifier will query FxA's /v1/jwks
-    /// endpoint to get the latest public keys.
+    /// If FxA's public keys were not supplied, then the verifier will use the keys cached from
+    /// FxA's /v1/jwks endpoint, refetching them first if the token's signed by an unknown key.
     ///
     /// If verifying the tokens fails because the keys are
     /// invalid, or because the keys were valid but the tokens have changed their structure, then
@@ -193,23 +188,23 @@ where
         token: String,
         metrics: &Metrics,
     ) -> Result<VerifyOutput, TokenserverError> {
-        let mut verifiers = self
-            .jwk_verifiers
-            .iter()
-            .map(Cow::Borrowed)
-            .collect::<Vec<_>>();
-        if self.jwk_verifiers.is_empty() {
-            verifiers = self
-                .get_remote_jwks()
-                .await
-                .unwrap_or_else(|e| {
-                    slog_scope::warn!("Error requesting remote jwks: {}", e);
-                    vec![]
-                })
-                .into_iter()
-                .map(Cow::Owned)
-                .collect();
-        }
+        let verifiers = match &self.jwks {
+            Some(jwks) => {
+                let kid = jsonwebtoken::decode_header(&token)
+                    .ok()
+                    .and_then(|header| header.kid);
+                jwks.verifiers_for(kid.as_deref())
+                    .await
+                    .into_iter()
+                    .map(Cow::Owned)
+                    .collect()
+            }
+            None => self
+                .jwk_verifiers
+                .iter()
+                .map(Cow::Borrowed)
+                .collect::<Vec<_>>(),
+        };
 
         let claims = match self.verify_jwt_locally(&verifiers, &token) {
             Ok(res) => res,
@@ -227,19 +222,9 @@ where
         };
         claims.validate()
     }
-}
-
-fn unauthorized_err_with_ctx<E: std::fmt::Display>(err: E) -> TokenserverError {
-    TokenserverError {
-        context: err.to_string(),
-        ..TokenserverError::invalid_credentials("Unauthorized".to_string())
-    }
-}
 
-fn internal_err_with_ctx<E: std::fmt::Display>(err: E) -> TokenserverError {
-    TokenserverError {
-        context: err.to_string(),
-        ..TokenserverError::internal_error()
+    fn jwks_status(&self) -> Option<JwksStatus> {
+        self.jwks.as_ref().map(|jwks| jwks.status())
     }
 }
 
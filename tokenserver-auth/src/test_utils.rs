@@ -98,3 +98,13 @@ pub fn make_set(
     let key = EncodingKey::from_rsa_pem(private_key_pem).expect("invalid PEM key");
     encode(&Header::new(Algorithm::RS256), &claims, &key).unwrap()
 }
+
+/// Sign a JWT with the given claims, key id and (RSA) PEM.
+pub fn make_jwt(kid: &str, claims: &serde_json::Value, private_key_pem: &[u8]) -> String {
+    let header = Header {
+        kid: Some(kid.to_owned()),
+        ..Header::new(Algorithm::RS256)
+    };
+    let key = EncodingKey::from_rsa_pem(private_key_pem).expect("invalid PEM key");
+    encode(&header, claims, &key).unwrap()
+}
@@ -15,3 +15,14 @@ pub enum NodeType {
     #[serde(rename = "postgres")]
     Postgres,
 }
+
+/// How Tokenserver picks the node new users are allocated to.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AllocationPolicy {
+    /// The node with the smallest share of its capacity in use.
+    #[default]
+    LeastLoaded,
+    /// Spread users across nodes in proportion to their `weight`.
+    Weighted,
+}
@@ -26,7 +26,6 @@ impl DbError {
         DbErrorKind::PoolTimeout(timeout_type).into()
     }
 
-    #[cfg(debug_assertions)]
     pub fn is_diesel_not_found(&self) -> bool {
         matches!(&self.kind, DbErrorKind::Sql(e) if e.is_diesel_not_found())
     }
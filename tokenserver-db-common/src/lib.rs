@@ -22,6 +22,32 @@ pub const MAX_GENERATION: i64 = i64::MAX;
 
 pub const SYNC_SERVICE_NAME: &str = "sync-1.5";
 
+/// The allocation weight of nodes created without one.
+pub const DEFAULT_NODE_WEIGHT: i32 = 100;
+
+/// The FxA account event types applied by [`Db::apply_fxa_event`], see
+/// <https://github.com/mozilla/fxa/tree/main/packages/fxa-event-broker>
+pub mod fxa_events {
+    pub const DELETE_USER: &str = "https://schemas.accounts.firefox.com/event/delete-user";
+    pub const PASSWORD_CHANGE: &str = "https://schemas.accounts.firefox.com/event/password-change";
+    pub const PROFILE_CHANGE: &str = "https://schemas.accounts.firefox.com/event/profile-change";
+    pub const SUBSCRIPTION_STATE_CHANGE: &str =
+        "https://schemas.accounts.firefox.com/event/subscription-state-change";
+    pub const METRICS_CHANGE: &str = "https://schemas.accounts.firefox.com/event/metrics-change";
+
+    /// The name an event type is reported under in metrics and logs, or `None` when it's unknown
+    pub fn name(event_type: &str) -> Option<&'static str> {
+        Some(match event_type {
+            DELETE_USER => "delete_user",
+            PASSWORD_CHANGE => "password_change",
+            PROFILE_CHANGE => "profile_change",
+            SUBSCRIPTION_STATE_CHANGE => "subscription_state_change",
+            METRICS_CHANGE => "metrics_change",
+            _ => return None,
+        })
+    }
+}
+
 #[async_trait(?Send)]
 pub trait DbPool: Sync + Send + GetPoolStatus {
     async fn init(&mut self) -> DbResult<()>;
@@ -50,6 +76,15 @@ pub trait Db {
         None
     }
 
+    /// Begin a transaction, or a savepoint within the one already begun.
+    async fn begin(&mut self) -> DbResult<()>;
+
+    /// Commit the innermost transaction (or savepoint).
+    async fn commit(&mut self) -> DbResult<()>;
+
+    /// Roll back the innermost transaction (or savepoint).
+    async fn rollback(&mut self) -> DbResult<()>;
+
     /// Mark the user with the given uid and service ID as being replaced.
     async fn replace_user(&mut self, params: params::ReplaceUser)
     -> DbResult<results::ReplaceUser>;
@@ -77,6 +112,62 @@ pub trait Db {
     /// future logins.
     async fn retire_user(&mut self, params: params::RetireUser) -> DbResult<results::RetireUser>;
 
+    /// Count the current (not replaced) users allocated to each node.
+    async fn count_users(&mut self, params: params::CountUsers) -> DbResult<results::CountUsers>;
+
+    /// Get a page of the user records replaced before the given timestamp, e.g. to be purged.
+    async fn get_old_users(
+        &mut self,
+        params: params::GetOldUsers,
+    ) -> DbResult<results::GetOldUsers>;
+
+    /// Delete a user record, releasing its slot on the node it was allocated to.
+    async fn delete_user(&mut self, params: params::DeleteUser) -> DbResult<results::DeleteUser>;
+
+    /// Change the email of the user records matching the given email and service ID.
+    async fn update_user_email(
+        &mut self,
+        params: params::UpdateUserEmail,
+    ) -> DbResult<results::UpdateUserEmail>;
+
+    /// Record the FxA webhook Security Event Token with the given `jti` as processed, returning
+    /// false (and doing nothing) when it already is. Within a transaction, this claims the token:
+    /// concurrent attempts at marking it wait for the transaction to end.
+    async fn mark_event_processed(
+        &mut self,
+        params: params::MarkEventProcessed,
+    ) -> DbResult<results::MarkEventProcessed>;
+
+    /// Delete the records of the Security Event Tokens processed before the given timestamp.
+    async fn delete_processed_events(
+        &mut self,
+        params: params::DeleteProcessedEvents,
+    ) -> DbResult<results::DeleteProcessedEvents>;
+
+    /// Store an FxA account event that failed to process, to be replayed later.
+    async fn post_failed_event(
+        &mut self,
+        params: params::PostFailedEvent,
+    ) -> DbResult<results::PostFailedEvent>;
+
+    /// Get the oldest failed events.
+    async fn get_failed_events(
+        &mut self,
+        params: params::GetFailedEvents,
+    ) -> DbResult<results::GetFailedEvents>;
+
+    /// Record another failed attempt at processing a failed event.
+    async fn update_failed_event(
+        &mut self,
+        params: params::UpdateFailedEvent,
+    ) -> DbResult<results::UpdateFailedEvent>;
+
+    /// Delete a failed event, e.g. once it's been replayed.
+    async fn delete_failed_event(
+        &mut self,
+        params: params::DeleteFailedEvent,
+    ) -> DbResult<results::DeleteFailedEvent>;
+
     /// Show database uptime status and health as boolean.
     async fn check(&mut self) -> DbResult<results::Check>;
 
@@ -84,11 +175,45 @@ pub trait Db {
     /// Returns whether a node entry was added.
     async fn insert_sync15_node(&mut self, params: params::Sync15Node) -> DbResult<bool>;
 
+    /// Insert a service record.  Does nothing when the service exists.
+    /// Returns whether a service entry was added.
+    async fn insert_service(&mut self, params: params::PostService) -> DbResult<bool>;
+
+    /// Create a complete node and return insert id from node.
+    async fn post_node(&mut self, params: params::PostNode) -> DbResult<results::PostNode>;
+
+    /// Get complete node entry based on passed id.
+    async fn get_node(&mut self, params: params::GetNode) -> DbResult<results::GetNode>;
+
+    /// Get every node of a service, ordered by id.
+    async fn get_nodes(&mut self, params: params::GetNodes) -> DbResult<results::GetNodes>;
+
+    /// Update the given fields of a node. `None` leaves the existing value unchanged.
+    async fn update_node(&mut self, params: params::UpdateNode) -> DbResult<results::UpdateNode>;
+
+    /// Based on Node ID, unassign node from `users`.
+    async fn unassign_node(
+        &mut self,
+        params: params::UnassignNode,
+    ) -> DbResult<results::UnassignNode>;
+
+    /// Mark a batch of the current users allocated to a node as replaced, returning how many were.
+    async fn drain_node(&mut self, params: params::DrainNode) -> DbResult<results::DrainNode>;
+
+    /// Get every node of a service along with its number of current users, ordered by id.
+    async fn get_node_loads(
+        &mut self,
+        params: params::GetNodeLoads,
+    ) -> DbResult<results::GetNodeLoads>;
+
+    /// Remove Node based on Node ID
+    async fn remove_node(&mut self, params: params::RemoveNode) -> DbResult<results::RemoveNode>;
+
     /// Get Node ID based on service_id and node string.
     async fn get_node_id(&mut self, params: params::GetNodeId) -> DbResult<results::GetNodeId>;
 
-    /// Get Node ID and string identifier based on node
-    /// with lowest capacity or high release rate.
+    /// Get the node to allocate a new user to, according to the configured allocation policy and
+    /// region, releasing more capacity when no node has any available.
     async fn get_best_node(
         &mut self,
         params: params::GetBestNode,
@@ -280,6 +405,74 @@ pub trait Db {
         })
     }
 
+    /// Apply an FxA account event to the user with the given email and service ID.
+    ///
+    /// Returns whether the event was handled: unknown event types and events missing the data
+    /// they need are dropped.
+    async fn apply_fxa_event(
+        &mut self,
+        params: params::ApplyFxaEvent,
+    ) -> DbResult<results::ApplyFxaEvent> {
+        let params::ApplyFxaEvent {
+            service_id,
+            email,
+            event_type,
+            payload,
+        } = params;
+        match event_type.as_str() {
+            fxa_events::DELETE_USER => {
+                info!("Processing account delete for {}", email);
+                self.retire_user(params::RetireUser { service_id, email })
+                    .await?;
+            }
+            fxa_events::PASSWORD_CHANGE => {
+                let Some(change_time_ms) = payload.get("changeTime").and_then(|t| t.as_i64())
+                else {
+                    return Ok(false);
+                };
+                info!("Processing password change for {}", email);
+                // Locks out devices with an older generation, while still accepting the new
+                // generation with its new client state
+                self.update_user_generation(params::UpdateUserGeneration {
+                    service_id,
+                    email,
+                    generation: Some(change_time_ms - 1),
+                    keys_changed_at: None,
+                })
+                .await?;
+            }
+            fxa_events::PROFILE_CHANGE => {
+                // Users are recorded as `[FxA uid]@[FxA email domain]` rather than under their
+                // email address, which Tokenserver never sees: an email change leaves their
+                // records as they are, and only a change of uid moves them
+                if payload.get("email").is_some() {
+                    debug!("Ignoring email change for {}", email);
+                }
+                let new_uid = payload.get("uid").and_then(|uid| uid.as_str());
+                match (new_uid, email.split_once('@')) {
+                    (Some(new_uid), Some((uid, domain))) if new_uid != uid => {
+                        let new_email = format!("{}@{}", new_uid, domain);
+                        info!("Processing uid change for {} to {}", email, new_email);
+                        self.update_user_email(params::UpdateUserEmail {
+                            service_id,
+                            email,
+                            new_email,
+                        })
+                        .await?;
+                    }
+                    _ => debug!("Ignoring profile change for {}", email),
+                }
+            }
+            // Sync's data doesn't depend on a user's subscriptions or metrics opt-out: these are
+            // only acknowledged
+            fxa_events::SUBSCRIPTION_STATE_CHANGE | fxa_events::METRICS_CHANGE => {
+                debug!("Ignoring {} for {}", event_type, email);
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
     // Internal methods used by the db tests
 
     #[cfg(debug_assertions)]
@@ -299,25 +492,6 @@ pub trait Db {
     #[cfg(debug_assertions)]
     async fn get_user(&mut self, params: params::GetUser) -> DbResult<results::GetUser>;
 
-    /// Create a complete node and return insert id from node.
-    #[cfg(debug_assertions)]
-    async fn post_node(&mut self, params: params::PostNode) -> DbResult<results::PostNode>;
-
-    /// Get complete node entry based on passed id.
-    #[cfg(debug_assertions)]
-    async fn get_node(&mut self, params: params::GetNode) -> DbResult<results::GetNode>;
-
-    /// Based on Node ID, unassign node from `users`.
-    #[cfg(debug_assertions)]
-    async fn unassign_node(
-        &mut self,
-        params: params::UnassignNode,
-    ) -> DbResult<results::UnassignNode>;
-
-    /// Remove Node based on Node ID
-    #[cfg(debug_assertions)]
-    async fn remove_node(&mut self, params: params::RemoveNode) -> DbResult<results::RemoveNode>;
-
     #[cfg(debug_assertions)]
     /// Creates new service and returns new service_id.
     async fn post_service(&mut self, params: params::PostService)
@@ -1,6 +1,6 @@
 //! Parameter types for database methods.
 
-#[derive(Clone, Default)]
+#[derive(Clone)]
 pub struct PostNode {
     pub service_id: i32,
     pub node: String,
@@ -9,6 +9,24 @@ pub struct PostNode {
     pub capacity: i32,
     pub downed: i32,
     pub backoff: i32,
+    pub weight: i32,
+    pub region: Option<String>,
+}
+
+impl Default for PostNode {
+    fn default() -> Self {
+        Self {
+            service_id: 0,
+            node: String::new(),
+            available: 0,
+            current_load: 0,
+            capacity: 0,
+            downed: 0,
+            backoff: 0,
+            weight: crate::DEFAULT_NODE_WEIGHT,
+            region: None,
+        }
+    }
 }
 
 #[derive(Clone, Default)]
@@ -16,6 +34,35 @@ pub struct GetNode {
     pub id: i64,
 }
 
+pub struct GetNodes {
+    pub service_id: i32,
+}
+
+#[derive(Default)]
+pub struct UpdateNode {
+    pub id: i64,
+    pub available: Option<i32>,
+    pub current_load: Option<i32>,
+    pub capacity: Option<i32>,
+    pub downed: Option<i32>,
+    pub backoff: Option<i32>,
+    pub weight: Option<i32>,
+    /// `Some(None)` clears the node's region, `None` leaves it unchanged
+    pub region: Option<Option<String>>,
+}
+
+/// Mark up to `limit` of the current users allocated to a node as replaced, so they're moved to
+/// another node on their next token request.
+pub struct DrainNode {
+    pub node_id: i64,
+    pub limit: i64,
+    pub replaced_at: i64,
+}
+
+pub struct GetNodeLoads {
+    pub service_id: i32,
+}
+
 #[derive(Default)]
 pub struct PostService {
     pub service: String,
@@ -74,6 +121,25 @@ pub struct ReplaceUser {
     pub replaced_at: i64,
 }
 
+pub struct CountUsers {
+    pub service_id: i32,
+    /// Only count users created at or before this timestamp (in milliseconds)
+    pub created_before: i64,
+}
+
+pub struct GetOldUsers {
+    pub service_id: i32,
+    /// Only get users replaced before this timestamp (in milliseconds)
+    pub replaced_before: i64,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+pub struct DeleteUser {
+    pub service_id: i32,
+    pub uid: i64,
+}
+
 pub struct RetireUser {
     pub service_id: i32,
     pub email: String,
@@ -86,6 +152,58 @@ pub struct UpdateUserGeneration {
     pub keys_changed_at: Option<i64>,
 }
 
+pub struct UpdateUserEmail {
+    pub service_id: i32,
+    pub email: String,
+    pub new_email: String,
+}
+
+/// An FxA account event, keyed by its schema URL (e.g.
+/// `https://schemas.accounts.firefox.com/event/delete-user`), to apply to the user with the given
+/// email and service ID.
+pub struct ApplyFxaEvent {
+    pub service_id: i32,
+    pub email: String,
+    pub event_type: String,
+    pub payload: serde_json::Value,
+}
+
+pub struct MarkEventProcessed {
+    pub jti: String,
+    pub processed_at: i64,
+}
+
+pub struct DeleteProcessedEvents {
+    /// Only delete the events processed before this timestamp (in milliseconds)
+    pub processed_before: i64,
+}
+
+pub struct PostFailedEvent {
+    /// The `jti` of the Security Event Token the event was received in
+    pub jti: String,
+    pub email: String,
+    pub event_type: String,
+    /// The JSON encoded event payload
+    pub payload: String,
+    pub error: String,
+    pub failed_at: i64,
+}
+
+pub struct GetFailedEvents {
+    pub limit: i64,
+}
+
+/// Record another failed attempt at processing a failed event.
+pub struct UpdateFailedEvent {
+    pub id: i64,
+    pub error: String,
+    pub failed_at: i64,
+}
+
+pub struct DeleteFailedEvent {
+    pub id: i64,
+}
+
 #[derive(Debug, Default)]
 pub struct GetNodeId {
     pub service_id: i32,
@@ -126,12 +244,10 @@ pub struct GetUser {
     pub id: i64,
 }
 
-#[cfg(debug_assertions)]
 pub struct UnassignNode {
     pub node_id: i64,
 }
 
-#[cfg(debug_assertions)]
 pub struct RemoveNode {
     pub node_id: i64,
 }
@@ -55,12 +55,84 @@ pub struct PostUser {
     pub uid: i64,
 }
 
+/// The number of current users allocated to a node
+#[derive(Debug, Default, Eq, PartialEq, QueryableByName, Serialize)]
+pub struct NodeUserCount {
+    #[diesel(sql_type = Text)]
+    pub node: String,
+    #[diesel(sql_type = Bigint)]
+    pub count: i64,
+}
+
+pub type CountUsers = Vec<NodeUserCount>;
+
+/// A user record that has been replaced (or retired), along with the node it was allocated to.
+/// `node` and `downed` are `None` when the node has since been removed.
+#[derive(Clone, Debug, Default, Eq, PartialEq, QueryableByName)]
+pub struct OldUser {
+    #[diesel(sql_type = Bigint)]
+    pub uid: i64,
+    #[diesel(sql_type = Text)]
+    pub email: String,
+    #[diesel(sql_type = Bigint)]
+    pub generation: i64,
+    #[diesel(sql_type = Nullable<Bigint>)]
+    pub keys_changed_at: Option<i64>,
+    #[diesel(sql_type = Text)]
+    pub client_state: String,
+    #[diesel(sql_type = Nullable<Text>)]
+    pub node: Option<String>,
+    #[diesel(sql_type = Nullable<Integer>)]
+    pub downed: Option<i32>,
+    #[diesel(sql_type = Bigint)]
+    pub created_at: i64,
+    #[diesel(sql_type = Bigint)]
+    pub replaced_at: i64,
+}
+
+pub type GetOldUsers = Vec<OldUser>;
+pub type DeleteUser = ();
 pub type ReplaceUsers = ();
 pub type ReplaceUser = ();
 pub type RetireUser = ();
 pub type UpdateUserGeneration = ();
+pub type UpdateUserEmail = ();
 pub type PutUser = ();
 
+/// Whether the event was handled, rather than dropped as unknown or malformed
+pub type ApplyFxaEvent = bool;
+
+/// Whether the event was newly marked as processed
+pub type MarkEventProcessed = bool;
+/// The number of processed events deleted
+pub type DeleteProcessedEvents = usize;
+
+/// An FxA account event that failed to process, kept to be replayed.
+#[derive(Clone, Debug, Default, Eq, PartialEq, QueryableByName)]
+pub struct FailedEvent {
+    #[diesel(sql_type = Bigint)]
+    pub id: i64,
+    #[diesel(sql_type = Text)]
+    pub jti: String,
+    #[diesel(sql_type = Text)]
+    pub email: String,
+    #[diesel(sql_type = Text)]
+    pub event_type: String,
+    #[diesel(sql_type = Text)]
+    pub payload: String,
+    #[diesel(sql_type = Text)]
+    pub error: String,
+    #[diesel(sql_type = Integer)]
+    pub attempts: i32,
+    #[diesel(sql_type = Bigint)]
+    pub failed_at: i64,
+}
+
+pub type PostFailedEvent = ();
+pub type GetFailedEvents = Vec<FailedEvent>;
+pub type UpdateFailedEvent = ();
+pub type DeleteFailedEvent = ();
+
 #[derive(Default, QueryableByName)]
 pub struct GetNodeId {
     #[diesel(sql_type = Bigint)]
@@ -104,15 +176,13 @@ pub struct GetUser {
     pub keys_changed_at: Option<i64>,
 }
 
-#[cfg(debug_assertions)]
 #[derive(Default, QueryableByName)]
 pub struct PostNode {
     #[diesel(sql_type = Bigint)]
     pub id: i64,
 }
 
-#[cfg(debug_assertions)]
-#[derive(Default, QueryableByName)]
+#[derive(Debug, Default, Eq, PartialEq, QueryableByName)]
 pub struct GetNode {
     #[diesel(sql_type = Bigint)]
     pub id: i64,
@@ -131,8 +201,46 @@ pub struct GetNode {
     pub downed: i32,
     #[diesel(sql_type = Integer)]
     pub backoff: i32,
+    #[diesel(sql_type = Integer)]
+    pub weight: i32,
+    #[diesel(sql_type = Nullable<Text>)]
+    pub region: Option<String>,
+}
+
+pub type GetNodes = Vec<GetNode>;
+
+/// A node along with the number of current (not replaced) users allocated to it.
+#[derive(Clone, Debug, Default, Eq, PartialEq, QueryableByName)]
+pub struct NodeLoad {
+    #[diesel(sql_type = Bigint)]
+    pub id: i64,
+    #[diesel(sql_type = Text)]
+    pub node: String,
+    #[diesel(sql_type = Integer)]
+    pub available: i32,
+    #[diesel(sql_type = Integer)]
+    pub current_load: i32,
+    #[diesel(sql_type = Integer)]
+    pub capacity: i32,
+    #[diesel(sql_type = Integer)]
+    pub downed: i32,
+    #[diesel(sql_type = Integer)]
+    pub backoff: i32,
+    #[diesel(sql_type = Integer)]
+    pub weight: i32,
+    #[diesel(sql_type = Nullable<Text>)]
+    pub region: Option<String>,
+    #[diesel(sql_type = Bigint)]
+    pub current_users: i64,
 }
 
+pub type GetNodeLoads = Vec<NodeLoad>;
+
+/// The number of users marked as replaced
+pub type DrainNode = usize;
+
+pub type UpdateNode = ();
+
 #[cfg(debug_assertions)]
 #[derive(Default, QueryableByName)]
 pub struct PostService {
@@ -148,8 +256,6 @@ pub type SetUserReplacedAt = ();
 
 pub type Check = bool;
 
-#[cfg(debug_assertions)]
 pub type UnassignNode = ();
 
-#[cfg(debug_assertions)]
 pub type RemoveNode = ();
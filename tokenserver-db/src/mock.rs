@@ -1,6 +1,9 @@
 #![allow(clippy::new_without_default)]
 
-use std::sync::{Arc, LazyLock, Mutex};
+use std::sync::{
+    Arc, LazyLock, Mutex,
+    atomic::{AtomicBool, Ordering},
+};
 
 use async_trait::async_trait;
 use syncserver_common::Metrics;
@@ -11,6 +14,21 @@ use tokenserver_db_common::{Db, DbError, DbPool, params, results};
 pub struct CallLog {
     pub retire_user: Arc<Mutex<Vec<params::RetireUser>>>,
     pub update_user_generation: Arc<Mutex<Vec<params::UpdateUserGeneration>>>,
+    pub update_user_email: Arc<Mutex<Vec<params::UpdateUserEmail>>>,
+    /// The `jti`s of the events marked as processed
+    pub processed_events: Arc<Mutex<Vec<String>>>,
+    pub failed_events: Arc<Mutex<Vec<params::PostFailedEvent>>>,
+    /// Makes the user updates applying account events fail when set
+    pub fail_user_updates: Arc<AtomicBool>,
+}
+
+impl CallLog {
+    fn check_user_update(&self) -> Result<(), DbError> {
+        if self.fail_user_updates.load(Ordering::Relaxed) {
+            return Err(DbError::internal("Mock user update failure".to_owned()));
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone, Default)]
@@ -71,6 +89,18 @@ impl MockDb {
 
 #[async_trait(?Send)]
 impl Db for MockDb {
+    async fn begin(&mut self) -> Result<(), DbError> {
+        Ok(())
+    }
+
+    async fn commit(&mut self) -> Result<(), DbError> {
+        Ok(())
+    }
+
+    async fn rollback(&mut self) -> Result<(), DbError> {
+        Ok(())
+    }
+
     async fn replace_user(
         &mut self,
         _params: params::ReplaceUser,
@@ -97,6 +127,7 @@ impl Db for MockDb {
         &mut self,
         params: params::UpdateUserGeneration,
     ) -> Result<results::UpdateUserGeneration, DbError> {
+        self.call_log.check_user_update()?;
         self.call_log
             .update_user_generation
             .lock()
@@ -109,10 +140,89 @@ impl Db for MockDb {
         &mut self,
         params: params::RetireUser,
     ) -> Result<results::RetireUser, DbError> {
+        self.call_log.check_user_update()?;
         self.call_log.retire_user.lock().unwrap().push(params);
         Ok(())
     }
 
+    async fn count_users(
+        &mut self,
+        _params: params::CountUsers,
+    ) -> Result<results::CountUsers, DbError> {
+        Ok(vec![])
+    }
+
+    async fn get_old_users(
+        &mut self,
+        _params: params::GetOldUsers,
+    ) -> Result<results::GetOldUsers, DbError> {
+        Ok(vec![])
+    }
+
+    async fn delete_user(
+        &mut self,
+        _params: params::DeleteUser,
+    ) -> Result<results::DeleteUser, DbError> {
+        Ok(())
+    }
+
+    async fn update_user_email(
+        &mut self,
+        params: params::UpdateUserEmail,
+    ) -> Result<results::UpdateUserEmail, DbError> {
+        self.call_log.check_user_update()?;
+        self.call_log.update_user_email.lock().unwrap().push(params);
+        Ok(())
+    }
+
+    async fn mark_event_processed(
+        &mut self,
+        params: params::MarkEventProcessed,
+    ) -> Result<results::MarkEventProcessed, DbError> {
+        let mut processed_events = self.call_log.processed_events.lock().unwrap();
+        if processed_events.contains(&params.jti) {
+            return Ok(false);
+        }
+        processed_events.push(params.jti);
+        Ok(true)
+    }
+
+    async fn delete_processed_events(
+        &mut self,
+        _params: params::DeleteProcessedEvents,
+    ) -> Result<results::DeleteProcessedEvents, DbError> {
+        Ok(0)
+    }
+
+    async fn post_failed_event(
+        &mut self,
+        params: params::PostFailedEvent,
+    ) -> Result<results::PostFailedEvent, DbError> {
+        self.call_log.failed_events.lock().unwrap().push(params);
+        Ok(())
+    }
+
+    async fn get_failed_events(
+        &mut self,
+        _params: params::GetFailedEvents,
+    ) -> Result<results::GetFailedEvents, DbError> {
+        Ok(vec![])
+    }
+
+    async fn update_failed_event(
+        &mut self,
+        _params: params::UpdateFailedEvent,
+    ) -> Result<results::UpdateFailedEvent, DbError> {
+        Ok(())
+    }
+
+    async fn delete_failed_event(
+        &mut self,
+        _params: params::DeleteFailedEvent,
+    ) -> Result<results::DeleteFailedEvent, DbError> {
+        Ok(())
+    }
+
     async fn check(&mut self) -> Result<results::Check, DbError> {
         Ok(true)
     }
@@ -160,6 +270,10 @@ impl Db for MockDb {
         Ok(false)
     }
 
+    async fn insert_service(&mut self, _params: params::PostService) -> Result<bool, DbError> {
+        Ok(false)
+    }
+
     fn metrics(&self) -> &Metrics {
         static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::noop);
         &METRICS
@@ -186,17 +300,25 @@ impl Db for MockDb {
         Ok(results::GetUser::default())
     }
 
-    #[cfg(debug_assertions)]
     async fn post_node(&mut self, _params: params::PostNode) -> Result<results::PostNode, DbError> {
         Ok(results::PostNode::default())
     }
 
-    #[cfg(debug_assertions)]
     async fn get_node(&mut self, _params: params::GetNode) -> Result<results::GetNode, DbError> {
         Ok(results::GetNode::default())
     }
 
-    #[cfg(debug_assertions)]
+    async fn get_nodes(&mut self, _params: params::GetNodes) -> Result<results::GetNodes, DbError> {
+        Ok(results::GetNodes::default())
+    }
+
+    async fn update_node(
+        &mut self,
+        _params: params::UpdateNode,
+    ) -> Result<results::UpdateNode, DbError> {
+        Ok(())
+    }
+
     async fn unassign_node(
         &mut self,
         _params: params::UnassignNode,
@@ -204,7 +326,20 @@ impl Db for MockDb {
         Ok(())
     }
 
-    #[cfg(debug_assertions)]
+    async fn drain_node(
+        &mut self,
+        _params: params::DrainNode,
+    ) -> Result<results::DrainNode, DbError> {
+        Ok(0)
+    }
+
+    async fn get_node_loads(
+        &mut self,
+        _params: params::GetNodeLoads,
+    ) -> Result<results::GetNodeLoads, DbError> {
+        Ok(vec![])
+    }
+
     async fn remove_node(
         &mut self,
         _params: params::RemoveNode,
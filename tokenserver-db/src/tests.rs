@@ -3,7 +3,10 @@ use std::thread;
 
 use syncserver_common::Metrics;
 use syncserver_settings::Settings;
-use tokenserver_db_common::{DbError, DbPool, DbResult, MAX_GENERATION, params, results};
+use tokenserver_common::AllocationPolicy;
+use tokenserver_db_common::{
+    DEFAULT_NODE_WEIGHT, Db, DbError, DbPool, DbResult, MAX_GENERATION, fxa_events, params, results,
+};
 
 use crate::pool_from_settings;
 
@@ -712,6 +715,259 @@ async fn test_allocation_is_not_allowed_to_backoff_nodes() -> DbResult<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_weighted_allocation() -> DbResult<()> {
+    let pool = db_pool().await?;
+    let mut db = pool.get().await?;
+    let service_id = db
+        .get_service_id(params::GetServiceId {
+            service: "sync-1.5".to_owned(),
+        })
+        .await?
+        .id;
+    let (_, node2_id) = post_weighted_nodes(&mut *db, service_id).await?;
+
+    // node2 is the least loaded
+    let best_node = db
+        .get_best_node(params::GetBestNode {
+            service_id,
+            capacity_release_rate: None,
+        })
+        .await?;
+    assert_eq!(best_node.id, node2_id);
+    drop(db);
+    drop(pool);
+
+    let mut settings = Settings::test_settings();
+    settings.tokenserver.allocation_policy = AllocationPolicy::Weighted;
+    let pool = db_pool_with_settings(settings).await?;
+    let mut db = pool.get().await?;
+    let (node1_id, _) = post_weighted_nodes(&mut *db, service_id).await?;
+
+    // but node1 has the least load per unit of weight
+    let best_node = db
+        .get_best_node(params::GetBestNode {
+            service_id,
+            capacity_release_rate: None,
+        })
+        .await?;
+    assert_eq!(best_node.id, node1_id);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_allocation_prefers_region() -> DbResult<()> {
+    let mut settings = Settings::test_settings();
+    settings.tokenserver.allocation_policy = AllocationPolicy::Weighted;
+    settings.tokenserver.allocation_region = Some("eu".to_owned());
+    let pool = db_pool_with_settings(settings).await?;
+    let mut db = pool.get().await?;
+    let service_id = db
+        .get_service_id(params::GetServiceId {
+            service: "sync-1.5".to_owned(),
+        })
+        .await?
+        .id;
+    let (_, node2_id) = post_weighted_nodes(&mut *db, service_id).await?;
+
+    // node2 is in the configured region, despite node1 being better weighted
+    let best_node = db
+        .get_best_node(params::GetBestNode {
+            service_id,
+            capacity_release_rate: None,
+        })
+        .await?;
+    assert_eq!(best_node.id, node2_id);
+
+    Ok(())
+}
+
+/// Add a node with a high weight and load, a lightly loaded node in the "eu"
+/// region, and a node without weight, returning the ids of the first two
+async fn post_weighted_nodes(db: &mut dyn Db, service_id: i32) -> DbResult<(i64, i64)> {
+    let node1_id = db
+        .post_node(params::PostNode {
+            service_id,
+            node: "https://node1".to_owned(),
+            current_load: 10,
+            capacity: 100,
+            available: 90,
+            weight: 300,
+            ..Default::default()
+        })
+        .await?
+        .id;
+    let node2_id = db
+        .post_node(params::PostNode {
+            service_id,
+            node: "https://node2".to_owned(),
+            current_load: 5,
+            capacity: 100,
+            available: 95,
+            weight: 100,
+            region: Some("eu".to_owned()),
+            ..Default::default()
+        })
+        .await?
+        .id;
+    // Nodes without weight are never allocated to
+    db.post_node(params::PostNode {
+        service_id,
+        node: "https://node3".to_owned(),
+        capacity: 100,
+        available: 100,
+        weight: 0,
+        region: Some("eu".to_owned()),
+        ..Default::default()
+    })
+    .await?;
+    Ok((node1_id, node2_id))
+}
+
+#[tokio::test]
+async fn drain_node_and_get_node_loads() -> DbResult<()> {
+    let pool = db_pool().await?;
+    let mut db = pool.get().await?;
+    let service_id = db
+        .get_service_id(params::GetServiceId {
+            service: "sync-1.5".to_owned(),
+        })
+        .await?
+        .id;
+
+    let node1_id = db
+        .post_node(params::PostNode {
+            service_id,
+            node: "https://node1".to_owned(),
+            capacity: 100,
+            available: 100,
+            ..Default::default()
+        })
+        .await?
+        .id;
+    let node2_id = db
+        .post_node(params::PostNode {
+            service_id,
+            node: "https://node2".to_owned(),
+            capacity: 100,
+            available: 100,
+            weight: 50,
+            region: Some("eu".to_owned()),
+            ..Default::default()
+        })
+        .await?
+        .id;
+    for (i, node_id) in [node1_id, node1_id, node1_id, node2_id]
+        .into_iter()
+        .enumerate()
+    {
+        db.post_user(params::PostUser {
+            service_id,
+            node_id,
+            email: format!("test{i}@test.com"),
+            ..Default::default()
+        })
+        .await?;
+    }
+
+    let loads = db
+        .get_node_loads(params::GetNodeLoads { service_id })
+        .await?;
+    assert_eq!(loads.len(), 2);
+    assert_eq!(loads[0].id, node1_id);
+    assert_eq!(loads[0].current_users, 3);
+    assert_eq!(loads[0].weight, DEFAULT_NODE_WEIGHT);
+    assert_eq!(loads[1].id, node2_id);
+    assert_eq!(loads[1].current_users, 1);
+    assert_eq!(loads[1].weight, 50);
+    assert_eq!(loads[1].region.as_deref(), Some("eu"));
+
+    // Users are drained in batches
+    let drain = |limit| params::DrainNode {
+        node_id: node1_id,
+        limit,
+        replaced_at: Utc::now().timestamp_millis(),
+    };
+    assert_eq!(db.drain_node(drain(2)).await?, 2);
+    let loads = db
+        .get_node_loads(params::GetNodeLoads { service_id })
+        .await?;
+    assert_eq!(loads[0].current_users, 1);
+    assert_eq!(loads[1].current_users, 1);
+    assert_eq!(db.drain_node(drain(2)).await?, 1);
+    assert_eq!(db.drain_node(drain(2)).await?, 0);
+
+    let loads = db
+        .get_node_loads(params::GetNodeLoads { service_id })
+        .await?;
+    assert_eq!(loads[0].current_users, 0);
+    assert_eq!(loads[1].current_users, 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_allocation_per_service() -> DbResult<()> {
+    let pool = db_pool().await?;
+    let mut db = pool.get().await?;
+
+    let sync_service_id = db
+        .get_service_id(params::GetServiceId {
+            service: "sync-1.5".to_owned(),
+        })
+        .await?
+        .id;
+
+    // Services are only inserted once
+    let post_service = || params::PostService {
+        service: "sync-1.6".to_owned(),
+        pattern: "{node}/1.6/{uid}".to_owned(),
+    };
+    assert!(db.insert_service(post_service()).await?);
+    assert!(!db.insert_service(post_service()).await?);
+    let service_id = db
+        .get_service_id(params::GetServiceId {
+            service: "sync-1.6".to_owned(),
+        })
+        .await?
+        .id;
+    assert_ne!(service_id, sync_service_id);
+
+    // Each service allocates users to its own nodes
+    for (service_id, node) in [
+        (sync_service_id, "https://node1"),
+        (service_id, "https://node2"),
+    ] {
+        db.post_node(params::PostNode {
+            service_id,
+            node: node.to_owned(),
+            capacity: 100,
+            available: 100,
+            ..Default::default()
+        })
+        .await?;
+    }
+    for (service_id, node) in [
+        (sync_service_id, "https://node1"),
+        (service_id, "https://node2"),
+    ] {
+        let user = db
+            .allocate_user(params::AllocateUser {
+                service_id,
+                generation: 1234,
+                email: "test@test.com".to_owned(),
+                client_state: "aaaa".to_owned(),
+                keys_changed_at: Some(1234),
+                capacity_release_rate: None,
+            })
+            .await?;
+        assert_eq!(user.node, node);
+    }
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_node_reassignment_when_records_are_replaced() -> DbResult<()> {
     let pool = db_pool().await?;
@@ -987,6 +1243,346 @@ async fn test_node_reassignment_and_removal() -> DbResult<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn get_and_update_nodes() -> DbResult<()> {
+    let pool = db_pool().await?;
+    let mut db = pool.get().await?;
+
+    let service_id = db
+        .get_service_id(params::GetServiceId {
+            service: "sync-1.5".to_owned(),
+        })
+        .await?
+        .id;
+
+    let node1_id = db
+        .post_node(params::PostNode {
+            service_id,
+            node: "https://node1".to_owned(),
+            current_load: 0,
+            capacity: 100,
+            available: 10,
+            ..Default::default()
+        })
+        .await?
+        .id;
+    let node2_id = db
+        .post_node(params::PostNode {
+            service_id,
+            node: "https://node2".to_owned(),
+            current_load: 5,
+            capacity: 50,
+            available: 5,
+            downed: 1,
+            ..Default::default()
+        })
+        .await?
+        .id;
+
+    let nodes = db.get_nodes(params::GetNodes { service_id }).await?;
+    assert_eq!(
+        nodes.iter().map(|node| node.id).collect::<Vec<_>>(),
+        vec![node1_id, node2_id]
+    );
+    assert_eq!(nodes[1].node, "https://node2");
+    assert_eq!(nodes[1].current_load, 5);
+    assert_eq!(nodes[1].downed, 1);
+
+    // Only the given fields are updated
+    db.update_node(params::UpdateNode {
+        id: node1_id,
+        capacity: Some(200),
+        backoff: Some(1),
+        ..Default::default()
+    })
+    .await?;
+    let node = db.get_node(params::GetNode { id: node1_id }).await?;
+    assert_eq!(node.capacity, 200);
+    assert_eq!(node.backoff, 1);
+    assert_eq!(node.available, 10);
+    assert_eq!(node.current_load, 0);
+    assert_eq!(node.downed, 0);
+
+    // A region is only changed when given, and can be cleared
+    db.update_node(params::UpdateNode {
+        id: node1_id,
+        region: Some(Some("us-west".to_owned())),
+        ..Default::default()
+    })
+    .await?;
+    db.update_node(params::UpdateNode {
+        id: node1_id,
+        weight: Some(5),
+        ..Default::default()
+    })
+    .await?;
+    let node = db.get_node(params::GetNode { id: node1_id }).await?;
+    assert_eq!(node.region.as_deref(), Some("us-west"));
+    db.update_node(params::UpdateNode {
+        id: node1_id,
+        region: Some(None),
+        ..Default::default()
+    })
+    .await?;
+    let node = db.get_node(params::GetNode { id: node1_id }).await?;
+    assert_eq!(node.region, None);
+
+    // Other nodes are untouched
+    let node = db.get_node(params::GetNode { id: node2_id }).await?;
+    assert_eq!(node.capacity, 50);
+    assert_eq!(node.backoff, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn count_and_delete_old_users() -> DbResult<()> {
+    let pool = db_pool().await?;
+    let mut db = pool.get().await?;
+
+    let service_id = db
+        .get_service_id(params::GetServiceId {
+            service: "sync-1.5".to_owned(),
+        })
+        .await?
+        .id;
+    let node_id = db
+        .post_node(params::PostNode {
+            service_id,
+            node: "https://node1".to_owned(),
+            current_load: 0,
+            capacity: 100,
+            available: 100,
+            ..Default::default()
+        })
+        .await?
+        .id;
+
+    let mut uids = vec![];
+    for email in ["test1@test.com", "test2@test.com", "test3@test.com"] {
+        let user = db
+            .get_or_create_user(params::GetOrCreateUser {
+                service_id,
+                email: email.to_owned(),
+                generation: 1234,
+                client_state: "aaaa".to_owned(),
+                keys_changed_at: Some(1234),
+                capacity_release_rate: None,
+            })
+            .await?;
+        uids.push(user.uid);
+    }
+
+    let now = Utc::now().timestamp_millis();
+    let counts = db
+        .count_users(params::CountUsers {
+            service_id,
+            created_before: now,
+        })
+        .await?;
+    assert_eq!(
+        counts,
+        vec![results::NodeUserCount {
+            node: "https://node1".to_owned(),
+            count: 3,
+        }]
+    );
+
+    // Replace one user long ago and retire another just now
+    db.set_user_replaced_at(params::SetUserReplacedAt {
+        uid: uids[0],
+        replaced_at: now - 10_000,
+    })
+    .await?;
+    db.retire_user(params::RetireUser {
+        service_id,
+        email: "test2@test.com".to_owned(),
+    })
+    .await?;
+
+    let counts = db
+        .count_users(params::CountUsers {
+            service_id,
+            created_before: now,
+        })
+        .await?;
+    assert_eq!(counts[0].count, 1);
+
+    // Only the user replaced outside of the grace period is old
+    let old_users = db
+        .get_old_users(params::GetOldUsers {
+            service_id,
+            replaced_before: now - 5_000,
+            limit: 10,
+            offset: 0,
+        })
+        .await?;
+    assert_eq!(old_users.len(), 1);
+    let old_user = &old_users[0];
+    assert_eq!(old_user.uid, uids[0]);
+    assert_eq!(old_user.email, "test1@test.com");
+    assert_eq!(old_user.node.as_deref(), Some("https://node1"));
+    assert_eq!(old_user.downed, Some(0));
+    assert_eq!(old_user.replaced_at, now - 10_000);
+
+    let old_users = db
+        .get_old_users(params::GetOldUsers {
+            service_id,
+            replaced_before: Utc::now().timestamp_millis() + 1,
+            limit: 10,
+            offset: 0,
+        })
+        .await?;
+    assert_eq!(old_users.len(), 2);
+
+    // Deleting a user releases its slot on the node
+    let node = db.get_node(params::GetNode { id: node_id }).await?;
+    db.delete_user(params::DeleteUser {
+        service_id,
+        uid: uids[0],
+    })
+    .await?;
+    let old_users = db
+        .get_old_users(params::GetOldUsers {
+            service_id,
+            replaced_before: now - 5_000,
+            limit: 10,
+            offset: 0,
+        })
+        .await?;
+    assert!(old_users.is_empty());
+    let users = db
+        .get_users(params::GetUsers {
+            service_id,
+            email: "test1@test.com".to_owned(),
+        })
+        .await?;
+    assert!(users.is_empty());
+    let freed_node = db.get_node(params::GetNode { id: node_id }).await?;
+    assert_eq!(freed_node.current_load, node.current_load - 1);
+    assert_eq!(freed_node.available, node.available + 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn apply_fxa_events() -> DbResult<()> {
+    let pool = db_pool().await?;
+    let mut db = pool.get().await?;
+
+    let service_id = db
+        .get_service_id(params::GetServiceId {
+            service: "sync-1.5".to_owned(),
+        })
+        .await?
+        .id;
+    db.post_node(params::PostNode {
+        service_id,
+        node: "https://node1".to_owned(),
+        current_load: 0,
+        capacity: 100,
+        available: 100,
+        ..Default::default()
+    })
+    .await?;
+    let user = db
+        .get_or_create_user(params::GetOrCreateUser {
+            service_id,
+            email: "olduid@test.com".to_owned(),
+            generation: 1234,
+            client_state: "aaaa".to_owned(),
+            keys_changed_at: Some(1234),
+            capacity_release_rate: None,
+        })
+        .await?;
+
+    let apply = |event_type: &str, payload: serde_json::Value| params::ApplyFxaEvent {
+        service_id,
+        email: "olduid@test.com".to_owned(),
+        event_type: event_type.to_owned(),
+        payload,
+    };
+    // Email changes don't affect the records of users, keyed by their uid
+    assert!(
+        db.apply_fxa_event(apply(
+            fxa_events::PROFILE_CHANGE,
+            serde_json::json!({"email": "new@example.com"}),
+        ))
+        .await?
+    );
+    assert_eq!(
+        db.get_users(params::GetUsers {
+            service_id,
+            email: "olduid@test.com".to_owned(),
+        })
+        .await?
+        .len(),
+        1
+    );
+    assert!(
+        !db.apply_fxa_event(apply(fxa_events::PASSWORD_CHANGE, serde_json::json!({})))
+            .await?
+    );
+    assert!(
+        !db.apply_fxa_event(apply(
+            "https://schemas.accounts.firefox.com/event/unknown-event",
+            serde_json::json!({}),
+        ))
+        .await?
+    );
+
+    // uid changes move their records
+    assert!(
+        db.apply_fxa_event(apply(
+            fxa_events::PROFILE_CHANGE,
+            serde_json::json!({"uid": "newuid"}),
+        ))
+        .await?
+    );
+    assert!(
+        db.get_users(params::GetUsers {
+            service_id,
+            email: "olduid@test.com".to_owned(),
+        })
+        .await?
+        .is_empty()
+    );
+    let moved = db
+        .get_users(params::GetUsers {
+            service_id,
+            email: "newuid@test.com".to_owned(),
+        })
+        .await?;
+    assert_eq!(moved.len(), 1);
+    assert_eq!(moved[0].uid, user.uid);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_mark_event_processed() -> DbResult<()> {
+    let pool = db_pool().await?;
+    let mut db = pool.get().await?;
+
+    let mark = || params::MarkEventProcessed {
+        jti: "wibble".to_owned(),
+        processed_at: 1234,
+    };
+
+    // A rolled back claim is released
+    db.begin().await?;
+    assert!(db.mark_event_processed(mark()).await?);
+    db.rollback().await?;
+
+    db.begin().await?;
+    assert!(db.mark_event_processed(mark()).await?);
+    db.commit().await?;
+    // Only the first committed claim succeeds
+    assert!(!db.mark_event_processed(mark()).await?);
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_gradual_release_of_node_capacity() -> DbResult<()> {
     let pool = db_pool().await?;
@@ -1413,9 +2009,12 @@ async fn heartbeat() -> Result<(), DbError> {
 }
 
 async fn db_pool() -> DbResult<Box<dyn DbPool>> {
+    db_pool_with_settings(Settings::test_settings()).await
+}
+
+async fn db_pool_with_settings(mut settings: Settings) -> DbResult<Box<dyn DbPool>> {
     let _ = env_logger::try_init();
 
-    let mut settings = Settings::test_settings();
     settings.tokenserver.run_migrations = true;
     let use_test_transactions = true;
 
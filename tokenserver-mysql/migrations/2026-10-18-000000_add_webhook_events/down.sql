@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS `webhook_failed_events`;
+
+DROP TABLE IF EXISTS `webhook_events`;
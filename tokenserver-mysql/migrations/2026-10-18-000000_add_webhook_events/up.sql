@@ -0,0 +1,20 @@
+-- The FxA webhook Security Event Tokens already processed, by their `jti`
+CREATE TABLE IF NOT EXISTS `webhook_events` (
+  `jti` varchar(128) NOT NULL,
+  `processed_at` bigint NOT NULL,
+  PRIMARY KEY (`jti`),
+  KEY `processed_at_idx` (`processed_at`)
+);
+
+-- The FxA account events that failed to process, to be replayed
+CREATE TABLE IF NOT EXISTS `webhook_failed_events` (
+  `id` bigint NOT NULL AUTO_INCREMENT,
+  `jti` varchar(128) NOT NULL,
+  `email` varchar(255) NOT NULL,
+  `event_type` varchar(255) NOT NULL,
+  `payload` text NOT NULL,
+  `error` text NOT NULL,
+  `attempts` int NOT NULL,
+  `failed_at` bigint NOT NULL,
+  PRIMARY KEY (`id`)
+);
@@ -0,0 +1,3 @@
+ALTER TABLE `nodes`
+  ADD COLUMN `weight` int NOT NULL DEFAULT '100',
+  ADD COLUMN `region` varchar(64) DEFAULT NULL;
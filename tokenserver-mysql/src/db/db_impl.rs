@@ -4,17 +4,34 @@ use async_trait::async_trait;
 use chrono::Utc;
 use diesel::{
     OptionalExtension,
-    sql_types::{Bigint, Float, Integer, Nullable, Text},
+    sql_types::{Bigint, Bool, Float, Integer, Nullable, Text},
 };
-use diesel_async::RunQueryDsl;
+use diesel_async::{AsyncConnection, RunQueryDsl, TransactionManager};
 use http::StatusCode;
 use syncserver_common::Metrics;
-use tokenserver_db_common::{Db, DbError, DbResult, params, results};
+use tokenserver_common::AllocationPolicy;
+use tokenserver_db_common::{Db, DbError, DbResult, SYNC_SERVICE_NAME, params, results};
 
 use super::TokenserverDb;
+use crate::pool::Conn;
 
 #[async_trait(?Send)]
 impl Db for TokenserverDb {
+    async fn begin(&mut self) -> DbResult<()> {
+        <Conn as AsyncConnection>::TransactionManager::begin_transaction(&mut self.conn).await?;
+        Ok(())
+    }
+
+    async fn commit(&mut self) -> DbResult<()> {
+        <Conn as AsyncConnection>::TransactionManager::commit_transaction(&mut self.conn).await?;
+        Ok(())
+    }
+
+    async fn rollback(&mut self) -> DbResult<()> {
+        <Conn as AsyncConnection>::TransactionManager::rollback_transaction(&mut self.conn).await?;
+        Ok(())
+    }
+
     async fn get_node_id(&mut self, params: params::GetNodeId) -> DbResult<results::GetNodeId> {
         const QUERY: &str = r#"
             SELECT id
@@ -197,6 +214,214 @@ impl Db for TokenserverDb {
         Ok(result)
     }
 
+    async fn count_users(&mut self, params: params::CountUsers) -> DbResult<results::CountUsers> {
+        const QUERY: &str = r#"
+              SELECT nodes.node, COUNT(users.uid) AS count
+                FROM users
+                JOIN nodes ON users.nodeid = nodes.id
+               WHERE users.service = ?
+                 AND users.replaced_at IS NULL
+                 AND users.created_at <= ?
+            GROUP BY nodes.node
+            ORDER BY nodes.node
+        "#;
+
+        let result = diesel::sql_query(QUERY)
+            .bind::<Integer, _>(params.service_id)
+            .bind::<Bigint, _>(params.created_before)
+            .load::<results::NodeUserCount>(&mut self.conn)
+            .await?;
+        Ok(result)
+    }
+
+    async fn get_old_users(
+        &mut self,
+        params: params::GetOldUsers,
+    ) -> DbResult<results::GetOldUsers> {
+        const QUERY: &str = r#"
+                     SELECT uid, email, generation, keys_changed_at, client_state, nodes.node,
+                            nodes.downed, created_at, replaced_at
+                       FROM users
+            LEFT OUTER JOIN nodes ON users.nodeid = nodes.id
+                      WHERE users.service = ?
+                        AND replaced_at IS NOT NULL
+                        AND replaced_at < ?
+                      LIMIT ?
+                     OFFSET ?
+        "#;
+
+        let mut metrics = self.metrics.clone();
+        metrics.start_timer("storage.get_old_users", None);
+
+        let result = diesel::sql_query(QUERY)
+            .bind::<Integer, _>(params.service_id)
+            .bind::<Bigint, _>(params.replaced_before)
+            .bind::<Bigint, _>(params.limit)
+            .bind::<Bigint, _>(params.offset)
+            .load::<results::OldUser>(&mut self.conn)
+            .await?;
+        Ok(result)
+    }
+
+    async fn delete_user(&mut self, params: params::DeleteUser) -> DbResult<results::DeleteUser> {
+        // Spanner nodes don't track their available slots (see `add_user_to_node`)
+        const FREE_SLOT_QUERY: &str = r#"
+            UPDATE nodes
+               SET available = available + 1,
+                   current_load = current_load - 1
+             WHERE id = (SELECT nodeid FROM users WHERE service = ? AND uid = ?)
+        "#;
+        const DELETE_QUERY: &str = r#"
+            DELETE FROM users
+             WHERE service = ?
+               AND uid = ?
+        "#;
+
+        let mut metrics = self.metrics.clone();
+        metrics.start_timer("storage.delete_user", None);
+
+        if self.spanner_node_id.is_none() {
+            diesel::sql_query(FREE_SLOT_QUERY)
+                .bind::<Integer, _>(params.service_id)
+                .bind::<Bigint, _>(params.uid)
+                .execute(&mut self.conn)
+                .await?;
+        }
+        diesel::sql_query(DELETE_QUERY)
+            .bind::<Integer, _>(params.service_id)
+            .bind::<Bigint, _>(params.uid)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn update_user_email(
+        &mut self,
+        params: params::UpdateUserEmail,
+    ) -> DbResult<results::UpdateUserEmail> {
+        const QUERY: &str = r#"
+            UPDATE users
+               SET email = ?
+             WHERE service = ?
+               AND email = ?
+        "#;
+
+        diesel::sql_query(QUERY)
+            .bind::<Text, _>(params.new_email)
+            .bind::<Integer, _>(params.service_id)
+            .bind::<Text, _>(params.email)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn mark_event_processed(
+        &mut self,
+        params: params::MarkEventProcessed,
+    ) -> DbResult<results::MarkEventProcessed> {
+        const QUERY: &str = r#"
+            INSERT IGNORE INTO webhook_events (jti, processed_at)
+            VALUES (?, ?)
+        "#;
+
+        let inserted = diesel::sql_query(QUERY)
+            .bind::<Text, _>(params.jti)
+            .bind::<Bigint, _>(params.processed_at)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(inserted == 1)
+    }
+
+    async fn delete_processed_events(
+        &mut self,
+        params: params::DeleteProcessedEvents,
+    ) -> DbResult<results::DeleteProcessedEvents> {
+        const QUERY: &str = r#"
+            DELETE FROM webhook_events
+             WHERE processed_at < ?
+        "#;
+
+        let deleted = diesel::sql_query(QUERY)
+            .bind::<Bigint, _>(params.processed_before)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(deleted)
+    }
+
+    async fn post_failed_event(
+        &mut self,
+        params: params::PostFailedEvent,
+    ) -> DbResult<results::PostFailedEvent> {
+        const QUERY: &str = r#"
+            INSERT INTO webhook_failed_events (jti, email, event_type, payload, error, attempts,
+                                               failed_at)
+            VALUES (?, ?, ?, ?, ?, 1, ?)
+        "#;
+
+        diesel::sql_query(QUERY)
+            .bind::<Text, _>(params.jti)
+            .bind::<Text, _>(params.email)
+            .bind::<Text, _>(params.event_type)
+            .bind::<Text, _>(params.payload)
+            .bind::<Text, _>(params.error)
+            .bind::<Bigint, _>(params.failed_at)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_failed_events(
+        &mut self,
+        params: params::GetFailedEvents,
+    ) -> DbResult<results::GetFailedEvents> {
+        const QUERY: &str = r#"
+              SELECT id, jti, email, event_type, payload, error, attempts, failed_at
+                FROM webhook_failed_events
+            ORDER BY id
+               LIMIT ?
+        "#;
+
+        let result = diesel::sql_query(QUERY)
+            .bind::<Bigint, _>(params.limit)
+            .load::<results::FailedEvent>(&mut self.conn)
+            .await?;
+        Ok(result)
+    }
+
+    async fn update_failed_event(
+        &mut self,
+        params: params::UpdateFailedEvent,
+    ) -> DbResult<results::UpdateFailedEvent> {
+        const QUERY: &str = r#"
+            UPDATE webhook_failed_events
+               SET error = ?,
+                   attempts = attempts + 1,
+                   failed_at = ?
+             WHERE id = ?
+        "#;
+
+        diesel::sql_query(QUERY)
+            .bind::<Text, _>(params.error)
+            .bind::<Bigint, _>(params.failed_at)
+            .bind::<Bigint, _>(params.id)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_failed_event(
+        &mut self,
+        params: params::DeleteFailedEvent,
+    ) -> DbResult<results::DeleteFailedEvent> {
+        const QUERY: &str = "DELETE FROM webhook_failed_events WHERE id = ?";
+
+        diesel::sql_query(QUERY)
+            .bind::<Bigint, _>(params.id)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
     async fn check(&mut self) -> DbResult<results::Check> {
         diesel::sql_query("SELECT 1")
             .execute(&mut self.conn)
@@ -204,7 +429,8 @@ impl Db for TokenserverDb {
         Ok(true)
     }
 
-    /// Gets the least-loaded node that has available slots.
+    /// Gets the least-loaded node that has available slots, preferring nodes in the configured
+    /// region. With the weighted policy, load is measured per unit of node weight instead.
     async fn get_best_node(
         &mut self,
         params: params::GetBestNode,
@@ -218,7 +444,23 @@ impl Db for TokenserverDb {
                  AND capacity > current_load
                  AND downed = 0
                  AND backoff = 0
-            ORDER BY LOG(current_load) / LOG(capacity)
+                 AND weight > 0
+            ORDER BY CASE WHEN region = ? THEN 0 ELSE 1 END,
+                     LOG(current_load) / LOG(capacity)
+               LIMIT 1
+        "#;
+        const GET_BEST_WEIGHTED_NODE_QUERY: &str = r#"
+              SELECT id, node
+                FROM nodes
+               WHERE service = ?
+                 AND available > 0
+                 AND capacity > current_load
+                 AND downed = 0
+                 AND backoff = 0
+                 AND weight > 0
+            ORDER BY CASE WHEN region = ? THEN 0 ELSE 1 END,
+                     current_load / weight,
+                     id
                LIMIT 1
         "#;
         const RELEASE_CAPACITY_QUERY: &str = r#"
@@ -253,9 +495,15 @@ impl Db for TokenserverDb {
         } else {
             // We may have to retry the query if we need to release more capacity. This loop allows
             // a maximum of five retries before bailing out.
+            let query = match self.allocation_policy {
+                AllocationPolicy::LeastLoaded => GET_BEST_NODE_QUERY,
+                AllocationPolicy::Weighted => GET_BEST_WEIGHTED_NODE_QUERY,
+            };
+
             for _ in 0..5 {
-                let maybe_result = diesel::sql_query(GET_BEST_NODE_QUERY)
+                let maybe_result = diesel::sql_query(query)
                     .bind::<Integer, _>(params.service_id)
+                    .bind::<Nullable<Text>, _>(self.allocation_region.as_deref())
                     .get_result::<results::GetBestNode>(&mut self.conn)
                     .await
                     .optional()?;
@@ -356,7 +604,11 @@ impl Db for TokenserverDb {
              WHERE service = ?
         "#;
 
-        if let Some(id) = self.service_id {
+        // Only the Sync 1.5 service id is cached
+        if let Some(id) = self
+            .service_id
+            .filter(|_| params.service == SYNC_SERVICE_NAME)
+        {
             Ok(results::GetServiceId { id })
         } else {
             let result = diesel::sql_query(QUERY)
@@ -448,11 +700,26 @@ impl Db for TokenserverDb {
         Ok(affected_rows == 1)
     }
 
-    #[cfg(debug_assertions)]
+    async fn insert_service(&mut self, params: params::PostService) -> DbResult<bool> {
+        const QUERY: &str = r#"
+            INSERT IGNORE INTO services (service, pattern)
+            VALUES (?, ?)
+        "#;
+
+        let affected_rows = diesel::sql_query(QUERY)
+            .bind::<Text, _>(&params.service)
+            .bind::<Text, _>(&params.pattern)
+            .execute(&mut self.conn)
+            .await?;
+
+        Ok(affected_rows == 1)
+    }
+
     async fn post_node(&mut self, params: params::PostNode) -> DbResult<results::PostNode> {
         const QUERY: &str = r#"
-            INSERT INTO nodes (service, node, available, current_load, capacity, downed, backoff)
-            VALUES (?, ?, ?, ?, ?, ?, ?)
+            INSERT INTO nodes (service, node, available, current_load, capacity, downed, backoff,
+                               weight, region)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
         "#;
         diesel::sql_query(QUERY)
             .bind::<Integer, _>(params.service_id)
@@ -462,6 +729,8 @@ impl Db for TokenserverDb {
             .bind::<Integer, _>(params.capacity)
             .bind::<Integer, _>(params.downed)
             .bind::<Integer, _>(params.backoff)
+            .bind::<Integer, _>(params.weight)
+            .bind::<Nullable<Text>, _>(&params.region)
             .execute(&mut self.conn)
             .await?;
 
@@ -471,7 +740,6 @@ impl Db for TokenserverDb {
         Ok(result)
     }
 
-    #[cfg(debug_assertions)]
     async fn get_node(&mut self, params: params::GetNode) -> DbResult<results::GetNode> {
         const QUERY: &str = r#"
             SELECT *
@@ -486,7 +754,49 @@ impl Db for TokenserverDb {
         Ok(result)
     }
 
-    #[cfg(debug_assertions)]
+    async fn get_nodes(&mut self, params: params::GetNodes) -> DbResult<results::GetNodes> {
+        const QUERY: &str = r#"
+              SELECT *
+                FROM nodes
+               WHERE service = ?
+            ORDER BY id
+        "#;
+
+        let result = diesel::sql_query(QUERY)
+            .bind::<Integer, _>(params.service_id)
+            .load::<results::GetNode>(&mut self.conn)
+            .await?;
+        Ok(result)
+    }
+
+    async fn update_node(&mut self, params: params::UpdateNode) -> DbResult<results::UpdateNode> {
+        const QUERY: &str = r#"
+            UPDATE nodes
+               SET available = COALESCE(?, available),
+                   current_load = COALESCE(?, current_load),
+                   capacity = COALESCE(?, capacity),
+                   downed = COALESCE(?, downed),
+                   backoff = COALESCE(?, backoff),
+                   weight = COALESCE(?, weight),
+                   region = CASE WHEN ? THEN ? ELSE region END
+             WHERE id = ?
+        "#;
+
+        diesel::sql_query(QUERY)
+            .bind::<Nullable<Integer>, _>(params.available)
+            .bind::<Nullable<Integer>, _>(params.current_load)
+            .bind::<Nullable<Integer>, _>(params.capacity)
+            .bind::<Nullable<Integer>, _>(params.downed)
+            .bind::<Nullable<Integer>, _>(params.backoff)
+            .bind::<Nullable<Integer>, _>(params.weight)
+            .bind::<Bool, _>(params.region.is_some())
+            .bind::<Nullable<Text>, _>(params.region.flatten())
+            .bind::<Bigint, _>(params.id)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
     async fn unassign_node(
         &mut self,
         params: params::UnassignNode,
@@ -507,7 +817,47 @@ impl Db for TokenserverDb {
         Ok(())
     }
 
-    #[cfg(debug_assertions)]
+    async fn drain_node(&mut self, params: params::DrainNode) -> DbResult<results::DrainNode> {
+        const QUERY: &str = r#"
+              UPDATE users
+                 SET replaced_at = ?
+               WHERE nodeid = ?
+                 AND replaced_at IS NULL
+            ORDER BY uid
+               LIMIT ?
+        "#;
+
+        let drained = diesel::sql_query(QUERY)
+            .bind::<Bigint, _>(params.replaced_at)
+            .bind::<Bigint, _>(params.node_id)
+            .bind::<Bigint, _>(params.limit)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(drained)
+    }
+
+    async fn get_node_loads(
+        &mut self,
+        params: params::GetNodeLoads,
+    ) -> DbResult<results::GetNodeLoads> {
+        const QUERY: &str = r#"
+               SELECT nodes.id, nodes.node, nodes.available, nodes.current_load,
+                      nodes.capacity, nodes.downed, nodes.backoff, nodes.weight, nodes.region,
+                      COUNT(users.uid) AS current_users
+                 FROM nodes
+            LEFT JOIN users ON users.nodeid = nodes.id AND users.replaced_at IS NULL
+                WHERE nodes.service = ?
+             GROUP BY nodes.id
+             ORDER BY nodes.id
+        "#;
+
+        let result = diesel::sql_query(QUERY)
+            .bind::<Integer, _>(params.service_id)
+            .load::<results::NodeLoad>(&mut self.conn)
+            .await?;
+        Ok(result)
+    }
+
     async fn remove_node(&mut self, params: params::RemoveNode) -> DbResult<results::RemoveNode> {
         const QUERY: &str = "DELETE FROM nodes WHERE id = ?";
 
@@ -1,6 +1,7 @@
 use std::time::Duration;
 
 use syncserver_common::Metrics;
+use tokenserver_common::AllocationPolicy;
 
 use super::pool::Conn;
 
@@ -11,6 +12,8 @@ pub struct TokenserverDb {
     metrics: Metrics,
     service_id: Option<i32>,
     spanner_node_id: Option<i32>,
+    allocation_policy: AllocationPolicy,
+    allocation_region: Option<String>,
     pub timeout: Option<Duration>,
 }
 
@@ -20,7 +23,6 @@ impl TokenserverDb {
     // most recently-inserted record *for a given connection*. If connections were shared across
     // requests, using this function would introduce a race condition, as we could potentially
     // get IDs from records created during other requests.
-    #[allow(dead_code)]
     const LAST_INSERT_ID_QUERY: &'static str = "SELECT LAST_INSERT_ID() AS id";
     const LAST_INSERT_UID_QUERY: &'static str = "SELECT LAST_INSERT_ID() AS uid";
 
@@ -29,6 +31,8 @@ impl TokenserverDb {
         metrics: &Metrics,
         service_id: Option<i32>,
         spanner_node_id: Option<i32>,
+        allocation_policy: AllocationPolicy,
+        allocation_region: Option<String>,
         timeout: Option<Duration>,
     ) -> Self {
         Self {
@@ -36,6 +40,8 @@ impl TokenserverDb {
             metrics: metrics.clone(),
             service_id,
             spanner_node_id,
+            allocation_policy,
+            allocation_region,
             timeout,
         }
     }
@@ -16,6 +16,7 @@ use syncserver_db_common::{
     GetPoolStatus, establish_connection_with_logging, manager_config_with_logging,
     run_embedded_migrations,
 };
+use tokenserver_common::AllocationPolicy;
 use tokenserver_db_common::{Db, DbError, DbPool, DbResult, params};
 
 use tokenserver_settings::Settings;
@@ -34,6 +35,8 @@ pub struct TokenserverPool {
     // This field is public so the service ID can be set after the pool is created
     pub service_id: Option<i32>,
     spanner_node_id: Option<i32>,
+    allocation_policy: AllocationPolicy,
+    allocation_region: Option<String>,
     pub timeout: Option<Duration>,
     run_migrations: bool,
     database_url: String,
@@ -88,6 +91,8 @@ impl TokenserverPool {
             inner: pool,
             metrics: metrics.clone(),
             spanner_node_id: settings.spanner_node_id,
+            allocation_policy: settings.allocation_policy,
+            allocation_region: settings.allocation_region.clone(),
             service_id: None,
             timeout,
             run_migrations: settings.run_migrations,
@@ -103,6 +108,8 @@ impl TokenserverPool {
             &self.metrics,
             self.service_id,
             self.spanner_node_id,
+            self.allocation_policy,
+            self.allocation_region.clone(),
             self.timeout,
         ))
     }
@@ -0,0 +1,19 @@
+-- The FxA webhook Security Event Tokens already processed, by their `jti`
+CREATE TABLE IF NOT EXISTS webhook_events (
+    jti VARCHAR(128) PRIMARY KEY,
+    processed_at BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS processed_at_idx ON webhook_events (processed_at);
+
+-- The FxA account events that failed to process, to be replayed
+CREATE TABLE IF NOT EXISTS webhook_failed_events (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    jti VARCHAR(128) NOT NULL,
+    email VARCHAR(255) NOT NULL,
+    event_type VARCHAR(255) NOT NULL,
+    payload TEXT NOT NULL,
+    error TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    failed_at BIGINT NOT NULL
+);
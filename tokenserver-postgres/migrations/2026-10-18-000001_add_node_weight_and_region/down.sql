@@ -0,0 +1,3 @@
+ALTER TABLE nodes
+    DROP COLUMN IF EXISTS region,
+    DROP COLUMN IF EXISTS weight;
@@ -0,0 +1,3 @@
+ALTER TABLE nodes
+    ADD COLUMN IF NOT EXISTS weight INTEGER NOT NULL DEFAULT 100,
+    ADD COLUMN IF NOT EXISTS region VARCHAR(64);
@@ -7,18 +7,35 @@ use async_trait::async_trait;
 use chrono::Utc;
 use diesel::{
     OptionalExtension,
-    sql_types::{BigInt, Float, Integer, Nullable, Text},
+    sql_types::{BigInt, Bool, Float, Integer, Nullable, Text},
 };
-use diesel_async::RunQueryDsl;
+use diesel_async::{AsyncConnection, RunQueryDsl, TransactionManager};
 use http::StatusCode;
 
 use syncserver_common::Metrics;
-use tokenserver_db_common::{Db, DbError, DbResult, params, results};
+use tokenserver_common::AllocationPolicy;
+use tokenserver_db_common::{Db, DbError, DbResult, SYNC_SERVICE_NAME, params, results};
 
 use super::TokenserverPgDb;
+use crate::pool::Conn;
 
 #[async_trait(?Send)]
 impl Db for TokenserverPgDb {
+    async fn begin(&mut self) -> DbResult<()> {
+        <Conn as AsyncConnection>::TransactionManager::begin_transaction(&mut self.conn).await?;
+        Ok(())
+    }
+
+    async fn commit(&mut self) -> DbResult<()> {
+        <Conn as AsyncConnection>::TransactionManager::commit_transaction(&mut self.conn).await?;
+        Ok(())
+    }
+
+    async fn rollback(&mut self) -> DbResult<()> {
+        <Conn as AsyncConnection>::TransactionManager::rollback_transaction(&mut self.conn).await?;
+        Ok(())
+    }
+
     // Services Table Methods
 
     /// Acquire service_id through passed in service string.
@@ -32,7 +49,11 @@ impl Db for TokenserverPgDb {
              WHERE service = $1
         "#;
 
-        if let Some(id) = self.service_id {
+        // Only the Sync 1.5 service id is cached
+        if let Some(id) = self
+            .service_id
+            .filter(|_| params.service == SYNC_SERVICE_NAME)
+        {
             Ok(results::GetServiceId { id })
         } else {
             let result = diesel::sql_query(QUERY)
@@ -89,10 +110,25 @@ impl Db for TokenserverPgDb {
         Ok(affected_rows == 1)
     }
 
+    async fn insert_service(&mut self, params: params::PostService) -> DbResult<bool> {
+        const QUERY: &str = r#"
+            INSERT INTO services (service, pattern)
+            VALUES ($1, $2)
+            ON CONFLICT (service) DO NOTHING
+        "#;
+
+        let affected_rows = diesel::sql_query(QUERY)
+            .bind::<Text, _>(&params.service)
+            .bind::<Text, _>(&params.pattern)
+            .execute(&mut self.conn)
+            .await?;
+
+        Ok(affected_rows == 1)
+    }
+
     /// Get Node with complete metadata, given a provided Node ID.
     /// Returns a complete Node, including id, service_id, node string identifier
     /// availability, and current load.
-    #[cfg(debug_assertions)]
     async fn get_node(&mut self, params: params::GetNode) -> DbResult<results::GetNode> {
         const QUERY: &str = r#"
             SELECT *
@@ -132,8 +168,9 @@ impl Db for TokenserverPgDb {
         }
     }
 
-    /// Get the best Node ID, which is the least loaded node with most available slots,
-    /// given a provided service string and node.
+    /// Get the best Node ID, preferring nodes in the configured region and then the least
+    /// loaded node (or, with the weighted policy, the node with the least load per unit of
+    /// weight) with available slots, given a provided service string and node.
     /// Returns a node_id and identifier string.
     async fn get_best_node(
         &mut self,
@@ -148,7 +185,23 @@ impl Db for TokenserverPgDb {
                AND capacity > current_load
                AND downed = 0
                AND backoff = 0
-             ORDER BY CASE WHEN current_load = 0 THEN -1 ELSE LN(current_load) / LN(capacity) END
+               AND weight > 0
+             ORDER BY CASE WHEN region = $2 THEN 0 ELSE 1 END,
+                      CASE WHEN current_load = 0 THEN -1 ELSE LN(current_load) / LN(capacity) END
+             LIMIT 1
+            "#;
+        const GET_BEST_WEIGHTED_NODE_QUERY: &str = r#"
+            SELECT id, node
+              FROM nodes
+             WHERE service = $1
+               AND available > 0
+               AND capacity > current_load
+               AND downed = 0
+               AND backoff = 0
+               AND weight > 0
+             ORDER BY CASE WHEN region = $2 THEN 0 ELSE 1 END,
+                      current_load * 1.0 / weight,
+                      id
              LIMIT 1
             "#;
         const RELEASE_CAPACITY_QUERY: &str = r#"
@@ -183,15 +236,20 @@ impl Db for TokenserverPgDb {
                     db_error
                 })
         } else {
+            let query = match self.allocation_policy {
+                AllocationPolicy::LeastLoaded => GET_BEST_NODE_QUERY,
+                AllocationPolicy::Weighted => GET_BEST_WEIGHTED_NODE_QUERY,
+            };
+
             // This loop allows for a maximum of 5 retries before stopping.
             // This allows for query retries if more capacity needs to be released.
             for _ in 0..5 {
-                let possible_result: Option<results::GetBestNode> =
-                    diesel::sql_query(GET_BEST_NODE_QUERY)
-                        .bind::<Integer, _>(params.service_id)
-                        .get_result::<results::GetBestNode>(&mut self.conn)
-                        .await
-                        .optional()?;
+                let possible_result: Option<results::GetBestNode> = diesel::sql_query(query)
+                    .bind::<Integer, _>(params.service_id)
+                    .bind::<Nullable<Text>, _>(self.allocation_region.as_deref())
+                    .get_result::<results::GetBestNode>(&mut self.conn)
+                    .await
+                    .optional()?;
 
                 if let Some(result) = possible_result {
                     return Ok(result);
@@ -223,11 +281,11 @@ impl Db for TokenserverPgDb {
 
     /// Create and Insert a new node.
     /// Returns the last inserted `id` of the newly created node.
-    #[cfg(debug_assertions)]
     async fn post_node(&mut self, params: params::PostNode) -> DbResult<results::PostNode> {
         const QUERY: &str = r#"
-            INSERT INTO nodes (service, node, available, current_load, capacity, downed, backoff)
-            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            INSERT INTO nodes (service, node, available, current_load, capacity, downed, backoff,
+                               weight, region)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
             RETURNING id
         "#;
         let result = diesel::sql_query(QUERY)
@@ -238,6 +296,8 @@ impl Db for TokenserverPgDb {
             .bind::<Integer, _>(params.capacity)
             .bind::<Integer, _>(params.downed)
             .bind::<Integer, _>(params.backoff)
+            .bind::<Integer, _>(params.weight)
+            .bind::<Nullable<Text>, _>(params.region)
             .get_result::<results::PostNode>(&mut self.conn)
             .await?;
         Ok(result)
@@ -284,7 +344,6 @@ impl Db for TokenserverPgDb {
     }
 
     /// Remove a node given the node ID.
-    #[cfg(debug_assertions)]
     async fn remove_node(&mut self, params: params::RemoveNode) -> DbResult<results::RemoveNode> {
         const QUERY: &str = "DELETE FROM nodes WHERE id = $1";
 
@@ -498,9 +557,51 @@ impl Db for TokenserverPgDb {
         Ok(())
     }
 
+    async fn get_nodes(&mut self, params: params::GetNodes) -> DbResult<results::GetNodes> {
+        const QUERY: &str = r#"
+              SELECT *
+                FROM nodes
+               WHERE service = $1
+            ORDER BY id
+        "#;
+
+        let result = diesel::sql_query(QUERY)
+            .bind::<Integer, _>(params.service_id)
+            .load::<results::GetNode>(&mut self.conn)
+            .await?;
+        Ok(result)
+    }
+
+    async fn update_node(&mut self, params: params::UpdateNode) -> DbResult<results::UpdateNode> {
+        const QUERY: &str = r#"
+            UPDATE nodes
+               SET available = COALESCE($1, available),
+                   current_load = COALESCE($2, current_load),
+                   capacity = COALESCE($3, capacity),
+                   downed = COALESCE($4, downed),
+                   backoff = COALESCE($5, backoff),
+                   weight = COALESCE($6, weight),
+                   region = CASE WHEN $7 THEN $8 ELSE region END
+             WHERE id = $9
+        "#;
+
+        diesel::sql_query(QUERY)
+            .bind::<Nullable<Integer>, _>(params.available)
+            .bind::<Nullable<Integer>, _>(params.current_load)
+            .bind::<Nullable<Integer>, _>(params.capacity)
+            .bind::<Nullable<Integer>, _>(params.downed)
+            .bind::<Nullable<Integer>, _>(params.backoff)
+            .bind::<Nullable<Integer>, _>(params.weight)
+            .bind::<Bool, _>(params.region.is_some())
+            .bind::<Nullable<Text>, _>(params.region.flatten())
+            .bind::<BigInt, _>(params.id)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
     /// Given ONLY a particular `node_id`, update the users table to indicate an unassigned
     /// node by updating the `replaced_at` field with the current time since Unix Epoch.
-    #[cfg(debug_assertions)]
     async fn unassign_node(
         &mut self,
         params: params::UnassignNode,
@@ -521,6 +622,51 @@ impl Db for TokenserverPgDb {
         Ok(())
     }
 
+    async fn drain_node(&mut self, params: params::DrainNode) -> DbResult<results::DrainNode> {
+        const QUERY: &str = r#"
+            UPDATE users
+               SET replaced_at = $1
+             WHERE uid IN (
+                      SELECT uid
+                        FROM users
+                       WHERE nodeid = $2
+                         AND replaced_at IS NULL
+                    ORDER BY uid
+                       LIMIT $3
+                   )
+        "#;
+
+        let drained = diesel::sql_query(QUERY)
+            .bind::<BigInt, _>(params.replaced_at)
+            .bind::<BigInt, _>(params.node_id)
+            .bind::<BigInt, _>(params.limit)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(drained)
+    }
+
+    async fn get_node_loads(
+        &mut self,
+        params: params::GetNodeLoads,
+    ) -> DbResult<results::GetNodeLoads> {
+        const QUERY: &str = r#"
+               SELECT nodes.id, nodes.node, nodes.available, nodes.current_load,
+                      nodes.capacity, nodes.downed, nodes.backoff, nodes.weight, nodes.region,
+                      COUNT(users.uid) AS current_users
+                 FROM nodes
+            LEFT JOIN users ON users.nodeid = nodes.id AND users.replaced_at IS NULL
+                WHERE nodes.service = $1
+             GROUP BY nodes.id
+             ORDER BY nodes.id
+        "#;
+
+        let result = diesel::sql_query(QUERY)
+            .bind::<Integer, _>(params.service_id)
+            .load::<results::NodeLoad>(&mut self.conn)
+            .await?;
+        Ok(result)
+    }
+
     /// Given ONLY a particular `uid`, update the users table `created_at` value
     /// with the passed parameter.
     #[cfg(debug_assertions)]
@@ -564,6 +710,215 @@ impl Db for TokenserverPgDb {
     }
 
     /// Simple check function to ensure database liveliness.
+    async fn count_users(&mut self, params: params::CountUsers) -> DbResult<results::CountUsers> {
+        const QUERY: &str = r#"
+              SELECT nodes.node, COUNT(users.uid) AS count
+                FROM users
+                JOIN nodes ON users.nodeid = nodes.id
+               WHERE users.service = $1
+                 AND users.replaced_at IS NULL
+                 AND users.created_at <= $2
+            GROUP BY nodes.node
+            ORDER BY nodes.node
+        "#;
+
+        let result = diesel::sql_query(QUERY)
+            .bind::<Integer, _>(params.service_id)
+            .bind::<BigInt, _>(params.created_before)
+            .load::<results::NodeUserCount>(&mut self.conn)
+            .await?;
+        Ok(result)
+    }
+
+    async fn get_old_users(
+        &mut self,
+        params: params::GetOldUsers,
+    ) -> DbResult<results::GetOldUsers> {
+        const QUERY: &str = r#"
+                     SELECT uid, email, generation, keys_changed_at, client_state, nodes.node,
+                            nodes.downed, created_at, replaced_at
+                       FROM users
+            LEFT OUTER JOIN nodes ON users.nodeid = nodes.id
+                      WHERE users.service = $1
+                        AND replaced_at IS NOT NULL
+                        AND replaced_at < $2
+                      LIMIT $3
+                     OFFSET $4
+        "#;
+
+        let mut metrics = self.metrics.clone();
+        metrics.start_timer("storage.get_old_users", None);
+
+        let result = diesel::sql_query(QUERY)
+            .bind::<Integer, _>(params.service_id)
+            .bind::<BigInt, _>(params.replaced_before)
+            .bind::<BigInt, _>(params.limit)
+            .bind::<BigInt, _>(params.offset)
+            .load::<results::OldUser>(&mut self.conn)
+            .await?;
+        Ok(result)
+    }
+
+    async fn delete_user(&mut self, params: params::DeleteUser) -> DbResult<results::DeleteUser> {
+        // Spanner nodes don't track their available slots (see `add_user_to_node`)
+        const FREE_SLOT_QUERY: &str = r#"
+            UPDATE nodes
+               SET available = available + 1,
+                   current_load = current_load - 1
+             WHERE id = (SELECT nodeid FROM users WHERE service = $1 AND uid = $2)
+        "#;
+        const DELETE_QUERY: &str = r#"
+            DELETE FROM users
+             WHERE service = $1
+               AND uid = $2
+        "#;
+
+        let mut metrics = self.metrics.clone();
+        metrics.start_timer("storage.delete_user", None);
+
+        if self.spanner_node_id.is_none() {
+            diesel::sql_query(FREE_SLOT_QUERY)
+                .bind::<Integer, _>(params.service_id)
+                .bind::<BigInt, _>(params.uid)
+                .execute(&mut self.conn)
+                .await?;
+        }
+        diesel::sql_query(DELETE_QUERY)
+            .bind::<Integer, _>(params.service_id)
+            .bind::<BigInt, _>(params.uid)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn update_user_email(
+        &mut self,
+        params: params::UpdateUserEmail,
+    ) -> DbResult<results::UpdateUserEmail> {
+        const QUERY: &str = r#"
+            UPDATE users
+               SET email = $1
+             WHERE service = $2
+               AND email = $3
+        "#;
+
+        diesel::sql_query(QUERY)
+            .bind::<Text, _>(params.new_email)
+            .bind::<Integer, _>(params.service_id)
+            .bind::<Text, _>(params.email)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn mark_event_processed(
+        &mut self,
+        params: params::MarkEventProcessed,
+    ) -> DbResult<results::MarkEventProcessed> {
+        const QUERY: &str = r#"
+            INSERT INTO webhook_events (jti, processed_at)
+            VALUES ($1, $2)
+            ON CONFLICT (jti) DO NOTHING
+        "#;
+
+        let inserted = diesel::sql_query(QUERY)
+            .bind::<Text, _>(params.jti)
+            .bind::<BigInt, _>(params.processed_at)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(inserted == 1)
+    }
+
+    async fn delete_processed_events(
+        &mut self,
+        params: params::DeleteProcessedEvents,
+    ) -> DbResult<results::DeleteProcessedEvents> {
+        const QUERY: &str = r#"
+            DELETE FROM webhook_events
+             WHERE processed_at < $1
+        "#;
+
+        let deleted = diesel::sql_query(QUERY)
+            .bind::<BigInt, _>(params.processed_before)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(deleted)
+    }
+
+    async fn post_failed_event(
+        &mut self,
+        params: params::PostFailedEvent,
+    ) -> DbResult<results::PostFailedEvent> {
+        const QUERY: &str = r#"
+            INSERT INTO webhook_failed_events (jti, email, event_type, payload, error, attempts,
+                                               failed_at)
+            VALUES ($1, $2, $3, $4, $5, 1, $6)
+        "#;
+
+        diesel::sql_query(QUERY)
+            .bind::<Text, _>(params.jti)
+            .bind::<Text, _>(params.email)
+            .bind::<Text, _>(params.event_type)
+            .bind::<Text, _>(params.payload)
+            .bind::<Text, _>(params.error)
+            .bind::<BigInt, _>(params.failed_at)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_failed_events(
+        &mut self,
+        params: params::GetFailedEvents,
+    ) -> DbResult<results::GetFailedEvents> {
+        const QUERY: &str = r#"
+              SELECT id, jti, email, event_type, payload, error, attempts, failed_at
+                FROM webhook_failed_events
+            ORDER BY id
+               LIMIT $1
+        "#;
+
+        let result = diesel::sql_query(QUERY)
+            .bind::<BigInt, _>(params.limit)
+            .load::<results::FailedEvent>(&mut self.conn)
+            .await?;
+        Ok(result)
+    }
+
+    async fn update_failed_event(
+        &mut self,
+        params: params::UpdateFailedEvent,
+    ) -> DbResult<results::UpdateFailedEvent> {
+        const QUERY: &str = r#"
+            UPDATE webhook_failed_events
+               SET error = $1,
+                   attempts = attempts + 1,
+                   failed_at = $2
+             WHERE id = $3
+        "#;
+
+        diesel::sql_query(QUERY)
+            .bind::<Text, _>(params.error)
+            .bind::<BigInt, _>(params.failed_at)
+            .bind::<BigInt, _>(params.id)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_failed_event(
+        &mut self,
+        params: params::DeleteFailedEvent,
+    ) -> DbResult<results::DeleteFailedEvent> {
+        const QUERY: &str = "DELETE FROM webhook_failed_events WHERE id = $1";
+
+        diesel::sql_query(QUERY)
+            .bind::<BigInt, _>(params.id)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
     async fn check(&mut self) -> DbResult<results::Check> {
         diesel::sql_query("SELECT 1")
             .execute(&mut self.conn)
@@ -1,6 +1,7 @@
 use std::time::Duration;
 
 use syncserver_common::Metrics;
+use tokenserver_common::AllocationPolicy;
 
 use crate::pool::Conn;
 
@@ -19,6 +20,10 @@ pub struct TokenserverPgDb {
     service_id: Option<i32>,
     /// Optional Spanner Node ID.
     spanner_node_id: Option<i32>,
+    /// How new users are allocated to nodes.
+    allocation_policy: AllocationPolicy,
+    /// The region new users are preferably allocated to.
+    allocation_region: Option<String>,
     /// Settings specified timeout for Db Connection.
     pub timeout: Option<Duration>,
 }
@@ -30,6 +35,8 @@ impl TokenserverPgDb {
         metrics: &Metrics,
         service_id: Option<i32>,
         spanner_node_id: Option<i32>,
+        allocation_policy: AllocationPolicy,
+        allocation_region: Option<String>,
         timeout: Option<Duration>,
     ) -> Self {
         Self {
@@ -37,6 +44,8 @@ impl TokenserverPgDb {
             metrics: metrics.clone(),
             service_id,
             spanner_node_id,
+            allocation_policy,
+            allocation_region,
             timeout,
         }
     }
@@ -33,4 +33,6 @@ pub struct Node {
     pub capacity: i32,
     pub downed: i32,
     pub backoff: i32,
+    pub weight: i32,
+    pub region: Option<String>,
 }
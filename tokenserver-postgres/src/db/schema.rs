@@ -9,6 +9,9 @@ diesel::table! {
         capacity -> Int4,
         downed -> Int4,
         backoff -> Int4,
+        weight -> Int4,
+        #[max_length = 64]
+        region -> Nullable<Varchar>,
     }
 }
 
@@ -38,4 +41,34 @@ diesel::table! {
     }
 }
 
-diesel::allow_tables_to_appear_in_same_query!(nodes, services, users,);
+diesel::table! {
+    webhook_events (jti) {
+        #[max_length = 128]
+        jti -> Varchar,
+        processed_at -> Int8,
+    }
+}
+
+diesel::table! {
+    webhook_failed_events (id) {
+        id -> Int8,
+        #[max_length = 128]
+        jti -> Varchar,
+        #[max_length = 255]
+        email -> Varchar,
+        #[max_length = 255]
+        event_type -> Varchar,
+        payload -> Text,
+        error -> Text,
+        attempts -> Int4,
+        failed_at -> Int8,
+    }
+}
+
+diesel::allow_tables_to_appear_in_same_query!(
+    nodes,
+    services,
+    users,
+    webhook_events,
+    webhook_failed_events,
+);
@@ -14,6 +14,7 @@ use syncserver_common::Metrics;
 #[cfg(debug_assertions)]
 use syncserver_db_common::test::test_transaction_hook;
 use syncserver_db_common::{GetPoolStatus, manager_config_with_logging, run_embedded_migrations};
+use tokenserver_common::AllocationPolicy;
 use tokenserver_db_common::{Db, DbError, DbPool, DbResult, params};
 
 use crate::db::TokenserverPgDb;
@@ -37,6 +38,8 @@ pub struct TokenserverPgPool {
     pub service_id: Option<i32>,
     /// Optional associated spanner node.
     spanner_node_id: Option<i32>,
+    allocation_policy: AllocationPolicy,
+    allocation_region: Option<String>,
     /// Optional pool timeout duration, defined as i32.
     pub timeout: Option<Duration>,
     /// Config setting flag to determine if migrations should run.
@@ -94,6 +97,8 @@ impl TokenserverPgPool {
             inner: pool,
             metrics: metrics.clone(),
             spanner_node_id: settings.spanner_node_id,
+            allocation_policy: settings.allocation_policy,
+            allocation_region: settings.allocation_region.clone(),
             service_id: None,
             timeout,
             run_migrations: settings.run_migrations,
@@ -108,6 +113,8 @@ impl TokenserverPgPool {
             &self.metrics,
             self.service_id,
             self.spanner_node_id,
+            self.allocation_policy,
+            self.allocation_region.clone(),
             self.timeout,
         ))
     }
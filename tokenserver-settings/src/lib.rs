@@ -1,6 +1,6 @@
 use jsonwebtoken::jwk::Jwk;
 use serde::Deserialize;
-use tokenserver_common::NodeType;
+use tokenserver_common::{AllocationPolicy, NodeType};
 
 #[derive(Clone, Debug, Deserialize)]
 #[serde(default)]
@@ -28,6 +28,10 @@ pub struct Settings {
     /// The email domain for users' FxA accounts. This should be set according to the
     /// desired FxA environment (production or stage).
     pub fxa_email_domain: String,
+    /// The identity provider Sync clients authenticate against.
+    pub identity_provider: IdentityProviderType,
+    /// The generic OpenID Connect identity provider, used when `identity_provider` is `oidc`.
+    pub oidc: OidcSettings,
     /// The URL of the FxA server used for verifying OAuth tokens.
     pub fxa_oauth_server_url: String,
     /// The timeout to be used when making requests to the FxA OAuth verification server.
@@ -40,10 +44,23 @@ pub struct Settings {
     /// A secondary JWK to be used to verify OAuth tokens. This is intended to be used to enable
     /// seamless key rotations on FxA.
     pub fxa_oauth_secondary_jwk: Option<Jwk>,
+    /// How often, in seconds, the keys on the FxA OAuth server's `/v1/jwks` endpoint are
+    /// refetched when its response has no `Cache-Control` max-age. Only used when neither
+    /// `fxa_oauth_primary_jwk` nor `fxa_oauth_secondary_jwk` is set.
+    pub fxa_oauth_jwks_refresh_interval: u64,
+    /// The minimum time, in seconds, between fetches of the FxA OAuth server's keys, e.g. when
+    /// tokens signed by an unknown key id are received or after a failed fetch.
+    pub fxa_oauth_jwks_min_refresh_interval: u64,
     /// Sync's client id assigned by FxA.  It is used to validate the `aud` of JWKs.
     pub fxa_client_id: Option<String>,
     /// The rate at which capacity should be released from nodes that are at capacity.
     pub node_capacity_release_rate: Option<f32>,
+    /// How new users are allocated to nodes: to the least loaded node, or in proportion to the
+    /// nodes' weights.
+    pub allocation_policy: AllocationPolicy,
+    /// The region (as recorded on the nodes) new users are preferably allocated to. Nodes in other
+    /// regions are only used when none in this region are available.
+    pub allocation_region: Option<String>,
     /// The type of the storage nodes used by this instance of Tokenserver.
     #[serde(default)]
     pub node_type: NodeType,
@@ -62,6 +79,9 @@ pub struct Settings {
     pub additional_blocking_threads_for_fxa_requests: Option<u32>,
     /// The amount of time in seconds before a token provided by Tokenserver expires.
     pub token_duration: u64,
+    /// Services tokens are issued for in addition to Sync 1.5, each allocating users to its own
+    /// nodes.
+    pub services: Vec<ServiceSettings>,
     /// The storage node URL to insert into the `nodes` table on startup.  The insert is performed
     /// only when this value is set.  On db record conflict it will do nothing.
     pub init_node_url: Option<String>,
@@ -75,6 +95,26 @@ pub struct Settings {
     /// are counted but not processed.
     /// Defaults to false.
     pub fxa_webhook_metrics_only: bool,
+    /// How long, in seconds, the ids of the processed Security Event Tokens are kept to skip FxA's
+    /// redeliveries of them. Older ones are purged hourly while the webhook is enabled.
+    pub fxa_webhook_processed_events_retention: u32,
+    /// Bearer token for the `/__admin__` endpoints (e.g. node management).
+    /// Disabled when unset.
+    pub admin_token: Option<String>,
+    /// How often, in seconds, a background task purges the users replaced (or retired) more than
+    /// `purge_replaced_users_grace_period` ago, along with their data on their storage node.
+    /// Disabled when unset.
+    pub purge_replaced_users_interval: Option<u32>,
+    /// How long, in seconds, a replaced user is kept before being purged.
+    pub purge_replaced_users_grace_period: u32,
+    /// Max number of replaced users fetched from the database at a time.
+    pub purge_replaced_users_batch_size: u32,
+    /// Max number of storage deletions issued per second. Unlimited when 0.
+    pub purge_replaced_users_max_per_second: u32,
+    /// Only log the users that would be purged, deleting nothing.
+    pub purge_replaced_users_dry_run: bool,
+    /// The timeout, in seconds, of the requests deleting a user's storage.
+    pub purge_replaced_users_request_timeout: u64,
 }
 
 impl Default for Settings {
@@ -89,22 +129,118 @@ impl Default for Settings {
             enabled: false,
             fxa_email_domain: "api-accounts.stage.mozaws.net".to_owned(),
             fxa_metrics_hash_secret: "secret".to_owned(),
+            identity_provider: IdentityProviderType::Fxa,
+            oidc: OidcSettings::default(),
             fxa_oauth_server_url: "https://oauth.stage.mozaws.net".to_owned(),
             fxa_oauth_request_timeout: 10,
             fxa_oauth_primary_jwk: None,
             fxa_oauth_secondary_jwk: None,
+            fxa_oauth_jwks_refresh_interval: 3600,
+            fxa_oauth_jwks_min_refresh_interval: 60,
             fxa_client_id: None,
             node_capacity_release_rate: None,
+            allocation_policy: AllocationPolicy::LeastLoaded,
+            allocation_region: None,
             node_type: NodeType::Spanner,
             statsd_label: "syncstorage.tokenserver".to_owned(),
             run_migrations: cfg!(test),
             spanner_node_id: None,
             additional_blocking_threads_for_fxa_requests: Some(1),
             token_duration: 3600,
+            services: Vec::new(),
             init_node_url: None,
             init_node_capacity: 100000,
             fxa_webhook_enabled: false,
             fxa_webhook_metrics_only: false,
+            fxa_webhook_processed_events_retention: 86400,
+            admin_token: None,
+            purge_replaced_users_interval: None,
+            purge_replaced_users_grace_period: 86400,
+            purge_replaced_users_batch_size: 100,
+            purge_replaced_users_max_per_second: 10,
+            purge_replaced_users_dry_run: false,
+            purge_replaced_users_request_timeout: 60,
+        }
+    }
+}
+
+/// A service Tokenserver issues tokens for, requested at `/1.0/{application}/{version}`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceSettings {
+    /// The application, e.g. `sync`.
+    pub application: String,
+    /// The version of the application's storage protocol, e.g. `1.6`. Clients are sent to
+    /// `[node]/[version]/[uid]` on the service's nodes.
+    pub version: String,
+    /// The amount of time in seconds before the service's tokens expire. Defaults to
+    /// `token_duration`.
+    pub token_duration: Option<u64>,
+    /// The secret the service's tokens are signed with, shared with its nodes. Defaults to the
+    /// master secret.
+    pub master_secret: Option<String>,
+    /// The rate at which capacity is released from the service's nodes that are at capacity.
+    /// Defaults to `node_capacity_release_rate`.
+    pub node_capacity_release_rate: Option<f32>,
+}
+
+impl ServiceSettings {
+    /// The service's name in the `services` table, e.g. `sync-1.6`.
+    pub fn name(&self) -> String {
+        format!("{}-{}", self.application, self.version)
+    }
+}
+
+/// The identity providers Tokenserver can authenticate Sync clients against.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum IdentityProviderType {
+    /// Firefox Accounts, configured by the `fxa_*` settings.
+    #[default]
+    Fxa,
+    /// A generic OpenID Connect provider, configured by the `oidc` settings.
+    Oidc,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct OidcSettings {
+    /// The provider's issuer URL. Its `/.well-known/openid-configuration` is fetched on startup
+    /// to discover its keys.
+    pub issuer: String,
+    /// The audience (`aud`) tokens must be issued for, e.g. Sync's client id. Required.
+    pub audience: Option<String>,
+    /// A scope tokens must grant. Not checked when unset.
+    pub scope: Option<String>,
+    /// The claim holding the user's id.
+    pub user_id_claim: String,
+    /// The claim holding the user's generation: a timestamp increasing on every
+    /// authentication-related change to their account. Not tracked when unset.
+    pub generation_claim: Option<String>,
+    /// The domain of the email addresses users are recorded under (as `[user id]@[domain]`).
+    /// Defaults to the issuer's host.
+    pub email_domain: Option<String>,
+    /// The timeout, in seconds, of requests to the provider.
+    pub request_timeout: u64,
+    /// How often, in seconds, the provider's keys are refetched when its response has no
+    /// `Cache-Control` max-age.
+    pub jwks_refresh_interval: u64,
+    /// The minimum time, in seconds, between fetches of the provider's keys.
+    pub jwks_min_refresh_interval: u64,
+}
+
+impl Default for OidcSettings {
+    fn default() -> Self {
+        Self {
+            issuer: String::new(),
+            audience: None,
+            scope: None,
+            user_id_claim: "sub".to_owned(),
+            generation_claim: None,
+            email_domain: None,
+            request_timeout: 10,
+            jwks_refresh_interval: 3600,
+            jwks_min_refresh_interval: 60,
         }
     }
 }
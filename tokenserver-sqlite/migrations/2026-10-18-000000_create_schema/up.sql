@@ -0,0 +1,41 @@
+-- AUTOINCREMENT prevents ids (particularly user uids, which key the user's
+-- storage) from being reused after their rows are deleted
+CREATE TABLE IF NOT EXISTS services (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    service TEXT UNIQUE,
+    pattern TEXT
+);
+
+CREATE TABLE IF NOT EXISTS nodes (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    service INTEGER NOT NULL,
+    node TEXT NOT NULL,
+    available INTEGER NOT NULL,
+    current_load INTEGER NOT NULL,
+    capacity INTEGER NOT NULL,
+    downed INTEGER NOT NULL,
+    backoff INTEGER NOT NULL,
+    UNIQUE (service, node)
+);
+
+CREATE TABLE IF NOT EXISTS users (
+    uid INTEGER PRIMARY KEY AUTOINCREMENT,
+    service INTEGER NOT NULL,
+    email TEXT NOT NULL,
+    generation INTEGER NOT NULL,
+    client_state TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    replaced_at INTEGER,
+    nodeid INTEGER NOT NULL,
+    keys_changed_at INTEGER
+);
+
+CREATE INDEX IF NOT EXISTS lookup_idx ON users (email, service, created_at);
+
+CREATE INDEX IF NOT EXISTS replaced_at_idx ON users (service, replaced_at);
+
+CREATE INDEX IF NOT EXISTS node_idx ON users (nodeid);
+
+-- The standard Sync service entry
+INSERT INTO services (service, pattern) VALUES
+    ('sync-1.5', '{node}/1.5/{uid}');
@@ -0,0 +1,19 @@
+-- The FxA webhook Security Event Tokens already processed, by their `jti`
+CREATE TABLE IF NOT EXISTS webhook_events (
+    jti TEXT PRIMARY KEY,
+    processed_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS processed_at_idx ON webhook_events (processed_at);
+
+-- The FxA account events that failed to process, to be replayed
+CREATE TABLE IF NOT EXISTS webhook_failed_events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    jti TEXT NOT NULL,
+    email TEXT NOT NULL,
+    event_type TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    error TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    failed_at INTEGER NOT NULL
+);
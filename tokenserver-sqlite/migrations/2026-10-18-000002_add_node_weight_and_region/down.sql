@@ -0,0 +1,3 @@
+ALTER TABLE nodes DROP COLUMN region;
+
+ALTER TABLE nodes DROP COLUMN weight;
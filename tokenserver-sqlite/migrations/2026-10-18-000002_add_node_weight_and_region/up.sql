@@ -0,0 +1,3 @@
+ALTER TABLE nodes ADD COLUMN weight INTEGER NOT NULL DEFAULT 100;
+
+ALTER TABLE nodes ADD COLUMN region TEXT;
@@ -0,0 +1,926 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use diesel::{
+    QueryableByName,
+    sql_types::{Bigint, Bool, Float, Integer, Nullable, Text},
+};
+use diesel_async::{AsyncConnection, RunQueryDsl, TransactionManager};
+use http::StatusCode;
+use syncserver_common::Metrics;
+use tokenserver_common::AllocationPolicy;
+use tokenserver_db_common::{Db, DbError, DbResult, SYNC_SERVICE_NAME, params, results};
+
+use super::TokenserverSqliteDb;
+use crate::pool::Conn;
+
+#[async_trait(?Send)]
+impl Db for TokenserverSqliteDb {
+    async fn begin(&mut self) -> DbResult<()> {
+        <Conn as AsyncConnection>::TransactionManager::begin_transaction(&mut self.conn).await?;
+        Ok(())
+    }
+
+    async fn commit(&mut self) -> DbResult<()> {
+        <Conn as AsyncConnection>::TransactionManager::commit_transaction(&mut self.conn).await?;
+        Ok(())
+    }
+
+    async fn rollback(&mut self) -> DbResult<()> {
+        <Conn as AsyncConnection>::TransactionManager::rollback_transaction(&mut self.conn).await?;
+        Ok(())
+    }
+
+    async fn get_node_id(&mut self, params: params::GetNodeId) -> DbResult<results::GetNodeId> {
+        const QUERY: &str = r#"
+            SELECT id
+              FROM nodes
+             WHERE service = ?
+               AND node = ?
+        "#;
+
+        if let Some(id) = self.spanner_node_id {
+            Ok(results::GetNodeId { id: id as i64 })
+        } else {
+            let mut metrics = self.metrics.clone();
+            metrics.start_timer("storage.get_node_id", None);
+
+            let result = diesel::sql_query(QUERY)
+                .bind::<Integer, _>(params.service_id)
+                .bind::<Text, _>(&params.node)
+                .get_result(&mut self.conn)
+                .await?;
+            Ok(result)
+        }
+    }
+
+    /// Mark users matching the given email and service ID as replaced.
+    async fn replace_users(
+        &mut self,
+        params: params::ReplaceUsers,
+    ) -> DbResult<results::ReplaceUsers> {
+        const QUERY: &str = r#"
+            UPDATE users
+               SET replaced_at = ?
+             WHERE service = ?
+               AND email = ?
+               AND replaced_at IS NULL
+               AND created_at < ?
+        "#;
+
+        let mut metrics = self.metrics.clone();
+        metrics.start_timer("storage.replace_users", None);
+
+        diesel::sql_query(QUERY)
+            .bind::<Bigint, _>(params.replaced_at)
+            .bind::<Integer, _>(&params.service_id)
+            .bind::<Text, _>(&params.email)
+            .bind::<Bigint, _>(params.replaced_at)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Mark the user with the given uid and service ID as being replaced.
+    async fn replace_user(
+        &mut self,
+        params: params::ReplaceUser,
+    ) -> DbResult<results::ReplaceUser> {
+        const QUERY: &str = r#"
+            UPDATE users
+               SET replaced_at = ?
+             WHERE service = ?
+               AND uid = ?
+        "#;
+
+        diesel::sql_query(QUERY)
+            .bind::<Bigint, _>(params.replaced_at)
+            .bind::<Integer, _>(params.service_id)
+            .bind::<Bigint, _>(params.uid)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Mark a user as retired by email.
+    async fn retire_user(&mut self, params: params::RetireUser) -> DbResult<results::RetireUser> {
+        const QUERY: &str = r#"
+            UPDATE users
+               SET generation = ?,
+                   replaced_at = ?
+             WHERE service = ?
+               AND email = ?
+               AND replaced_at IS NULL
+        "#;
+
+        let now = Utc::now().timestamp_millis();
+
+        diesel::sql_query(QUERY)
+            .bind::<Bigint, _>(tokenserver_db_common::MAX_GENERATION)
+            .bind::<Bigint, _>(now)
+            .bind::<Integer, _>(params.service_id)
+            .bind::<Text, _>(params.email)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Update the user with the given email and service ID with the given `generation` and
+    /// `keys_changed_at`.
+    async fn put_user(&mut self, params: params::PutUser) -> DbResult<results::PutUser> {
+        // The `where` clause on this statement is designed as an extra layer of
+        // protection, to ensure that concurrent updates don't accidentally move
+        // timestamp fields backwards in time. The handling of `keys_changed_at`
+        // is additionally weird because we want to treat the default `NULL` value
+        // as zero.
+        const QUERY: &str = r#"
+            UPDATE users
+               SET generation = ?,
+                   keys_changed_at = ?
+             WHERE service = ?
+               AND email = ?
+               AND generation <= ?
+               AND COALESCE(keys_changed_at, 0) <= COALESCE(?, keys_changed_at, 0)
+               AND replaced_at IS NULL
+        "#;
+
+        let mut metrics = self.metrics.clone();
+        metrics.start_timer("storage.put_user", None);
+
+        diesel::sql_query(QUERY)
+            .bind::<Bigint, _>(params.generation)
+            .bind::<Nullable<Bigint>, _>(params.keys_changed_at)
+            .bind::<Integer, _>(&params.service_id)
+            .bind::<Text, _>(&params.email)
+            .bind::<Bigint, _>(params.generation)
+            .bind::<Nullable<Bigint>, _>(params.keys_changed_at)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn update_user_generation(
+        &mut self,
+        params: params::UpdateUserGeneration,
+    ) -> DbResult<results::UpdateUserGeneration> {
+        const QUERY: &str = r#"
+            UPDATE users
+               SET generation = COALESCE(?, generation),
+                   keys_changed_at = COALESCE(?, keys_changed_at)
+             WHERE service = ?
+               AND email = ?
+               AND generation <= COALESCE(?, generation)
+               AND COALESCE(keys_changed_at, 0) <= COALESCE(?, keys_changed_at, 0)
+               AND replaced_at IS NULL
+        "#;
+
+        diesel::sql_query(QUERY)
+            .bind::<Nullable<Bigint>, _>(params.generation)
+            .bind::<Nullable<Bigint>, _>(params.keys_changed_at)
+            .bind::<Integer, _>(params.service_id)
+            .bind::<Text, _>(&params.email)
+            .bind::<Nullable<Bigint>, _>(params.generation)
+            .bind::<Nullable<Bigint>, _>(params.keys_changed_at)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Create a new user.
+    async fn post_user(&mut self, user: params::PostUser) -> DbResult<results::PostUser> {
+        const QUERY: &str = r#"
+            INSERT INTO users (service, email, generation, client_state, created_at, nodeid, keys_changed_at, replaced_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, NULL)
+            RETURNING uid
+        "#;
+
+        let mut metrics = self.metrics.clone();
+        metrics.start_timer("storage.post_user", None);
+
+        let result = diesel::sql_query(QUERY)
+            .bind::<Integer, _>(user.service_id)
+            .bind::<Text, _>(&user.email)
+            .bind::<Bigint, _>(user.generation)
+            .bind::<Text, _>(&user.client_state)
+            .bind::<Bigint, _>(user.created_at)
+            .bind::<Bigint, _>(user.node_id)
+            .bind::<Nullable<Bigint>, _>(user.keys_changed_at)
+            .get_result::<results::PostUser>(&mut self.conn)
+            .await?;
+        Ok(result)
+    }
+
+    async fn count_users(&mut self, params: params::CountUsers) -> DbResult<results::CountUsers> {
+        const QUERY: &str = r#"
+              SELECT nodes.node, COUNT(users.uid) AS count
+                FROM users
+                JOIN nodes ON users.nodeid = nodes.id
+               WHERE users.service = ?
+                 AND users.replaced_at IS NULL
+                 AND users.created_at <= ?
+            GROUP BY nodes.node
+            ORDER BY nodes.node
+        "#;
+
+        let result = diesel::sql_query(QUERY)
+            .bind::<Integer, _>(params.service_id)
+            .bind::<Bigint, _>(params.created_before)
+            .load::<results::NodeUserCount>(&mut self.conn)
+            .await?;
+        Ok(result)
+    }
+
+    async fn get_old_users(
+        &mut self,
+        params: params::GetOldUsers,
+    ) -> DbResult<results::GetOldUsers> {
+        const QUERY: &str = r#"
+                     SELECT uid, email, generation, keys_changed_at, client_state, nodes.node,
+                            nodes.downed, created_at, replaced_at
+                       FROM users
+            LEFT OUTER JOIN nodes ON users.nodeid = nodes.id
+                      WHERE users.service = ?
+                        AND replaced_at IS NOT NULL
+                        AND replaced_at < ?
+                      LIMIT ?
+                     OFFSET ?
+        "#;
+
+        let mut metrics = self.metrics.clone();
+        metrics.start_timer("storage.get_old_users", None);
+
+        let result = diesel::sql_query(QUERY)
+            .bind::<Integer, _>(params.service_id)
+            .bind::<Bigint, _>(params.replaced_before)
+            .bind::<Bigint, _>(params.limit)
+            .bind::<Bigint, _>(params.offset)
+            .load::<results::OldUser>(&mut self.conn)
+            .await?;
+        Ok(result)
+    }
+
+    async fn delete_user(&mut self, params: params::DeleteUser) -> DbResult<results::DeleteUser> {
+        // Spanner nodes don't track their available slots (see `add_user_to_node`)
+        const FREE_SLOT_QUERY: &str = r#"
+            UPDATE nodes
+               SET available = available + 1,
+                   current_load = current_load - 1
+             WHERE id = (SELECT nodeid FROM users WHERE service = ? AND uid = ?)
+        "#;
+        const DELETE_QUERY: &str = r#"
+            DELETE FROM users
+             WHERE service = ?
+               AND uid = ?
+        "#;
+
+        let mut metrics = self.metrics.clone();
+        metrics.start_timer("storage.delete_user", None);
+
+        if self.spanner_node_id.is_none() {
+            diesel::sql_query(FREE_SLOT_QUERY)
+                .bind::<Integer, _>(params.service_id)
+                .bind::<Bigint, _>(params.uid)
+                .execute(&mut self.conn)
+                .await?;
+        }
+        diesel::sql_query(DELETE_QUERY)
+            .bind::<Integer, _>(params.service_id)
+            .bind::<Bigint, _>(params.uid)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn update_user_email(
+        &mut self,
+        params: params::UpdateUserEmail,
+    ) -> DbResult<results::UpdateUserEmail> {
+        const QUERY: &str = r#"
+            UPDATE users
+               SET email = ?
+             WHERE service = ?
+               AND email = ?
+        "#;
+
+        diesel::sql_query(QUERY)
+            .bind::<Text, _>(params.new_email)
+            .bind::<Integer, _>(params.service_id)
+            .bind::<Text, _>(params.email)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn mark_event_processed(
+        &mut self,
+        params: params::MarkEventProcessed,
+    ) -> DbResult<results::MarkEventProcessed> {
+        const QUERY: &str = r#"
+            INSERT OR IGNORE INTO webhook_events (jti, processed_at)
+            VALUES (?, ?)
+        "#;
+
+        let inserted = diesel::sql_query(QUERY)
+            .bind::<Text, _>(params.jti)
+            .bind::<Bigint, _>(params.processed_at)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(inserted == 1)
+    }
+
+    async fn delete_processed_events(
+        &mut self,
+        params: params::DeleteProcessedEvents,
+    ) -> DbResult<results::DeleteProcessedEvents> {
+        const QUERY: &str = r#"
+            DELETE FROM webhook_events
+             WHERE processed_at < ?
+        "#;
+
+        let deleted = diesel::sql_query(QUERY)
+            .bind::<Bigint, _>(params.processed_before)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(deleted)
+    }
+
+    async fn post_failed_event(
+        &mut self,
+        params: params::PostFailedEvent,
+    ) -> DbResult<results::PostFailedEvent> {
+        const QUERY: &str = r#"
+            INSERT INTO webhook_failed_events (jti, email, event_type, payload, error, attempts,
+                                               failed_at)
+            VALUES (?, ?, ?, ?, ?, 1, ?)
+        "#;
+
+        diesel::sql_query(QUERY)
+            .bind::<Text, _>(params.jti)
+            .bind::<Text, _>(params.email)
+            .bind::<Text, _>(params.event_type)
+            .bind::<Text, _>(params.payload)
+            .bind::<Text, _>(params.error)
+            .bind::<Bigint, _>(params.failed_at)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_failed_events(
+        &mut self,
+        params: params::GetFailedEvents,
+    ) -> DbResult<results::GetFailedEvents> {
+        const QUERY: &str = r#"
+              SELECT id, jti, email, event_type, payload, error, attempts, failed_at
+                FROM webhook_failed_events
+            ORDER BY id
+               LIMIT ?
+        "#;
+
+        let result = diesel::sql_query(QUERY)
+            .bind::<Bigint, _>(params.limit)
+            .load::<results::FailedEvent>(&mut self.conn)
+            .await?;
+        Ok(result)
+    }
+
+    async fn update_failed_event(
+        &mut self,
+        params: params::UpdateFailedEvent,
+    ) -> DbResult<results::UpdateFailedEvent> {
+        const QUERY: &str = r#"
+            UPDATE webhook_failed_events
+               SET error = ?,
+                   attempts = attempts + 1,
+                   failed_at = ?
+             WHERE id = ?
+        "#;
+
+        diesel::sql_query(QUERY)
+            .bind::<Text, _>(params.error)
+            .bind::<Bigint, _>(params.failed_at)
+            .bind::<Bigint, _>(params.id)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_failed_event(
+        &mut self,
+        params: params::DeleteFailedEvent,
+    ) -> DbResult<results::DeleteFailedEvent> {
+        const QUERY: &str = "DELETE FROM webhook_failed_events WHERE id = ?";
+
+        diesel::sql_query(QUERY)
+            .bind::<Bigint, _>(params.id)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn check(&mut self) -> DbResult<results::Check> {
+        diesel::sql_query("SELECT 1")
+            .execute(&mut self.conn)
+            .await?;
+        Ok(true)
+    }
+
+    /// Gets the least-loaded node that has available slots, preferring nodes
+    /// in the configured region. With the weighted policy, load is measured
+    /// per unit of node weight instead.
+    ///
+    /// SQLite lacks the `LOG` function (unless built with
+    /// `SQLITE_ENABLE_MATH_FUNCTIONS`) so the candidate nodes are ranked here
+    /// rather than by the query.
+    async fn get_best_node(
+        &mut self,
+        params: params::GetBestNode,
+    ) -> DbResult<results::GetBestNode> {
+        const DEFAULT_CAPACITY_RELEASE_RATE: f32 = 0.1;
+        const GET_BEST_NODE_QUERY: &str = r#"
+              SELECT id, node, current_load, capacity, weight, region
+                FROM nodes
+               WHERE service = ?
+                 AND available > 0
+                 AND capacity > current_load
+                 AND downed = 0
+                 AND backoff = 0
+                 AND weight > 0
+            ORDER BY id
+        "#;
+        const RELEASE_CAPACITY_QUERY: &str = r#"
+            UPDATE nodes
+               SET available = CAST(ROUND(MIN(capacity * ?, capacity - current_load)) AS INTEGER)
+             WHERE service = ?
+               AND available <= 0
+               AND capacity > current_load
+               AND downed = 0
+        "#;
+        const SPANNER_QUERY: &str = r#"
+              SELECT id, node
+                FROM nodes
+               WHERE id = ?
+               LIMIT 1
+        "#;
+
+        let mut metrics = self.metrics.clone();
+        metrics.start_timer("storage.get_best_node", None);
+
+        if let Some(spanner_node_id) = self.spanner_node_id {
+            diesel::sql_query(SPANNER_QUERY)
+                .bind::<Integer, _>(spanner_node_id)
+                .get_result::<results::GetBestNode>(&mut self.conn)
+                .await
+                .map_err(|e| {
+                    let mut db_error =
+                        DbError::internal(format!("unable to get Spanner node: {}", e));
+                    db_error.status = StatusCode::SERVICE_UNAVAILABLE;
+                    db_error
+                })
+        } else {
+            // We may have to retry the query if we need to release more capacity. This loop allows
+            // a maximum of five retries before bailing out.
+            for _ in 0..5 {
+                let maybe_result = diesel::sql_query(GET_BEST_NODE_QUERY)
+                    .bind::<Integer, _>(params.service_id)
+                    .load::<CandidateNode>(&mut self.conn)
+                    .await?
+                    .into_iter()
+                    .min_by(|a, b| {
+                        let region = self.allocation_region.as_deref();
+                        a.in_region(region)
+                            .cmp(&b.in_region(region))
+                            .reverse()
+                            .then_with(|| {
+                                a.load(self.allocation_policy)
+                                    .total_cmp(&b.load(self.allocation_policy))
+                            })
+                    });
+
+                if let Some(result) = maybe_result {
+                    return Ok(results::GetBestNode {
+                        id: result.id,
+                        node: result.node,
+                    });
+                }
+
+                // There were no available nodes. Try to release additional capacity from any nodes
+                // that are not fully occupied.
+                let affected_rows = diesel::sql_query(RELEASE_CAPACITY_QUERY)
+                    .bind::<Float, _>(
+                        params
+                            .capacity_release_rate
+                            .unwrap_or(DEFAULT_CAPACITY_RELEASE_RATE),
+                    )
+                    .bind::<Integer, _>(params.service_id)
+                    .execute(&mut self.conn)
+                    .await?;
+
+                // If no nodes were affected by the last query, give up.
+                if affected_rows == 0 {
+                    break;
+                }
+            }
+
+            let mut db_error = DbError::internal("unable to get a node".to_owned());
+            db_error.status = StatusCode::SERVICE_UNAVAILABLE;
+            Err(db_error)
+        }
+    }
+
+    async fn add_user_to_node(
+        &mut self,
+        params: params::AddUserToNode,
+    ) -> DbResult<results::AddUserToNode> {
+        let mut metrics = self.metrics.clone();
+        metrics.start_timer("storage.add_user_to_node", None);
+
+        const QUERY: &str = r#"
+            UPDATE nodes
+               SET current_load = current_load + 1,
+                   available = MAX(available - 1, 0)
+             WHERE service = ?
+               AND node = ?
+        "#;
+        const SPANNER_QUERY: &str = r#"
+            UPDATE nodes
+               SET current_load = current_load + 1
+             WHERE service = ?
+               AND node = ?
+        "#;
+
+        let query = if self.spanner_node_id.is_some() {
+            SPANNER_QUERY
+        } else {
+            QUERY
+        };
+
+        diesel::sql_query(query)
+            .bind::<Integer, _>(params.service_id)
+            .bind::<Text, _>(&params.node)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_users(&mut self, params: params::GetUsers) -> DbResult<results::GetUsers> {
+        let mut metrics = self.metrics.clone();
+        metrics.start_timer("storage.get_users", None);
+
+        const QUERY: &str = r#"
+                     SELECT uid, nodes.node, generation, keys_changed_at, client_state, created_at,
+                            replaced_at
+                       FROM users
+            LEFT OUTER JOIN nodes ON users.nodeid = nodes.id
+                      WHERE email = ?
+                        AND users.service = ?
+                   ORDER BY created_at DESC, uid DESC
+                      LIMIT 20
+        "#;
+
+        let result = diesel::sql_query(QUERY)
+            .bind::<Text, _>(&params.email)
+            .bind::<Integer, _>(params.service_id)
+            .load::<results::GetRawUser>(&mut self.conn)
+            .await?;
+        Ok(result)
+    }
+
+    async fn get_service_id(
+        &mut self,
+        params: params::GetServiceId,
+    ) -> DbResult<results::GetServiceId> {
+        const QUERY: &str = r#"
+            SELECT id
+              FROM services
+             WHERE service = ?
+        "#;
+
+        // Only the Sync 1.5 service id is cached
+        if let Some(id) = self
+            .service_id
+            .filter(|_| params.service == SYNC_SERVICE_NAME)
+        {
+            Ok(results::GetServiceId { id })
+        } else {
+            let result = diesel::sql_query(QUERY)
+                .bind::<Text, _>(params.service)
+                .get_result::<results::GetServiceId>(&mut self.conn)
+                .await?;
+            Ok(result)
+        }
+    }
+
+    fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    #[cfg(debug_assertions)]
+    async fn set_user_created_at(
+        &mut self,
+        params: params::SetUserCreatedAt,
+    ) -> DbResult<results::SetUserCreatedAt> {
+        const QUERY: &str = r#"
+            UPDATE users
+               SET created_at = ?
+             WHERE uid = ?
+        "#;
+        diesel::sql_query(QUERY)
+            .bind::<Bigint, _>(params.created_at)
+            .bind::<Bigint, _>(&params.uid)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
+    #[cfg(debug_assertions)]
+    async fn set_user_replaced_at(
+        &mut self,
+        params: params::SetUserReplacedAt,
+    ) -> DbResult<results::SetUserReplacedAt> {
+        const QUERY: &str = r#"
+            UPDATE users
+               SET replaced_at = ?
+             WHERE uid = ?
+        "#;
+        diesel::sql_query(QUERY)
+            .bind::<Bigint, _>(params.replaced_at)
+            .bind::<Bigint, _>(&params.uid)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
+    #[cfg(debug_assertions)]
+    async fn get_user(&mut self, params: params::GetUser) -> DbResult<results::GetUser> {
+        const QUERY: &str = r#"
+            SELECT service, email, generation, client_state, replaced_at, nodeid, keys_changed_at
+              FROM users
+             WHERE uid = ?
+        "#;
+
+        let result = diesel::sql_query(QUERY)
+            .bind::<Bigint, _>(params.id)
+            .get_result::<results::GetUser>(&mut self.conn)
+            .await?;
+        Ok(result)
+    }
+
+    async fn insert_sync15_node(&mut self, params: params::Sync15Node) -> DbResult<bool> {
+        let query = format!(
+            r#"
+            INSERT INTO nodes (service, node, available, current_load, capacity, downed, backoff)
+            VALUES (
+                (SELECT id FROM services WHERE service = '{}'),
+                ?, ?, 0, ?, 0, 0
+            )
+            ON CONFLICT (service, node) DO NOTHING
+            "#,
+            params::Sync15Node::SERVICE_NAME
+        );
+
+        let affected_rows = diesel::sql_query(query)
+            .bind::<Text, _>(&params.node)
+            .bind::<Integer, _>(params.capacity)
+            .bind::<Integer, _>(params.capacity)
+            .execute(&mut self.conn)
+            .await?;
+
+        Ok(affected_rows == 1)
+    }
+
+    async fn insert_service(&mut self, params: params::PostService) -> DbResult<bool> {
+        const QUERY: &str = r#"
+            INSERT INTO services (service, pattern)
+            VALUES (?, ?)
+            ON CONFLICT (service) DO NOTHING
+        "#;
+
+        let affected_rows = diesel::sql_query(QUERY)
+            .bind::<Text, _>(&params.service)
+            .bind::<Text, _>(&params.pattern)
+            .execute(&mut self.conn)
+            .await?;
+
+        Ok(affected_rows == 1)
+    }
+
+    async fn post_node(&mut self, params: params::PostNode) -> DbResult<results::PostNode> {
+        const QUERY: &str = r#"
+            INSERT INTO nodes (service, node, available, current_load, capacity, downed, backoff,
+                               weight, region)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+            RETURNING id
+        "#;
+        let result = diesel::sql_query(QUERY)
+            .bind::<Integer, _>(params.service_id)
+            .bind::<Text, _>(&params.node)
+            .bind::<Integer, _>(params.available)
+            .bind::<Integer, _>(params.current_load)
+            .bind::<Integer, _>(params.capacity)
+            .bind::<Integer, _>(params.downed)
+            .bind::<Integer, _>(params.backoff)
+            .bind::<Integer, _>(params.weight)
+            .bind::<Nullable<Text>, _>(&params.region)
+            .get_result::<results::PostNode>(&mut self.conn)
+            .await?;
+        Ok(result)
+    }
+
+    async fn get_node(&mut self, params: params::GetNode) -> DbResult<results::GetNode> {
+        const QUERY: &str = r#"
+            SELECT *
+              FROM nodes
+             WHERE id = ?
+        "#;
+
+        let result = diesel::sql_query(QUERY)
+            .bind::<Bigint, _>(params.id)
+            .get_result::<results::GetNode>(&mut self.conn)
+            .await?;
+        Ok(result)
+    }
+
+    async fn get_nodes(&mut self, params: params::GetNodes) -> DbResult<results::GetNodes> {
+        const QUERY: &str = r#"
+              SELECT *
+                FROM nodes
+               WHERE service = ?
+            ORDER BY id
+        "#;
+
+        let result = diesel::sql_query(QUERY)
+            .bind::<Integer, _>(params.service_id)
+            .load::<results::GetNode>(&mut self.conn)
+            .await?;
+        Ok(result)
+    }
+
+    async fn update_node(&mut self, params: params::UpdateNode) -> DbResult<results::UpdateNode> {
+        const QUERY: &str = r#"
+            UPDATE nodes
+               SET available = COALESCE(?, available),
+                   current_load = COALESCE(?, current_load),
+                   capacity = COALESCE(?, capacity),
+                   downed = COALESCE(?, downed),
+                   backoff = COALESCE(?, backoff),
+                   weight = COALESCE(?, weight),
+                   region = CASE WHEN ? THEN ? ELSE region END
+             WHERE id = ?
+        "#;
+
+        diesel::sql_query(QUERY)
+            .bind::<Nullable<Integer>, _>(params.available)
+            .bind::<Nullable<Integer>, _>(params.current_load)
+            .bind::<Nullable<Integer>, _>(params.capacity)
+            .bind::<Nullable<Integer>, _>(params.downed)
+            .bind::<Nullable<Integer>, _>(params.backoff)
+            .bind::<Nullable<Integer>, _>(params.weight)
+            .bind::<Bool, _>(params.region.is_some())
+            .bind::<Nullable<Text>, _>(params.region.flatten())
+            .bind::<Bigint, _>(params.id)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn unassign_node(
+        &mut self,
+        params: params::UnassignNode,
+    ) -> DbResult<results::UnassignNode> {
+        const QUERY: &str = r#"
+            UPDATE users
+               SET replaced_at = ?
+             WHERE nodeid = ?
+        "#;
+
+        let current_time = Utc::now().timestamp_millis();
+
+        diesel::sql_query(QUERY)
+            .bind::<Bigint, _>(current_time)
+            .bind::<Bigint, _>(params.node_id)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn drain_node(&mut self, params: params::DrainNode) -> DbResult<results::DrainNode> {
+        const QUERY: &str = r#"
+            UPDATE users
+               SET replaced_at = ?
+             WHERE uid IN (
+                      SELECT uid
+                        FROM users
+                       WHERE nodeid = ?
+                         AND replaced_at IS NULL
+                    ORDER BY uid
+                       LIMIT ?
+                   )
+        "#;
+
+        let drained = diesel::sql_query(QUERY)
+            .bind::<Bigint, _>(params.replaced_at)
+            .bind::<Bigint, _>(params.node_id)
+            .bind::<Bigint, _>(params.limit)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(drained)
+    }
+
+    async fn get_node_loads(
+        &mut self,
+        params: params::GetNodeLoads,
+    ) -> DbResult<results::GetNodeLoads> {
+        const QUERY: &str = r#"
+               SELECT nodes.id, nodes.node, nodes.available, nodes.current_load,
+                      nodes.capacity, nodes.downed, nodes.backoff, nodes.weight, nodes.region,
+                      COUNT(users.uid) AS current_users
+                 FROM nodes
+            LEFT JOIN users ON users.nodeid = nodes.id AND users.replaced_at IS NULL
+                WHERE nodes.service = ?
+             GROUP BY nodes.id
+             ORDER BY nodes.id
+        "#;
+
+        let result = diesel::sql_query(QUERY)
+            .bind::<Integer, _>(params.service_id)
+            .load::<results::NodeLoad>(&mut self.conn)
+            .await?;
+        Ok(result)
+    }
+
+    async fn remove_node(&mut self, params: params::RemoveNode) -> DbResult<results::RemoveNode> {
+        const QUERY: &str = "DELETE FROM nodes WHERE id = ?";
+
+        diesel::sql_query(QUERY)
+            .bind::<Bigint, _>(params.node_id)
+            .execute(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+
+    #[cfg(debug_assertions)]
+    async fn post_service(
+        &mut self,
+        params: params::PostService,
+    ) -> DbResult<results::PostService> {
+        const INSERT_SERVICE_QUERY: &str = r#"
+            INSERT INTO services (service, pattern)
+            VALUES (?, ?)
+            RETURNING id
+        "#;
+
+        let result = diesel::sql_query(INSERT_SERVICE_QUERY)
+            .bind::<Text, _>(&params.service)
+            .bind::<Text, _>(&params.pattern)
+            .get_result::<results::PostService>(&mut self.conn)
+            .await?;
+        Ok(result)
+    }
+
+    #[cfg(debug_assertions)]
+    fn set_spanner_node_id(&mut self, params: params::SpannerNodeId) {
+        self.spanner_node_id = params;
+    }
+}
+
+/// A candidate node for `get_best_node`
+#[derive(QueryableByName)]
+struct CandidateNode {
+    #[diesel(sql_type = Bigint)]
+    id: i64,
+    #[diesel(sql_type = Text)]
+    node: String,
+    #[diesel(sql_type = Integer)]
+    current_load: i32,
+    #[diesel(sql_type = Integer)]
+    capacity: i32,
+    #[diesel(sql_type = Integer)]
+    weight: i32,
+    #[diesel(sql_type = Nullable<Text>)]
+    region: Option<String>,
+}
+
+impl CandidateNode {
+    /// Matches MySQL's `LOG(current_load) / LOG(capacity)` ordering, where
+    /// empty nodes (`LOG(0)` is `NULL`) sort first, or its
+    /// `current_load / weight` ordering for the weighted policy
+    fn load(&self, policy: AllocationPolicy) -> f64 {
+        match policy {
+            AllocationPolicy::LeastLoaded if self.current_load == 0 => -1.0,
+            AllocationPolicy::LeastLoaded => {
+                f64::from(self.current_load).ln() / f64::from(self.capacity).ln()
+            }
+            AllocationPolicy::Weighted => f64::from(self.current_load) / f64::from(self.weight),
+        }
+    }
+
+    fn in_region(&self, region: Option<&str>) -> bool {
+        region.is_some() && self.region.as_deref() == region
+    }
+}
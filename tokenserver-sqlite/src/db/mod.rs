@@ -0,0 +1,40 @@
+use std::time::Duration;
+
+use syncserver_common::Metrics;
+use tokenserver_common::AllocationPolicy;
+
+use super::pool::Conn;
+
+mod db_impl;
+
+pub struct TokenserverSqliteDb {
+    conn: Conn,
+    metrics: Metrics,
+    service_id: Option<i32>,
+    spanner_node_id: Option<i32>,
+    allocation_policy: AllocationPolicy,
+    allocation_region: Option<String>,
+    pub timeout: Option<Duration>,
+}
+
+impl TokenserverSqliteDb {
+    pub fn new(
+        conn: Conn,
+        metrics: &Metrics,
+        service_id: Option<i32>,
+        spanner_node_id: Option<i32>,
+        allocation_policy: AllocationPolicy,
+        allocation_region: Option<String>,
+        timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            conn,
+            metrics: metrics.clone(),
+            service_id,
+            spanner_node_id,
+            allocation_policy,
+            allocation_region,
+            timeout,
+        }
+    }
+}
@@ -0,0 +1,8 @@
+#[macro_use]
+extern crate slog_scope;
+
+mod db;
+mod pool;
+
+pub use db::TokenserverSqliteDb;
+pub use pool::TokenserverSqlitePool;
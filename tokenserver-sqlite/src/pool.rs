@@ -0,0 +1,226 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use diesel::{ConnectionError, ConnectionResult, SqliteConnection};
+use diesel_async::{
+    SimpleAsyncConnection,
+    pooled_connection::{
+        AsyncDieselConnectionManager, ManagerConfig,
+        deadpool::{Object, Pool},
+    },
+    sync_connection_wrapper::SyncConnectionWrapper,
+};
+use diesel_migrations::{EmbeddedMigrations, embed_migrations};
+use syncserver_common::Metrics;
+#[cfg(debug_assertions)]
+use syncserver_db_common::test::test_transaction_hook;
+use syncserver_db_common::{
+    GetPoolStatus, establish_connection_with_logging, run_embedded_migrations,
+};
+use tokenserver_common::AllocationPolicy;
+use tokenserver_db_common::{Db, DbError, DbPool, DbResult, params};
+
+use tokenserver_settings::Settings;
+
+use crate::db::TokenserverSqliteDb;
+
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
+
+/// diesel's `SqliteConnection` is synchronous: diesel-async runs its queries
+/// on the blocking threadpool.
+pub(crate) type AsyncSqliteConnection = SyncConnectionWrapper<SqliteConnection>;
+
+pub(crate) type Conn = Object<AsyncSqliteConnection>;
+
+/// Applied to every new connection.
+///
+/// WAL allows readers to proceed concurrently with the (single) writer and
+/// `busy_timeout` makes writers wait on each other rather than immediately
+/// failing with `SQLITE_BUSY`.
+const CONNECTION_PRAGMAS: &str = "
+    PRAGMA journal_mode = WAL;
+    PRAGMA synchronous = NORMAL;
+    PRAGMA busy_timeout = 30000;
+";
+
+#[derive(Clone)]
+pub struct TokenserverSqlitePool {
+    /// Pool of db connections
+    inner: Pool<AsyncSqliteConnection>,
+    metrics: Metrics,
+    // This field is public so the service ID can be set after the pool is created
+    pub service_id: Option<i32>,
+    spanner_node_id: Option<i32>,
+    allocation_policy: AllocationPolicy,
+    allocation_region: Option<String>,
+    pub timeout: Option<Duration>,
+    run_migrations: bool,
+    database_path: String,
+    init_node_url: Option<String>,
+    init_node_capacity: i32,
+}
+
+impl TokenserverSqlitePool {
+    pub fn new(
+        settings: &Settings,
+        metrics: &Metrics,
+        _use_test_transactions: bool,
+    ) -> DbResult<Self> {
+        let database_path = database_path(&settings.database_url)?.to_owned();
+        let mut manager_config = ManagerConfig::<AsyncSqliteConnection>::default();
+        manager_config.custom_setup = Box::new(|path| Box::pin(establish_connection(path)));
+        let manager = AsyncDieselConnectionManager::<AsyncSqliteConnection>::new_with_config(
+            &database_path,
+            manager_config,
+        );
+
+        let wait = settings
+            .database_pool_connection_timeout
+            .map(|seconds| Duration::from_secs(seconds as u64));
+        let timeouts = deadpool::managed::Timeouts {
+            wait,
+            ..Default::default()
+        };
+        let config = deadpool::managed::PoolConfig {
+            max_size: settings.database_pool_max_size as usize,
+            timeouts,
+            ..Default::default()
+        };
+
+        let builder = Pool::builder(manager)
+            .config(config)
+            .runtime(deadpool::Runtime::Tokio1);
+        #[cfg(debug_assertions)]
+        let builder = if _use_test_transactions {
+            builder.post_create(deadpool::managed::Hook::async_fn(|conn, _| {
+                Box::pin(async { test_transaction_hook(conn).await })
+            }))
+        } else {
+            builder
+        };
+        let pool = builder.build().map_err(|e| {
+            DbError::internal(format!("Couldn't build Tokenserver SQLite Db Pool: {e}"))
+        })?;
+
+        let timeout = settings
+            .database_request_timeout
+            .map(|v| Duration::from_secs(v as u64));
+
+        Ok(Self {
+            inner: pool,
+            metrics: metrics.clone(),
+            spanner_node_id: settings.spanner_node_id,
+            allocation_policy: settings.allocation_policy,
+            allocation_region: settings.allocation_region.clone(),
+            service_id: None,
+            timeout,
+            run_migrations: settings.run_migrations,
+            database_path,
+            init_node_url: settings.init_node_url.clone(),
+            init_node_capacity: settings.init_node_capacity,
+        })
+    }
+
+    pub async fn get_tokenserver_db(&self) -> Result<TokenserverSqliteDb, DbError> {
+        Ok(TokenserverSqliteDb::new(
+            self.inner.get().await?,
+            &self.metrics,
+            self.service_id,
+            self.spanner_node_id,
+            self.allocation_policy,
+            self.allocation_region.clone(),
+            self.timeout,
+        ))
+    }
+
+    /// Cache the common "sync-1.5" service_id
+    async fn init_service_id(&mut self) -> Result<(), tokenserver_common::TokenserverError> {
+        let service_id = self
+            .get()
+            .await?
+            .get_service_id(params::GetServiceId {
+                service: "sync-1.5".to_owned(),
+            })
+            .await?;
+        self.service_id = Some(service_id.id);
+        Ok(())
+    }
+
+    /// Bootstrap the initial Sync 1.5 node record if init_node_url is set.
+    async fn init_sync15_node(&mut self, node_url: String, capacity: i32) -> Result<(), DbError> {
+        let node_added = self
+            .get()
+            .await?
+            .insert_sync15_node(params::Sync15Node {
+                node: node_url.clone(),
+                capacity,
+            })
+            .await?;
+        if node_added {
+            info!("Initialized syncstorage node entry, node: {node_url:?} capacity: {capacity}");
+        }
+        Ok(())
+    }
+}
+
+/// Convert a `sqlite://` `database_url` into the file path handed to SQLite,
+/// e.g. `sqlite:///var/lib/tokenserver.db` -> `/var/lib/tokenserver.db`.
+fn database_path(database_url: &str) -> DbResult<&str> {
+    database_url
+        .strip_prefix("sqlite://")
+        .filter(|path| !path.is_empty())
+        .ok_or_else(|| {
+            DbError::internal(format!(
+                "Invalid SQLite database_url (expected sqlite://<path>): {database_url}"
+            ))
+        })
+}
+
+/// Establish a connection configured via [CONNECTION_PRAGMAS]
+async fn establish_connection(path: &str) -> ConnectionResult<AsyncSqliteConnection> {
+    let mut conn = establish_connection_with_logging::<AsyncSqliteConnection>(path).await?;
+    conn.batch_execute(CONNECTION_PRAGMAS)
+        .await
+        .map_err(ConnectionError::CouldntSetupConfiguration)?;
+    Ok(conn)
+}
+
+#[async_trait(?Send)]
+impl DbPool for TokenserverSqlitePool {
+    async fn init(&mut self) -> Result<(), DbError> {
+        if self.run_migrations {
+            // Run on a separate conn so the migrations aren't wrapped in the
+            // pool's test transactions
+            let conn = establish_connection(&self.database_path).await?;
+            run_embedded_migrations(conn, MIGRATIONS).await?;
+        }
+
+        // NOTE: Provided there's a "sync-1.5" service record in the database, it is highly
+        // unlikely for this query to fail outside of random errors
+        let _ = self.init_service_id().await;
+
+        // Init the Sync 1.5 node record if init_node_url is set
+        if let Some(node_url) = self.init_node_url.clone() {
+            self.init_sync15_node(node_url, self.init_node_capacity)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn get(&self) -> Result<Box<dyn Db>, DbError> {
+        let mut metrics = self.metrics.clone();
+        metrics.start_timer("storage.get_pool", None);
+        Ok(Box::new(self.get_tokenserver_db().await?) as Box<dyn Db>)
+    }
+
+    fn box_clone(&self) -> Box<dyn DbPool> {
+        Box::new(self.clone())
+    }
+}
+
+impl GetPoolStatus for TokenserverSqlitePool {
+    fn status(&self) -> deadpool::Status {
+        self.inner.status()
+    }
+}